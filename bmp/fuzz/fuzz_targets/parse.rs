@@ -0,0 +1,33 @@
+// Fuzzing of BmpFile::parse, which must return an error on any malformed input rather than panic.
+// Run from the fuzz directory, with cargo-fuzz (https://github.com/rust-fuzz/cargo-fuzz):
+//   ./seed_corpus.sh
+//   cargo +nightly fuzz run parse
+// bmp being a binary crate, the modules needed for parsing are included from its sources.
+#![no_main]
+#![allow(dead_code)]
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/bitreader.rs"]
+mod bitreader;
+#[path = "../../src/bmp.rs"]
+mod bmp;
+#[cfg(any(feature = "jpeg", feature = "png"))]
+#[path = "../../src/embedded.rs"]
+mod embedded;
+#[path = "../../src/icc.rs"]
+mod icc;
+#[path = "../../src/image.rs"]
+mod image;
+#[path = "../../src/os2.rs"]
+mod os2;
+#[path = "../../src/reader.rs"]
+mod reader;
+
+// A few bytes of RLE or Huffman data can describe a huge image, which is only limited to a billion
+// pixels by default. Those would just find the memory limit of the fuzzer.
+const MAX_PIXELS: usize = 1 << 24;
+
+fuzz_target!(|data: &[u8]| {
+    let options = bmp::DecodeOptions{ max_pixels: Some(MAX_PIXELS), ..Default::default() };
+    let _ = bmp::BmpFile::parse_with_options(data, options);
+});
@@ -0,0 +1,10 @@
+#!/bin/sh
+# Seeds the corpus of the parse target with the bitmaps of the test suites (see ../tests/README.txt).
+
+cd `dirname $0`
+mkdir -p corpus/parse
+find ../tests/ -iname \*.bmp | while read file
+do
+  cp "$file" corpus/parse/`echo "$file" | sed 's|^\.\./tests/||; s|/|_|g'`
+done
+ls corpus/parse | wc -l
@@ -2,13 +2,20 @@
 
 echo -n "" > crashing.txt
 echo -n "" > not_crashing.txt
+rm -f errors_*.txt
 find tests/ -name \*.bmp | while read file
 do
   echo "$file"
-  cargo run --quiet parse $file > /dev/null
-  if [ $? -eq 0 ]
+  # Parse errors are printed on stderr as 'Couldn't parse "<file>": <cause>: <details>'
+  error=`cargo run --quiet parse $file 2>&1 > /dev/null`
+  status=$?
+  if [ $status -eq 0 ]
   then
     echo $file >> not_crashing.txt
+  elif [ $status -eq 1 ]
+  then
+    cause=`echo "$error" | sed -n 's/^Couldn.t parse "[^"]*": \([a-z_]*\): .*/\1/p'`
+    echo $file >> errors_${cause:-unknown}.txt
   else
     echo $file >> crashing.txt
   fi
@@ -0,0 +1,102 @@
+use std::io;
+use std::io::Write;
+
+pub struct BitWriter<T> {
+    write: T,
+    current: u8,
+    mask: u8,
+}
+
+impl <T: Write> BitWriter<T> {
+    pub fn new(write: T) -> BitWriter<T> {
+        BitWriter {
+            write,
+            current: 0,
+            mask: 1 << 7,
+        }
+    }
+
+    pub fn write_bit(&mut self, bit: bool) -> io::Result<()> {
+        if bit {
+            self.current |= self.mask;
+        }
+        self.mask >>= 1;
+
+        if self.mask == 0 {
+            self.write_current_byte()?;
+        }
+        Ok(())
+    }
+
+    // Writes the `length` lowest bits of `value`, most significant bit first.
+    pub fn write_bits(&mut self, value: u32, length: usize) -> io::Result<()> {
+        for i in (0..length).rev() {
+            self.write_bit((value >> i) & 1 != 0)?;
+        }
+        Ok(())
+    }
+
+    // Writes the pending bits, if any, padding the last byte with zeros.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.mask != 1 << 7 {
+            self.write_current_byte()?;
+        }
+        self.write.flush()
+    }
+
+    fn write_current_byte(&mut self) -> io::Result<()> {
+        self.write.write_all(&[self.current])?;
+
+        self.current = 0;
+        self.mask = 1 << 7;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitreader::BitReader;
+
+    #[test]
+    fn test_empty_writer() {
+        let mut buf = vec!();
+        let mut bit_writer = BitWriter::new(&mut buf);
+        bit_writer.flush().unwrap();
+
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_writer() {
+        let mut buf = vec!();
+        let mut bit_writer = BitWriter::new(&mut buf);
+
+        for _ in 0..4 {
+            bit_writer.write_bit(true).unwrap();
+            bit_writer.write_bit(false).unwrap();
+        }
+        bit_writer.write_bits(0b11110000, 8).unwrap();
+        bit_writer.write_bits(0b111, 3).unwrap();
+        bit_writer.flush().unwrap();
+
+        assert_eq!(buf, vec!(0b10101010, 0b11110000, 0b11100000));
+    }
+
+    #[test]
+    fn test_write_then_read() {
+        let mut buf = vec!();
+        let mut bit_writer = BitWriter::new(&mut buf);
+        let bits = (0..100).map(|i| i % 3 == 0 || i % 7 == 0).collect::<Vec<_>>();
+        for b in bits.iter() {
+            bit_writer.write_bit(*b).unwrap();
+        }
+        bit_writer.flush().unwrap();
+
+        let mut bit_reader = BitReader::new(buf.as_slice());
+        for b in bits.iter() {
+            assert_eq!(bit_reader.read_bit().unwrap(), Some(*b));
+        }
+    }
+}
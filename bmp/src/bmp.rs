@@ -1,18 +1,24 @@
 extern crate nom;
 
-use crate::bitreader::BitReader;
+use crate::icc::IccProfile;
+use crate::image::{Image, Indexed};
+use crate::reader::BmpReader;
 use num_enum::TryFromPrimitive;
 use sdl2::gfx::primitives::ToColor;
 use std::collections::HashMap;
 use std::convert::TryFrom;
 
 // cf. https://en.wikipedia.org/wiki/BMP_file_format
-#[derive(Debug, Default, PartialEq)]
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct BmpFile {
     pub magic: Magic,
     pub filesize: u32,
+    // For icons and pointers, stored in the reserved field.
+    pub hotspot: Option<(u16, u16)>,
     pub offset: u32,
     pub dib_header_size: DibHeaderSize,
+    // Only for 12 bytes headers.
+    pub core_header: Option<CoreHeader>,
     pub width: i32,
     pub height: i32,
     pub planes: u16,
@@ -27,13 +33,19 @@ pub struct BmpFile {
     pub colors_in_table: u32,
     pub color_space_type: ColorSpaceType,
     pub intent: Option<IntentType>,
+    // Position of the profile relative to the start of the DIB header, and its size.
+    pub profile_data: u32,
+    pub profile_size: u32,
+    pub icc_profile: Option<IccProfile>,
+    // Linked profiles are not loaded: the path is on the machine that wrote the file.
+    pub linked_profile: Option<String>,
 
     pub palette: Vec<Color>,
     pub data: Vec<u8>,
-    pub pixels: Vec<Vec<Color>>,
+    pub image: Image,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Bitmasks {
     pub red_mask: u32,
     pub red_shift: u32,
@@ -45,21 +57,47 @@ pub struct Bitmasks {
     pub alpha_shift: u32,
 }
 
-#[derive(Debug, PartialEq)]
+impl Bitmasks {
+    pub fn from_masks(red_mask: u32, green_mask: u32, blue_mask: u32, alpha_mask: u32) -> Self {
+        Bitmasks {
+            red_mask,
+            red_shift: shift_from_mask(red_mask),
+            green_mask,
+            green_shift: shift_from_mask(green_mask),
+            blue_mask,
+            blue_shift: shift_from_mask(blue_mask),
+            alpha_mask,
+            alpha_shift: shift_from_mask(alpha_mask),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Endpoint {
     pub x: f64,
     pub y: f64,
     pub z: f64,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Endpoints {
     pub red: Endpoint,
     pub green: Endpoint,
     pub blue: Endpoint,
 }
 
-#[derive(Debug, PartialEq)]
+impl Endpoints {
+    // XYZ of the white, all the primaries at full intensity.
+    pub fn white(&self) -> [f64; 3] {
+        [
+            self.red.x + self.green.x + self.blue.x,
+            self.red.y + self.green.y + self.blue.y,
+            self.red.z + self.green.z + self.blue.z,
+        ]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Gammas {
     pub red: f64,
     pub green: f64,
@@ -70,11 +108,11 @@ pub struct Gammas {
 #[repr(u16)]
 pub enum Magic {
     BM = 0x4d42,
-    // TODO: BA has an extra header before the regular BM one:
+    // OS/2 bitmap array, see os2.rs: BmpFile::parse returns its first image, parse_array all of
+    // them.
     // https://www.fileformat.info/format/os2bmp/egff.htm#X058-9-OS2BMP-FG-2
-    // I guess we should either skip the header and read the first BM, or read all the BMs and have
-    // a vec of pictures?
     BA = 0x4142,
+    // OS/2 color icon and pointer, and monochrome icon and pointer.
     CI = 0x4943,
     CP = 0x5043,
     IC = 0x4349,
@@ -88,10 +126,8 @@ impl Default for Magic {
 #[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
 #[repr(u32)]
 pub enum DibHeaderSize {
+    // Also OS21XBITMAPHEADER, see CoreHeader.
     BITMAPCOREHEADER = 12,
-    // TODO: Understand how we're supposed to differentiate between this one and the previous one
-    // ...
-    //OS21XBITMAPHEADER = 12,
     OS22XBITMAPHEADER16 = 16,
     OS22XBITMAPHEADER64 = 64,
     BITMAPINFOHEADER = 40,
@@ -105,6 +141,14 @@ impl Default for DibHeaderSize {
     fn default() -> Self { DibHeaderSize::BITMAPCOREHEADER }
 }
 
+// 12 bytes headers have the same layout in OS/2 1.x (OS21XBITMAPHEADER) and Windows 2.x
+// (BITMAPCOREHEADER), but the dimensions are unsigned in the former and signed in the latter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoreHeader {
+    Os21x,
+    Windows2x,
+}
+
 const OUT_OF_U32: u64 = u32::MAX as u64 + 1;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
@@ -163,6 +207,43 @@ pub enum IntentType {
     LCS_GM_ABS_COLORIMETRIC = 8,
 }
 
+impl IntentType {
+    // Whether the white of the source is mapped to the white of sRGB. The color spaces of BMP
+    // files are matrix based and have no gamut mapping, so as with ICC matrix/TRC profiles the
+    // perceptual (images) and saturation (business) intents are relative colorimetric (graphics).
+    pub fn adapts_white_point(self) -> bool {
+        self != IntentType::LCS_GM_ABS_COLORIMETRIC
+    }
+}
+
+// How the colors of the file are converted to sRGB, and which images are decoded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DecodeOptions {
+    // Return the values of the file as they are, ignoring its color space.
+    pub raw: bool,
+    // Used instead of the intent of the file, which defaults to perceptual.
+    pub intent: Option<IntentType>,
+    // Used instead of guessing the kind of 12 bytes headers.
+    pub core_header: Option<CoreHeader>,
+    // Images with more pixels are refused by BmpFile, which holds them in memory, None for no
+    // limit. BmpReader processes images of any size row by row.
+    pub max_pixels: Option<usize>,
+}
+
+// A billion pixels, 4GB once decoded.
+pub const DEFAULT_MAX_PIXELS: usize = 1024*1024*1024;
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        DecodeOptions {
+            raw: false,
+            intent: None,
+            core_header: None,
+            max_pixels: Some(DEFAULT_MAX_PIXELS),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
 pub struct HuffmanCodeWord {
     pub length: usize,
@@ -181,14 +262,14 @@ pub enum HuffmanColor {
 }
 
 impl HuffmanColor {
-    fn next(&self) -> Self {
+    pub fn next(&self) -> Self {
 	match self {
 	    HuffmanColor::White => HuffmanColor::Black,
 	    HuffmanColor::Black => HuffmanColor::White,
 	}
     }
 
-    fn pal_entry(&self) -> usize {
+    pub fn pal_entry(&self) -> usize {
         match self {
 	    HuffmanColor::White => 0,
 	    HuffmanColor::Black => 1,
@@ -196,7 +277,7 @@ impl HuffmanColor {
     }
 }
 
-fn huffman_table(color: &HuffmanColor) -> HashMap<HuffmanCodeWord, HuffmanCommand> {
+pub fn huffman_table(color: &HuffmanColor) -> HashMap<HuffmanCodeWord, HuffmanCommand> {
     // TODO: RTC is 6x EOL. Should we handle it here?
     match color {
 	HuffmanColor::White => [
@@ -448,6 +529,99 @@ impl ToColor for Color {
 pub type Input<'a> = &'a [u8];
 pub type Error<'a> = nom::error::VerboseError<Input<'a>>;
 pub type Result<'a, O> = nom::IResult<Input<'a>, O, Error<'a>>;
+pub type BmpResult<T> = std::result::Result<T, BmpError>;
+
+// Size of the bitmap file header, which is followed by the DIB header size.
+pub const FILE_HEADER_SIZE: usize = 14;
+
+// Why a file couldn't be decoded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BmpErrorKind {
+    // The file ends before the end of a header, the palette or the compressed data.
+    Truncated,
+    // The value of a header field is not one we know (e.g unknown compression).
+    InvalidValue,
+    DataOffsetTooLarge{ offset: u32, file_size: usize },
+    // With the headers, which are still worth showing.
+    ImageTooLarge{ width: i32, height: i32, header: Box<BmpFile> },
+    UnsupportedBpp{ bpp: u16, compression: Option<CompressionMethod> },
+    // The compression is valid but support for it was not built in.
+    #[cfg_attr(all(feature = "jpeg", feature = "png"), allow(dead_code))]
+    MissingFeature(&'static str),
+    MissingData{ expected: usize, available: usize },
+    ColorOutsideOfPalette{ index: usize, palette_size: usize },
+    PixelOutsideOfImage{ x: i32, y: i32 },
+    InvalidHuffmanCode(HuffmanCodeWord),
+    #[cfg_attr(not(any(feature = "jpeg", feature = "png")), allow(dead_code))]
+    EmbeddedImage(String),
+    #[cfg_attr(not(any(feature = "jpeg", feature = "png")), allow(dead_code))]
+    EmbeddedImageSize{ width: usize, height: usize },
+    Io(String),
+}
+
+impl BmpErrorKind {
+    // Short name of the cause, stable enough to be used by scripts.
+    pub fn cause(&self) -> &'static str {
+        match self {
+            BmpErrorKind::Truncated => "truncated",
+            BmpErrorKind::InvalidValue => "invalid_value",
+            BmpErrorKind::DataOffsetTooLarge{..} => "data_offset_too_large",
+            BmpErrorKind::ImageTooLarge{..} => "image_too_large",
+            BmpErrorKind::UnsupportedBpp{..} => "unsupported_bpp",
+            BmpErrorKind::MissingFeature(_) => "missing_feature",
+            BmpErrorKind::MissingData{..} => "missing_data",
+            BmpErrorKind::ColorOutsideOfPalette{..} => "color_outside_of_palette",
+            BmpErrorKind::PixelOutsideOfImage{..} => "pixel_outside_of_image",
+            BmpErrorKind::InvalidHuffmanCode(_) => "invalid_huffman_code",
+            BmpErrorKind::EmbeddedImage(_) => "embedded_image",
+            BmpErrorKind::EmbeddedImageSize{..} => "embedded_image_size",
+            BmpErrorKind::Io(_) => "io",
+        }
+    }
+}
+
+impl std::fmt::Display for BmpErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BmpErrorKind::Truncated => write!(f, "unexpected end of file"),
+            BmpErrorKind::InvalidValue => write!(f, "invalid value"),
+            BmpErrorKind::DataOffsetTooLarge{offset, file_size} => write!(f, "data offset {} is past the end of the file ({} bytes)", offset, file_size),
+            BmpErrorKind::ImageTooLarge{width, height, ..} => write!(f, "image too large ({}x{})", width, height),
+            BmpErrorKind::UnsupportedBpp{bpp, compression} => write!(f, "unsupported bpp {} for compression {:?}", bpp, compression),
+            BmpErrorKind::MissingFeature(feature) => write!(f, "built without the {} feature", feature),
+            BmpErrorKind::MissingData{expected, available} => write!(f, "expected {} bytes of data but only {} are available", expected, available),
+            BmpErrorKind::ColorOutsideOfPalette{index, palette_size} => write!(f, "color {} outside of palette of {} colors", index, palette_size),
+            BmpErrorKind::PixelOutsideOfImage{x, y} => write!(f, "pixel ({}, {}) outside of image", x, y),
+            BmpErrorKind::InvalidHuffmanCode(code) => write!(f, "invalid Huffman code {:0width$b}", code.value, width = code.length),
+            BmpErrorKind::EmbeddedImage(e) => write!(f, "couldn't decode embedded image: {}", e),
+            BmpErrorKind::EmbeddedImageSize{width, height} => write!(f, "embedded image is {}x{}, which doesn't match the header", width, height),
+            BmpErrorKind::Io(e) => write!(f, "read error: {}", e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BmpError {
+    pub kind: BmpErrorKind,
+    // The header field or the part of the file where the error was found.
+    pub field: &'static str,
+    // Position in the file of the offending byte.
+    pub offset: usize,
+    // None if the error happened before the DIB header size could be read.
+    pub header: Option<DibHeaderSize>,
+}
+
+impl std::fmt::Display for BmpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {} in {} at byte {}", self.kind.cause(), self.kind, self.field, self.offset)?;
+        if let Some(header) = self.header {
+            write!(f, " ({:?})", header)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for BmpError {}
 
 impl Color {
     fn parse(input: Input, on4bytes: bool) -> Result<Self> {
@@ -467,6 +641,34 @@ impl Color {
         }
         Ok((input, Self{red, green, blue, alpha: 255}))
     }
+
+    // CMYK colors are stored as a CMYK() COLORREF, i.e. a little endian u32 with cyan in the most
+    // significant byte.
+    fn parse_cmyk(input: Input) -> Result<Self> {
+        use nom::{
+            error::context,
+                number::complete::le_u8,
+                sequence::tuple,
+        };
+        let (input, (black, yellow, magenta, cyan)) = tuple((
+                    context("Black", le_u8),
+                    context("Yellow", le_u8),
+                    context("Magenta", le_u8),
+                    context("Cyan", le_u8),
+                    ))(input)?;
+        Ok((input, denormalize(cmyk_to_rgb(cyan, magenta, yellow, black))))
+    }
+}
+
+// From https://www.fileformat.info/format/bmp/egff.htm#MICBMP-DMYID.3.6:
+// The Compression field must always be a value of 3 (bitfields encoding) when a file stores 16-bit
+// data.
+// And yet other places mention a default 16 bits masks of RGB-555
+pub fn default_bitmasks(bpp: u16) -> Bitmasks {
+    match bpp {
+        16 => Bitmasks::from_masks(0x7c00, 0x3e0, 0x1f, 0),
+        _ => Bitmasks::from_masks(0xff0000, 0x00ff00, 0x0000ff, 0),
+    }
 }
 
 // TODO: Review which functions should be pub
@@ -540,6 +742,17 @@ pub fn unapply_gammas(color: Colorf, gammas: &Gammas) -> Colorf {
     }
 }
 
+// Naive conversion, there's no way to specify a CMYK profile in a BMP.
+pub fn cmyk_to_rgb(cyan: u8, magenta: u8, yellow: u8, black: u8) -> Colorf {
+    let white = 1.0 - normalize_u8(black);
+    Colorf {
+        red: (1.0 - normalize_u8(cyan)) * white,
+        green: (1.0 - normalize_u8(magenta)) * white,
+        blue: (1.0 - normalize_u8(yellow)) * white,
+        alpha: 1.0,
+    }
+}
+
 pub fn to_xyz(color: Colorf, endpoints: &Endpoints) -> Colorf {
     let x = endpoints.red.x*color.red + endpoints.green.x*color.green + endpoints.blue.x*color.blue;
     let y = endpoints.red.y*color.red + endpoints.green.y*color.green + endpoints.blue.y*color.blue;
@@ -568,6 +781,34 @@ pub fn xyz_to_srgb(color: Colorf) -> Colorf {
     Colorf { red, green, blue, alpha: color.alpha }
 }
 
+// CIE XYZ of the D65 white point of sRGB.
+pub const D65: [f64; 3] = [0.95047, 1.0, 1.08883];
+
+// From XYZ to the cone response domain, and back.
+// cf. http://www.brucelindbloom.com/index.html?Eqn_ChromAdapt.html
+const BRADFORD: [[f64; 3]; 3] = [
+    [0.8951, 0.2664, -0.1614],
+    [-0.7502, 1.7135, 0.0367],
+    [0.0389, -0.0685, 1.0296],
+];
+const BRADFORD_INVERSE: [[f64; 3]; 3] = [
+    [0.9869929, -0.1470543, 0.1599627],
+    [0.4323053, 0.5183603, 0.0492912],
+    [-0.0085287, 0.0400428, 0.9684867],
+];
+
+fn multiply(matrix: &[[f64; 3]; 3], v: [f64; 3]) -> [f64; 3] {
+    matrix.map(|row| row[0]*v[0] + row[1]*v[1] + row[2]*v[2])
+}
+
+// Bradford chromatic adaptation of an XYZ color, so that the white `from` becomes `to`.
+pub fn adapt_white_point(color: Colorf, from: [f64; 3], to: [f64; 3]) -> Colorf {
+    let (from, to) = (multiply(&BRADFORD, from), multiply(&BRADFORD, to));
+    let cone = multiply(&BRADFORD, [color.red, color.green, color.blue]);
+    let [x, y, z] = multiply(&BRADFORD_INVERSE, [0, 1, 2].map(|i| cone[i] * to[i] / from[i]));
+    Colorf { red: x, green: y, blue: z, alpha: color.alpha }
+}
+
 #[allow(non_snake_case)]
 pub fn xyY_to_xyz(color: Colorf) -> Colorf {
     if color.green == 0.0 {
@@ -600,21 +841,24 @@ impl <'a>BmpFile {
     pub fn parse_file_header(&mut self, input: Input<'a>) -> Result<'a, ()> {
         //println!("parse_file_header: {:?}", &input[0..64]);
         use nom::{
-            bytes::complete::take,
             combinator::map_res,
             error::context,
             number::complete::{le_u16, le_u32},
             sequence::tuple,
         };
         // Bitmap file header (https://en.wikipedia.org/wiki/BMP_file_format#Bitmap_file_header)
-        let (i, (magic, filesize, _, offset)) = tuple((
+        let (i, (magic, filesize, hotspot, offset)) = tuple((
             context("Magic", map_res(le_u16, |x| Magic::try_from(x))),
             context("FileSize", le_u32),
-            context("Reserved", take(4_u8)),
+            context("Reserved", tuple((le_u16, le_u16))),
             context("Offset", le_u32),
         ))(input)?;
         self.magic = magic;
         self.filesize = filesize;
+        self.hotspot = match magic {
+            Magic::CI | Magic::CP | Magic::IC | Magic::PT => Some(hotspot),
+            _ => None,
+        };
         self.offset = offset;
         Ok((i, ()))
     }
@@ -631,20 +875,19 @@ impl <'a>BmpFile {
         Ok((i, ()))
     }
 
-    pub fn parse_i16_width_and_height(&mut self, input: Input<'a>) -> Result<'a, ()> {
-        //println!("parse_i16_width_and_height: {:?}", &input[0..64]);
+    // Raw values, see set_core_header.
+    pub fn parse_u16_width_and_height(&mut self, input: Input<'a>) -> Result<'a, ()> {
         use nom::{
-            combinator::{map, verify},
             error::context,
-            number::complete::le_i16,
+            number::complete::le_u16,
             sequence::tuple,
         };
         let (i, (width, height)) = tuple((
-                context("Image Width (i16)", verify(map(le_i16, |x| x as i32), |x| *x >= 0)),
-                context("Image Height (i16)", map(le_i16, |x| x as i32)),
+                context("Image Width (u16)", le_u16),
+                context("Image Height (u16)", le_u16),
         ))(input)?;
-        self.width = width;
-        self.height = height;
+        self.width = width as i32;
+        self.height = height as i32;
         Ok((i, ()))
     }
 
@@ -680,11 +923,12 @@ impl <'a>BmpFile {
         self.planes = planes;
         self.bpp = bpp;
         // Will be overriden later if present in the header
-        self.image_size = (self.width as i64*self.height as i64*self.bpp as i64) as u64/8;
+        self.image_size = self.default_image_size();
         // https://www.fileformat.info/format/bmp/egff.htm#MICBMP-DMYID.3.6
         // One-, 4-, and 8-bit BMP files are expected to always contain a color palette. Sixteen-,
         // 24-, and 32-bit BMP files never contain color palettes.
-        if self.bpp <= 8 {
+        // BI_JPEG and BI_PNG have a bpp of 0 and no palette.
+        if self.bpp > 0 && self.bpp <= 8 {
             // Will be overriden later if present in the header
             self.colors_in_table = 2_u32.pow(self.bpp as u32)
         }
@@ -824,7 +1068,7 @@ impl <'a>BmpFile {
             context("Gamma for blue channel", map(le_u32, |x| fixpt16_16(x))),
         ))(input)?;
         self.color_space_type = cs_type;
-        // TODO: Waht if all endpoints are 0?
+        // All zero endpoints, which have no white, are ignored by to_srgb.
         if cs_type == ColorSpaceType::LCS_CALIBRATED_RGB {
             self.endpoints = Some(Endpoints{
                 red: Endpoint{x: red_x, y: red_y, z: red_z},
@@ -848,7 +1092,7 @@ impl <'a>BmpFile {
             sequence::tuple,
         };
         let (i, (
-                    intent, _icc_data, _icc_size, _reserved,
+                    intent, profile_data, profile_size, _reserved,
         )) = tuple((
             context("Intent", map_res(le_u32, |x| IntentType::try_from(x))),
             context("ICC profile data", le_u32),
@@ -856,9 +1100,38 @@ impl <'a>BmpFile {
             context("Reserved", le_u32),
         ))(input)?;
         self.intent = if intent != IntentType::UNKNOWN { Some(intent) } else { None };
+        self.profile_data = profile_data;
+        self.profile_size = profile_size;
         Ok((i, ()))
     }
 
+    // Position of the profile in the file. It's usually after the pixels, so it's not part of the
+    // headers.
+    pub fn profile_range(&self) -> Option<std::ops::Range<usize>> {
+        match self.color_space_type {
+            ColorSpaceType::LCS_PROFILE_EMBEDDED | ColorSpaceType::LCS_PROFILE_LINKED if self.profile_size > 0 => {
+                let start = FILE_HEADER_SIZE + self.profile_data as usize;
+                Some(start..start + self.profile_size as usize)
+            },
+            _ => None,
+        }
+    }
+
+    pub fn set_profile(&mut self, profile: &[u8]) {
+        match self.color_space_type {
+            // Profiles we don't support (e.g LUT based or CMYK) are ignored and pixels are used as
+            // is, which is still better than refusing to display the image.
+            ColorSpaceType::LCS_PROFILE_EMBEDDED => {
+                self.icc_profile = IccProfile::parse(profile).ok().map(|(_, p)| p);
+            },
+            // Null terminated path, in Windows-1252 which is close enough to Latin-1.
+            ColorSpaceType::LCS_PROFILE_LINKED => {
+                self.linked_profile = Some(profile.iter().take_while(|c| **c != 0).map(|c| *c as char).collect());
+            },
+            _ => {},
+        }
+    }
+
     fn parse_os2_64_part(&mut self, input: Input<'a>) -> Result<'a, ()> {
         //println!("parse_os2_64_part: {:?}", &input[0..64]);
         use nom::{
@@ -882,536 +1155,677 @@ impl <'a>BmpFile {
         Ok((i, ()))
     }
 
-    fn parse_colors(&mut self, input: Input<'a>, nb_colors: u32) -> Result<'a, ()> {
+    pub fn parse_colors(&mut self, input: Input<'a>, nb_colors: u32) -> Result<'a, ()> {
         let mut i = input;
         for _ in 0..nb_colors {
-            let (j, color) = Color::parse(i, self.dib_header_size != DibHeaderSize::BITMAPCOREHEADER)?;
+            let (j, color) = if self.is_cmyk() {
+                Color::parse_cmyk(i)?
+            } else {
+                Color::parse(i, self.dib_header_size != DibHeaderSize::BITMAPCOREHEADER)?
+            };
             self.palette.push(color);
             i = j;
         }
         Ok((i, ()))
     }
 
-    fn to_srgb(&self, color: Color) -> Color {
-        if self.gammas == None && self.endpoints == None {
+    pub fn is_cmyk(&self) -> bool {
+        matches!(self.compression,
+                 Some(CompressionMethod::BI_CMYK) |
+                 Some(CompressionMethod::BI_CMYKRLE4) |
+                 Some(CompressionMethod::BI_CMYKRLE8))
+    }
+
+    // Calibrated RGB goes through XYZ: the gammas linearize the values, and the endpoints are
+    // the XYZ of the primaries, their sum being the white of the file.
+    pub fn to_srgb(&self, color: Color, options: &DecodeOptions) -> Color {
+        if options.raw {
             return color;
         }
-        let mut colorf = normalize(color);
-        /*
-        if let Some(endpoints) = &self.endpoints {
-            colorf = to_xyz(colorf, endpoints);
-            colorf = xyz_to_srgb(colorf);
+        let adapt = options.intent.or(self.intent).is_none_or(|intent| intent.adapts_white_point());
+        if let Some(profile) = &self.icc_profile {
+            return denormalize(profile.to_srgb(normalize(color), adapt));
+        }
+        // The gammas of the V4 part are only meaningful with LCS_CALIBRATED_RGB.
+        let endpoints = match &self.endpoints {
+            Some(endpoints) => endpoints,
+            None => return color,
+        };
+        let white = endpoints.white();
+        if white[1] <= 0.0 {
+            return color;
         }
-        */
+        let mut colorf = normalize(color);
         if let Some(gammas) = &self.gammas {
             colorf = apply_gammas(colorf, gammas);
         }
-        if let Some(endpoints) = &self.endpoints {
-            colorf = to_xyz(colorf, endpoints);
-            // It turns out xyz in BMP is actually xyY. Either the specs are wrong and it should
-            // read xyY instead of XYZ, or the example I found is wrong (as well as firefox &
-            // chrome implementations). The latter is not that unlikely considering BMP with gamma
-            // are rare and both implementations used the said example to test their code.
-            //colorf = xyY_to_xyz(colorf);
-            colorf = xyz_to_srgb(colorf);
-            // TODO: Remove this: this is just a test
-            colorf.red = 210.0 * colorf.red / 255.0;
-            colorf.green = 255.0 * colorf.green / 236.0;
-            colorf.blue = 255.0 * colorf.blue / 235.0;
-        }
-        // Unapply sRGB gamma as it will be applied by the display?
-        /*
-        if let Some(_) = &self.gammas {
-            colorf = unapply_gammas(colorf, &Gammas{ red: 2.2, green: 2.2, blue: 2.2 });
+        colorf = to_xyz(colorf, endpoints);
+        if adapt {
+            colorf = adapt_white_point(colorf, white, D65);
         }
-        */
-        denormalize(colorf)
+        denormalize(xyz_to_srgb(colorf))
     }
 
-    fn put_pixels(&mut self, x: &mut i32, y: &i32, idx: usize, length: usize) -> Result<'a, ()> {
-        if idx >= self.palette.len() {
-            // TODO: Better error (e.g color outside of palette)
-            return Err(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::TooLarge)));
-        }
-        let color = self.palette[idx];
-        for _ in 0..length {
-            if *x as usize >= self.pixels.len() {
-                // TODO: Better error (e.g pixel outside of image)
-                return Err(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::TooLarge)));
-            }
-            if *y as usize > self.pixels[*x as usize].len() {
-                // TODO: Better error (e.g pixel outside of image)
-                return Err(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::TooLarge)));
-            }
-            self.pixels[*x as usize][*y as usize] = self.to_srgb(color);
-            *x += 1;
+    pub fn error(&self, kind: BmpErrorKind, field: &'static str, offset: usize) -> BmpError {
+        BmpError {
+            kind,
+            field,
+            offset,
+            header: Some(self.dib_header_size),
         }
-        Ok((&[], ()))
     }
 
-    fn put_24bpp_pixels(&mut self, x: &mut i32, y: &i32, color: Color, length: usize) -> Result<'a, ()> {
-        for _ in 0..length {
-            if *x as usize >= self.pixels.len() {
-                // TODO: Better error (e.g pixel outside of image)
-                return Err(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::TooLarge)));
-            }
-            if *y as usize > self.pixels[*x as usize].len() {
-                // TODO: Better error (e.g pixel outside of image)
-                return Err(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::TooLarge)));
-            }
-            self.pixels[*x as usize][*y as usize] = self.to_srgb(color);
-            *x += 1;
-        }
-        Ok((&[], ()))
-    }
-
-    fn pixels_from_rle(&mut self) -> Result<'a, ()> {
-        // Copying the data just to avoid data_iter borrowing self as well as put_pixels ...
-        // TODO: Avoid this copy ... Maybe by extracting data and pixels in different struct.
-        let data_copy = self.data.clone();
-        let mut data_iter = data_copy.iter();
-        let topdown = self.height < 0;
-        let mut y = if topdown { 0 } else { self.height-1 };
-        let dir_y = if topdown { 1 } else { -1 };
-        let mut x = 0;
-        while let Some(control) = data_iter.next() {
-            // TODO: Better error (e.g missing escape byte)
-            let escape = *data_iter.next().ok_or(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::TooLarge)))? as usize;
-            match control {
-                0 => {
-                    match escape {
-                        0 => {
-                            // end of line
-                            x = 0;
-                            y += dir_y;
-                        },
-                        1 => {
-                            // end of image
-                            return Ok((&[], ()));
-                        },
-                        2 => {
-                            // displacement mode
-                            // TODO: Better error (e.g missing x/y in displacement mode)
-                            let dx = *data_iter.next().ok_or(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::TooLarge)))? as i32;
-                            let dy = *data_iter.next().ok_or(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::TooLarge)))? as i32;
-                            x += dx;
-                            y += dir_y * dy;
-                        },
-                        pixels => {
-                            // absolute mode
-                            let bytes;
-                            match self.bpp {
-                                4 => {
-                                    bytes = (pixels + 1) / 2;
-                                    let mut i = 0;
-                                    while i < pixels {
-                                        let indexes = *data_iter.next().ok_or(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::TooLarge)))? as usize;
-                                        let idx = (indexes & 0xf0) >> 4;
-                                        self.put_pixels(&mut x, &y, idx, 1)?;
-                                        i += 1;
-                                        if i >= pixels {
-                                            break;
-                                        }
-                                        let idx = indexes & 0xf;
-                                        self.put_pixels(&mut x, &y, idx, 1)?;
-                                        i += 1;
-                                    }
-                                },
-                                8 => {
-                                    bytes = pixels;
-                                    for _ in 0..pixels {
-                                        // TODO: Better error (e.g missing pixel in absolute mode)
-                                        let idx = *data_iter.next().ok_or(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::TooLarge)))? as usize;
-                                        self.put_pixels(&mut x, &y, idx, 1)?;
-                                    }
-                                },
-                                24 => {
-                                    // TODO: Find examples of RLE24 to test this
-                                    bytes = pixels * 3;
-                                    for _ in 0..pixels {
-                                        // TODO: Better error (e.g missing pixel in absolute mode)
-                                        // I'm assuming bitmasks are not used with RLE24. I may be
-                                        // wrong ...
-                                        let blue = *data_iter.next().ok_or(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::TooLarge)))?;
-                                        let green = *data_iter.next().ok_or(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::TooLarge)))?;
-                                        let red = *data_iter.next().ok_or(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::TooLarge)))?;
-                                        self.put_24bpp_pixels(&mut x, &y, Color{red, green, blue, alpha: 255}, 1)?;
-                                    }
-                                },
-                                _ => {
-                                    // TODO: Better error (e.g unsupported bpp for RLE)
-                                    return Err(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::TooLarge)));
-                                },
-                            }
-                            if (bytes % 2) == 1 {
-                                // TODO: Better error (e.g missing padding in absolute mode)
-                                data_iter.next().ok_or(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::TooLarge)))?;
-                            }
-                        },
-                    }
-                },
-                length => {
-                    match self.bpp {
-                        4 => {
-                            let mut i = 0;
-                            let idx1 = (escape & 0xf0) >> 4;
-                            let idx2 = escape & 0xf;
-                            while i < *length {
-                                self.put_pixels(&mut x, &y, idx1, 1)?;
-                                i += 1;
-                                if i >= *length {
-                                    break;
-                                }
-                                self.put_pixels(&mut x, &y, idx2, 1)?;
-                                i += 1;
-                            }
-                        },
-                        8 => {
-                            self.put_pixels(&mut x, &y, escape, *length as usize)?;
-                        },
-                        24 => {
-                            let blue = escape as u8;
-                            let green = *data_iter.next().ok_or(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::TooLarge)))?;
-                            let red = *data_iter.next().ok_or(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::TooLarge)))?;
-                            self.put_24bpp_pixels(&mut x, &y, Color{red, green, blue, alpha: 255}, *length as usize)?;
-                        },
-                        // TODO: Better error (e.g unsupported bpp for RLE)
-                        _ => {
-                            return Err(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::TooLarge)));
-                        },
-                    }
-                },
-            }
+    // Position of the Bpp field in the file.
+    fn bpp_offset(&self) -> usize {
+        match self.dib_header_size {
+            // 16 bits width and height
+            DibHeaderSize::BITMAPCOREHEADER => FILE_HEADER_SIZE + 10,
+            _ => FILE_HEADER_SIZE + 14,
         }
-
-        Ok((&[], ()))
-    }
-
-    // CCITT Group 3 1-Dimensional (G31D) encoding, improperly referred to as Huffman about
-    // everywhere.
-    // Well described in http://zig.tgschultz.com/bmp_file_format.txt
-    // See https://www.itu.int/rec/T-REC-T.4-200307-I/en for specification
-    fn pixels_from_huffman1d(&mut self) -> Result<'a, ()> {
-	let mut color = HuffmanColor::White;
-        // TODO: Extract data and pixels in separate structures to avoid having to copy.
-        // This copy is done to workaround the borrow checker as data is borrowed here and pixels
-        // later.
-        let data = self.data.clone();
-        let mut bit_reader = BitReader::new(data.as_slice());
-        let mut nb_eol = 0;
-        let mut x = 0;
-        let mut y = self.height;
-        println!("Decoding {:?}", self.data);
-	loop {
-                println!("Color: {:?}", color.pal_entry());
-		let table = huffman_table(&color);
-                let mut value = 0 as u32;
-		let mut length = 0;
-		while length <= 13 {
-                    value <<= 1;
-                    match bit_reader.read_bit().unwrap() {
-                        Some(b) => {
-                            if b {
-                                value += 1;
-                            }
-                        },
-                        None => {
-                            break;
-                        }
-                    }
-		    length += 1;
-                    if table.contains_key(&HuffmanCodeWord{length, value}) {
-                        break;
-                    }
-		}
-                let code = HuffmanCodeWord{length, value};
-                let command = table.get(&code);
-                println!("Code: {:?}", code);
-                match command {
-                    Some(HuffmanCommand::Eol) => {
-                        println!("EOL");
-                        nb_eol += 1;
-                        color = HuffmanColor::White;
-                        x = 0;
-                        y -= 1;
-                    },
-                    Some(HuffmanCommand::Value(run_length)) => {
-                        nb_eol = 0;
-                        println!("{:?} {:?} pixels", *run_length, color.pal_entry());
-                        self.put_pixels(&mut x, &y, color.pal_entry(), *run_length)?;
-                        if *run_length <= 63 {
-                            color = color.next();
-                        }
-                    },
-                    None => {
-                        println!("No match for Huffman code: {:?}", code);
-                        // TODO: Better error (e.g couldn't decode Huffman code)
-                        return Err(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::RegexpCaptures)));
-                    }
-                }
-                // 6xEOL (End of Line) indicates RTC (Return to Control), i.e
-                // end of image.
-                if nb_eol == 6 {
-                    break;
-                }
-	}
-        Ok((&[], ()))
     }
 
-    fn pixels_from_uncompressed(&mut self) -> Result<'a, ()> {
-        if self.bpp == 0 {
-            // TODO: Better error (e.g bpp == 0)
-            return Err(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::TooLarge)));
-        }
-        let mut line_bytes = ((self.width*self.bpp as i32 + 7)/8) as usize;
-        let ppb = (8/self.bpp) as usize;
-        if line_bytes%4 != 0 {
-            line_bytes += 4 - (line_bytes%4);
-        }
-        let topdown = self.height < 0;
-        let image_bytes = line_bytes * self.height.abs() as usize;
-        if image_bytes > self.data.len() {
-            // TODO: Better error (e.g file smaller than expected)
-            return Err(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::TooLarge)));
-        }
-        match self.bpp {
-            1 | 2 | 4 | 8 => {
-                for b in 0..(image_bytes as usize) {
-                    let indexes = self.data[b] as usize;
-                    for d in 0..ppb {
-                        let x = (ppb*b + d)%(line_bytes*ppb);
-                        if x >= self.width as usize {
-                            // We're in padding bytes
-                            continue;
-                        }
-                        let y = b/line_bytes;
-                        let shift = 8-(d+1)*self.bpp as usize;
-                        let mask = (2_u32.pow(self.bpp as u32) as usize-1) << shift;
-                        let idx = (indexes & mask) >> shift;
-                        if idx >= self.palette.len() {
-                            // TODO: Better error (e.g color outside of palette)
-                            return Err(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::TooLarge)));
-                        }
-                        let color = self.palette[idx];
-                        let y = if topdown { y } else {self.height as usize - y - 1 };
-                        self.pixels[x][y] = self.to_srgb(color);
-                    }
-                }
-            },
-            16 | 24 | 32 => {
-                let bytespp = self.bpp / 8;
-                let mut aligned_bytes = self.width as u64*bytespp as u64;
-                while aligned_bytes % 4 != 0 {
-                    aligned_bytes += 1;
-                }
-                let bitmasks = if let Some(bm) = &self.bitmasks {
-                    bm
-                } else {
-                    match self.bpp {
-                        16 => {
-                            // From https://www.fileformat.info/format/bmp/egff.htm#MICBMP-DMYID.3.6:
-                            // The Compression field must always be a value of 3 (bitfields encoding) when
-                            // a file stores 16-bit data.
-                            // And yet other places mention a default 16 bits masks of RGB-555
-                            &Bitmasks {
-                                red_mask: 0x7c00,
-                                red_shift: 10,
-                                green_mask: 0x3e0,
-                                green_shift: 5,
-                                blue_mask: 0x1f,
-                                blue_shift: 0,
-                                alpha_mask: 0,
-                                alpha_shift: 0,
-                            }
-                        },
-                        24 => {
-                            &Bitmasks {
-                                red_mask: 0xff0000,
-                                red_shift: 16,
-                                green_mask: 0x00ff00,
-                                green_shift: 8,
-                                blue_mask: 0x0000ff,
-                                blue_shift: 0,
-                                alpha_mask: 0,
-                                alpha_shift: 0,
-                            }
-                        },
-                        32 => {
-                            &Bitmasks {
-                                red_mask: 0x00ff0000,
-                                red_shift: 16,
-                                green_mask: 0x0000ff00,
-                                green_shift: 8,
-                                blue_mask: 0x000000ff,
-                                blue_shift: 0,
-                                alpha_mask: 0,
-                                alpha_shift: 0,
-                            }
-                        },
-                        _ => {
-                            // TODO: Maybe replace by an error once I find out how to put any
-                            // message in them.
-                            panic!("Got bpp={}, which is totally unexpected!", self.bpp);
-                        },
-                    }
-                };
-                for x in 0..(self.width as usize) {
-                    for y in 0..(self.height.abs() as usize) {
-                        let offset = y*aligned_bytes as usize + bytespp as usize*x as usize;
-                        let mut val = 0;
-                        for i in 0..bytespp as usize {
-                            val += (self.data[offset+i] as u32) << 8*i;
-                        }
-                        let red = normalize_from_mask(val, bitmasks.red_mask, bitmasks.red_shift);
-                        let green = normalize_from_mask(val, bitmasks.green_mask, bitmasks.green_shift);
-                        let blue = normalize_from_mask(val, bitmasks.blue_mask, bitmasks.blue_shift);
-                        let alpha = normalize_from_mask(val, bitmasks.alpha_mask, bitmasks.alpha_shift);
-                        let y = if topdown { y } else {self.height as usize - y - 1 };
-                        self.pixels[x][y] = self.to_srgb(Color{red, green, blue, alpha});
-                    }
-                }
-            }
-            _ => {
-                // TODO: Better error (e.g unsupported bpp)
-                return Err(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::TooLarge)));
-            }
-        };
-        Ok((&[], ()))
+    pub fn unsupported_bpp(&self) -> BmpError {
+        self.error(BmpErrorKind::UnsupportedBpp{ bpp: self.bpp, compression: self.compression }, "Bpp", self.bpp_offset())
     }
 
-    fn pixels_from_data(&mut self) -> Result<'a, ()> {
-        // Cowardly refuse to handle images bigger than 1 billion pixels (1GB for 8bpp, 3GB for
-        // 24bpp).
-        if self.height.abs() as usize * self.width as usize > 1024*1024*1024 {
-            // TODO: Better error (e.g unsupported bpp)
-            return Err(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::TooLarge)));
-        }
-        self.pixels = vec![vec![Color{ ..Default::default() }; self.height.abs() as usize]; self.width as usize];
-        match self.compression {
-            None |
-            Some(CompressionMethod::BI_RGB) |
-            Some(CompressionMethod::BI_BITFIELDS) |
-            Some(CompressionMethod::BI_ALPHABITFIELDS) => self.pixels_from_uncompressed(),
-
-            Some(CompressionMethod::BI_RLE4) |
-            Some(CompressionMethod::BI_RLE8) |
-            Some(CompressionMethod::BI_RLE24) => self.pixels_from_rle(),
-
-            Some(CompressionMethod::BI_HUFFMAN1D) => self.pixels_from_huffman1d(),
-
-            // TODO: Support anything below this
-            Some(CompressionMethod::BI_CMYK) |
-            Some(CompressionMethod::BI_CMYKRLE4) |
-            Some(CompressionMethod::BI_CMYKRLE8) |
-            Some(CompressionMethod::BI_JPEG) |
-            Some(CompressionMethod::BI_PNG) => {
-                // TODO: Better error (e.g Unsupported compression method)
-                return Err(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::TooLarge)));
-            }
+    // Converts an error from the header parsers, using the innermost context as the field.
+    pub fn nom_error(&self, input: Input, error: nom::Err<Error>) -> BmpError {
+        use nom::error::{ErrorKind, VerboseErrorKind};
+        let errors = match error {
+            nom::Err::Error(e) | nom::Err::Failure(e) => e.errors,
+            nom::Err::Incomplete(_) => vec!(),
+        };
+        let offset = errors.first().map_or(input.len(), |(i, _)| input.len() - i.len());
+        let field = errors.iter().find_map(|(_, kind)| match kind {
+            VerboseErrorKind::Context(c) => Some(*c),
+            _ => None,
+        }).unwrap_or("Header");
+        let kind = match errors.first() {
+            None | Some((_, VerboseErrorKind::Nom(ErrorKind::Eof))) => BmpErrorKind::Truncated,
+            _ => BmpErrorKind::InvalidValue,
+        };
+        BmpError {
+            kind,
+            field,
+            offset,
+            // The header version is only known once its size has been read.
+            header: if offset >= FILE_HEADER_SIZE + 4 { Some(self.dib_header_size) } else { None },
         }
     }
-}
 
-impl BmpFile {
-    pub fn parse(input: Input) -> Result<Self> {
-        let mut result = BmpFile { ..Default::default() };
-        let (i, _) = result.parse_file_header(input)?;
-        let (i, _) = result.parse_dib_header_size(i)?;
-        let (i, _) = match result.dib_header_size {
-            // From https://en.wikipedia.org/wiki/BMP_file_format:
-            // "The Windows 2.x BITMAPCOREHEADER differs from the OS/2 1.x BITMAPCOREHEADER [...]
-            // in [...] that the image width and height fields are signed integers, not unsigned."
-            //
-            // But it seems there's no way to differentiate both versions (same dib header size, no
-            // other difference). So we have to pick:
-            //  - Decoding a OS/2 bitmap bigger with signed would fail on bitmap bigger than 16k
-            //  - Decoding a Win bitmap with unsigned would fail on bitmap with negative height
-            //    (which is valid, it means top-down instead of bottom-up)
-            // The latter seems more likely than the former, especially considering these are very
-            // old versions of the header (pre-1992). So let's go with i16 ...
-            DibHeaderSize::BITMAPCOREHEADER => result.parse_i16_width_and_height(i)?,
-            //DibHeaderSize::OS21XBITMAPHEADER => result.parse_u16_width_and_height(i)?,
-            _ => result.parse_i32_width_and_height(i)?,
+    // Parses everything up to the palette. The dimensions of 12 bytes headers are left unsigned
+    // until set_core_header is called.
+    pub fn parse_headers(&mut self, input: Input<'a>) -> Result<'a, ()> {
+        let (i, _) = self.parse_file_header(input)?;
+        let (i, _) = self.parse_dib_header_size(i)?;
+        let (i, _) = match self.dib_header_size {
+            DibHeaderSize::BITMAPCOREHEADER => self.parse_u16_width_and_height(i)?,
+            _ => self.parse_i32_width_and_height(i)?,
         };
-        let (i, _) = result.parse_end_of_core_header(i)?;
+        let (i, _) = self.parse_end_of_core_header(i)?;
 
-        let (mut i, _) = match result.dib_header_size {
+        let (mut i, _) = match self.dib_header_size {
             DibHeaderSize::BITMAPCOREHEADER |
-            //DibHeaderSize::OS21XBITMAPHEADER |
             DibHeaderSize::OS22XBITMAPHEADER16 => {
                 // Nothing to do
                 (i, ())
             },
             _ => {
-                result.parse_info_header(i)?
+                self.parse_info_header(i)?
             }
         };
-        if result.dib_header_size == DibHeaderSize::BITMAPINFOHEADER &&
-            (result.compression == Some(CompressionMethod::BI_BITFIELDS) ||
-             result.compression == Some(CompressionMethod::BI_ALPHABITFIELDS)) {
+        if self.dib_header_size == DibHeaderSize::BITMAPINFOHEADER &&
+            (self.compression == Some(CompressionMethod::BI_BITFIELDS) ||
+             self.compression == Some(CompressionMethod::BI_ALPHABITFIELDS)) {
             // https://docs.microsoft.com/en-us/windows/win32/gdi/bitmap-header-types
             // When the biCompression member of BITMAPINFOHEADER is set to BI_BITFIELDS and the
             // function receives an argument of type LPBITMAPINFO, the color masks will immediately
             // follow the header. [...] BITMAPCOREHEADER bitmaps do not support color masks.
-            let (j, _) = result.parse_rgb_masks(i)?;
+            let (j, _) = self.parse_rgb_masks(i)?;
             i = j;
-            if result.compression == Some(CompressionMethod::BI_ALPHABITFIELDS) {
-                let (j, _) = result.parse_alpha_mask(i)?;
+            if self.compression == Some(CompressionMethod::BI_ALPHABITFIELDS) {
+                let (j, _) = self.parse_alpha_mask(i)?;
                 i = j;
             }
         }
-        match result.dib_header_size {
-            //DibHeaderSize::OS21XBITMAPHEADER |
+        match self.dib_header_size {
             DibHeaderSize::OS22XBITMAPHEADER16 |
             DibHeaderSize::BITMAPCOREHEADER |
             DibHeaderSize::BITMAPINFOHEADER => {
                 // Nothing more to parse
             }
             DibHeaderSize::OS22XBITMAPHEADER64 => {
-                let (j, _) = result.parse_os2_64_part(i)?;
+                let (j, _) = self.parse_os2_64_part(i)?;
                 i = j;
             }
             DibHeaderSize::BITMAPV2INFOHEADER |
             DibHeaderSize::BITMAPV3INFOHEADER |
             DibHeaderSize::BITMAPV4HEADER |
             DibHeaderSize::BITMAPV5HEADER => {
-                // TODO: Ensure stuff below is set in result
-                if result.dib_header_size as u32 >= DibHeaderSize::BITMAPV2INFOHEADER as u32 {
+                // TODO: Ensure stuff below is set in self
+                if self.dib_header_size as u32 >= DibHeaderSize::BITMAPV2INFOHEADER as u32 {
                     // BITMAPV2INFOHEADER part.
-                    let (j, _) = result.parse_rgb_masks(i)?;
+                    let (j, _) = self.parse_rgb_masks(i)?;
                     i = j;
                 }
-                if result.dib_header_size as u32 >= DibHeaderSize::BITMAPV3INFOHEADER as u32 {
+                if self.dib_header_size as u32 >= DibHeaderSize::BITMAPV3INFOHEADER as u32 {
                     // BITMAPV3INFOHEADER part.
-                    let (j, _) = result.parse_alpha_mask(i)?;
+                    let (j, _) = self.parse_alpha_mask(i)?;
                     i = j;
                 }
-                if result.dib_header_size as u32 >= DibHeaderSize::BITMAPV4HEADER as u32 {
+                if self.dib_header_size as u32 >= DibHeaderSize::BITMAPV4HEADER as u32 {
                     // BITMAPV4HEADER part.
-                    let (j, _) = result.parse_v4_part(i)?;
+                    let (j, _) = self.parse_v4_part(i)?;
                     i = j;
                 }
-                if result.dib_header_size as u32 >= DibHeaderSize::BITMAPV5HEADER as u32 {
+                if self.dib_header_size as u32 >= DibHeaderSize::BITMAPV5HEADER as u32 {
                     // BITMAPV5HEADER part.
-                    let (j, _) = result.parse_v5_part(i)?;
+                    let (j, _) = self.parse_v5_part(i)?;
                     i = j;
                 }
             }
         }
+        Ok((i, ()))
+    }
+
+    fn default_image_size(&self) -> u64 {
+        let pixels = self.width.unsigned_abs() as u64 * self.height.unsigned_abs() as u64;
+        pixels.saturating_mul(self.bpp as u64)/8
+    }
+
+    // From https://en.wikipedia.org/wiki/BMP_file_format:
+    // "The Windows 2.x BITMAPCOREHEADER differs from the OS/2 1.x BITMAPCOREHEADER [...] in [...]
+    // that the image width and height fields are signed integers, not unsigned."
+    //
+    // Nothing else differs, so the values have to be checked against the rest of the file. When
+    // both readings are plausible, Windows is picked as negative heights (top-down bitmaps) seem
+    // more likely than OS/2 bitmaps bigger than 32k. file_size is the size of the whole file.
+    pub fn guess_core_header(&self, file_size: usize) -> CoreHeader {
+        let (width, height) = (self.width as u16, self.height as u16);
+        // Only OS/2 has arrays, icons and pointers.
+        if self.magic != Magic::BM {
+            return CoreHeader::Os21x;
+        }
+        // Windows bitmaps can't have a negative width.
+        if width >= 0x8000 {
+            return CoreHeader::Os21x;
+        }
+        // Both readings agree.
+        if height < 0x8000 {
+            return CoreHeader::Windows2x;
+        }
+        // Core headers have no compression, so the rows take a known size: enough data for all of
+        // them means an OS/2 bitmap, as a top-down Windows one would have far less rows.
+        let line_bytes = (width as usize * self.bpp as usize).div_ceil(32) * 4;
+        let os2_size = line_bytes * height as usize;
+        let windows_size = line_bytes * (0x10000 - height as usize);
+        let offset = self.offset as usize;
+        let available = file_size.saturating_sub(offset);
+        if available >= os2_size {
+            CoreHeader::Os21x
+        } else if available >= windows_size {
+            CoreHeader::Windows2x
+        // Truncated, trust the size the file claims.
+        } else if self.filesize as usize == offset + os2_size {
+            CoreHeader::Os21x
+        } else {
+            CoreHeader::Windows2x
+        }
+    }
+
+    // Reads the dimensions parsed from a 12 bytes header as signed or unsigned.
+    pub fn set_core_header(&mut self, core_header: CoreHeader) -> BmpResult<()> {
+        let (width, height) = (self.width as u16, self.height as u16);
+        let (width, height) = match core_header {
+            CoreHeader::Os21x => (width as i32, height as i32),
+            CoreHeader::Windows2x => (width as i16 as i32, height as i16 as i32),
+        };
+        if width < 0 {
+            return Err(self.error(BmpErrorKind::InvalidValue, "Image Width (i16)", FILE_HEADER_SIZE + 4));
+        }
+        self.width = width;
+        self.height = height;
+        self.core_header = Some(core_header);
+        self.image_size = self.default_image_size();
+        Ok(())
+    }
+
+    // Size of the palette in the file.
+    pub fn palette_size(&self) -> usize {
+        let entry_size = if self.dib_header_size == DibHeaderSize::BITMAPCOREHEADER { 3 } else { 4 };
+        self.colors_in_table as usize * entry_size
+    }
+}
+
+impl BmpFile {
+    pub fn parse(input: Input) -> BmpResult<(Input, Self)> {
+        Self::parse_with_options(input, DecodeOptions::default())
+    }
+
+    pub fn parse_with_options(input: Input, options: DecodeOptions) -> BmpResult<(Input, Self)> {
+        let (end, bitmap) = Self::parse_first(input, options)?;
+        Ok((&input[end..], bitmap))
+    }
+
+    // Decodes the bitmap whose file header is at start, without handling icons. Also returns the
+    // end of its headers and palette.
+    pub fn parse_bitmap_at(input: Input, start: usize, options: DecodeOptions) -> BmpResult<(usize, Self)> {
+        let mut reader = BmpReader::at(std::io::Cursor::new(input), start, options)?;
+        let (width, height) = (reader.header.width as usize, reader.header.height.unsigned_abs() as usize);
+        if options.max_pixels.is_some_and(|max_pixels| width * height > max_pixels) {
+            let kind = BmpErrorKind::ImageTooLarge{ width: reader.header.width, height: reader.header.height, header: Box::new(reader.header.clone()) };
+            return Err(reader.header.error(kind, "Image Width", start + FILE_HEADER_SIZE + 4));
+        }
+        let mut image = Image::new(width, height);
+        // Only kept when every row comes with its palette indexes.
+        let mut indexes = Some(vec![0; width * height]);
+        for row in &mut reader {
+            let row = row?;
+            image.set_row(row.y, &row.pixels);
+            match (&mut indexes, row.indexes) {
+                (Some(all), Some(row_indexes)) => all[row.y * width..(row.y + 1) * width].copy_from_slice(&row_indexes),
+                _ => indexes = None,
+            }
+        }
+        let header_size = reader.header_size;
+        let mut result = reader.header;
+        image.indexed = indexes.map(|indexes| Indexed {
+            indexes,
+            palette: result.palette.iter().map(|c| result.to_srgb(*c, &options)).collect(),
+        });
+        result.data = input[result.offset as usize..].to_vec();
+        result.image = image;
+        Ok((start + header_size, result))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // Bitmap of the tests, for files BmpWriter doesn't write: CMYK, embedded JPEG or PNG, OS/2
+    // icons, invalid values... Headers bigger than BITMAPINFOHEADER have no masks, and V4 and V5
+    // fields are only written for their color space.
+    #[derive(Debug, Clone)]
+    pub struct TestBitmap {
+        pub magic: Magic,
+        // In the reserved fields of the file header, for OS/2 icons and pointers.
+        pub hotspot: (u16, u16),
+        pub dib_header_size: DibHeaderSize,
+        pub width: i32,
+        pub height: i32,
+        pub bpp: u16,
+        pub compression: CompressionMethod,
+        pub color_space: ColorSpaceType,
+        pub endpoints: [[f64; 3]; 3],
+        pub gammas: [f64; 3],
+        pub intent: IntentType,
+        // As in the file, blue first, without the fourth byte for 12 bytes headers.
+        pub palette: Vec<[u8; 4]>,
+        pub data: Vec<u8>,
+        // Embedded profile or path of a linked one, after the data.
+        pub profile: Vec<u8>,
+    }
+
+    impl Default for TestBitmap {
+        fn default() -> Self {
+            TestBitmap {
+                magic: Magic::BM,
+                hotspot: (0, 0),
+                dib_header_size: DibHeaderSize::BITMAPINFOHEADER,
+                width: 1,
+                height: 1,
+                bpp: 24,
+                compression: CompressionMethod::BI_RGB,
+                color_space: ColorSpaceType::LCS_sRGB,
+                endpoints: [[0.0; 3]; 3],
+                gammas: [0.0; 3],
+                intent: IntentType::LCS_GM_IMAGES,
+                palette: vec!(),
+                data: vec![0; 4],
+                profile: vec!(),
+            }
+        }
+    }
+
+    impl TestBitmap {
+        pub fn write(&self) -> Vec<u8> {
+            let core = self.dib_header_size == DibHeaderSize::BITMAPCOREHEADER;
+            let mut dib = vec!();
+            dib.extend_from_slice(&(self.dib_header_size as u32).to_le_bytes());
+            if core {
+                dib.extend_from_slice(&(self.width as u16).to_le_bytes());
+                dib.extend_from_slice(&(self.height as u16).to_le_bytes());
+            } else {
+                dib.extend_from_slice(&self.width.to_le_bytes());
+                dib.extend_from_slice(&self.height.to_le_bytes());
+            }
+            dib.extend_from_slice(&1_u16.to_le_bytes());
+            dib.extend_from_slice(&self.bpp.to_le_bytes());
+            if !core {
+                dib.extend_from_slice(&(self.compression as u64 as u32).to_le_bytes());
+                dib.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+                dib.extend_from_slice(&[0; 8]);
+                dib.extend_from_slice(&(self.palette.len() as u32).to_le_bytes());
+                dib.extend_from_slice(&[0; 4]);
+            }
+            let size = self.dib_header_size as usize;
+            if size >= DibHeaderSize::BITMAPV4HEADER as usize {
+                dib.resize(56, 0);
+                dib.extend_from_slice(&(self.color_space as u32).to_le_bytes());
+                for v in self.endpoints.iter().flatten() {
+                    dib.extend_from_slice(&((v * 2_f64.powi(30)).round() as u32).to_le_bytes());
+                }
+                for v in self.gammas.iter() {
+                    dib.extend_from_slice(&((v * 65536.0).round() as u32).to_le_bytes());
+                }
+            }
+            if size >= DibHeaderSize::BITMAPV5HEADER as usize {
+                let profile_data = size + 4 * self.palette.len() + self.data.len();
+                dib.extend_from_slice(&(self.intent as u32).to_le_bytes());
+                dib.extend_from_slice(&(if self.profile.is_empty() { 0 } else { profile_data as u32 }).to_le_bytes());
+                dib.extend_from_slice(&(self.profile.len() as u32).to_le_bytes());
+            }
+            dib.resize(size, 0);
+            for c in self.palette.iter() {
+                dib.extend_from_slice(&c[..if core { 3 } else { 4 }]);
+            }
+
+            let offset = FILE_HEADER_SIZE + dib.len();
+            let mut out = vec!();
+            out.extend_from_slice(&(self.magic as u16).to_le_bytes());
+            out.extend_from_slice(&((offset + self.data.len() + self.profile.len()) as u32).to_le_bytes());
+            out.extend_from_slice(&self.hotspot.0.to_le_bytes());
+            out.extend_from_slice(&self.hotspot.1.to_le_bytes());
+            out.extend_from_slice(&(offset as u32).to_le_bytes());
+            out.extend_from_slice(&dib);
+            out.extend_from_slice(&self.data);
+            out.extend_from_slice(&self.profile);
+            out
+        }
+    }
 
-        let (i, _) = result.parse_colors(i, result.colors_in_table)?;
+    const WHITE: [u8; 4] = [0, 0, 0, 0];
+    const CYAN: [u8; 4] = [255, 0, 0, 0];
+    const RED: [u8; 4] = [0, 255, 255, 0];
+    const BLACK: [u8; 4] = [0, 0, 0, 255];
 
-        if result.offset as usize > input.len() {
-            // TODO: Better error (e.g Data offset too large)
-            return Err(nom::Err::Failure(nom::error::make_error::<Input, nom::error::VerboseError<Input>>(&[], nom::error::ErrorKind::TooLarge)));
+    fn rgb(red: u8, green: u8, blue: u8) -> Color {
+        Color{ red, green, blue, alpha: 255 }
+    }
+
+    // As stored in a CMYK() COLORREF.
+    fn cmyk_bytes(cmyk: [u8; 4]) -> [u8; 4] {
+        [cmyk[3], cmyk[2], cmyk[1], cmyk[0]]
+    }
+
+    // There are no CMYK samples in the test suites, so we build our own.
+    fn cmyk_bitmap(width: i32, height: i32, bpp: u16, compression: CompressionMethod, palette: &[[u8; 4]], data: &[u8]) -> Vec<u8> {
+        let palette = palette.iter().map(|c| cmyk_bytes(*c)).collect();
+        TestBitmap{ width, height, bpp, compression, palette, data: data.to_vec(), ..Default::default() }.write()
+    }
+
+    #[test]
+    fn test_cmyk_to_rgb() {
+        assert_eq!(denormalize(cmyk_to_rgb(0, 0, 0, 0)), rgb(255, 255, 255));
+        assert_eq!(denormalize(cmyk_to_rgb(255, 0, 0, 0)), rgb(0, 255, 255));
+        assert_eq!(denormalize(cmyk_to_rgb(0, 255, 255, 0)), rgb(255, 0, 0));
+        assert_eq!(denormalize(cmyk_to_rgb(12, 34, 56, 255)), rgb(0, 0, 0));
+    }
+
+    #[test]
+    fn test_cmyk_32bpp() {
+        let data = [cmyk_bytes(RED), cmyk_bytes(BLACK), cmyk_bytes(WHITE), cmyk_bytes(CYAN)].concat();
+        let (_, bmp) = BmpFile::parse(&cmyk_bitmap(2, 2, 32, CompressionMethod::BI_CMYK, &[], &data)).unwrap();
+        assert_eq!(bmp.image.colors().collect::<Vec<_>>(), vec!(rgb(255, 255, 255), rgb(0, 255, 255), rgb(255, 0, 0), rgb(0, 0, 0)));
+    }
+
+    #[test]
+    fn test_cmyk_indexed() {
+        let (_, bmp) = BmpFile::parse(&cmyk_bitmap(4, 1, 8, CompressionMethod::BI_CMYK, &[WHITE, CYAN, RED, BLACK], &[0, 1, 2, 3])).unwrap();
+        assert_eq!(bmp.palette, vec!(rgb(255, 255, 255), rgb(0, 255, 255), rgb(255, 0, 0), rgb(0, 0, 0)));
+        assert_eq!(bmp.image.colors().collect::<Vec<_>>(), bmp.palette);
+        assert_eq!(bmp.image.indexed, Some(Indexed{ indexes: vec!(0, 1, 2, 3), palette: bmp.palette.clone() }));
+    }
+
+    #[test]
+    fn test_cmyk_rle8() {
+        // Bottom line: 4 red pixels. Top line: absolute mode with all colors.
+        let data = [4, 2, 0, 0, 0, 4, 0, 1, 2, 3, 0, 1];
+        let (_, bmp) = BmpFile::parse(&cmyk_bitmap(4, 2, 8, CompressionMethod::BI_CMYKRLE8, &[WHITE, CYAN, RED, BLACK], &data)).unwrap();
+        for x in 0..4 {
+            assert_eq!(bmp.image.pixel(x, 0), bmp.palette[x]);
+            assert_eq!(bmp.image.pixel(x, 1), rgb(255, 0, 0));
         }
-        let (_, data) = input.split_at(result.offset as usize);
-        //let (data, _) = data.split_at(result.image_size as usize);
-        result.data = data.to_vec();
+    }
+
+    #[test]
+    fn test_cmyk_rle4() {
+        let data = [5, 0x13, 0, 1];
+        let (_, bmp) = BmpFile::parse(&cmyk_bitmap(5, 1, 4, CompressionMethod::BI_CMYKRLE4, &[WHITE, CYAN, RED, BLACK], &data)).unwrap();
+        let (cyan, black) = (rgb(0, 255, 255), rgb(0, 0, 0));
+        assert_eq!(bmp.image.colors().collect::<Vec<_>>(), vec!(cyan, black, cyan, black, cyan));
+        assert_eq!(bmp.image.indexed, None);
+    }
+
+    #[test]
+    fn test_cmyk_unsupported_bpp() {
+        assert!(BmpFile::parse(&cmyk_bitmap(1, 1, 24, CompressionMethod::BI_CMYK, &[], &[0; 4])).is_err());
+        assert!(BmpFile::parse(&cmyk_bitmap(2, 1, 8, CompressionMethod::BI_CMYKRLE4, &[WHITE], &[2, 0, 0, 1])).is_err());
+    }
+
+    #[test]
+    fn test_embedded_profile() {
+        use crate::icc::tests::{profile, SRGB_COLORANTS};
+
+        let gray = Color{ red: 128, green: 128, blue: 128, alpha: 255 };
+        // Linear profile, so 50% gray is much lighter in sRGB.
+        let profile = profile(&SRGB_COLORANTS, b"curv\0\0\0\0\0\0\0\0");
+        let mut bitmap = TestBitmap{
+            dib_header_size: DibHeaderSize::BITMAPV5HEADER,
+            color_space: ColorSpaceType::LCS_PROFILE_EMBEDDED,
+            data: vec!(128, 128, 128, 0),
+            profile: profile.clone(),
+            ..Default::default()
+        }.write();
+
+        let (_, bmp) = BmpFile::parse(&bitmap).unwrap();
+        assert!(bmp.icc_profile.is_some());
+        let pixel = bmp.image.pixel(0, 0);
+        assert!((186..=188).contains(&pixel.red), "{:?}", pixel);
+        assert_eq!((pixel.red, pixel.red), (pixel.green, pixel.blue));
+
+        // Unsupported profiles are ignored.
+        let end = bitmap.len();
+        bitmap[end - profile.len() + 16..end - profile.len() + 20].copy_from_slice(b"GRAY");
+        let (_, bmp) = BmpFile::parse(&bitmap).unwrap();
+        assert_eq!((bmp.icc_profile, bmp.image.pixel(0, 0)), (None, gray));
+    }
+
+    // 1x1 V5 bitmap in calibrated RGB, with linear gammas.
+    fn calibrated(colorants: &[[f64; 3]; 3], color: Color, intent: IntentType) -> Vec<u8> {
+        TestBitmap{
+            dib_header_size: DibHeaderSize::BITMAPV5HEADER,
+            color_space: ColorSpaceType::LCS_CALIBRATED_RGB,
+            endpoints: *colorants,
+            gammas: [1.0; 3],
+            intent,
+            data: vec!(color.blue, color.green, color.red, 0),
+            ..Default::default()
+        }.write()
+    }
 
-        //println!("{:#?}", result);
-        result.pixels_from_data()?;
+    #[test]
+    fn test_calibrated_rgb() {
+        use crate::icc::tests::SRGB_COLORANTS;
+
+        // sRGB primaries, whose white is D65.
+        let srgb_d65 = [
+            [0.4124564, 0.2126729, 0.0193339],
+            [0.3575761, 0.7151522, 0.1191920],
+            [0.1804375, 0.0721750, 0.9503041],
+        ];
+        let gray = Color{ red: 128, green: 128, blue: 128, alpha: 255 };
+        let white = Color{ red: 255, green: 255, blue: 255, alpha: 255 };
+        let decode = |bitmap: &[u8], options: DecodeOptions| BmpFile::parse_with_options(bitmap, options).unwrap().1.image.pixel(0, 0);
+
+        // Linear values, so 50% gray is much lighter in sRGB.
+        let bitmap = calibrated(&srgb_d65, gray, IntentType::LCS_GM_IMAGES);
+        let pixel = decode(&bitmap, DecodeOptions::default());
+        assert!([pixel.red, pixel.green, pixel.blue].iter().all(|c| (186..=188).contains(c)), "{:?}", pixel);
+        assert_eq!(decode(&bitmap, DecodeOptions{ raw: true, ..Default::default() }), gray);
+
+        // The D50 white of the file is only kept by the absolute colorimetric intent.
+        let bitmap = calibrated(&SRGB_COLORANTS, white, IntentType::LCS_GM_GRAPHICS);
+        let pixel = decode(&bitmap, DecodeOptions::default());
+        assert!([pixel.red, pixel.green, pixel.blue].iter().all(|c| *c >= 254), "{:?}", pixel);
+        let absolute = DecodeOptions{ intent: Some(IntentType::LCS_GM_ABS_COLORIMETRIC), ..Default::default() };
+        let pixel = decode(&bitmap, absolute);
+        assert!(pixel.red == 255 && pixel.blue < 230, "{:?}", pixel);
+        let bitmap = calibrated(&SRGB_COLORANTS, white, IntentType::LCS_GM_ABS_COLORIMETRIC);
+        assert_eq!(decode(&bitmap, DecodeOptions::default()), pixel);
+
+        // Endpoints without white are ignored.
+        assert_eq!(decode(&calibrated(&[[0.0; 3]; 3], gray, IntentType::LCS_GM_IMAGES), DecodeOptions::default()), gray);
+    }
+
+    #[test]
+    fn test_linked_profile() {
+        // As rgb24lprof from bmpsuite: the profile is on the machine which wrote the file, so
+        // colors are left as they are.
+        let gray = Color{ red: 128, green: 128, blue: 128, alpha: 255 };
+        let bitmap = TestBitmap{
+            dib_header_size: DibHeaderSize::BITMAPV5HEADER,
+            color_space: ColorSpaceType::LCS_PROFILE_LINKED,
+            data: vec!(128, 128, 128, 0),
+            profile: b"C:\\Windows\\sRGB.icc\0".to_vec(),
+            ..Default::default()
+        }.write();
+
+        let (_, bmp) = BmpFile::parse(&bitmap).unwrap();
+        assert_eq!(bmp.linked_profile.as_deref(), Some("C:\\Windows\\sRGB.icc"));
+        assert_eq!(bmp.image.pixel(0, 0), gray);
+    }
+
+    // 1 bpp bitmap with a 12 bytes header, whose first row in the file has a white pixel.
+    fn core_bitmap(width: u16, height: u16, rows: usize) -> Vec<u8> {
+        let mut data = vec![0; 4*rows];
+        data[0] = 0x80;
+        TestBitmap{
+            dib_header_size: DibHeaderSize::BITMAPCOREHEADER,
+            width: width.into(),
+            height: height.into(),
+            bpp: 1,
+            palette: vec!([0, 0, 0, 0], [255, 255, 255, 0]),
+            data,
+            ..Default::default()
+        }.write()
+    }
+
+    #[test]
+    fn test_core_header() {
+        let white = Color{ red: 255, green: 255, blue: 255, alpha: 255 };
+        let options = |core_header| DecodeOptions{ core_header: Some(core_header), ..Default::default() };
+
+        // More than 32k rows, all in the file.
+        let os2 = core_bitmap(1, 0x8001, 0x8001);
+        let (_, bmp) = BmpFile::parse(&os2).unwrap();
+        assert_eq!((bmp.core_header, bmp.height), (Some(CoreHeader::Os21x), 0x8001));
+        assert_eq!(bmp.image.pixel(0, 0x8000), white);
+        let (_, bmp) = BmpFile::parse_with_options(&os2, options(CoreHeader::Windows2x)).unwrap();
+        assert_eq!((bmp.height, bmp.image.pixel(0, 0)), (-0x7fff, white));
+
+        // Top-down, with only 2 rows.
+        let windows = core_bitmap(1, 0xfffe, 2);
+        let (_, bmp) = BmpFile::parse(&windows).unwrap();
+        assert_eq!((bmp.core_header, bmp.height), (Some(CoreHeader::Windows2x), -2));
+        assert_eq!(bmp.image.pixel(0, 0), white);
+        let error = BmpFile::parse_with_options(&windows, options(CoreHeader::Os21x)).unwrap_err();
+        assert_eq!(error.kind, BmpErrorKind::MissingData{ expected: 4 * 0xfffe, available: 8 });
+
+        // Windows can't have negative widths.
+        let wide = core_bitmap(0x8000, 1, 0x1000);
+        assert_eq!(BmpFile::parse(&wide).unwrap().1.width, 0x8000);
+        let error = BmpFile::parse_with_options(&wide, options(CoreHeader::Windows2x)).unwrap_err();
+        assert_eq!((error.kind, error.field, error.offset), (BmpErrorKind::InvalidValue, "Image Width (i16)", 18));
+
+        // Truncated files are guessed from the size they claim.
+        let header = BmpFile{ width: 1, height: 0x8001, bpp: 1, offset: 32, filesize: 32 + 4 * 0x8001, ..Default::default() };
+        assert_eq!(header.guess_core_header(100), CoreHeader::Os21x);
+        assert_eq!(BmpFile{ filesize: 100, ..header }.guess_core_header(100), CoreHeader::Windows2x);
+        let pointer = BmpFile{ magic: Magic::PT, width: 1, height: 2, ..Default::default() };
+        assert_eq!(pointer.guess_core_header(0), CoreHeader::Os21x);
+    }
+
+    fn parse_error(input: &[u8]) -> BmpError {
+        BmpFile::parse(input).unwrap_err()
+    }
+
+    #[test]
+    fn test_error_header() {
+        let bitmap = cmyk_bitmap(4, 1, 8, CompressionMethod::BI_RGB, &[WHITE, BLACK], &[0, 1, 1, 0]);
+
+        let error = parse_error(&bitmap[..12]);
+        assert_eq!(error, BmpError{ kind: BmpErrorKind::Truncated, field: "Offset", offset: 10, header: None });
+
+        let error = parse_error(&bitmap[..20]);
+        assert_eq!(error, BmpError{ kind: BmpErrorKind::Truncated, field: "Image Width (i32)", offset: 18, header: Some(DibHeaderSize::BITMAPINFOHEADER) });
+
+        let mut invalid = bitmap.clone();
+        invalid[30] = 42;
+        let error = parse_error(&invalid);
+        assert_eq!(error, BmpError{ kind: BmpErrorKind::InvalidValue, field: "Compression", offset: 30, header: Some(DibHeaderSize::BITMAPINFOHEADER) });
+        assert_eq!(error.to_string(), "invalid_value: invalid value in Compression at byte 30 (BITMAPINFOHEADER)");
 
-        Ok((i, result))
+        let mut invalid = bitmap;
+        invalid[10..14].copy_from_slice(&1000_u32.to_le_bytes());
+        let error = parse_error(&invalid);
+        assert_eq!(error.kind, BmpErrorKind::DataOffsetTooLarge{ offset: 1000, file_size: 66 });
+        assert_eq!((error.field, error.offset), ("Offset", 10));
+    }
+
+    #[test]
+    fn test_error_pixels() {
+        let offset = 14 + 40 + 8;
+
+        let error = parse_error(&cmyk_bitmap(4, 2, 8, CompressionMethod::BI_RGB, &[WHITE, BLACK], &[0, 1, 1, 0]));
+        assert_eq!(error.kind, BmpErrorKind::MissingData{ expected: 8, available: 4 });
+        assert_eq!((error.field, error.offset), ("Pixel data", offset));
+
+        let error = parse_error(&cmyk_bitmap(4, 1, 8, CompressionMethod::BI_RGB, &[WHITE, BLACK], &[0, 1, 5, 0]));
+        assert_eq!(error.kind, BmpErrorKind::ColorOutsideOfPalette{ index: 5, palette_size: 2 });
+        assert_eq!((error.field, error.offset), ("Pixel data", offset + 2));
+
+        let error = parse_error(&cmyk_bitmap(4, 1, 8, CompressionMethod::BI_RLE8, &[WHITE, BLACK], &[2, 1, 0, 3, 1, 0]));
+        assert_eq!(error.kind, BmpErrorKind::Truncated);
+        assert_eq!((error.field, error.offset), ("RLE data", offset + 5));
+
+        let error = parse_error(&cmyk_bitmap(1, 1, 24, CompressionMethod::BI_CMYK, &[], &[0; 4]));
+        assert_eq!(error.kind, BmpErrorKind::UnsupportedBpp{ bpp: 24, compression: Some(CompressionMethod::BI_CMYK) });
+        assert_eq!((error.field, error.offset), ("Bpp", 28));
+    }
+
+    // Cheap stand-in for the fuzz target: no prefix or single byte corruption may panic.
+    #[test]
+    fn test_malformed_input() {
+        let bitmaps = [
+            cmyk_bitmap(4, 2, 8, CompressionMethod::BI_RGB, &[WHITE, BLACK], &[0, 1, 1, 0, 1, 0, 0, 1]),
+            cmyk_bitmap(4, -2, 4, CompressionMethod::BI_CMYKRLE4, &[WHITE, RED, CYAN], &[4, 0x12, 2, 0x01, 0, 0, 4, 0x21, 0, 1]),
+            cmyk_bitmap(2, 2, 32, CompressionMethod::BI_CMYK, &[], &[CYAN, RED, BLACK, WHITE].concat()),
+        ];
+        for bitmap in bitmaps.iter() {
+            // Missing RLE data is left blank, so only truncated headers are errors.
+            let offset = u32::from_le_bytes([bitmap[10], bitmap[11], bitmap[12], bitmap[13]]) as usize;
+            for end in 0..bitmap.len() {
+                assert!(BmpFile::parse(&bitmap[..end]).is_err() || end >= offset);
+            }
+            for i in 0..bitmap.len() {
+                for value in [0, 1, 0x7f, 0x80, 0xff] {
+                    let mut bitmap = bitmap.clone();
+                    bitmap[i] = value;
+                    let _ = BmpFile::parse(&bitmap);
+                }
+            }
+        }
     }
 }
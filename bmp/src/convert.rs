@@ -0,0 +1,423 @@
+// Conversion between BMP and other image formats (PNG, and the binary PPM/PGM and PAM from
+// Netpbm), for the convert subcommand.
+use crate::bmp::*;
+use crate::image::Image;
+use crate::writer::{BmpWriter, WriteError};
+use std::fmt;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Bmp,
+    // Also reads PGM, always writes PPM.
+    Ppm,
+    Pam,
+    Png,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Option<Format> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "bmp" | "dib" => Some(Format::Bmp),
+            "ppm" | "pgm" | "pnm" => Some(Format::Ppm),
+            "pam" => Some(Format::Pam),
+            "png" => Some(Format::Png),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ConvertError {
+    Bmp(BmpError),
+    Write(WriteError),
+    Pnm(String),
+    #[cfg_attr(not(feature = "png"), allow(dead_code))]
+    Png(String),
+    #[cfg_attr(feature = "png", allow(dead_code))]
+    MissingFeature(&'static str),
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConvertError::Bmp(e) => write!(f, "{}", e),
+            ConvertError::Write(e) => write!(f, "{}", e),
+            ConvertError::Pnm(e) => write!(f, "invalid PNM file: {}", e),
+            ConvertError::Png(e) => write!(f, "PNG error: {}", e),
+            ConvertError::MissingFeature(feature) => write!(f, "built without the {} feature", feature),
+        }
+    }
+}
+
+impl std::error::Error for ConvertError {}
+
+impl From<BmpError> for ConvertError {
+    fn from(e: BmpError) -> Self {
+        ConvertError::Bmp(e)
+    }
+}
+
+impl From<WriteError> for ConvertError {
+    fn from(e: WriteError) -> Self {
+        ConvertError::Write(e)
+    }
+}
+
+pub type ConvertResult<T> = std::result::Result<T, ConvertError>;
+
+// Parsers for the command line options.
+pub fn parse_header(value: &str) -> std::result::Result<DibHeaderSize, String> {
+    match value {
+        "core" => Ok(DibHeaderSize::BITMAPCOREHEADER),
+        "os2-16" => Ok(DibHeaderSize::OS22XBITMAPHEADER16),
+        "os2" => Ok(DibHeaderSize::OS22XBITMAPHEADER64),
+        "info" => Ok(DibHeaderSize::BITMAPINFOHEADER),
+        "v2" => Ok(DibHeaderSize::BITMAPV2INFOHEADER),
+        "v3" => Ok(DibHeaderSize::BITMAPV3INFOHEADER),
+        "v4" => Ok(DibHeaderSize::BITMAPV4HEADER),
+        "v5" => Ok(DibHeaderSize::BITMAPV5HEADER),
+        _ => Err(format!("unknown header {}, expected one of core, os2-16, os2, info, v2, v3, v4 or v5", value)),
+    }
+}
+
+pub fn parse_compression(value: &str) -> std::result::Result<CompressionMethod, String> {
+    match value {
+        "rgb" => Ok(CompressionMethod::BI_RGB),
+        "rle4" => Ok(CompressionMethod::BI_RLE4),
+        "rle8" => Ok(CompressionMethod::BI_RLE8),
+        "rle24" => Ok(CompressionMethod::BI_RLE24),
+        "huffman1d" => Ok(CompressionMethod::BI_HUFFMAN1D),
+        "bitfields" => Ok(CompressionMethod::BI_BITFIELDS),
+        "alphabitfields" => Ok(CompressionMethod::BI_ALPHABITFIELDS),
+        _ => Err(format!("unknown compression {}, expected one of rgb, rle4, rle8, rle24, huffman1d, bitfields or alphabitfields", value)),
+    }
+}
+
+pub fn parse_intent(value: &str) -> std::result::Result<IntentType, String> {
+    match value {
+        "business" => Ok(IntentType::LCS_GM_BUSINESS),
+        "graphics" => Ok(IntentType::LCS_GM_GRAPHICS),
+        "images" => Ok(IntentType::LCS_GM_IMAGES),
+        "absolute" => Ok(IntentType::LCS_GM_ABS_COLORIMETRIC),
+        _ => Err(format!("unknown intent {}, expected one of business, graphics, images or absolute", value)),
+    }
+}
+
+pub fn parse_core_header(value: &str) -> std::result::Result<CoreHeader, String> {
+    match value {
+        "os2" => Ok(CoreHeader::Os21x),
+        "windows" => Ok(CoreHeader::Windows2x),
+        _ => Err(format!("unknown core header {}, expected os2 or windows", value)),
+    }
+}
+
+// Layout of the BMP output. Unset fields are taken from the input when it's a BMP file.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BmpOptions {
+    pub bpp: Option<u16>,
+    pub header: Option<DibHeaderSize>,
+    pub compression: Option<CompressionMethod>,
+    pub topdown: bool,
+}
+
+impl BmpOptions {
+    pub fn writer(&self, source: Option<&BmpFile>, image: &Image) -> BmpWriter {
+        // The writer can't produce embedded JPEG/PNG nor CMYK, such files are written like any
+        // other image.
+        let source = source.filter(|bitmap| !matches!(bitmap.compression,
+            Some(CompressionMethod::BI_JPEG) | Some(CompressionMethod::BI_PNG) |
+            Some(CompressionMethod::BI_CMYK) | Some(CompressionMethod::BI_CMYKRLE8) |
+            Some(CompressionMethod::BI_CMYKRLE4)));
+        let mut writer = match source {
+            Some(bitmap) => bitmap.writer(),
+            None if image.colors().any(|c| c.alpha != 255) => BmpWriter {
+                compression: CompressionMethod::BI_BITFIELDS,
+                ..BmpWriter::new(DibHeaderSize::BITMAPV5HEADER, 32)
+            },
+            None => BmpWriter::default(),
+        };
+        if let Some(header) = self.header {
+            writer.dib_header_size = header;
+        }
+        if let Some(bpp) = self.bpp.filter(|bpp| *bpp != writer.bpp) {
+            // Neither the compression nor the masks of the input are likely to fit.
+            writer.bpp = bpp;
+            writer.compression = CompressionMethod::BI_RGB;
+            writer.bitmasks = None;
+            if writer.palette.as_ref().is_some_and(|p| p.len() > 1 << bpp.min(8)) {
+                writer.palette = None;
+            }
+        }
+        if let Some(compression) = self.compression {
+            writer.compression = compression;
+        }
+        let is_bitfields = writer.compression == CompressionMethod::BI_BITFIELDS ||
+            writer.compression == CompressionMethod::BI_ALPHABITFIELDS;
+        if is_bitfields && writer.bitmasks.is_none() {
+            writer.bitmasks = Some(match writer.bpp {
+                32 => Bitmasks::from_masks(0xff0000, 0x00ff00, 0x0000ff, 0xff000000),
+                bpp => default_bitmasks(bpp),
+            });
+        }
+        writer.topdown |= self.topdown;
+        writer
+    }
+}
+
+// Whitespace separated tokens of a PNM header, skipping comments.
+struct Tokens<'a> {
+    input: &'a [u8],
+    position: usize,
+}
+
+fn pnm_error<T>(message: String) -> ConvertResult<T> {
+    Err(ConvertError::Pnm(message))
+}
+
+impl<'a> Tokens<'a> {
+    fn next(&mut self) -> ConvertResult<&'a str> {
+        loop {
+            match self.input.get(self.position) {
+                Some(b'#') => {
+                    while self.input.get(self.position).is_some_and(|c| *c != b'\n') {
+                        self.position += 1;
+                    }
+                },
+                Some(c) if c.is_ascii_whitespace() => self.position += 1,
+                Some(_) => break,
+                None => return pnm_error("truncated header".to_string()),
+            }
+        }
+        let start = self.position;
+        while self.input.get(self.position).is_some_and(|c| !c.is_ascii_whitespace()) {
+            self.position += 1;
+        }
+        std::str::from_utf8(&self.input[start..self.position])
+            .or_else(|_| pnm_error(format!("invalid header at byte {}", start)))
+    }
+
+    fn number(&mut self) -> ConvertResult<usize> {
+        let token = self.next()?;
+        token.parse().or_else(|_| pnm_error(format!("invalid number {:?}", token)))
+    }
+}
+
+// Reads binary PGM (P5), PPM (P6) and PAM (P7) files. Samples are scaled to 8 bits.
+pub fn read_pnm(input: &[u8]) -> ConvertResult<Image> {
+    let mut tokens = Tokens{ input, position: 0 };
+    let magic = tokens.next()?;
+    let (width, height, depth, maxval) = match magic {
+        "P5" | "P6" => {
+            let width = tokens.number()?;
+            let height = tokens.number()?;
+            let maxval = tokens.number()?;
+            (width, height, if magic == "P5" { 1 } else { 3 }, maxval)
+        },
+        "P7" => {
+            let (mut width, mut height, mut depth, mut maxval) = (None, None, None, None);
+            loop {
+                match tokens.next()? {
+                    "ENDHDR" => break,
+                    "WIDTH" => width = Some(tokens.number()?),
+                    "HEIGHT" => height = Some(tokens.number()?),
+                    "DEPTH" => depth = Some(tokens.number()?),
+                    "MAXVAL" => maxval = Some(tokens.number()?),
+                    // The depth is enough to know what the samples are.
+                    "TUPLTYPE" => { tokens.next()?; },
+                    field => return pnm_error(format!("unknown PAM header field {:?}", field)),
+                }
+            }
+            match (width, height, depth, maxval) {
+                (Some(width), Some(height), Some(depth), Some(maxval)) => (width, height, depth, maxval),
+                _ => return pnm_error("missing PAM header field".to_string()),
+            }
+        },
+        _ => return pnm_error(format!("unsupported magic {:?}", magic)),
+    };
+    if maxval == 0 || maxval > 65535 {
+        return pnm_error(format!("invalid maxval {}", maxval));
+    }
+    if depth == 0 || depth > 4 {
+        return pnm_error(format!("unsupported depth {}", depth));
+    }
+    // A single whitespace separates the header from the samples.
+    let data = &input[(tokens.position + 1).min(input.len())..];
+    let sample_size = if maxval > 255 { 2 } else { 1 };
+    let expected = width.checked_mul(height).and_then(|n| n.checked_mul(depth * sample_size));
+    if expected.is_none_or(|expected| data.len() < expected) {
+        return pnm_error(format!("{} bytes of data for {}x{}x{}", data.len(), width, height, depth));
+    }
+    let sample = |i: usize| {
+        let value = if sample_size == 2 {
+            (data[2*i] as usize) << 8 | data[2*i + 1] as usize
+        } else {
+            data[i] as usize
+        };
+        ((value * 255 + maxval / 2) / maxval) as u8
+    };
+    Ok(Image::from_fn(width, height, |x, y| {
+        let i = (y * width + x) * depth;
+        match depth {
+            1 => Color{ red: sample(i), green: sample(i), blue: sample(i), alpha: 255 },
+            2 => Color{ red: sample(i), green: sample(i), blue: sample(i), alpha: sample(i + 1) },
+            3 => Color{ red: sample(i), green: sample(i + 1), blue: sample(i + 2), alpha: 255 },
+            _ => Color{ red: sample(i), green: sample(i + 1), blue: sample(i + 2), alpha: sample(i + 3) },
+        }
+    }))
+}
+
+// PPM has no alpha channel, it's dropped.
+pub fn write_ppm(image: &Image) -> Vec<u8> {
+    let mut out = format!("P6\n{} {}\n255\n", image.width, image.height).into_bytes();
+    for c in image.colors() {
+        out.extend_from_slice(&[c.red, c.green, c.blue]);
+    }
+    out
+}
+
+pub fn write_pam(image: &Image) -> Vec<u8> {
+    let mut out = format!("P7\nWIDTH {}\nHEIGHT {}\nDEPTH 4\nMAXVAL 255\nTUPLTYPE RGB_ALPHA\nENDHDR\n", image.width, image.height).into_bytes();
+    for y in 0..image.height {
+        out.extend_from_slice(image.row(y));
+    }
+    out
+}
+
+#[cfg(feature = "png")]
+pub fn read_png(input: &[u8]) -> ConvertResult<Image> {
+    let rows = crate::embedded::decode_png(input).map_err(ConvertError::Png)?;
+    let mut image = Image::new(rows.first().map_or(0, |r| r.len()), rows.len());
+    for (y, row) in rows.iter().enumerate() {
+        image.set_row(y, row);
+    }
+    Ok(image)
+}
+
+#[cfg(not(feature = "png"))]
+pub fn read_png(_input: &[u8]) -> ConvertResult<Image> {
+    Err(ConvertError::MissingFeature("png"))
+}
+
+#[cfg(feature = "png")]
+pub fn write_png(image: &Image) -> ConvertResult<Vec<u8>> {
+    let mut out = vec!();
+    {
+        let mut encoder = png::Encoder::new(&mut out, image.width as u32, image.height as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let data = (0..image.height).flat_map(|y| image.row(y).iter().copied()).collect::<Vec<_>>();
+        encoder.write_header()
+            .and_then(|mut writer| writer.write_image_data(&data))
+            .map_err(|e| ConvertError::Png(e.to_string()))?;
+    }
+    Ok(out)
+}
+
+#[cfg(not(feature = "png"))]
+pub fn write_png(_image: &Image) -> ConvertResult<Vec<u8>> {
+    Err(ConvertError::MissingFeature("png"))
+}
+
+pub fn convert(input: &[u8], from: Format, to: Format, decode: DecodeOptions, options: &BmpOptions) -> ConvertResult<Vec<u8>> {
+    let source = match from {
+        Format::Bmp => Some(BmpFile::parse_with_options(input, decode)?.1),
+        _ => None,
+    };
+    let decoded;
+    let image = match (&source, from) {
+        (Some(bitmap), _) => &bitmap.image,
+        (None, Format::Png) => {
+            decoded = read_png(input)?;
+            &decoded
+        },
+        (None, _) => {
+            decoded = read_pnm(input)?;
+            &decoded
+        },
+    };
+    match to {
+        Format::Bmp => Ok(options.writer(source.as_ref(), image).write(image)?),
+        Format::Ppm => Ok(write_ppm(image)),
+        Format::Pam => Ok(write_pam(image)),
+        Format::Png => write_png(image),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(width: usize, height: usize) -> Image {
+        Image::from_fn(width, height, |x, y| Color{ red: (x*40) as u8, green: (y*30) as u8, blue: 77, alpha: (255 - x) as u8 })
+    }
+
+    fn opaque(image: &Image) -> Image {
+        Image::from_fn(image.width, image.height, |x, y| Color{ alpha: 255, ..image.pixel(x, y) })
+    }
+
+    #[test]
+    fn test_pnm_roundtrip() {
+        let image = gradient(5, 3);
+        assert_eq!(read_pnm(&write_pam(&image)).unwrap(), image);
+        assert_eq!(read_pnm(&write_ppm(&image)).unwrap(), opaque(&image));
+    }
+
+    #[test]
+    fn test_read_pnm() {
+        let pgm = b"P5 # comment\n2 1\n65535\n\xff\xff\x80\x00";
+        let gray = |v| Color{ red: v, green: v, blue: v, alpha: 255 };
+        assert_eq!(read_pnm(pgm).unwrap().colors().collect::<Vec<_>>(), vec!(gray(255), gray(128)));
+        assert_eq!(read_pnm(&pgm[..pgm.len() - 1]), Err(ConvertError::Pnm("3 bytes of data for 2x1x1".to_string())));
+        assert!(read_pnm(b"P7\nWIDTH 1\nHEIGHT 1\nENDHDR\n\0\0\0").is_err());
+        assert!(read_pnm(b"P3\n1 1\n255\n0 0 0").is_err());
+    }
+
+    #[test]
+    fn test_bmp_roundtrip() {
+        let image = gradient(6, 4);
+        let pam = write_pam(&image);
+        let bmp = convert(&pam, Format::Pam, Format::Bmp, DecodeOptions::default(), &BmpOptions::default()).unwrap();
+        let (_, bitmap) = BmpFile::parse(&bmp).unwrap();
+        // Alpha is kept.
+        assert_eq!((bitmap.dib_header_size, bitmap.bpp), (DibHeaderSize::BITMAPV5HEADER, 32));
+        assert_eq!(convert(&bmp, Format::Bmp, Format::Pam, DecodeOptions::default(), &BmpOptions::default()).unwrap(), pam);
+
+        let options = BmpOptions{ bpp: Some(24), header: Some(DibHeaderSize::BITMAPCOREHEADER), ..Default::default() };
+        let core = convert(&bmp, Format::Bmp, Format::Bmp, DecodeOptions::default(), &options).unwrap();
+        let (_, bitmap) = BmpFile::parse(&core).unwrap();
+        assert_eq!((bitmap.dib_header_size, bitmap.bpp, bitmap.compression), (DibHeaderSize::BITMAPCOREHEADER, 24, None));
+        assert_eq!(bitmap.image.data, opaque(&image).data);
+    }
+
+    #[test]
+    fn test_options() {
+        let image = Image::from_fn(4, 4, |x, _| Color{ red: x as u8, green: 0, blue: 0, alpha: 255 });
+        let options = BmpOptions{ bpp: Some(8), compression: Some(CompressionMethod::BI_RLE8), topdown: true, ..Default::default() };
+        let (_, bitmap) = BmpFile::parse(&options.writer(None, &image).write(&image).unwrap()).unwrap();
+        assert_eq!((bitmap.bpp, bitmap.compression, bitmap.height), (8, Some(CompressionMethod::BI_RLE8), -4));
+        assert_eq!(bitmap.image.data, image.data);
+
+        let options = BmpOptions{ bpp: Some(16), compression: Some(CompressionMethod::BI_BITFIELDS), ..Default::default() };
+        assert_eq!(options.writer(None, &image).bitmasks, Some(default_bitmasks(16)));
+        let options = BmpOptions{ bpp: Some(1), ..Default::default() };
+        assert_eq!(options.writer(None, &image).write(&image), Err(WriteError::TooManyColors(4)));
+
+        assert_eq!(parse_header("v4"), Ok(DibHeaderSize::BITMAPV4HEADER));
+        assert_eq!(parse_compression("rle4"), Ok(CompressionMethod::BI_RLE4));
+        assert!(parse_compression("jpeg").is_err());
+        assert_eq!(Format::from_path(Path::new("a/b.PNG")), Some(Format::Png));
+        assert_eq!(Format::from_path(Path::new("a/b")), None);
+    }
+
+    #[cfg(feature = "png")]
+    #[test]
+    fn test_png_roundtrip() {
+        let image = gradient(7, 5);
+        let png = convert(&write_pam(&image), Format::Pam, Format::Png, DecodeOptions::default(), &BmpOptions::default()).unwrap();
+        assert_eq!(read_png(&png).unwrap(), image);
+        let bmp = convert(&png, Format::Png, Format::Bmp, DecodeOptions::default(), &BmpOptions::default()).unwrap();
+        assert_eq!(BmpFile::parse(&bmp).unwrap().1.image.data, image.data);
+    }
+}
@@ -0,0 +1,120 @@
+// Decoding of the JPEG and PNG images embedded in BMP files with BI_JPEG and BI_PNG compressions.
+// Both return the rows of the image, top first.
+use crate::bmp::Color;
+
+fn to_rows(width: usize, height: usize, colors: &[Color]) -> Vec<Vec<Color>> {
+    colors.chunks(width.max(1)).take(height).map(|r| r.to_vec()).collect()
+}
+
+#[cfg(feature = "jpeg")]
+pub fn decode_jpeg(data: &[u8]) -> std::result::Result<Vec<Vec<Color>>, String> {
+    use jpeg_decoder::PixelFormat;
+
+    let mut decoder = jpeg_decoder::Decoder::new(data);
+    let buffer = decoder.decode().map_err(|e| e.to_string())?;
+    let info = decoder.info().ok_or("missing JPEG info")?;
+    let (width, height) = (info.width as usize, info.height as usize);
+    let colors = match info.pixel_format {
+        PixelFormat::L8 => buffer.iter().map(|l| Color{ red: *l, green: *l, blue: *l, alpha: 255 }).collect(),
+        // Big endian, keep the most significant byte.
+        PixelFormat::L16 => buffer.chunks(2).map(|l| Color{ red: l[0], green: l[0], blue: l[0], alpha: 255 }).collect(),
+        PixelFormat::RGB24 => buffer.chunks(3).map(|c| Color{ red: c[0], green: c[1], blue: c[2], alpha: 255 }).collect(),
+        // Adobe applications write inverted CMYK, which is about the only source of CMYK JPEG.
+        PixelFormat::CMYK32 => buffer.chunks(4).map(|c| {
+            let k = c[3] as u32;
+            let channel = |v: u8| (v as u32 * k / 255) as u8;
+            Color{ red: channel(c[0]), green: channel(c[1]), blue: channel(c[2]), alpha: 255 }
+        }).collect::<Vec<_>>(),
+    };
+    if colors.len() < width * height {
+        return Err(format!("JPEG data too short: {} pixels for {}x{}", colors.len(), width, height));
+    }
+    Ok(to_rows(width, height, &colors))
+}
+
+#[cfg(feature = "png")]
+pub fn decode_png(data: &[u8]) -> std::result::Result<Vec<Vec<Color>>, String> {
+    use png::ColorType;
+
+    let mut decoder = png::Decoder::new(data);
+    // Expand palettes, transparency and low bit depths, and strip 16 bits channels to 8 bits.
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(|e| e.to_string())?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer).map_err(|e| e.to_string())?;
+    let (width, height) = (info.width as usize, info.height as usize);
+    let mut colors = Vec::with_capacity(width * height);
+    for row in buffer.chunks(info.line_size).take(height) {
+        let pixels = row.chunks(info.color_type.samples()).take(width);
+        colors.extend(pixels.map(|p| match info.color_type {
+            ColorType::Grayscale => Color{ red: p[0], green: p[0], blue: p[0], alpha: 255 },
+            ColorType::GrayscaleAlpha => Color{ red: p[0], green: p[0], blue: p[0], alpha: p[1] },
+            ColorType::Rgb | ColorType::Indexed => Color{ red: p[0], green: p[1], blue: p[2], alpha: 255 },
+            ColorType::Rgba => Color{ red: p[0], green: p[1], blue: p[2], alpha: p[3] },
+        }));
+    }
+    if colors.len() < width * height {
+        return Err(format!("PNG data too short: {} pixels for {}x{}", colors.len(), width, height));
+    }
+    Ok(to_rows(width, height, &colors))
+}
+
+#[cfg(test)]
+mod tests {
+    // For Color, which only the PNG test uses.
+    #[cfg(feature = "png")]
+    use super::*;
+    use crate::bmp::tests::TestBitmap;
+    use crate::bmp::{BmpFile, CompressionMethod};
+
+    // BITMAPINFOHEADER file with bpp = 0 and the embedded image as data, as written by Windows.
+    fn embed(payload: &[u8], width: u32, height: u32, compression: CompressionMethod) -> Vec<u8> {
+        TestBitmap{ width: width as i32, height: height as i32, bpp: 0, compression, data: payload.to_vec(), ..Default::default() }.write()
+    }
+
+    #[cfg(feature = "png")]
+    #[test]
+    fn test_png() {
+        let (width, height) = (7, 5);
+        let rgba = (0..width*height).flat_map(|i| vec!(i as u8 * 7, 255 - i as u8, 3, if i % 2 == 0 { 255 } else { 100 })).collect::<Vec<_>>();
+        let mut payload = vec!();
+        {
+            let mut encoder = png::Encoder::new(&mut payload, width, height);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.write_header().unwrap().write_image_data(&rgba).unwrap();
+        }
+        let (_, bmp) = BmpFile::parse(&embed(&payload, width, height, CompressionMethod::BI_PNG)).unwrap();
+        for x in 0..width as usize {
+            for y in 0..height as usize {
+                let p = &rgba[4*(y*width as usize + x)..];
+                assert_eq!(bmp.image.pixel(x, y), Color{ red: p[0], green: p[1], blue: p[2], alpha: p[3] });
+            }
+        }
+        assert!(BmpFile::parse(&embed(&payload, width + 1, height, CompressionMethod::BI_PNG)).is_err());
+    }
+
+    #[cfg(feature = "jpeg")]
+    #[test]
+    fn test_jpeg() {
+        let (width, height) = (16, 8);
+        let rgb = (0..width*height).flat_map(|i| if (i % width) < 8 { vec!(200, 30, 30) } else { vec!(20, 40, 220) }).collect::<Vec<_>>();
+        let mut payload = vec!();
+        jpeg_encoder::Encoder::new(&mut payload, 100).encode(&rgb, width, height, jpeg_encoder::ColorType::Rgb).unwrap();
+        let (_, bmp) = BmpFile::parse(&embed(&payload, width as u32, height as u32, CompressionMethod::BI_JPEG)).unwrap();
+        let close = |a: u8, b: u8| (a as i32 - b as i32).abs() <= 4;
+        for x in 0..width as usize {
+            for y in 0..height as usize {
+                let p = &rgb[3*(y*width as usize + x)..];
+                let c = bmp.image.pixel(x, y);
+                assert!(close(c.red, p[0]) && close(c.green, p[1]) && close(c.blue, p[2]), "{:?} at {}x{}", c, x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_payload() {
+        let payload = [0_u8; 16];
+        assert!(BmpFile::parse(&embed(&payload, 2, 2, CompressionMethod::BI_PNG)).is_err());
+    }
+}
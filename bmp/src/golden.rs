@@ -0,0 +1,195 @@
+// Golden-image regression tests over the bmpsuite and bmptestsuite files, which are not committed
+// (see tests/README.txt), and the small fixtures which are. The result of decoding each file is
+// compared with tests/golden.tsv, which is regenerated by running the tests with
+// BMP_UPDATE_GOLDEN=1.
+use crate::bmp::BmpFile;
+use crate::image::Image;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// The fixtures are small files written by BmpWriter, sorted like bmpsuite in g (good), b (bad) and
+// q (questionable). Those of b and q/pal8offs.bmp have patched headers: truncated, a bpp of 3, a
+// pixel offset past the end, 8 colors for indexes up to 12 and 100 bytes between palette and
+// pixels.
+const SUITES: [&str; 3] = ["tests/bmpsuite", "tests/bmptestsuite", "tests/fixtures"];
+const REFERENCE: &str = "tests/golden.tsv";
+
+// How the suites classify their files, from the name of the directory they're in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Expectation {
+    // Must decode.
+    Good,
+    // Decoding or rejecting them are both fine, but the result must not change silently.
+    Questionable,
+    // Ideally rejected. No file may crash the decoder anyway.
+    Bad,
+}
+
+fn expectation(path: &str) -> Expectation {
+    for dir in path.split('/') {
+        match dir {
+            "g" | "valid" => return Expectation::Good,
+            "b" | "corrupt" => return Expectation::Bad,
+            _ => {},
+        }
+    }
+    Expectation::Questionable
+}
+
+// FNV-1a, which unlike DefaultHasher is guaranteed to be stable.
+fn hash(image: &Image) -> u64 {
+    let mut hash = 0xcbf29ce484222325_u64;
+    let size = [image.width as u64, image.height as u64];
+    let bytes = size.iter().flat_map(|s| s.to_le_bytes()).chain((0..image.height).flat_map(|y| image.row(y).iter().copied()));
+    for b in bytes {
+        hash = (hash ^ b as u64).wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// Either the size and hash of the pixels, or why the file couldn't be decoded.
+fn decode(path: &Path) -> String {
+    let input = match fs::read(path) {
+        Ok(input) => input,
+        Err(e) => return format!("io:{}", e),
+    };
+    match std::panic::catch_unwind(|| BmpFile::parse(&input).map(|(_, bitmap)| bitmap.image)) {
+        Ok(Ok(image)) => format!("{}x{}:{:016x}", image.width, image.height, hash(&image)),
+        Ok(Err(e)) => format!("error:{}", e.kind.cause()),
+        Err(_) => "panic".to_string(),
+    }
+}
+
+fn bmp_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            bmp_files(&path, files);
+        } else if path.extension().and_then(|e| e.to_str()).is_some_and(|e| e.eq_ignore_ascii_case("bmp")) {
+            files.push(path);
+        }
+    }
+}
+
+// Results by path relative to root, for the suites found under root.
+fn decode_suites(root: &Path) -> BTreeMap<String, String> {
+    let mut files = vec!();
+    for suite in SUITES.iter() {
+        bmp_files(&root.join(suite), &mut files);
+    }
+    files.iter().map(|path| {
+        let name = path.strip_prefix(root).unwrap_or(path).components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        (name, decode(path))
+    }).collect()
+}
+
+fn read_reference(path: &Path) -> BTreeMap<String, String> {
+    let content = fs::read_to_string(path).unwrap_or_default();
+    content.lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(name, result)| (name.to_string(), result.to_string()))
+        .collect()
+}
+
+fn write_reference(path: &Path, results: &BTreeMap<String, String>) {
+    let mut content = "# Decoding results of the test suites, see golden.rs.\n".to_string();
+    for (name, result) in results.iter() {
+        content += &format!("{}\t{}\n", name, result);
+    }
+    fs::write(path, content).unwrap();
+}
+
+// Files which aren't in the reference only need to match their expectation. Files of the
+// reference which are missing are ignored, as the suites may not be there at all.
+fn check(results: &BTreeMap<String, String>, reference: &BTreeMap<String, String>) -> Vec<String> {
+    let mut failures = vec!();
+    for (name, result) in results.iter() {
+        if result == "panic" {
+            failures.push(format!("{}: crashed", name));
+        } else if expectation(name) == Expectation::Good && result.starts_with("error:") {
+            failures.push(format!("{}: good file not decoded ({})", name, result));
+        }
+        match reference.get(name) {
+            Some(expected) if expected != result => failures.push(format!("{}: expected {}, got {}", name, expected, result)),
+            _ => {},
+        }
+    }
+    failures
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bmp::{Color, DibHeaderSize};
+    use crate::writer::BmpWriter;
+
+    #[test]
+    fn test_suites() {
+        let results = decode_suites(Path::new("."));
+        if results.is_empty() {
+            println!("No test suite found, see tests/README.txt");
+            return;
+        }
+        if std::env::var_os("BMP_UPDATE_GOLDEN").is_some() {
+            write_reference(Path::new(REFERENCE), &results);
+            return;
+        }
+        let reference = read_reference(Path::new(REFERENCE));
+        let missing = results.keys().filter(|name| !reference.contains_key(*name)).count();
+        if missing > 0 {
+            println!("{} files are not in {}, run the tests with BMP_UPDATE_GOLDEN=1 to add them", missing, REFERENCE);
+        }
+        // The fixtures are committed with their results.
+        let unlisted = results.keys().filter(|name| name.starts_with("tests/fixtures/") && !reference.contains_key(*name)).collect::<Vec<_>>();
+        assert!(unlisted.is_empty(), "fixtures not in {}: {:?}", REFERENCE, unlisted);
+        let failures = check(&results, &reference);
+        assert!(failures.is_empty(), "{} regressions:\n{}", failures.len(), failures.join("\n"));
+    }
+
+    #[test]
+    fn test_harness() {
+        let root = std::env::temp_dir().join(format!("bmp-golden-{}", std::process::id()));
+        let image = Image::from_fn(3, 2, |x, y| Color{ red: x as u8, green: y as u8, blue: 0, alpha: 255 });
+        let bitmap = BmpWriter::new(DibHeaderSize::BITMAPINFOHEADER, 24).write(&image).unwrap();
+        let files = [
+            ("tests/bmpsuite/g/ok.bmp", &bitmap[..]),
+            ("tests/bmpsuite/b/truncated.bmp", &bitmap[..20]),
+            ("tests/bmptestsuite/corrupt/ok.BMP", &bitmap[..]),
+            ("tests/bmptestsuite/valid/truncated.bmp", &bitmap[..20]),
+        ];
+        for (name, content) in files.iter() {
+            let path = root.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        let results = decode_suites(&root);
+        write_reference(&root.join("golden.tsv"), &results);
+        let reference = read_reference(&root.join("golden.tsv"));
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(reference, results);
+
+        assert_eq!(results.len(), 4);
+        assert_eq!(results["tests/bmpsuite/g/ok.bmp"], format!("3x2:{:016x}", hash(&image)));
+        assert_eq!(results["tests/bmpsuite/b/truncated.bmp"], "error:truncated");
+        assert_eq!(check(&results, &BTreeMap::new()), vec!("tests/bmptestsuite/valid/truncated.bmp: good file not decoded (error:truncated)"));
+
+        let mut reference = results.clone();
+        reference.insert("tests/bmpsuite/b/truncated.bmp".to_string(), "error:invalid_value".to_string());
+        reference.remove("tests/bmptestsuite/valid/truncated.bmp");
+        reference.insert("tests/bmpsuite/q/gone.bmp".to_string(), "error:truncated".to_string());
+        assert_eq!(check(&results, &reference), vec!(
+            "tests/bmpsuite/b/truncated.bmp: expected error:invalid_value, got error:truncated",
+            "tests/bmptestsuite/valid/truncated.bmp: good file not decoded (error:truncated)",
+        ));
+        assert_eq!(expectation("tests/bmpsuite/q/pal8os2v2-sz.bmp"), Expectation::Questionable);
+    }
+}
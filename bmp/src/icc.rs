@@ -0,0 +1,372 @@
+// Minimal support of ICC profiles as embedded in BITMAPV5HEADER bitmaps.
+// Only matrix/TRC RGB profiles (the kind of profile describing a display, like sRGB) are handled.
+// cf. https://www.color.org/specification/ICC.1-2022-05.pdf
+use crate::bmp::{Colorf, Endpoint, Input, Result, xyz_to_srgb};
+
+// Tone reproduction curve of a channel, converting from the device value to a linear value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Curve {
+    Identity,
+    Gamma(f64),
+    // Samples evenly spaced over [0, 1], linearly interpolated.
+    Table(Vec<f64>),
+    // Parametric curves of all types expressed with the most generic one (type 4):
+    // Y = (a*X + b)^g + e if X >= d, c*X + f otherwise.
+    Parametric{ g: f64, a: f64, b: f64, c: f64, d: f64, e: f64, f: f64 },
+}
+
+impl Curve {
+    pub fn apply(&self, value: f64) -> f64 {
+        match self {
+            Curve::Identity => value,
+            Curve::Gamma(gamma) => value.powf(*gamma),
+            Curve::Table(table) => {
+                if table.len() < 2 {
+                    return value;
+                }
+                let pos = value.clamp(0.0, 1.0) * (table.len() - 1) as f64;
+                let idx = (pos as usize).min(table.len() - 2);
+                let frac = pos - idx as f64;
+                table[idx] * (1.0 - frac) + table[idx+1] * frac
+            },
+            Curve::Parametric{g, a, b, c, d, e, f} => {
+                if value >= *d {
+                    let base = a*value + b;
+                    if base > 0.0 { base.powf(*g) + e } else { *e }
+                } else {
+                    c*value + f
+                }
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IccProfile {
+    pub size: u32,
+    pub version: (u8, u8),
+    pub description: Option<String>,
+    // Colorants, in the D50 adapted XYZ profile connection space.
+    pub red: Endpoint,
+    pub green: Endpoint,
+    pub blue: Endpoint,
+    pub red_trc: Curve,
+    pub green_trc: Curve,
+    pub blue_trc: Curve,
+}
+
+// Bradford chromatic adaptation from D50 (the profile connection space) to D65 (sRGB).
+// cf. http://www.brucelindbloom.com/index.html?Eqn_ChromAdapt.html
+const D50_TO_D65: [[f64; 3]; 3] = [
+    [0.9555766, -0.0230393, 0.0631636],
+    [-0.0282895, 1.0099416, 0.0210077],
+    [0.0122982, -0.0204830, 1.3299098],
+];
+
+fn s15_fixed16(value: i32) -> f64 {
+    value as f64 / 65536.0
+}
+
+fn tag_signature(input: Input) -> Result<[u8; 4]> {
+    use nom::{
+        bytes::complete::take,
+        combinator::map,
+    };
+    map(take(4_u8), |s: Input| [s[0], s[1], s[2], s[3]])(input)
+}
+
+fn parse_xyz(input: Input) -> Result<Endpoint> {
+    use nom::{
+        bytes::complete::{tag, take},
+        error::context,
+        number::complete::be_i32,
+        sequence::tuple,
+    };
+    let (i, (_, _, x, y, z)) = tuple((
+        context("XYZ type", tag("XYZ ")),
+        context("Reserved", take(4_u8)),
+        context("X", be_i32),
+        context("Y", be_i32),
+        context("Z", be_i32),
+    ))(input)?;
+    Ok((i, Endpoint{ x: s15_fixed16(x), y: s15_fixed16(y), z: s15_fixed16(z) }))
+}
+
+fn parse_curve(input: Input) -> Result<Curve> {
+    use nom::{
+        bytes::complete::take,
+        error::{context, make_error, ErrorKind},
+        multi::count,
+        number::complete::{be_i32, be_u16, be_u32},
+        sequence::tuple,
+    };
+    let (i, (signature, _)) = tuple((
+        context("Curve type", tag_signature),
+        context("Reserved", take(4_u8)),
+    ))(input)?;
+    match &signature {
+        b"curv" => {
+            let (i, nb_entries) = context("Curve entries count", be_u32)(i)?;
+            match nb_entries {
+                0 => Ok((i, Curve::Identity)),
+                1 => {
+                    let (i, gamma) = context("Curve gamma", be_u16)(i)?;
+                    Ok((i, Curve::Gamma(gamma as f64 / 256.0)))
+                },
+                n => {
+                    if n as usize * 2 > i.len() {
+                        return Err(nom::Err::Failure(make_error(input, ErrorKind::Eof)));
+                    }
+                    let (i, table) = context("Curve table", count(be_u16, n as usize))(i)?;
+                    Ok((i, Curve::Table(table.iter().map(|v| *v as f64 / 65535.0).collect())))
+                },
+            }
+        },
+        b"para" => {
+            let (i, (function, _)) = tuple((
+                context("Parametric curve function", be_u16),
+                context("Reserved", be_u16),
+            ))(i)?;
+            let nb_params = match function {
+                0 => 1,
+                1 => 3,
+                2 => 4,
+                3 => 5,
+                4 => 7,
+                _ => return Err(nom::Err::Failure(make_error(input, ErrorKind::Switch))),
+            };
+            let (i, params) = context("Parametric curve parameters", count(be_i32, nb_params))(i)?;
+            let p = params.iter().map(|v| s15_fixed16(*v)).collect::<Vec<_>>();
+            let curve = match function {
+                0 => Curve::Parametric{ g: p[0], a: 1.0, b: 0.0, c: 0.0, d: 0.0, e: 0.0, f: 0.0 },
+                1 => Curve::Parametric{ g: p[0], a: p[1], b: p[2], c: 0.0, d: -p[2]/p[1], e: 0.0, f: 0.0 },
+                2 => Curve::Parametric{ g: p[0], a: p[1], b: p[2], c: 0.0, d: -p[2]/p[1], e: p[3], f: p[3] },
+                3 => Curve::Parametric{ g: p[0], a: p[1], b: p[2], c: p[3], d: p[4], e: 0.0, f: 0.0 },
+                _ => Curve::Parametric{ g: p[0], a: p[1], b: p[2], c: p[3], d: p[4], e: p[5], f: p[6] },
+            };
+            Ok((i, curve))
+        },
+        _ => Err(nom::Err::Failure(make_error(input, ErrorKind::Tag))),
+    }
+}
+
+// Only the ASCII part of the description is kept, which is present in v2 'desc' tags. v4 profiles
+// use a 'mluc' tag, of which we take the first record.
+fn parse_description(input: Input) -> Result<String> {
+    use nom::{
+        bytes::complete::take,
+        error::{context, make_error, ErrorKind},
+        number::complete::be_u32,
+        sequence::tuple,
+    };
+    let (i, (signature, _)) = tuple((
+        context("Description type", tag_signature),
+        context("Reserved", take(4_u8)),
+    ))(input)?;
+    match &signature {
+        b"desc" => {
+            let (i, length) = context("Description length", be_u32)(i)?;
+            let (i, text) = context("Description", take(length))(i)?;
+            let text = text.iter().take_while(|c| **c != 0).map(|c| *c as char).collect();
+            Ok((i, text))
+        },
+        b"mluc" => {
+            let (_, (nb_records, _, _, length, offset)) = tuple((
+                context("Records count", be_u32),
+                context("Record size", be_u32),
+                context("Language and country", be_u32),
+                context("Description length", be_u32),
+                context("Description offset", be_u32),
+            ))(i)?;
+            if nb_records == 0 || offset as usize + length as usize > input.len() {
+                return Err(nom::Err::Failure(make_error(input, ErrorKind::Eof)));
+            }
+            // UTF-16BE
+            let text = &input[offset as usize..offset as usize + length as usize];
+            let text = text.chunks(2).filter(|c| c.len() == 2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect::<Vec<_>>();
+            Ok((&[], String::from_utf16_lossy(&text)))
+        },
+        _ => Err(nom::Err::Failure(make_error(input, ErrorKind::Tag))),
+    }
+}
+
+impl IccProfile {
+    pub fn parse(input: Input) -> Result<Self> {
+        use nom::{
+            bytes::complete::{tag, take},
+            combinator::verify,
+            error::{context, make_error, ErrorKind},
+            multi::count,
+            number::complete::{be_u8, be_u32},
+            sequence::tuple,
+        };
+        let (i, (size, _, major, minor, _, _, _, _, _)) = tuple((
+            context("Profile size", verify(be_u32, |s| *s as usize <= input.len())),
+            context("Preferred CMM type", take(4_u8)),
+            context("Major version", be_u8),
+            context("Minor version", be_u8),
+            context("Reserved", take(2_u8)),
+            context("Device class", take(4_u8)),
+            context("Data color space", tag("RGB ")),
+            context("Profile connection space", tag("XYZ ")),
+            context("Rest of header", take(104_u8)),
+        ))(input)?;
+        let input = &input[..size as usize];
+        let (i, nb_tags) = context("Tag count", verify(be_u32, |n| *n as usize * 12 <= i.len()))(i)?;
+        let (_, tags) = context("Tag table", count(tuple((tag_signature, be_u32, be_u32)), nb_tags as usize))(i)?;
+        let find_tag = |signature: &[u8; 4]| -> std::result::Result<Input, nom::Err<crate::bmp::Error>> {
+            match tags.iter().find(|(s, _, _)| s == signature) {
+                Some((_, offset, size)) if *offset as usize + *size as usize <= input.len() => {
+                    Ok(&input[*offset as usize..*offset as usize + *size as usize])
+                },
+                // Either missing (i.e not a matrix/TRC profile) or out of the profile.
+                _ => Err(nom::Err::Failure(make_error(input, ErrorKind::Tag))),
+            }
+        };
+        let (_, red) = context("Red colorant", parse_xyz)(find_tag(b"rXYZ")?)?;
+        let (_, green) = context("Green colorant", parse_xyz)(find_tag(b"gXYZ")?)?;
+        let (_, blue) = context("Blue colorant", parse_xyz)(find_tag(b"bXYZ")?)?;
+        let (_, red_trc) = context("Red TRC", parse_curve)(find_tag(b"rTRC")?)?;
+        let (_, green_trc) = context("Green TRC", parse_curve)(find_tag(b"gTRC")?)?;
+        let (_, blue_trc) = context("Blue TRC", parse_curve)(find_tag(b"bTRC")?)?;
+        let description = find_tag(b"desc").ok().and_then(|d| parse_description(d).ok()).map(|(_, d)| d);
+        Ok((&input[input.len()..], IccProfile{
+            size,
+            version: (major, minor >> 4),
+            description,
+            red, green, blue,
+            red_trc, green_trc, blue_trc,
+        }))
+    }
+
+    pub fn to_xyz(&self, color: Colorf) -> Colorf {
+        let red = self.red_trc.apply(color.red);
+        let green = self.green_trc.apply(color.green);
+        let blue = self.blue_trc.apply(color.blue);
+        let x = self.red.x*red + self.green.x*green + self.blue.x*blue;
+        let y = self.red.y*red + self.green.y*green + self.blue.y*blue;
+        let z = self.red.z*red + self.green.z*green + self.blue.z*blue;
+        Colorf { red: x, green: y, blue: z, alpha: color.alpha }
+    }
+
+    // Without adaptation (absolute colorimetric intent) the connection space values are used as
+    // is: the media white point isn't read, so it's taken as the D50 of the connection space.
+    pub fn to_srgb(&self, color: Colorf, adapt: bool) -> Colorf {
+        let xyz = self.to_xyz(color);
+        if !adapt {
+            return xyz_to_srgb(xyz);
+        }
+        let [x, y, z] = D50_TO_D65.map(|row| row[0]*xyz.red + row[1]*xyz.green + row[2]*xyz.blue);
+        xyz_to_srgb(Colorf { red: x, green: y, blue: z, alpha: color.alpha })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // sRGB colorants, adapted to D50.
+    pub const SRGB_COLORANTS: [[f64; 3]; 3] = [
+        [0.4360747, 0.2225045, 0.0139322],
+        [0.3850649, 0.7168786, 0.0971045],
+        [0.1430804, 0.0606169, 0.7141733],
+    ];
+
+    fn s15(value: f64) -> [u8; 4] {
+        ((value * 65536.0).round() as i32).to_be_bytes()
+    }
+
+    // Builds a matrix/TRC profile with the same curve for all channels.
+    pub fn profile(colorants: &[[f64; 3]; 3], curve: &[u8]) -> Vec<u8> {
+        let mut tags: Vec<(&[u8; 4], Vec<u8>)> = vec!();
+        for (signature, xyz) in [b"rXYZ", b"gXYZ", b"bXYZ"].iter().zip(colorants.iter()) {
+            let mut data = b"XYZ \0\0\0\0".to_vec();
+            for v in xyz.iter() {
+                data.extend_from_slice(&s15(*v));
+            }
+            tags.push((signature, data));
+        }
+        for signature in [b"rTRC", b"gTRC", b"bTRC"] {
+            tags.push((signature, curve.to_vec()));
+        }
+        let mut desc = b"desc\0\0\0\0".to_vec();
+        desc.extend_from_slice(&5_u32.to_be_bytes());
+        desc.extend_from_slice(b"test\0");
+        tags.push((b"desc", desc));
+
+        let data_offset = 128 + 4 + 12*tags.len();
+        let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+        let mut data = vec!();
+        for (signature, tag) in tags.iter() {
+            table.extend_from_slice(*signature);
+            table.extend_from_slice(&((data_offset + data.len()) as u32).to_be_bytes());
+            table.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+            data.extend_from_slice(tag);
+            // Tags are 4 bytes aligned
+            while data.len() % 4 != 0 {
+                data.push(0);
+            }
+        }
+        let mut out = vec!();
+        out.extend_from_slice(&((128 + table.len() + data.len()) as u32).to_be_bytes());
+        out.extend_from_slice(b"none");
+        out.extend_from_slice(&[2, 0x10, 0, 0]);
+        out.extend_from_slice(b"mntrRGB XYZ ");
+        out.resize(128, 0);
+        out.extend_from_slice(&table);
+        out.extend_from_slice(&data);
+        out
+    }
+
+    // Parametric curve of type 3 with the sRGB parameters.
+    pub fn srgb_curve() -> Vec<u8> {
+        let mut curve = b"para\0\0\0\0\0\x03\0\0".to_vec();
+        for p in [2.4, 1.0/1.055, 0.055/1.055, 1.0/12.92, 0.04045] {
+            curve.extend_from_slice(&s15(p));
+        }
+        curve
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 0.005
+    }
+
+    #[test]
+    fn test_curves() {
+        assert!(close(Curve::Identity.apply(0.3), 0.3));
+        assert!(close(Curve::Gamma(2.0).apply(0.5), 0.25));
+        assert!(close(Curve::Table(vec!(0.0, 0.5, 0.6)).apply(0.75), 0.55));
+        let (_, srgb) = parse_curve(&srgb_curve()).unwrap();
+        assert!(close(srgb.apply(0.5), 0.214));
+        assert!(close(srgb.apply(0.02), 0.02/12.92));
+        let (_, gamma) = parse_curve(b"curv\0\0\0\0\0\0\0\x01\x02\x00").unwrap();
+        assert_eq!(gamma, Curve::Gamma(2.0));
+    }
+
+    #[test]
+    fn test_srgb_profile_is_identity() {
+        let (_, profile) = IccProfile::parse(&profile(&SRGB_COLORANTS, &srgb_curve())).unwrap();
+        assert_eq!(profile.description, Some("test".to_string()));
+        assert_eq!(profile.version, (2, 1));
+        for (r, g, b) in [(1.0, 1.0, 1.0), (0.2, 0.5, 0.8), (1.0, 0.0, 0.0), (0.0, 0.0, 0.0)] {
+            let srgb = profile.to_srgb(Colorf{ red: r, green: g, blue: b, alpha: 1.0 }, true);
+            assert!(close(srgb.red, r) && close(srgb.green, g) && close(srgb.blue, b), "{:?} for {} {} {}", srgb, r, g, b);
+        }
+    }
+
+    #[test]
+    fn test_linear_profile() {
+        let (_, profile) = IccProfile::parse(&profile(&SRGB_COLORANTS, b"curv\0\0\0\0\0\0\0\0")).unwrap();
+        let srgb = profile.to_srgb(Colorf{ red: 0.5, green: 0.5, blue: 0.5, alpha: 1.0 }, true);
+        assert!(close(srgb.red, 0.7354) && close(srgb.green, 0.7354) && close(srgb.blue, 0.7354), "{:?}", srgb);
+    }
+
+    #[test]
+    fn test_unsupported_profile() {
+        let mut cmyk = profile(&SRGB_COLORANTS, &srgb_curve());
+        cmyk[16..20].copy_from_slice(b"CMYK");
+        assert!(IccProfile::parse(&cmyk).is_err());
+        let srgb = profile(&SRGB_COLORANTS, &srgb_curve());
+        assert!(IccProfile::parse(&srgb[..200]).is_err());
+    }
+}
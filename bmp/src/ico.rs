@@ -0,0 +1,350 @@
+// Windows icons (.ico) and cursors (.cur).
+//
+// The file starts with a directory of images, usually the same picture in several sizes. Each
+// image is either a PNG file or a DIB, that is a bitmap without its file header. DIBs are twice as
+// high as the picture: the colors (XOR bitmap) are followed by a 1 bpp AND mask, which isn't
+// described by the header. Both kinds are decoded by wrapping them into a BMP file.
+// https://docs.microsoft.com/en-us/previous-versions/ms997538(v=msdn.10)
+use crate::bmp::*;
+use num_enum::TryFromPrimitive;
+use std::convert::TryFrom;
+
+// Reserved, type and number of images.
+pub const ICON_DIR_SIZE: usize = 6;
+pub const ICON_DIR_ENTRY_SIZE: usize = 16;
+pub const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u16)]
+pub enum IconType {
+    Icon = 1,
+    Cursor = 2,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct IconEntry {
+    // Size given by the directory, which is only a hint: the bitmap has the actual one.
+    pub width: u16,
+    pub height: u16,
+    // Headers of the DIB, or made up for PNG images (BI_PNG compression). The hotspot of cursors
+    // is given by the directory.
+    pub bitmap: BmpFile,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct IconDirectory {
+    pub icon_type: IconType,
+    pub entries: Vec<IconEntry>,
+}
+
+struct DirEntry {
+    width: u8,
+    height: u8,
+    // Color planes and bpp for icons, hotspot for cursors.
+    planes: u16,
+    bpp: u16,
+    size: u32,
+    offset: u32,
+}
+
+// Whether the input starts like an icon directory. Bitmaps can't, 0 not being a valid magic.
+pub fn is_icon_directory(input: Input) -> bool {
+    input.len() >= ICON_DIR_SIZE && input[0..2] == [0, 0] && IconType::try_from(u16::from_le_bytes([input[2], input[3]])).is_ok()
+}
+
+fn parse_directory(input: Input) -> BmpResult<(IconType, Vec<DirEntry>)> {
+    use nom::{
+        combinator::{map_res, verify},
+        error::context,
+        multi::count,
+        number::complete::{le_u8, le_u16, le_u32},
+        sequence::tuple,
+    };
+    let entry = tuple((
+        context("Image Width", le_u8),
+        context("Image Height", le_u8),
+        context("Color Count", le_u8),
+        context("Reserved", le_u8),
+        context("Planes", le_u16),
+        context("Bits Per Pixel", le_u16),
+        context("Image Size", le_u32),
+        context("Image Offset", le_u32),
+    ));
+    let result: Result<_> = tuple((
+        context("Reserved", verify(le_u16, |r| *r == 0)),
+        context("Icon Type", map_res(le_u16, IconType::try_from)),
+        context("Image Count", le_u16),
+    ))(input).and_then(|(i, (_, icon_type, n))| {
+        let (i, entries) = count(entry, n as usize)(i)?;
+        Ok((i, (icon_type, entries)))
+    });
+    match result {
+        Ok((_, (icon_type, entries))) => {
+            let entries = entries.into_iter().map(|(width, height, _, _, planes, bpp, size, offset)| {
+                DirEntry{ width, height, planes, bpp, size, offset }
+            }).collect();
+            Ok((icon_type, entries))
+        },
+        Err(e) => {
+            let mut error = BmpFile::default().nom_error(input, e);
+            error.header = None;
+            Err(error)
+        },
+    }
+}
+
+// BMP file made of a file header followed by the DIB, whose pixel data starts at offset.
+fn wrap(dib: &[u8], offset: usize) -> Vec<u8> {
+    let mut file = b"BM".to_vec();
+    file.extend_from_slice(&((FILE_HEADER_SIZE + dib.len()) as u32).to_le_bytes());
+    file.extend_from_slice(&[0; 4]);
+    file.extend_from_slice(&((FILE_HEADER_SIZE + offset) as u32).to_le_bytes());
+    file.extend_from_slice(dib);
+    file
+}
+
+// Moves the error from a wrapped file, whose first added bytes aren't in the icon file, to the
+// image at start.
+fn unwrap_error(mut error: BmpError, start: usize, added: usize) -> BmpError {
+    error.offset = start + error.offset.saturating_sub(added);
+    error
+}
+
+// The dimensions of the PNG image are needed for the BITMAPINFOHEADER the decoder checks them
+// against.
+fn parse_png(data: &[u8], start: usize, options: DecodeOptions) -> BmpResult<BmpFile> {
+    // Signature, then the IHDR chunk length and type.
+    let ihdr = PNG_SIGNATURE.len() + 8;
+    let size = match data.get(ihdr..ihdr + 8) {
+        Some(s) => [&s[0..4], &s[4..8]].map(|b| i32::from_be_bytes([b[0], b[1], b[2], b[3]])),
+        None => return Err(BmpError{ kind: BmpErrorKind::Truncated, field: "Embedded image", offset: start + data.len(), header: None }),
+    };
+    let mut dib = vec!();
+    dib.extend_from_slice(&(DibHeaderSize::BITMAPINFOHEADER as u32).to_le_bytes());
+    dib.extend_from_slice(&size[0].to_le_bytes());
+    dib.extend_from_slice(&size[1].to_le_bytes());
+    dib.extend_from_slice(&1_u16.to_le_bytes());
+    dib.extend_from_slice(&0_u16.to_le_bytes());
+    dib.extend_from_slice(&(CompressionMethod::BI_PNG as u32).to_le_bytes());
+    dib.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    dib.extend_from_slice(&[0; 16]);
+    let header_size = dib.len();
+    dib.extend_from_slice(data);
+    let (_, bitmap) = BmpFile::parse_bitmap_at(&wrap(&dib, header_size), 0, options)
+        .map_err(|e| unwrap_error(e, start, FILE_HEADER_SIZE + header_size))?;
+    Ok(bitmap)
+}
+
+// The result has the XOR bitmap's headers with the height of the picture, and the masks applied:
+// transparent where the screen is kept, and black where it is inverted, which can't be
+// represented. 32 bpp images have an alpha channel instead, unless it's all zeros.
+fn parse_dib(data: &[u8], start: usize, options: DecodeOptions) -> BmpResult<BmpFile> {
+    let mut file = wrap(data, 0);
+    let mut header = BmpFile::default();
+    let headers_end = match header.parse_headers(&file) {
+        Ok((i, _)) => file.len() - i.len(),
+        Err(e) => return Err(unwrap_error(header.nom_error(&file, e), start, FILE_HEADER_SIZE)),
+    };
+    let core = header.dib_header_size == DibHeaderSize::BITMAPCOREHEADER;
+    let height_offset = FILE_HEADER_SIZE + if core { 6 } else { 8 };
+    if header.height % 2 != 0 {
+        return Err(unwrap_error(header.error(BmpErrorKind::InvalidValue, "Image Height", height_offset), start, FILE_HEADER_SIZE));
+    }
+    let height = header.height / 2;
+    if core {
+        file[height_offset..height_offset + 2].copy_from_slice(&(height as i16).to_le_bytes());
+    } else {
+        file[height_offset..height_offset + 4].copy_from_slice(&height.to_le_bytes());
+    }
+    let offset = headers_end + header.palette_size();
+    file[10..14].copy_from_slice(&(offset as u32).to_le_bytes());
+    let (_, mut bitmap) = BmpFile::parse_bitmap_at(&file, 0, options).map_err(|e| unwrap_error(e, start, FILE_HEADER_SIZE))?;
+
+    let (width, rows) = (bitmap.image.width, bitmap.image.height);
+    // Position in the file of row y (from the top) of a bitmap, given its number of bytes per row.
+    let row_start = |bitmap_start: usize, line_bytes: usize, y: usize| {
+        let n = if height < 0 { y } else { rows - 1 - y };
+        bitmap_start + n * line_bytes
+    };
+    let line_bytes = (width * bitmap.bpp as usize).div_ceil(32) * 4;
+    let uncompressed = matches!(bitmap.compression, None | Some(CompressionMethod::BI_RGB));
+    if bitmap.bpp == 32 && uncompressed {
+        let alpha = |x: usize, y: usize| file.get(row_start(offset, line_bytes, y) + x * 4 + 3).copied().unwrap_or(0);
+        if (0..rows).any(|y| (0..width).any(|x| alpha(x, y) != 0)) {
+            for y in 0..rows {
+                for x in 0..width {
+                    let color = bitmap.image.pixel(x, y);
+                    bitmap.image.set_pixel(x, y, Color{ alpha: alpha(x, y), ..color });
+                }
+            }
+            return Ok(bitmap);
+        }
+    }
+    // The position of the mask is only known for uncompressed bitmaps. Missing bits don't hide
+    // anything.
+    if uncompressed || bitmap.compression == Some(CompressionMethod::BI_BITFIELDS) {
+        let mask_start = offset + line_bytes * rows;
+        let mask_line_bytes = width.div_ceil(32) * 4;
+        for y in 0..rows {
+            for x in 0..width {
+                let byte = file.get(row_start(mask_start, mask_line_bytes, y) + x / 8).copied().unwrap_or(0);
+                if byte & (0x80 >> (x % 8)) == 0 {
+                    continue;
+                }
+                let color = bitmap.image.pixel(x, y);
+                let inverted = (color.red, color.green, color.blue) != (0, 0, 0);
+                bitmap.image.set_pixel(x, y, Color{ red: 0, green: 0, blue: 0, alpha: if inverted { 255 } else { 0 } });
+            }
+        }
+    }
+    Ok(bitmap)
+}
+
+impl IconDirectory {
+    pub fn parse(input: Input, options: DecodeOptions) -> BmpResult<Self> {
+        let (icon_type, dir_entries) = parse_directory(input)?;
+        let mut entries = vec!();
+        for (n, entry) in dir_entries.iter().enumerate() {
+            let entry_start = ICON_DIR_SIZE + n * ICON_DIR_ENTRY_SIZE;
+            let start = entry.offset as usize;
+            if start > input.len() {
+                let kind = BmpErrorKind::DataOffsetTooLarge{ offset: entry.offset, file_size: input.len() };
+                return Err(BmpError{ kind, field: "Image Offset", offset: entry_start + 12, header: None });
+            }
+            let available = input.len() - start;
+            if entry.size as usize > available {
+                let kind = BmpErrorKind::MissingData{ expected: entry.size as usize, available };
+                return Err(BmpError{ kind, field: "Image Size", offset: entry_start + 8, header: None });
+            }
+            let data = &input[start..start + entry.size as usize];
+            let mut bitmap = if data.starts_with(PNG_SIGNATURE) {
+                parse_png(data, start, options)?
+            } else {
+                parse_dib(data, start, options)?
+            };
+            if icon_type == IconType::Cursor {
+                bitmap.hotspot = Some((entry.planes, entry.bpp));
+            }
+            // 0 stands for 256, the biggest size the directory can describe.
+            let size = |s: u8| if s == 0 { 256 } else { s as u16 };
+            entries.push(IconEntry{ width: size(entry.width), height: size(entry.height), bitmap });
+        }
+        Ok(IconDirectory{ icon_type, entries })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bmp::tests::TestBitmap;
+
+    const BLACK: Color = Color{ red: 0, green: 0, blue: 0, alpha: 255 };
+    const TRANSPARENT: Color = Color{ red: 0, green: 0, blue: 0, alpha: 0 };
+
+    // DIB of the bitmap with a doubled height, followed by the AND mask whose rows (from the top)
+    // are given as bytes.
+    fn dib(bitmap: TestBitmap, mask: &[u8]) -> Vec<u8> {
+        let mut data = bitmap.data.clone();
+        for row in mask.iter().rev() {
+            data.extend_from_slice(&[*row, 0, 0, 0]);
+        }
+        TestBitmap{ height: 2 * bitmap.height, data, ..bitmap }.write()[FILE_HEADER_SIZE..].to_vec()
+    }
+
+    fn directory(icon_type: IconType, images: &[(u8, u16, u16, &[u8])]) -> Vec<u8> {
+        let mut out = vec!();
+        out.extend_from_slice(&0_u16.to_le_bytes());
+        out.extend_from_slice(&(icon_type as u16).to_le_bytes());
+        out.extend_from_slice(&(images.len() as u16).to_le_bytes());
+        let mut offset = ICON_DIR_SIZE + images.len() * ICON_DIR_ENTRY_SIZE;
+        for (size, planes, bpp, data) in images.iter() {
+            out.extend_from_slice(&[*size, *size, 0, 0]);
+            out.extend_from_slice(&planes.to_le_bytes());
+            out.extend_from_slice(&bpp.to_le_bytes());
+            out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            out.extend_from_slice(&(offset as u32).to_le_bytes());
+            offset += data.len();
+        }
+        for (_, _, _, data) in images.iter() {
+            out.extend_from_slice(data);
+        }
+        out
+    }
+
+    #[test]
+    fn test_icon() {
+        // From the top: red and black, then white and black.
+        let red = Color{ red: 255, green: 0, blue: 0, alpha: 255 };
+        let small = TestBitmap{
+            width: 2,
+            height: 2,
+            bpp: 4,
+            palette: vec!([0, 0, 0, 0], [255, 255, 255, 0], [0, 0, 255, 0]),
+            data: vec!(0x10, 0, 0, 0, 0x20, 0, 0, 0),
+            ..Default::default()
+        };
+        let small = dib(small, &[0b0100_0000, 0b1100_0000]);
+        // Only the bottom right and top left pixels have some alpha.
+        let big = TestBitmap{ width: 2, height: 2, bpp: 32, data: vec!(255, 255, 255, 0, 0, 0, 0, 128, 0, 0, 255, 255, 0, 0, 0, 0), ..Default::default() };
+        let big = dib(big, &[0, 0]);
+        let file = directory(IconType::Icon, &[(2, 1, 4, &small), (0, 1, 32, &big)]);
+
+        assert!(is_icon_directory(&file));
+        let icon = IconDirectory::parse(&file, DecodeOptions::default()).unwrap();
+        assert_eq!((icon.icon_type, icon.entries.len()), (IconType::Icon, 2));
+        let bitmap = &icon.entries[0].bitmap;
+        assert_eq!((bitmap.width, bitmap.height, bitmap.bpp, bitmap.hotspot), (2, 2, 4, None));
+        assert_eq!(bitmap.image.colors().collect::<Vec<_>>(), vec!(red, TRANSPARENT, BLACK, TRANSPARENT));
+        assert_eq!((icon.entries[1].width, icon.entries[1].bitmap.bpp), (256, 32));
+        let alpha = icon.entries[1].bitmap.image.colors().map(|c| c.alpha).collect::<Vec<_>>();
+        assert_eq!(alpha, vec!(255, 0, 0, 128));
+    }
+
+    #[test]
+    fn test_cursor() {
+        // White then black pixels.
+        let bitmap = TestBitmap{ width: 3, bpp: 1, palette: vec!([0, 0, 0, 0], [255, 255, 255, 0]), data: vec!(0x80, 0, 0, 0), ..Default::default() };
+        let file = directory(IconType::Cursor, &[(3, 1, 0, &dib(bitmap, &[0b1010_0000]))]);
+        let icon = IconDirectory::parse(&file, DecodeOptions::default()).unwrap();
+        assert_eq!(icon.icon_type, IconType::Cursor);
+        let bitmap = &icon.entries[0].bitmap;
+        assert_eq!(bitmap.hotspot, Some((1, 0)));
+        assert_eq!(bitmap.image.colors().collect::<Vec<_>>(), vec!(BLACK, BLACK, TRANSPARENT));
+    }
+
+    #[cfg(feature = "png")]
+    #[test]
+    fn test_png() {
+        let mut png = vec!();
+        {
+            let mut encoder = png::Encoder::new(&mut png, 2, 1);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        }
+        let file = directory(IconType::Icon, &[(2, 0, 0, &png)]);
+        let bitmap = &IconDirectory::parse(&file, DecodeOptions::default()).unwrap().entries[0].bitmap;
+        assert_eq!((bitmap.width, bitmap.height, bitmap.compression), (2, 1, Some(CompressionMethod::BI_PNG)));
+        assert_eq!(bitmap.image.data, vec!(1, 2, 3, 4, 5, 6, 7, 8));
+    }
+
+    #[test]
+    fn test_errors() {
+        let data = dib(TestBitmap{ data: vec!(255, 255, 255, 0), ..Default::default() }, &[0]);
+        let mut file = directory(IconType::Icon, &[(1, 1, 24, &data)]);
+        let start = ICON_DIR_SIZE + ICON_DIR_ENTRY_SIZE;
+
+        let error = IconDirectory::parse(&file[..7], DecodeOptions::default()).unwrap_err();
+        assert_eq!((error.kind, error.field, error.offset), (BmpErrorKind::Truncated, "Image Height", 7));
+        let error = IconDirectory::parse(&file[..file.len() - 1], DecodeOptions::default()).unwrap_err();
+        assert_eq!((error.kind, error.field, error.offset), (BmpErrorKind::MissingData{ expected: data.len(), available: data.len() - 1 }, "Image Size", 14));
+        file[start + 8] = 3;
+        let error = IconDirectory::parse(&file, DecodeOptions::default()).unwrap_err();
+        assert_eq!((error.kind, error.field, error.offset), (BmpErrorKind::InvalidValue, "Image Height", start + 8));
+        file[2] = 3;
+        assert!(!is_icon_directory(&file));
+        let error = IconDirectory::parse(&file, DecodeOptions::default()).unwrap_err();
+        assert_eq!((error.kind, error.field, error.offset), (BmpErrorKind::InvalidValue, "Icon Type", 2));
+    }
+}
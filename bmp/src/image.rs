@@ -0,0 +1,125 @@
+// Decoded image, stored row by row from the top in a single RGBA8 buffer so that it can be handed
+// as is to SDL or image encoders.
+use crate::bmp::Color;
+use sdl2::pixels::PixelMasks;
+use sdl2::surface::Surface;
+
+// Palette based representation of an image, kept when all the pixels come from the palette.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Indexed {
+    // One index per pixel, row by row from the top, without padding.
+    pub indexes: Vec<u8>,
+    // Colors after color management, as in the RGBA buffer.
+    pub palette: Vec<Color>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    // Bytes from one row to the next.
+    pub stride: usize,
+    // RGBA8, the top row first.
+    pub data: Vec<u8>,
+    pub indexed: Option<Indexed>,
+}
+
+pub const BYTES_PER_PIXEL: usize = 4;
+
+impl Image {
+    // Transparent black image.
+    pub fn new(width: usize, height: usize) -> Self {
+        let stride = width * BYTES_PER_PIXEL;
+        Image {
+            width,
+            height,
+            stride,
+            data: vec![0; stride * height],
+            indexed: None,
+        }
+    }
+
+    pub fn from_fn<F: FnMut(usize, usize) -> Color>(width: usize, height: usize, mut f: F) -> Self {
+        let mut image = Image::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                image.set_pixel(x, y, f(x, y));
+            }
+        }
+        image
+    }
+
+    fn position(&self, x: usize, y: usize) -> usize {
+        y * self.stride + x * BYTES_PER_PIXEL
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Color {
+        let p = &self.data[self.position(x, y)..];
+        Color{ red: p[0], green: p[1], blue: p[2], alpha: p[3] }
+    }
+
+    // Also drops the indexed representation, which may not match anymore.
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: Color) {
+        let position = self.position(x, y);
+        self.data[position..position + BYTES_PER_PIXEL].copy_from_slice(&[color.red, color.green, color.blue, color.alpha]);
+        self.indexed = None;
+    }
+
+    // RGBA8 bytes of the row, without padding.
+    pub fn row(&self, y: usize) -> &[u8] {
+        let start = y * self.stride;
+        &self.data[start..start + self.width * BYTES_PER_PIXEL]
+    }
+
+    pub fn set_row(&mut self, y: usize, colors: &[Color]) {
+        for (x, c) in colors.iter().take(self.width).enumerate() {
+            self.set_pixel(x, y, *c);
+        }
+    }
+
+    // Pixels row by row from the top.
+    pub fn colors(&self) -> impl Iterator<Item = Color> + '_ {
+        (0..self.height).flat_map(move |y| {
+            self.row(y).chunks(BYTES_PER_PIXEL).map(|p| Color{ red: p[0], green: p[1], blue: p[2], alpha: p[3] })
+        })
+    }
+
+    // Copies the image to a surface whose memory layout matches the buffer (R, G, B, A bytes)
+    // whatever the endianness.
+    pub fn to_surface(&self) -> std::result::Result<Surface<'static>, String> {
+        let masks = PixelMasks {
+            bpp: 32,
+            rmask: u32::from_ne_bytes([0xff, 0, 0, 0]),
+            gmask: u32::from_ne_bytes([0, 0xff, 0, 0]),
+            bmask: u32::from_ne_bytes([0, 0, 0xff, 0]),
+            amask: u32::from_ne_bytes([0, 0, 0, 0xff]),
+        };
+        let mut surface = Surface::from_pixelmasks(self.width as u32, self.height as u32, masks)?;
+        let pitch = surface.pitch() as usize;
+        surface.with_lock_mut(|pixels| {
+            for y in 0..self.height {
+                let row = self.row(y);
+                pixels[y * pitch..y * pitch + row.len()].copy_from_slice(row);
+            }
+        });
+        Ok(surface)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pixels() {
+        let color = |x: usize, y: usize| Color{ red: x as u8, green: y as u8, blue: 7, alpha: 255 };
+        let mut image = Image::from_fn(3, 2, color);
+        assert_eq!((image.stride, image.data.len()), (12, 24));
+        assert_eq!(image.pixel(2, 1), color(2, 1));
+        assert_eq!(image.row(1), &[0, 1, 7, 255, 1, 1, 7, 255, 2, 1, 7, 255]);
+        assert_eq!(image.colors().nth(4), Some(color(1, 1)));
+        image.indexed = Some(Indexed{ indexes: vec![0; 6], palette: vec!(Color::default()) });
+        image.set_pixel(0, 0, Color::default());
+        assert_eq!((image.pixel(0, 0), image.indexed), (Color::default(), None));
+    }
+}
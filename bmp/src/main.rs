@@ -1,14 +1,21 @@
 use argh::FromArgs;
 use std::path::{Path, PathBuf};
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::gfx::primitives::DrawRenderer;
-use sdl2::pixels::Color;
-use sdl2::render::Canvas;
-use sdl2::video::Window;
 
 mod bitreader;
+mod bitwriter;
 mod bmp;
+mod convert;
+#[cfg(any(feature = "jpeg", feature = "png"))]
+mod embedded;
+#[cfg(test)]
+mod golden;
+mod icc;
+mod ico;
+mod image;
+mod os2;
+mod reader;
+mod viewer;
+mod writer;
 
 use bmp::*;
 use std::fs;
@@ -23,16 +30,20 @@ struct Args {
 #[derive(FromArgs)]
 #[argh(subcommand)]
 enum Subcommand {
+    Convert(BmpConvert),
     Display(BmpDisplay),
     Parse(BmpParse),
+    Roundtrip(BmpRoundtrip),
     MyTest(BmpMyTest),
 }
 
 impl Subcommand {
     fn run(self) {
         match self {
+            Subcommand::Convert(x) => x.run(),
             Subcommand::Display(x) => x.run(),
             Subcommand::Parse(x) => x.run(),
+            Subcommand::Roundtrip(x) => x.run(),
             Subcommand::MyTest(x) => x.run(),
         }
     }
@@ -131,127 +142,199 @@ impl BmpMyTest {
 
 #[derive(FromArgs)]
 #[argh(subcommand, name = "display")]
-/// Display a BMP file
+/// Display BMP files: n/p for the next/previous one, mouse wheel or +/- to zoom, drag or arrows to
+/// pan, 0 to fit, 1 for actual size, i for header info, c to switch the background
 pub struct BmpDisplay {
   #[argh(positional)]
   input_paths: Vec<PathBuf>,
 }
 
-const SCREEN_WIDTH : u32 = 600;
-const SCREEN_HEIGHT : u32 = 600;
-
 impl BmpDisplay {
-    fn fill_background(&self, canvas: &mut Canvas<Window>, color: Color) {
-        canvas.set_draw_color(color);
-        canvas.clear();
+    fn run(self) {
+        if let Err(e) = viewer::Viewer::new(self.input_paths).run() {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
     }
+}
 
-    fn draw_bitmap_on_canvas(&self, bitmap: BmpFile, canvas: &mut Canvas<Window>) {
-        for x in 0..bitmap.width as usize {
-            for y in 0..bitmap.height.abs() as usize {
-                canvas.pixel(x as i16, y as i16, bitmap.pixels[x][y]).unwrap();
-            }
-        }
+#[derive(FromArgs)]
+#[argh(subcommand, name = "parse")]
+/// Parse a BMP, ICO or CUR file and print content
+pub struct BmpParse {
+  #[argh(positional)]
+  input_path: PathBuf,
+}
+
+// On stderr and with a specific exit code so that sort_crashes.sh can tell errors (bucketed by
+// cause) from crashes.
+fn exit_with_parse_error(input_path: &Path, e: BmpError) -> ! {
+    eprintln!("Couldn't parse {:?}: {}", input_path, e);
+    // The headers of images too large to be decoded are still worth showing.
+    if let BmpErrorKind::ImageTooLarge{ header, .. } = e.kind {
+        println!("{:#?}", header);
     }
+    std::process::exit(1);
+}
 
+impl BmpParse {
     fn run(self) {
-        let sdl_context = sdl2::init().unwrap();
-        let mut event_pump = sdl_context.event_pump().unwrap();
-        let video_subsystem = sdl_context.video().unwrap();
-        let window = video_subsystem.window("BMP Viewer", SCREEN_WIDTH, SCREEN_HEIGHT)
-            .position_centered()
-            .build()
-            .unwrap();
-        let mut canvas = window.into_canvas().build().unwrap();
-
-        let mut idx = 0;
-        let mut bg_color = Color::RGB(0, 0, 0);
-        'display_loop: loop {
-            for event in event_pump.poll_iter() {
-                match event {
-                    Event::Quit {..} |
-                    Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
-                        break 'display_loop
-                    },
-                    Event::KeyDown { keycode: Some(Keycode::C), .. } => {
-                        if bg_color.r == 0 {
-                            bg_color = Color::RGB(255, 255, 255);
-                        } else {
-                            bg_color = Color::RGB(0, 0, 0);
-                        }
-                    },
-                    Event::KeyDown { keycode: Some(Keycode::N), .. } => {
-                        if idx < self.input_paths.len() - 1 {
-                            idx = idx + 1;
+        let input_path = self.input_path.as_path();
+        let input = fs::read(input_path);
+        match input {
+            Ok(inp) if ico::is_icon_directory(&inp) => {
+                match ico::IconDirectory::parse(&inp, DecodeOptions::default()) {
+                    Ok(icon) => {
+                        println!("{:?}: {:?} with {} images", input_path, icon.icon_type, icon.entries.len());
+                        for (n, entry) in icon.entries.iter().enumerate() {
+                            println!("Image {} ({}x{}):", n, entry.width, entry.height);
+                            println!("{:#?}", entry.bitmap);
                         }
-                        println!("{:?}", self.input_paths[idx]);
                     },
-                    Event::KeyDown { keycode: Some(Keycode::P), .. } => {
-                        if idx > 0 {
-                            idx = idx - 1;
+                    Err(e) => exit_with_parse_error(input_path, e),
+                }
+            },
+            Ok(inp) => {
+                let file = BmpFile::parse_array(&inp, DecodeOptions::default());
+                match file {
+                    Ok(entries) => {
+                        println!("{:?}", input_path);
+                        let is_array = inp.starts_with(b"BA");
+                        for (n, entry) in entries.iter().enumerate() {
+                            if is_array {
+                                println!("Image {} for {}x{} displays:", n, entry.display_width, entry.display_height);
+                            }
+                            println!("{:#?}", entry.bitmap);
                         }
-                        println!("{:?}", self.input_paths[idx]);
                     },
-                    _ => {}
+                    Err(e) => exit_with_parse_error(input_path, e),
                 }
             }
-            {
-                self.fill_background(&mut canvas, bg_color);
-            }
-            // TODO: Do not reload the file at each loop, do this only when pressing n or p.
-            let input_path = self.input_paths[idx].as_path();
-            // TODO: Remove this hack, it's just to avoid ooming ...
-            if input_path == Path::new("resources/reallybig.bmp") {
-                println!("Skip loading {:?} to avoid OOMing", input_path);
-                continue;
+            Err(e) => println!("Couldn't open {:?}: {}", input_path, e),
+        }
+    }
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "convert")]
+/// Convert between BMP, PNG, PPM/PGM and PAM files, formats being guessed from the extensions
+pub struct BmpConvert {
+  #[argh(positional)]
+  input_path: PathBuf,
+  #[argh(positional)]
+  output_path: PathBuf,
+  /// bits per pixel of the BMP output: 1, 2, 4, 8, 16, 24 or 32
+  #[argh(option)]
+  bpp: Option<u16>,
+  /// header of the BMP output: core, os2-16, os2, info, v2, v3, v4 or v5
+  #[argh(option, from_str_fn(convert::parse_header))]
+  header: Option<DibHeaderSize>,
+  /// compression of the BMP output: rgb, rle4, rle8, rle24, huffman1d, bitfields or alphabitfields
+  #[argh(option, from_str_fn(convert::parse_compression))]
+  compression: Option<CompressionMethod>,
+  /// write the BMP output top-down
+  #[argh(switch)]
+  topdown: bool,
+  /// keep the colors of a BMP input as they are, without color management
+  #[argh(switch)]
+  raw: bool,
+  /// rendering intent for the colors of a BMP input, instead of the one of the file: business,
+  /// graphics, images or absolute
+  #[argh(option, from_str_fn(convert::parse_intent))]
+  intent: Option<IntentType>,
+  /// how to read the dimensions of a BMP input with a 12 bytes header, instead of guessing: os2
+  /// (OS/2 1.x, unsigned) or windows (Windows 2.x, signed)
+  #[argh(option, from_str_fn(convert::parse_core_header))]
+  core_header: Option<CoreHeader>,
+}
+
+impl BmpConvert {
+    fn run(self) {
+        let format = |path: &Path| match convert::Format::from_path(path) {
+            Some(format) => format,
+            None => {
+                eprintln!("Unsupported format for {:?}, expected a bmp, dib, png, ppm, pgm, pnm or pam file", path);
+                std::process::exit(1);
+            },
+        };
+        let (from, to) = (format(&self.input_path), format(&self.output_path));
+        let input = match fs::read(&self.input_path) {
+            Ok(inp) => inp,
+            Err(e) => {
+                eprintln!("Couldn't open {:?}: {}", self.input_path, e);
+                std::process::exit(1);
             }
-            let input = fs::read(input_path);
-            match input {
-                Ok(inp) => {
-                    let file = BmpFile::parse(&inp);
-                    match file {
-                        Ok((_, bitmap)) => {
-                            self.draw_bitmap_on_canvas(bitmap, &mut canvas);
-                        },
-                            Err(e) => println!("Couldn't parse {:?}: {}", input_path, e),
-                    }
-                }
-                Err(e) => println!("Couldn't open {:?}: {}", input_path, e),
+        };
+        let options = convert::BmpOptions {
+            bpp: self.bpp,
+            header: self.header,
+            compression: self.compression,
+            topdown: self.topdown,
+        };
+        let decode = DecodeOptions{ raw: self.raw, intent: self.intent, core_header: self.core_header, ..Default::default() };
+        let output = match convert::convert(&input, from, to, decode, &options) {
+            Ok(output) => output,
+            Err(e) => {
+                eprintln!("Couldn't convert {:?}: {}", self.input_path, e);
+                std::process::exit(1);
             }
-            canvas.present();
+        };
+        if let Err(e) = fs::write(&self.output_path, &output) {
+            eprintln!("Couldn't save {:?}: {}", self.output_path, e);
+            std::process::exit(1);
         }
     }
 }
 
 #[derive(FromArgs)]
-#[argh(subcommand, name = "parse")]
-/// Parse a BMP file and print content
-pub struct BmpParse {
+#[argh(subcommand, name = "roundtrip")]
+/// Parse a BMP file, write it back and check the result decodes to the same pixels
+pub struct BmpRoundtrip {
   #[argh(positional)]
   input_path: PathBuf,
+  /// where to save the rewritten file
+  #[argh(option, short = 'o')]
+  output_path: Option<PathBuf>,
 }
 
-impl BmpParse {
+impl BmpRoundtrip {
     fn run(self) {
         let input_path = self.input_path.as_path();
-        // TODO: Remove this hack, it's just to avoid ooming ...
-        if input_path == Path::new("resources/reallybig.bmp") {
-            println!("Skip loading {:?} to avoid OOMing", input_path);
-            return;
+        let input = match fs::read(input_path) {
+            Ok(inp) => inp,
+            Err(e) => {
+                println!("Couldn't open {:?}: {}", input_path, e);
+                return;
+            }
+        };
+        let bitmap = match BmpFile::parse(&input) {
+            Ok((_, bitmap)) => bitmap,
+            Err(e) => {
+                println!("Couldn't parse {:?}: {}", input_path, e);
+                return;
+            }
+        };
+        let output = match bitmap.write() {
+            Ok(output) => output,
+            Err(e) => {
+                println!("Couldn't write {:?}: {}", input_path, e);
+                return;
+            }
+        };
+        if let Some(output_path) = &self.output_path {
+            if let Err(e) = fs::write(output_path, &output) {
+                println!("Couldn't save {:?}: {}", output_path, e);
+            }
         }
-        let input = fs::read(input_path);
-        match input {
-            Ok(inp) => {
-                let file = BmpFile::parse(&inp);
-                match file {
-                    Ok((_, bitmap)) => {
-                        println!("{:?}", input_path);
-                        println!("{:#?}", bitmap);
-                    },
-                    Err(e) => println!("Couldn't parse {:?}: {}", input_path, e),
+        match BmpFile::parse(&output) {
+            Ok((_, rewritten)) => {
+                if rewritten.image.data == bitmap.image.data {
+                    println!("{:?}: OK", input_path);
+                } else {
+                    println!("{:?}: pixels differ after rewrite", input_path);
                 }
-            }
-            Err(e) => println!("Couldn't open {:?}: {}", input_path, e),
+            },
+            Err(e) => println!("Couldn't parse rewritten {:?}: {}", input_path, e),
         }
     }
 }
@@ -0,0 +1,287 @@
+// OS/2 bitmap arrays, icons and pointers.
+//
+// A bitmap array (BA) is a linked list of array headers, each one followed by the file header of
+// an image, usually the same picture for different displays. Icons and pointers (IC, PT) are
+// monochrome bitmaps twice as high as the picture: the top half is the AND mask and the bottom
+// half the XOR mask. Color icons and pointers (CI, CP) have the same mask bitmap, immediately
+// followed by the file header of the colors. Offsets of the pixel data are from the start of the
+// file in all cases.
+// https://www.fileformat.info/format/os2bmp/egff.htm
+use crate::bmp::*;
+use crate::image::Image;
+use std::convert::TryFrom;
+
+// Magic, size of the header, offset of the next one and display resolution.
+pub const ARRAY_HEADER_SIZE: usize = 14;
+
+#[derive(Debug, PartialEq)]
+pub struct ArrayEntry {
+    // Resolution of the display the image is designed for, 0 for device independent images.
+    pub display_width: u16,
+    pub display_height: u16,
+    pub bitmap: BmpFile,
+}
+
+struct ArrayHeader {
+    next: u32,
+    display_width: u16,
+    display_height: u16,
+}
+
+fn magic_at(input: Input, start: usize) -> Option<Magic> {
+    let bytes = input.get(start..start + 2)?;
+    Magic::try_from(u16::from_le_bytes([bytes[0], bytes[1]])).ok()
+}
+
+fn parse_array_header(input: Input, start: usize) -> BmpResult<ArrayHeader> {
+    use nom::{
+        combinator::verify,
+        error::context,
+        number::complete::{le_u16, le_u32},
+        sequence::tuple,
+    };
+    let i = input.get(start..).unwrap_or_default();
+    let result: Result<_> = tuple((
+        context("Magic", verify(le_u16, |m| *m == Magic::BA as u16)),
+        context("Array Header Size", le_u32),
+        context("Next Array Header", le_u32),
+        context("Display Width", le_u16),
+        context("Display Height", le_u16),
+    ))(i);
+    match result {
+        Ok((_, (_, _, next, display_width, display_height))) => Ok(ArrayHeader{ next, display_width, display_height }),
+        Err(e) => {
+            let mut error = BmpFile::default().nom_error(i, e);
+            error.offset += start;
+            Err(error)
+        },
+    }
+}
+
+// Mask bits row by row from the top, from the palette indexes when they are available.
+fn mask_bits(mask: &BmpFile, options: &DecodeOptions) -> Vec<bool> {
+    match &mask.image.indexed {
+        Some(indexed) => indexed.indexes.iter().map(|i| *i != 0).collect(),
+        None => {
+            let zero = mask.palette.first().map(|c| mask.to_srgb(*c, options));
+            mask.image.colors().map(|c| Some(c) != zero).collect()
+        },
+    }
+}
+
+impl BmpFile {
+    // All the images of a bitmap array, or the only image of other files.
+    pub fn parse_array(input: Input, options: DecodeOptions) -> BmpResult<Vec<ArrayEntry>> {
+        if magic_at(input, 0) != Some(Magic::BA) {
+            let (_, bitmap) = Self::parse_image_at(input, 0, options)?;
+            return Ok(vec!(ArrayEntry{ display_width: 0, display_height: 0, bitmap }));
+        }
+        let mut entries = vec!();
+        let mut start = 0;
+        loop {
+            let header = parse_array_header(input, start)?;
+            let (_, bitmap) = Self::parse_image_at(input, start + ARRAY_HEADER_SIZE, options)?;
+            entries.push(ArrayEntry{ display_width: header.display_width, display_height: header.display_height, bitmap });
+            if header.next == 0 {
+                return Ok(entries);
+            }
+            // Headers only go forward, which also prevents loops.
+            if header.next as usize <= start {
+                return Err(BmpError{ kind: BmpErrorKind::InvalidValue, field: "Next Array Header", offset: start + 6, header: None });
+            }
+            start = header.next as usize;
+        }
+    }
+
+    // First image of bitmap arrays, or the image of other files, and the end of its headers.
+    pub fn parse_first(input: Input, options: DecodeOptions) -> BmpResult<(usize, Self)> {
+        if magic_at(input, 0) == Some(Magic::BA) {
+            parse_array_header(input, 0)?;
+            Self::parse_image_at(input, ARRAY_HEADER_SIZE, options)
+        } else {
+            Self::parse_image_at(input, 0, options)
+        }
+    }
+
+    fn parse_image_at(input: Input, start: usize, options: DecodeOptions) -> BmpResult<(usize, Self)> {
+        match magic_at(input, start) {
+            Some(Magic::CI) | Some(Magic::CP) | Some(Magic::IC) | Some(Magic::PT) => Self::parse_icon_at(input, start, options),
+            // Arrays can't be nested.
+            Some(Magic::BA) => Err(BmpError{ kind: BmpErrorKind::InvalidValue, field: "Magic", offset: start, header: None }),
+            _ => Self::parse_bitmap_at(input, start, options),
+        }
+    }
+
+    // The result has the headers of the color bitmap for color icons and pointers, and of the
+    // mask otherwise. Its image has the masks applied: transparent where the screen is kept, and
+    // black where it is inverted, which can't be represented.
+    fn parse_icon_at(input: Input, start: usize, options: DecodeOptions) -> BmpResult<(usize, Self)> {
+        let (end, mask) = Self::parse_bitmap_at(input, start, options)?;
+        if mask.bpp != 1 {
+            let mut error = mask.unsupported_bpp();
+            error.offset += start;
+            return Err(error);
+        }
+        let (width, height) = (mask.image.width, mask.image.height / 2);
+        if mask.image.height % 2 != 0 {
+            let height_offset = if mask.dib_header_size == DibHeaderSize::BITMAPCOREHEADER { 6 } else { 8 };
+            return Err(mask.error(BmpErrorKind::InvalidValue, "Image Height", start + FILE_HEADER_SIZE + height_offset));
+        }
+        let color = match mask.magic {
+            Magic::CI | Magic::CP => {
+                let color_start = end;
+                let (end, color) = Self::parse_bitmap_at(input, color_start, options)?;
+                if (color.image.width, color.image.height) != (width, height) {
+                    return Err(color.error(BmpErrorKind::InvalidValue, "Image Width", color_start + FILE_HEADER_SIZE + 4));
+                }
+                Some((end, color))
+            },
+            _ => None,
+        };
+
+        let bits = mask_bits(&mask, &options);
+        let image = Image::from_fn(width, height, |x, y| {
+            let and = bits[y * width + x];
+            let xor = bits[(y + height) * width + x];
+            match (and, xor) {
+                (false, _) => match &color {
+                    Some((_, color)) => color.image.pixel(x, y),
+                    None => mask.image.pixel(x, y + height),
+                },
+                (true, false) => Color{ ..Default::default() },
+                (true, true) => Color{ alpha: 255, ..Default::default() },
+            }
+        });
+        let hotspot = mask.hotspot;
+        let (end, mut result) = color.unwrap_or((end, mask));
+        result.image = image;
+        result.hotspot = hotspot;
+        Ok((end, result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bmp::tests::TestBitmap;
+    use crate::writer::BmpWriter;
+
+    const BLACK: Color = Color{ red: 0, green: 0, blue: 0, alpha: 255 };
+    const WHITE: Color = Color{ red: 255, green: 255, blue: 255, alpha: 255 };
+    const TRANSPARENT: Color = Color{ red: 0, green: 0, blue: 0, alpha: 0 };
+
+    fn offset(file: &[u8], start: usize) -> usize {
+        u32::from_le_bytes([file[start + 10], file[start + 11], file[start + 12], file[start + 13]]) as usize
+    }
+
+    fn set_offset(file: &mut [u8], start: usize, offset: usize) {
+        file[start + 10..start + 14].copy_from_slice(&(offset as u32).to_le_bytes());
+    }
+
+    // 2x2 masks, from the top: AND [0, 1] [1, 0], XOR [0, 0] [1, 1].
+    fn mask() -> TestBitmap {
+        TestBitmap{
+            dib_header_size: DibHeaderSize::BITMAPCOREHEADER,
+            width: 2,
+            height: 4,
+            bpp: 1,
+            palette: vec!([0, 0, 0, 0], [255, 255, 255, 0]),
+            data: vec!(0xc0, 0, 0, 0, 0, 0, 0, 0, 0x80, 0, 0, 0, 0x40, 0, 0, 0),
+            ..Default::default()
+        }
+    }
+
+    // 24 bpp bitmap with a 12 bytes header, whose pixels are given from the bottom.
+    fn color_bitmap(width: i32, height: i32, data: &[u8]) -> TestBitmap {
+        TestBitmap{ dib_header_size: DibHeaderSize::BITMAPCOREHEADER, width, height, data: data.to_vec(), ..Default::default() }
+    }
+
+    // Icon file with its hotspot at (3, 4), whose data offsets are for a file starting at base.
+    fn icon(magic: Magic, color: Option<TestBitmap>, base: usize) -> Vec<u8> {
+        let mut mask = TestBitmap{ magic, hotspot: (3, 4), ..mask() }.write();
+        let color = match color {
+            Some(color) => TestBitmap{ magic, ..color }.write(),
+            None => {
+                let offset = offset(&mask, 0);
+                set_offset(&mut mask, 0, base + offset);
+                return mask;
+            },
+        };
+        let (mask_headers, mask_data) = mask.split_at(offset(&mask, 0));
+        let (color_headers, color_data) = color.split_at(offset(&color, 0));
+        let mut out = [mask_headers, color_headers, mask_data, color_data].concat();
+        let data = base + mask_headers.len() + color_headers.len();
+        set_offset(&mut out, 0, data);
+        set_offset(&mut out, mask_headers.len(), data + mask_data.len());
+        out
+    }
+
+    fn array_header(next: usize, display_width: u16) -> Vec<u8> {
+        let mut out = b"BA".to_vec();
+        out.extend_from_slice(&40_u32.to_le_bytes());
+        out.extend_from_slice(&(next as u32).to_le_bytes());
+        out.extend_from_slice(&display_width.to_le_bytes());
+        out.extend_from_slice(&display_width.to_le_bytes());
+        out
+    }
+
+    #[test]
+    fn test_icon() {
+        let (_, bitmap) = BmpFile::parse(&icon(Magic::IC, None, 0)).unwrap();
+        assert_eq!((bitmap.magic, bitmap.hotspot), (Magic::IC, Some((3, 4))));
+        assert_eq!(bitmap.image.colors().collect::<Vec<_>>(), vec!(BLACK, TRANSPARENT, BLACK, WHITE));
+    }
+
+    #[test]
+    fn test_color_pointer() {
+        let red = Color{ red: 255, green: 0, blue: 0, alpha: 255 };
+        let blue = Color{ red: 0, green: 0, blue: 255, alpha: 255 };
+        // Red and blue rows.
+        let color = color_bitmap(2, 2, &[0, 0, 255, 255, 0, 0, 0, 0, 0, 0, 255, 255, 0, 0, 0, 0]);
+        let (_, bitmap) = BmpFile::parse(&icon(Magic::CP, Some(color.clone()), 0)).unwrap();
+        assert_eq!((bitmap.magic, bitmap.bpp, bitmap.hotspot), (Magic::CP, 24, Some((3, 4))));
+        assert_eq!(bitmap.image.colors().collect::<Vec<_>>(), vec!(red, TRANSPARENT, BLACK, blue));
+
+        let bad_size = icon(Magic::CP, Some(TestBitmap{ width: 3, data: vec![0; 24], ..color.clone() }), 0);
+        let color_start = FILE_HEADER_SIZE + 12 + 6;
+        let error = BmpFile::parse(&bad_size).unwrap_err();
+        assert_eq!((error.kind, error.field, error.offset), (BmpErrorKind::InvalidValue, "Image Width", color_start + 18));
+
+        let bad_mask = TestBitmap{ magic: Magic::CP, ..color }.write();
+        let error = BmpFile::parse(&bad_mask).unwrap_err();
+        assert_eq!((error.kind, error.offset), (BmpErrorKind::UnsupportedBpp{ bpp: 24, compression: None }, 24));
+    }
+
+    #[test]
+    fn test_array() {
+        let color = Image::from_fn(2, 2, |x, y| Color{ red: x as u8, green: y as u8, blue: 0, alpha: 255 });
+        let first = icon(Magic::CI, Some(color_bitmap(2, 2, &[0, 1, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0])), ARRAY_HEADER_SIZE);
+        let second_start = ARRAY_HEADER_SIZE + first.len();
+        let mut second = BmpWriter::new(DibHeaderSize::BITMAPINFOHEADER, 24).write(&color).unwrap();
+        let offset = offset(&second, 0);
+        set_offset(&mut second, 0, second_start + ARRAY_HEADER_SIZE + offset);
+        let mut array = [array_header(second_start, 640), first, array_header(0, 1024), second].concat();
+
+        let entries = BmpFile::parse_array(&array, DecodeOptions::default()).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].display_width, entries[0].bitmap.magic), (640, Magic::CI));
+        assert_eq!(entries[0].bitmap.image.pixel(0, 0), color.pixel(0, 0));
+        assert_eq!((entries[1].display_height, entries[1].bitmap.magic), (1024, Magic::BM));
+        assert_eq!(entries[1].bitmap.image.data, color.data);
+        assert_eq!(BmpFile::parse(&array).unwrap().1, entries.into_iter().next().unwrap().bitmap);
+
+        // Looping back to the first header.
+        array[second_start + 6..second_start + 10].copy_from_slice(&1_u32.to_le_bytes());
+        let error = BmpFile::parse_array(&array, DecodeOptions::default()).unwrap_err();
+        assert_eq!((error.kind, error.field, error.offset), (BmpErrorKind::InvalidValue, "Next Array Header", second_start + 6));
+        let error = BmpFile::parse_array(&array[..10], DecodeOptions::default()).unwrap_err();
+        assert_eq!((error.kind, error.field, error.offset), (BmpErrorKind::Truncated, "Display Width", 10));
+    }
+
+    #[test]
+    fn test_not_an_array() {
+        let bitmap = mask().write();
+        let entries = BmpFile::parse_array(&bitmap, DecodeOptions::default()).unwrap();
+        assert_eq!((entries.len(), entries[0].display_width, entries[0].bitmap.hotspot), (1, 0, None));
+    }
+}
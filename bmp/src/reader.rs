@@ -0,0 +1,708 @@
+// Streaming decoding of BMP files: headers are parsed upfront and rows are decoded on demand, so
+// that big images can be processed without holding all their pixels in memory.
+use crate::bitreader::BitReader;
+use crate::bmp::*;
+use std::collections::VecDeque;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+
+// A decoded row. y is the index of the row from the top of the image, as in BmpFile::image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    pub y: usize,
+    pub pixels: Vec<Color>,
+    // Palette indexes of the pixels, only for uncompressed images with a palette.
+    pub indexes: Option<Vec<u8>>,
+}
+
+enum Source<R> {
+    Uncompressed(BufReader<R>),
+    Rle(BufReader<R>),
+    Huffman(BitReader<BufReader<R>>),
+    // Embedded JPEG and PNG images are decoded at once, rows are top-down.
+    Decoded(std::vec::IntoIter<Vec<Color>>),
+}
+
+pub struct BmpReader<R> {
+    // Headers and palette. data and image are left empty.
+    pub header: BmpFile,
+    // Size of the headers and the palette.
+    pub header_size: usize,
+    source: Source<R>,
+    options: DecodeOptions,
+    bitmasks: Bitmasks,
+    // Bytes (bits for Huffman) of pixel data read so far, to report errors.
+    position: usize,
+    // Rows yielded so far.
+    yielded: usize,
+    // RLE and Huffman decoding state: rows completed but not yielded yet, as a delta or the end
+    // of the image complete several at once, and the row being decoded.
+    pending: VecDeque<Vec<Color>>,
+    completed: usize,
+    current: Vec<Color>,
+    x: usize,
+    color: HuffmanColor,
+    nb_eol: usize,
+    started: bool,
+    // End of image reached, remaining rows are blank.
+    ended: bool,
+    failed: bool,
+}
+
+// 16M pixels (64MB once decoded) per row, and as many rows.
+const MAX_DIMENSION: usize = 1 << 24;
+
+fn io_error(error: std::io::Error) -> BmpErrorKind {
+    BmpErrorKind::Io(error.to_string())
+}
+
+// Reads up to `size` bytes, less if the end of the file is reached.
+fn read_up_to<R: Read>(reader: &mut R, size: usize, out: &mut Vec<u8>) -> std::io::Result<()> {
+    reader.take(size as u64).read_to_end(out)?;
+    Ok(())
+}
+
+impl <R: Read + Seek> BmpReader<R> {
+    #[allow(dead_code)]
+    pub fn new(reader: R) -> BmpResult<Self> {
+        Self::at(reader, 0, DecodeOptions::default())
+    }
+
+    // Reads the headers, palette and profile of the image whose file header is at `start`.
+    // Positions in the returned header and errors are relative to `start`.
+    fn read_headers(reader: &mut R, start: usize, file_size: usize, options: &DecodeOptions) -> BmpResult<(BmpFile, usize)> {
+        let mut header = BmpFile { ..Default::default() };
+        let file_error = |kind| BmpError{ kind, field: "File", offset: 0, header: None };
+        reader.seek(SeekFrom::Start(start as u64)).map_err(|e| file_error(io_error(e)))?;
+
+        // Only read what the headers need: the DIB header size, then the biggest header with
+        // the masks that may follow it, then the palette.
+        let mut input = vec!();
+        read_up_to(reader, FILE_HEADER_SIZE + 4, &mut input).map_err(|e| file_error(io_error(e)))?;
+        let dib_header_size = match input.get(FILE_HEADER_SIZE..FILE_HEADER_SIZE + 4) {
+            Some(s) => u32::from_le_bytes([s[0], s[1], s[2], s[3]]) as usize,
+            None => 0,
+        };
+        let masks_size = 16;
+        let rest_of_header = dib_header_size.min(DibHeaderSize::BITMAPV5HEADER as usize).saturating_sub(4) + masks_size;
+        read_up_to(reader, rest_of_header, &mut input).map_err(|e| file_error(io_error(e)))?;
+        let headers_end = match header.parse_headers(&input) {
+            Ok((i, _)) => input.len() - i.len(),
+            Err(e) => return Err(header.nom_error(&input, e)),
+        };
+        input.truncate(headers_end);
+        if header.dib_header_size == DibHeaderSize::BITMAPCOREHEADER {
+            let core_header = options.core_header.unwrap_or_else(|| header.guess_core_header(file_size));
+            header.set_core_header(core_header)?;
+        }
+        reader.seek(SeekFrom::Start((start + headers_end) as u64)).map_err(|e| header.error(io_error(e), "Palette", headers_end))?;
+        read_up_to(reader, header.palette_size(), &mut input).map_err(|e| header.error(io_error(e), "Palette", headers_end))?;
+        let header_size = match header.parse_colors(&input[headers_end..], header.colors_in_table) {
+            Ok((i, _)) => input.len() - i.len(),
+            Err(e) => return Err(header.nom_error(&input, e)),
+        };
+
+        if header.offset as usize > file_size {
+            let kind = BmpErrorKind::DataOffsetTooLarge{ offset: header.offset, file_size };
+            return Err(header.error(kind, "Offset", 10));
+        }
+        if let Some(range) = header.profile_range() {
+            if start + range.end <= file_size {
+                let mut profile = vec!();
+                reader.seek(SeekFrom::Start((start + range.start) as u64)).map_err(|e| header.error(io_error(e), "Profile", range.start))?;
+                read_up_to(reader, range.len(), &mut profile).map_err(|e| header.error(io_error(e), "Profile", range.start))?;
+                header.set_profile(&profile);
+            }
+        }
+        Ok((header, header_size))
+    }
+
+    // Decodes the image whose file header is at `start`, which is not 0 for the images of OS/2
+    // bitmap arrays and color icons. The offset of the pixel data is from the start of the file
+    // anyway. Errors are reported with positions from the start of the file.
+    pub fn at(mut reader: R, start: usize, options: DecodeOptions) -> BmpResult<Self> {
+        let file_error = |kind| BmpError{ kind, field: "File", offset: 0, header: None };
+        let file_size = reader.seek(SeekFrom::End(0)).and_then(|_| reader.stream_position())
+            .map_err(|e| file_error(io_error(e)))? as usize;
+        // Header positions are relative to start, pixel data ones are already absolute.
+        let absolute = |mut e: BmpError| {
+            if e.field != "Pixel data" && e.field != "Embedded image" {
+                e.offset += start;
+            }
+            e
+        };
+        let (header, header_size) = Self::read_headers(&mut reader, start, file_size, &options).map_err(absolute)?;
+        let offset = header.offset as usize;
+        reader.seek(SeekFrom::Start(offset as u64)).map_err(|e| header.error(io_error(e), "Pixel data", offset))?;
+        let reader = BufReader::new(reader);
+        let data_size = file_size - offset;
+
+        let (width, height) = (header.width as usize, header.height.unsigned_abs() as usize);
+        // Rows are held in memory even when streaming, and a few bytes are enough to describe an
+        // arbitrarily wide RLE or Huffman image. Empty rows take no data at all.
+        if width > MAX_DIMENSION || height > MAX_DIMENSION {
+            let kind = BmpErrorKind::ImageTooLarge{ width: header.width, height: header.height, header: Box::new(header.clone()) };
+            let (field, offset) = if width > MAX_DIMENSION { ("Image Width", 4) } else { ("Image Height", 8) };
+            return Err(absolute(header.error(kind, field, FILE_HEADER_SIZE + offset)));
+        }
+        let source = match header.compression {
+            None |
+            Some(CompressionMethod::BI_RGB) |
+            Some(CompressionMethod::BI_BITFIELDS) |
+            Some(CompressionMethod::BI_ALPHABITFIELDS) => Source::Uncompressed(reader),
+
+            Some(CompressionMethod::BI_RLE4) if header.bpp == 4 => Source::Rle(reader),
+            Some(CompressionMethod::BI_RLE8) if header.bpp == 8 => Source::Rle(reader),
+            Some(CompressionMethod::BI_RLE24) if header.bpp == 24 => Source::Rle(reader),
+
+            Some(CompressionMethod::BI_HUFFMAN1D) => Source::Huffman(BitReader::new(reader)),
+
+            Some(CompressionMethod::BI_JPEG) => Source::Decoded(Self::decode_jpeg(&header, reader, data_size).map_err(absolute)?.into_iter()),
+            Some(CompressionMethod::BI_PNG) => Source::Decoded(Self::decode_png(&header, reader, data_size).map_err(absolute)?.into_iter()),
+
+            // Palettes have already been converted from CMYK, only 32 bpp needs to be handled
+            // differently.
+            Some(CompressionMethod::BI_CMYK) if header.bpp <= 8 || header.bpp == 32 => Source::Uncompressed(reader),
+            Some(CompressionMethod::BI_CMYKRLE4) if header.bpp == 4 => Source::Rle(reader),
+            Some(CompressionMethod::BI_CMYKRLE8) if header.bpp == 8 => Source::Rle(reader),
+
+            _ => return Err(absolute(header.unsupported_bpp())),
+        };
+        if let Source::Uncompressed(_) = source {
+            match header.bpp {
+                1 | 2 | 4 | 8 | 16 | 24 | 32 => {},
+                _ => return Err(absolute(header.unsupported_bpp())),
+            }
+            let image_bytes = Self::line_bytes(&header) * height;
+            if image_bytes > data_size {
+                return Err(header.error(BmpErrorKind::MissingData{ expected: image_bytes, available: data_size }, "Pixel data", offset));
+            }
+        }
+        let bitmasks = header.bitmasks.clone().unwrap_or_else(|| default_bitmasks(header.bpp));
+
+        Ok(BmpReader {
+            header,
+            header_size,
+            source,
+            options,
+            bitmasks,
+            position: 0,
+            yielded: 0,
+            pending: VecDeque::new(),
+            completed: 0,
+            current: vec![Color{ ..Default::default() }; width],
+            x: 0,
+            color: HuffmanColor::White,
+            nb_eol: 0,
+            started: false,
+            ended: false,
+            failed: false,
+        })
+    }
+
+    // The embedded image is used as is, without color management nor palette.
+    #[cfg(any(feature = "jpeg", feature = "png"))]
+    fn check_embedded(header: &BmpFile, rows: std::result::Result<Vec<Vec<Color>>, String>) -> BmpResult<Vec<Vec<Color>>> {
+        let offset = header.offset as usize;
+        let rows = rows.map_err(|e| header.error(BmpErrorKind::EmbeddedImage(e), "Embedded image", offset))?;
+        if rows.len() != header.height.unsigned_abs() as usize || rows.iter().any(|r| r.len() != header.width as usize) {
+            let kind = BmpErrorKind::EmbeddedImageSize{ width: rows.first().map_or(0, |r| r.len()), height: rows.len() };
+            return Err(header.error(kind, "Embedded image", offset));
+        }
+        Ok(rows)
+    }
+
+    #[cfg(any(feature = "jpeg", feature = "png"))]
+    fn read_embedded(header: &BmpFile, mut reader: BufReader<R>, data_size: usize) -> BmpResult<Vec<u8>> {
+        let mut data = Vec::with_capacity(data_size);
+        reader.read_to_end(&mut data).map_err(|e| header.error(io_error(e), "Embedded image", header.offset as usize))?;
+        Ok(data)
+    }
+
+    #[cfg(feature = "jpeg")]
+    fn decode_jpeg(header: &BmpFile, reader: BufReader<R>, data_size: usize) -> BmpResult<Vec<Vec<Color>>> {
+        let data = Self::read_embedded(header, reader, data_size)?;
+        Self::check_embedded(header, crate::embedded::decode_jpeg(&data))
+    }
+
+    #[cfg(not(feature = "jpeg"))]
+    fn decode_jpeg(header: &BmpFile, _reader: BufReader<R>, _data_size: usize) -> BmpResult<Vec<Vec<Color>>> {
+        Err(header.error(BmpErrorKind::MissingFeature("jpeg"), "Compression", FILE_HEADER_SIZE + 16))
+    }
+
+    #[cfg(feature = "png")]
+    fn decode_png(header: &BmpFile, reader: BufReader<R>, data_size: usize) -> BmpResult<Vec<Vec<Color>>> {
+        let data = Self::read_embedded(header, reader, data_size)?;
+        Self::check_embedded(header, crate::embedded::decode_png(&data))
+    }
+
+    #[cfg(not(feature = "png"))]
+    fn decode_png(header: &BmpFile, _reader: BufReader<R>, _data_size: usize) -> BmpResult<Vec<Vec<Color>>> {
+        Err(header.error(BmpErrorKind::MissingFeature("png"), "Compression", FILE_HEADER_SIZE + 16))
+    }
+}
+
+impl <R: Read> BmpReader<R> {
+    // Rows are padded to 4 bytes.
+    fn line_bytes(header: &BmpFile) -> usize {
+        (header.width as usize * header.bpp as usize).div_ceil(32) * 4
+    }
+
+    fn blank_row(&self) -> Vec<Color> {
+        vec![Color{ ..Default::default() }; self.header.width as usize]
+    }
+
+    // Index from the top of the n-th row in the file.
+    fn row_y(&self, n: usize) -> i32 {
+        match self.source {
+            Source::Decoded(_) => n as i32,
+            _ if self.header.height < 0 => n as i32,
+            _ => self.header.height - 1 - n as i32,
+        }
+    }
+
+    fn decode_uncompressed_row(&mut self) -> BmpResult<(Vec<Color>, Option<Vec<u8>>)> {
+        let line_bytes = Self::line_bytes(&self.header);
+        let row_offset = self.header.offset as usize + self.position;
+        let mut line = vec![0; line_bytes];
+        if let Source::Uncompressed(reader) = &mut self.source {
+            reader.read_exact(&mut line).map_err(|e| self.header.error(io_error(e), "Pixel data", row_offset))?;
+        }
+        self.position += line_bytes;
+
+        let width = self.header.width as usize;
+        let bpp = self.header.bpp as usize;
+        let mut row = Vec::with_capacity(width);
+        let mut indexes = None;
+        match bpp {
+            1 | 2 | 4 | 8 => {
+                let mask = (1 << bpp) - 1;
+                let mut row_indexes = Vec::with_capacity(width);
+                for x in 0..width {
+                    let bit = x * bpp;
+                    let shift = 8 - bpp - bit % 8;
+                    let idx = (line[bit / 8] as usize >> shift) & mask;
+                    if idx >= self.header.palette.len() {
+                        let kind = BmpErrorKind::ColorOutsideOfPalette{ index: idx, palette_size: self.header.palette.len() };
+                        return Err(self.header.error(kind, "Pixel data", row_offset + bit / 8));
+                    }
+                    row.push(self.header.to_srgb(self.header.palette[idx], &self.options));
+                    row_indexes.push(idx as u8);
+                }
+                indexes = Some(row_indexes);
+            },
+            32 if self.header.is_cmyk() => {
+                for cmyk in line.chunks(4).take(width) {
+                    let color = denormalize(cmyk_to_rgb(cmyk[3], cmyk[2], cmyk[1], cmyk[0]));
+                    row.push(self.header.to_srgb(color, &self.options));
+                }
+            },
+            _ => {
+                let bitmasks = &self.bitmasks;
+                for bytes in line.chunks(bpp / 8).take(width) {
+                    let mut val = 0;
+                    for (i, b) in bytes.iter().enumerate() {
+                        val += (*b as u32) << (8*i);
+                    }
+                    let red = normalize_from_mask(val, bitmasks.red_mask, bitmasks.red_shift);
+                    let green = normalize_from_mask(val, bitmasks.green_mask, bitmasks.green_shift);
+                    let blue = normalize_from_mask(val, bitmasks.blue_mask, bitmasks.blue_shift);
+                    let alpha = normalize_from_mask(val, bitmasks.alpha_mask, bitmasks.alpha_shift);
+                    row.push(self.header.to_srgb(Color{red, green, blue, alpha}, &self.options));
+                }
+            },
+        }
+        Ok((row, indexes))
+    }
+
+    fn finish_row(&mut self) {
+        let blank = self.blank_row();
+        let row = std::mem::replace(&mut self.current, blank);
+        if self.completed < self.header.height.unsigned_abs() as usize {
+            self.pending.push_back(row);
+        }
+        self.completed += 1;
+        self.x = 0;
+    }
+
+    fn put(&mut self, color: Color, length: usize) -> std::result::Result<(), BmpErrorKind> {
+        let color = self.header.to_srgb(color, &self.options);
+        for _ in 0..length {
+            if self.x >= self.current.len() || self.completed >= self.header.height.unsigned_abs() as usize {
+                return Err(BmpErrorKind::PixelOutsideOfImage{ x: self.x as i32, y: self.row_y(self.completed) });
+            }
+            self.current[self.x] = color;
+            self.x += 1;
+        }
+        Ok(())
+    }
+
+    fn put_index(&mut self, idx: usize, length: usize) -> std::result::Result<(), BmpErrorKind> {
+        match self.header.palette.get(idx) {
+            Some(color) => self.put(*color, length),
+            None => Err(BmpErrorKind::ColorOutsideOfPalette{ index: idx, palette_size: self.header.palette.len() }),
+        }
+    }
+
+    fn next_byte(&mut self) -> std::result::Result<Option<u8>, BmpErrorKind> {
+        let mut byte = [0; 1];
+        let read = match &mut self.source {
+            Source::Rle(reader) => reader.read(&mut byte).map_err(io_error)?,
+            _ => 0,
+        };
+        self.position += read;
+        Ok(if read == 1 { Some(byte[0]) } else { None })
+    }
+
+    fn byte(&mut self) -> std::result::Result<u8, BmpErrorKind> {
+        self.next_byte()?.ok_or(BmpErrorKind::Truncated)
+    }
+
+    // Decodes one RLE command.
+    fn decode_rle(&mut self) -> std::result::Result<(), BmpErrorKind> {
+        let control = match self.next_byte()? {
+            Some(c) => c as usize,
+            None => {
+                // Missing end of image
+                self.finish_row();
+                self.ended = true;
+                return Ok(());
+            },
+        };
+        let escape = self.byte()? as usize;
+        match control {
+            0 => {
+                match escape {
+                    0 => {
+                        // end of line
+                        self.finish_row();
+                    },
+                    1 => {
+                        // end of image
+                        self.finish_row();
+                        self.ended = true;
+                    },
+                    2 => {
+                        // displacement mode
+                        let dx = self.byte()? as usize;
+                        let dy = self.byte()? as usize;
+                        let x = self.x;
+                        for _ in 0..dy {
+                            self.finish_row();
+                        }
+                        self.x = x + dx;
+                    },
+                    pixels => {
+                        // absolute mode
+                        let bytes;
+                        match self.header.bpp {
+                            4 => {
+                                bytes = (pixels + 1) / 2;
+                                let mut i = 0;
+                                while i < pixels {
+                                    let indexes = self.byte()? as usize;
+                                    self.put_index((indexes & 0xf0) >> 4, 1)?;
+                                    i += 1;
+                                    if i >= pixels {
+                                        break;
+                                    }
+                                    self.put_index(indexes & 0xf, 1)?;
+                                    i += 1;
+                                }
+                            },
+                            8 => {
+                                bytes = pixels;
+                                for _ in 0..pixels {
+                                    let idx = self.byte()? as usize;
+                                    self.put_index(idx, 1)?;
+                                }
+                            },
+                            _ => {
+                                // TODO: Find examples of RLE24 to test this
+                                bytes = pixels * 3;
+                                for _ in 0..pixels {
+                                    // I'm assuming bitmasks are not used with RLE24. I may be
+                                    // wrong ...
+                                    let blue = self.byte()?;
+                                    let green = self.byte()?;
+                                    let red = self.byte()?;
+                                    self.put(Color{red, green, blue, alpha: 255}, 1)?;
+                                }
+                            },
+                        }
+                        if (bytes % 2) == 1 {
+                            // Padding to a 16 bits boundary
+                            self.byte()?;
+                        }
+                    },
+                }
+            },
+            length => {
+                match self.header.bpp {
+                    4 => {
+                        let mut i = 0;
+                        let idx1 = (escape & 0xf0) >> 4;
+                        let idx2 = escape & 0xf;
+                        while i < length {
+                            self.put_index(idx1, 1)?;
+                            i += 1;
+                            if i >= length {
+                                break;
+                            }
+                            self.put_index(idx2, 1)?;
+                            i += 1;
+                        }
+                    },
+                    8 => {
+                        self.put_index(escape, length)?;
+                    },
+                    _ => {
+                        let blue = escape as u8;
+                        let green = self.byte()?;
+                        let red = self.byte()?;
+                        self.put(Color{red, green, blue, alpha: 255}, length)?;
+                    },
+                }
+            },
+        }
+        Ok(())
+    }
+
+    // CCITT Group 3 1-Dimensional (G31D) encoding, improperly referred to as Huffman about
+    // everywhere.
+    // Well described in http://zig.tgschultz.com/bmp_file_format.txt
+    // See https://www.itu.int/rec/T-REC-T.4-200307-I/en for specification
+    // Decodes one code.
+    fn decode_huffman(&mut self) -> BmpResult<()> {
+        let table = huffman_table(&self.color);
+        let mut value = 0_u32;
+        let mut length = 0;
+        // Start of the code, for errors
+        let position = self.header.offset as usize + self.position/8;
+        if let Source::Huffman(bit_reader) = &mut self.source {
+            while length <= 13 {
+                value <<= 1;
+                let bit = match bit_reader.read_bit() {
+                    Ok(bit) => bit,
+                    Err(e) => return Err(self.header.error(io_error(e), "Huffman data", position)),
+                };
+                match bit {
+                    Some(b) => {
+                        if b {
+                            value += 1;
+                        }
+                    },
+                    None => {
+                        break;
+                    }
+                }
+                length += 1;
+                if table.contains_key(&HuffmanCodeWord{length, value}) {
+                    break;
+                }
+            }
+        }
+        self.position += length;
+        let code = HuffmanCodeWord{length, value};
+        match table.get(&code) {
+            Some(HuffmanCommand::Eol) => {
+                self.nb_eol += 1;
+                self.color = HuffmanColor::White;
+                // Data starts with an EOL, which doesn't end any row.
+                if self.started {
+                    self.finish_row();
+                }
+                self.started = true;
+            },
+            Some(HuffmanCommand::Value(run_length)) => {
+                self.nb_eol = 0;
+                let result = if self.started {
+                    self.put_index(self.color.pal_entry(), *run_length)
+                } else {
+                    Err(BmpErrorKind::PixelOutsideOfImage{ x: self.x as i32, y: self.header.height })
+                };
+                result.map_err(|kind| self.header.error(kind, "Huffman data", position))?;
+                if *run_length <= 63 {
+                    self.color = self.color.next();
+                }
+            },
+            None => {
+                return Err(self.header.error(BmpErrorKind::InvalidHuffmanCode(code), "Huffman data", position));
+            }
+        }
+        // 6xEOL (End of Line) indicates RTC (Return to Control), i.e
+        // end of image.
+        if self.nb_eol == 6 {
+            self.ended = true;
+        }
+        Ok(())
+    }
+
+    fn decode_row(&mut self) -> BmpResult<(Vec<Color>, Option<Vec<u8>>)> {
+        match &mut self.source {
+            Source::Uncompressed(_) => return self.decode_uncompressed_row(),
+            Source::Decoded(rows) => return Ok((rows.next().unwrap_or_default(), None)),
+            Source::Rle(_) => {
+                while self.pending.is_empty() && !self.ended {
+                    if let Err(kind) = self.decode_rle() {
+                        // The error is reported on the last byte read.
+                        let position = self.header.offset as usize + self.position.saturating_sub(1);
+                        return Err(self.header.error(kind, "RLE data", position));
+                    }
+                }
+            },
+            Source::Huffman(_) => {
+                while self.pending.is_empty() && !self.ended {
+                    self.decode_huffman()?;
+                }
+            },
+        }
+        Ok((self.pending.pop_front().unwrap_or_else(|| self.blank_row()), None))
+    }
+}
+
+// Rows are yielded in the order they are stored in the file, i.e bottom-up unless the height is
+// negative. Decoding stops after the first error.
+impl <R: Read> Iterator for BmpReader<R> {
+    type Item = BmpResult<Row>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.yielded >= self.header.height.unsigned_abs() as usize {
+            return None;
+        }
+        match self.decode_row() {
+            Ok((pixels, indexes)) => {
+                let y = self.row_y(self.yielded) as usize;
+                self.yielded += 1;
+                Some(Ok(Row{ y, pixels, indexes }))
+            },
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::Image;
+    use crate::writer::BmpWriter;
+    use std::io::Cursor;
+
+    fn gradient(width: usize, height: usize) -> Image {
+        Image::from_fn(width, height, |x, y| Color{ red: (x*7) as u8, green: (y*5) as u8, blue: (x^y) as u8, alpha: 255 })
+    }
+
+    #[test]
+    fn test_rows_in_file_order() {
+        let pixels = gradient(5, 4);
+        for topdown in [false, true] {
+            let writer = BmpWriter{ topdown, ..BmpWriter::new(DibHeaderSize::BITMAPINFOHEADER, 24) };
+            let reader = BmpReader::new(Cursor::new(writer.write(&pixels).unwrap())).unwrap();
+            let rows = reader.map(|r| r.unwrap()).collect::<Vec<_>>();
+            let ys = rows.iter().map(|r| r.y).collect::<Vec<_>>();
+            assert_eq!(ys, if topdown { vec!(0, 1, 2, 3) } else { vec!(3, 2, 1, 0) });
+            for row in rows.iter() {
+                assert_eq!(row.pixels, (0..5).map(|x| pixels.pixel(x, row.y)).collect::<Vec<_>>());
+                assert_eq!(row.indexes, None);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rle_delta_and_end_of_image() {
+        // 4x4 8bpp: bottom row red, then a delta moving 1 row up and 2 right, one blue pixel and
+        // the end of image.
+        let palette = vec!(Color{ red: 255, green: 0, blue: 0, alpha: 255 }, Color{ red: 0, green: 0, blue: 255, alpha: 255 });
+        let writer = BmpWriter{ compression: CompressionMethod::BI_RLE8, palette: Some(palette.clone()), ..BmpWriter::new(DibHeaderSize::BITMAPINFOHEADER, 8) };
+        let mut bitmap = writer.write(&Image::from_fn(4, 4, |_, _| palette[0])).unwrap();
+        let offset = u32::from_le_bytes([bitmap[10], bitmap[11], bitmap[12], bitmap[13]]) as usize;
+        bitmap.truncate(offset);
+        bitmap.extend_from_slice(&[4, 0, 0, 0, 0, 2, 2, 1, 1, 1, 0, 1]);
+
+        let blank = Color{ ..Default::default() };
+        let rows = BmpReader::new(Cursor::new(bitmap)).unwrap().map(|r| r.unwrap().pixels).collect::<Vec<_>>();
+        assert_eq!(rows, vec!(
+            vec![palette[0]; 4],
+            vec![blank; 4],
+            vec!(blank, blank, palette[1], blank),
+            vec![blank; 4],
+        ));
+    }
+
+    #[test]
+    fn test_stops_after_error() {
+        let palette = vec!(Color{ red: 255, green: 0, blue: 0, alpha: 255 }, Color{ red: 0, green: 0, blue: 255, alpha: 255 });
+        let writer = BmpWriter{ compression: CompressionMethod::BI_RLE8, palette: Some(palette.clone()), ..BmpWriter::new(DibHeaderSize::BITMAPINFOHEADER, 8) };
+        let mut bitmap = writer.write(&Image::from_fn(2, 2, |_, _| palette[0])).unwrap();
+        let offset = u32::from_le_bytes([bitmap[10], bitmap[11], bitmap[12], bitmap[13]]) as usize;
+        bitmap.truncate(offset);
+        // Second row uses a color outside of the palette.
+        bitmap.extend_from_slice(&[2, 0, 0, 0, 2, 7]);
+        let mut reader = BmpReader::new(Cursor::new(bitmap)).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        let error = reader.next().unwrap().unwrap_err();
+        assert_eq!(error.kind, BmpErrorKind::ColorOutsideOfPalette{ index: 7, palette_size: 2 });
+        assert_eq!(error.offset, offset + 5);
+        assert!(reader.next().is_none());
+    }
+
+    // More than a billion pixels, only possible to process row by row.
+    #[test]
+    fn test_big_image() {
+        let (width, height) = (40000_u32, 30000_i32);
+        let red = Color{ red: 255, green: 0, blue: 0, alpha: 255 };
+        let writer = BmpWriter{ compression: CompressionMethod::BI_RLE8, ..BmpWriter::new(DibHeaderSize::BITMAPINFOHEADER, 8) };
+        let mut bitmap = writer.write(&Image::from_fn(1, 1, |_, _| red)).unwrap();
+        bitmap[18..22].copy_from_slice(&width.to_le_bytes());
+        bitmap[22..26].copy_from_slice(&height.to_le_bytes());
+        let offset = u32::from_le_bytes([bitmap[10], bitmap[11], bitmap[12], bitmap[13]]) as usize;
+        bitmap.truncate(offset);
+        bitmap.extend_from_slice(&[1, 0, 0, 1]);
+
+        let error = BmpFile::parse(&bitmap).unwrap_err();
+        assert!(matches!(error.kind, BmpErrorKind::ImageTooLarge{ width: w, height: h, .. } if (w, h) == (width as i32, height)));
+        let mut reader = BmpReader::new(Cursor::new(&bitmap)).unwrap();
+        let row = reader.next().unwrap().unwrap();
+        assert_eq!((row.y, row.pixels.len(), row.pixels[0]), (height as usize - 1, width as usize, red));
+        let row = reader.next().unwrap().unwrap();
+        assert_eq!((row.y, row.pixels[0]), (height as usize - 2, Color{ ..Default::default() }));
+    }
+
+    #[test]
+    fn test_max_pixels() {
+        let image = Image::from_fn(3, 2, |x, y| Color{ red: x as u8, green: y as u8, blue: 0, alpha: 255 });
+        let bitmap = BmpWriter::new(DibHeaderSize::BITMAPINFOHEADER, 24).write(&image).unwrap();
+        let options = |max_pixels| DecodeOptions{ max_pixels, ..Default::default() };
+        let error = BmpFile::parse_with_options(&bitmap, options(Some(5))).unwrap_err();
+        assert_eq!((error.field, error.offset), ("Image Width", 18));
+        // The headers come with the error.
+        let (_, expected) = BmpFile::parse(&bitmap).unwrap();
+        match error.kind {
+            BmpErrorKind::ImageTooLarge{ width, height, header } => {
+                assert_eq!((width, height), (3, 2));
+                assert_eq!((header.width, header.height, header.bpp, header.offset), (3, 2, 24, expected.offset));
+            },
+            kind => panic!("unexpected {:?}", kind),
+        }
+        for max_pixels in [Some(6), None] {
+            assert_eq!(BmpFile::parse_with_options(&bitmap, options(max_pixels)).unwrap().1.image.data, image.data);
+        }
+    }
+
+    #[test]
+    fn test_too_large_rows() {
+        let red = Color{ red: 255, green: 0, blue: 0, alpha: 255 };
+        let writer = BmpWriter{ compression: CompressionMethod::BI_RLE8, ..BmpWriter::new(DibHeaderSize::BITMAPINFOHEADER, 8) };
+        let bitmap = writer.write(&Image::from_fn(1, 1, |_, _| red)).unwrap();
+        for (width, height, field, offset) in [(1 << 25, 1, "Image Width", 18), (0, 1_i32 << 25, "Image Height", 22)] {
+            let mut bitmap = bitmap.clone();
+            bitmap[18..22].copy_from_slice(&(width as u32).to_le_bytes());
+            bitmap[22..26].copy_from_slice(&height.to_le_bytes());
+            let error = BmpReader::new(Cursor::new(&bitmap)).err().unwrap();
+            assert!(matches!(error.kind, BmpErrorKind::ImageTooLarge{ width: w, height: h, .. } if (w, h) == (width, height)));
+            assert_eq!((error.field, error.offset), (field, offset));
+        }
+    }
+}
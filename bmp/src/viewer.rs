@@ -0,0 +1,342 @@
+// Interactive viewer for the display subcommand. Images are decoded once when selected, then drawn
+// as a texture that can be zoomed and panned, over a checkerboard showing transparency.
+use crate::bmp::{BmpFile, DecodeOptions};
+use sdl2::event::Event;
+use sdl2::gfx::primitives::DrawRenderer;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::{BlendMode, Canvas, Texture, TextureCreator};
+use sdl2::video::{Window, WindowContext};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const SCREEN_WIDTH: u32 = 600;
+const SCREEN_HEIGHT: u32 = 600;
+
+const MIN_ZOOM: f64 = 1.0 / 64.0;
+const MAX_ZOOM: f64 = 64.0;
+const ZOOM_STEP: f64 = 1.25;
+const PAN_STEP: f64 = 32.0;
+
+// In window pixels, so that the pattern doesn't get in the way of zoomed in pixels.
+const CHECKER_SIZE: i32 = 8;
+const CHECKER_LIGHT: Color = Color{ r: 204, g: 204, b: 204, a: 255 };
+const CHECKER_DARK: Color = Color{ r: 153, g: 153, b: 153, a: 255 };
+
+// The gfx primitives use an 8x8 font.
+const LINE_HEIGHT: i32 = 10;
+
+// Position of the top left corner of the image in the window, and scale.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct View {
+    pub x: f64,
+    pub y: f64,
+    pub zoom: f64,
+}
+
+impl View {
+    // Centered, and shrinked to fit the window if needed.
+    pub fn fit(image: (u32, u32), window: (u32, u32)) -> View {
+        let zoom = (window.0 as f64 / image.0.max(1) as f64)
+            .min(window.1 as f64 / image.1.max(1) as f64)
+            .clamp(MIN_ZOOM, 1.0);
+        View::centered(image, window, zoom)
+    }
+
+    pub fn centered(image: (u32, u32), window: (u32, u32), zoom: f64) -> View {
+        View {
+            x: (window.0 as f64 - image.0 as f64 * zoom) / 2.0,
+            y: (window.1 as f64 - image.1 as f64 * zoom) / 2.0,
+            zoom,
+        }
+    }
+
+    // Keeps the image point under (x, y) in place.
+    pub fn zoom_at(&mut self, x: f64, y: f64, factor: f64) {
+        let zoom = (self.zoom * factor).clamp(MIN_ZOOM, MAX_ZOOM);
+        self.x = x - (x - self.x) * zoom / self.zoom;
+        self.y = y - (y - self.y) * zoom / self.zoom;
+        self.zoom = zoom;
+    }
+
+    pub fn pan(&mut self, dx: f64, dy: f64) {
+        self.x += dx;
+        self.y += dy;
+    }
+
+    // Where the image is drawn, at least one pixel wide and high.
+    pub fn destination(&self, image: (u32, u32)) -> Rect {
+        let width = (image.0 as f64 * self.zoom).round().max(1.0);
+        let height = (image.1 as f64 * self.zoom).round().max(1.0);
+        Rect::new(self.x.round() as i32, self.y.round() as i32, width as u32, height as u32)
+    }
+}
+
+// Dark squares of a checkerboard aligned on origin, clipped to area.
+pub fn checkerboard(area: Rect, origin: (i32, i32), size: i32) -> Vec<Rect> {
+    let mut squares = Vec::new();
+    let first = |start: i32, origin: i32| start - (start - origin).rem_euclid(size);
+    let mut y = first(area.top(), origin.1);
+    while y < area.bottom() {
+        let mut x = first(area.left(), origin.0);
+        while x < area.right() {
+            if ((x - origin.0) / size + (y - origin.1) / size) % 2 != 0 {
+                if let Some(square) = Rect::new(x, y, size as u32, size as u32).intersection(area) {
+                    squares.push(square);
+                }
+            }
+            x += size;
+        }
+        y += size;
+    }
+    squares
+}
+
+// Lines of the header overlay.
+pub fn header_info(bitmap: &BmpFile) -> Vec<String> {
+    let mut lines = vec!(
+        format!("{:?} {:?} ({} bytes)", bitmap.magic, bitmap.dib_header_size, bitmap.dib_header_size as u32),
+        format!("{}x{} {}", bitmap.width, bitmap.height.unsigned_abs(), if bitmap.height < 0 { "top-down" } else { "bottom-up" }),
+        format!("{} bpp", bitmap.bpp),
+    );
+    if let Some(core_header) = bitmap.core_header {
+        lines.push(format!("Core header: {:?}", core_header));
+    }
+    match bitmap.compression {
+        Some(compression) => lines.push(format!("Compression: {:?}", compression)),
+        None => lines.push("Compression: none".to_string()),
+    }
+    if let Some(masks) = &bitmap.bitmasks {
+        lines.push(format!("Masks: R {:08x} G {:08x} B {:08x} A {:08x}", masks.red_mask, masks.green_mask, masks.blue_mask, masks.alpha_mask));
+    }
+    if !bitmap.palette.is_empty() {
+        lines.push(format!("Palette: {} colors", bitmap.palette.len()));
+    }
+    if let Some(intent) = bitmap.intent {
+        lines.push(format!("Intent: {:?}", intent));
+    }
+    lines
+}
+
+// Decoded image of the current file.
+struct Picture<'a> {
+    texture: Texture<'a>,
+    size: (u32, u32),
+    info: Vec<String>,
+}
+
+fn load<'a>(path: &Path, creator: &'a TextureCreator<WindowContext>) -> Result<Picture<'a>, String> {
+    let input = fs::read(path).map_err(|e| format!("Couldn't open {:?}: {}", path, e))?;
+    let (_, bitmap) = BmpFile::parse_with_options(&input, DecodeOptions::default())
+        .map_err(|e| format!("Couldn't parse {:?}: {}", path, e))?;
+    let image = &bitmap.image;
+    let surface = image.to_surface().map_err(|e| format!("Couldn't display {:?}: {}", path, e))?;
+    let mut texture = creator.create_texture_from_surface(&surface).map_err(|e| format!("Couldn't display {:?}: {}", path, e))?;
+    texture.set_blend_mode(BlendMode::Blend);
+    Ok(Picture{ texture, size: (image.width as u32, image.height as u32), info: header_info(&bitmap) })
+}
+
+pub struct Viewer {
+    paths: Vec<PathBuf>,
+    index: usize,
+    view: View,
+    background: Color,
+    show_info: bool,
+}
+
+impl Viewer {
+    pub fn new(paths: Vec<PathBuf>) -> Viewer {
+        Viewer {
+            paths,
+            index: 0,
+            view: View{ x: 0.0, y: 0.0, zoom: 1.0 },
+            background: Color::RGB(0, 0, 0),
+            show_info: false,
+        }
+    }
+
+    pub fn run(mut self) -> Result<(), String> {
+        if self.paths.is_empty() {
+            return Err("No file to display".to_string());
+        }
+        let sdl_context = sdl2::init()?;
+        let video_subsystem = sdl_context.video()?;
+        let window = video_subsystem.window("BMP Viewer", SCREEN_WIDTH, SCREEN_HEIGHT)
+            .position_centered()
+            .resizable()
+            .build()
+            .map_err(|e| e.to_string())?;
+        let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+        let creator = canvas.texture_creator();
+        let mut event_pump = sdl_context.event_pump()?;
+
+        let mut picture = self.select(0, &creator, canvas.output_size()?);
+        'display_loop: loop {
+            let window_size = canvas.output_size()?;
+            let (mouse_x, mouse_y) = {
+                let state = event_pump.mouse_state();
+                (state.x() as f64, state.y() as f64)
+            };
+            for event in event_pump.poll_iter() {
+                match event {
+                    Event::Quit {..} |
+                    Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
+                        break 'display_loop
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::N), .. } if self.index + 1 < self.paths.len() => {
+                        picture = self.select(self.index + 1, &creator, window_size);
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::P), .. } if self.index > 0 => {
+                        picture = self.select(self.index - 1, &creator, window_size);
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::C), .. } => {
+                        self.background = if self.background.r == 0 { Color::RGB(255, 255, 255) } else { Color::RGB(0, 0, 0) };
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::I), .. } => {
+                        self.show_info = !self.show_info;
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::Num0), .. } |
+                    Event::KeyDown { keycode: Some(Keycode::Kp0), .. } => {
+                        if let Some(picture) = &picture {
+                            self.view = View::fit(picture.size, window_size);
+                        }
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::Num1), .. } |
+                    Event::KeyDown { keycode: Some(Keycode::Kp1), .. } => {
+                        if let Some(picture) = &picture {
+                            self.view = View::centered(picture.size, window_size, 1.0);
+                        }
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::Plus), .. } |
+                    Event::KeyDown { keycode: Some(Keycode::Equals), .. } |
+                    Event::KeyDown { keycode: Some(Keycode::KpPlus), .. } => {
+                        self.view.zoom_at(window_size.0 as f64 / 2.0, window_size.1 as f64 / 2.0, ZOOM_STEP);
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::Minus), .. } |
+                    Event::KeyDown { keycode: Some(Keycode::KpMinus), .. } => {
+                        self.view.zoom_at(window_size.0 as f64 / 2.0, window_size.1 as f64 / 2.0, 1.0 / ZOOM_STEP);
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::Left), .. } => self.view.pan(PAN_STEP, 0.0),
+                    Event::KeyDown { keycode: Some(Keycode::Right), .. } => self.view.pan(-PAN_STEP, 0.0),
+                    Event::KeyDown { keycode: Some(Keycode::Up), .. } => self.view.pan(0.0, PAN_STEP),
+                    Event::KeyDown { keycode: Some(Keycode::Down), .. } => self.view.pan(0.0, -PAN_STEP),
+                    Event::MouseWheel { y, .. } if y != 0 => {
+                        self.view.zoom_at(mouse_x, mouse_y, ZOOM_STEP.powi(y));
+                    },
+                    Event::MouseMotion { mousestate, xrel, yrel, .. } if mousestate.left() => {
+                        self.view.pan(xrel as f64, yrel as f64);
+                    },
+                    _ => {}
+                }
+            }
+            canvas.set_draw_color(self.background);
+            canvas.clear();
+            if let Some(picture) = &picture {
+                self.draw(&mut canvas, picture, window_size)?;
+            }
+            canvas.present();
+            // Nothing is animated, no need to spin.
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        Ok(())
+    }
+
+    // Decodes the file at index, errors being reported on the console.
+    fn select<'a>(&mut self, index: usize, creator: &'a TextureCreator<WindowContext>, window_size: (u32, u32)) -> Option<Picture<'a>> {
+        self.index = index;
+        let path = &self.paths[index];
+        println!("{:?}", path);
+        match load(path, creator) {
+            Ok(picture) => {
+                self.view = View::fit(picture.size, window_size);
+                Some(picture)
+            },
+            Err(e) => {
+                println!("{}", e);
+                None
+            },
+        }
+    }
+
+    fn draw(&self, canvas: &mut Canvas<Window>, picture: &Picture, window_size: (u32, u32)) -> Result<(), String> {
+        let destination = self.view.destination(picture.size);
+        if let Some(visible) = destination.intersection(Rect::new(0, 0, window_size.0, window_size.1)) {
+            canvas.set_draw_color(CHECKER_LIGHT);
+            canvas.fill_rect(visible)?;
+            canvas.set_draw_color(CHECKER_DARK);
+            canvas.fill_rects(&checkerboard(visible, (destination.x(), destination.y()), CHECKER_SIZE))?;
+            canvas.copy(&picture.texture, None, destination)?;
+        }
+        if self.show_info {
+            let mut lines = vec!(format!("{:?} ({}/{})", self.paths[self.index], self.index + 1, self.paths.len()));
+            lines.extend(picture.info.iter().cloned());
+            lines.push(format!("Zoom: {:.0}%", self.view.zoom * 100.0));
+            let width = lines.iter().map(|l| l.chars().count()).max().unwrap_or(0) as i32 * 8;
+            let height = lines.len() as i32 * LINE_HEIGHT;
+            canvas.box_(0, 0, (width + 8) as i16, (height + 6) as i16, Color::RGBA(0, 0, 0, 192))?;
+            for (n, line) in lines.iter().enumerate() {
+                canvas.string(4, (4 + n as i32 * LINE_HEIGHT) as i16, line, Color::RGB(255, 255, 255))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bmp::{Bitmasks, CompressionMethod, DibHeaderSize};
+    use crate::image::Image;
+    use crate::writer::BmpWriter;
+
+    #[test]
+    fn test_fit() {
+        // Small images are not enlarged.
+        assert_eq!(View::fit((100, 50), (600, 600)), View{ x: 250.0, y: 275.0, zoom: 1.0 });
+        let view = View::fit((1200, 300), (600, 600));
+        assert_eq!(view, View{ x: 0.0, y: 225.0, zoom: 0.5 });
+        assert_eq!(view.destination((1200, 300)), Rect::new(0, 225, 600, 150));
+        // Empty images still get a pixel.
+        assert_eq!(View::fit((0, 0), (600, 600)).destination((0, 0)).size(), (1, 1));
+    }
+
+    #[test]
+    fn test_zoom_and_pan() {
+        let mut view = View{ x: 10.0, y: 20.0, zoom: 1.0 };
+        // Image pixel (40, 30) stays under the cursor.
+        view.zoom_at(50.0, 50.0, 2.0);
+        assert_eq!(view, View{ x: -30.0, y: -10.0, zoom: 2.0 });
+        view.pan(5.0, -5.0);
+        assert_eq!(view, View{ x: -25.0, y: -15.0, zoom: 2.0 });
+        view.zoom_at(0.0, 0.0, 1000.0);
+        assert_eq!(view.zoom, MAX_ZOOM);
+        view.zoom_at(0.0, 0.0, 0.0);
+        assert_eq!(view.zoom, MIN_ZOOM);
+    }
+
+    #[test]
+    fn test_checkerboard() {
+        let squares = checkerboard(Rect::new(0, 0, 16, 16), (0, 0), 8);
+        assert_eq!(squares, vec!(Rect::new(8, 0, 8, 8), Rect::new(0, 8, 8, 8)));
+        // The pattern moves with the image and is clipped to the visible part.
+        let squares = checkerboard(Rect::new(0, 0, 10, 6), (-4, -6), 8);
+        assert_eq!(squares, vec!(Rect::new(4, 0, 6, 2), Rect::new(0, 2, 4, 4)));
+    }
+
+    #[test]
+    fn test_header_info() {
+        let bitmasks = Some(Bitmasks::from_masks(0xf800, 0x07e0, 0x001f, 0));
+        let writer = BmpWriter{ compression: CompressionMethod::BI_BITFIELDS, bitmasks, ..BmpWriter::new(DibHeaderSize::BITMAPV3INFOHEADER, 16) };
+        let output = writer.write(&Image::from_fn(3, 2, |_, _| Default::default())).unwrap();
+        let (_, bitmap) = BmpFile::parse(&output).unwrap();
+        let info = header_info(&bitmap);
+        assert_eq!(info, vec!(
+            "BM BITMAPV3INFOHEADER (56 bytes)".to_string(),
+            "3x2 bottom-up".to_string(),
+            "16 bpp".to_string(),
+            "Compression: BI_BITFIELDS".to_string(),
+            "Masks: R 0000f800 G 000007e0 B 0000001f A 00000000".to_string(),
+        ));
+    }
+}
@@ -0,0 +1,774 @@
+use crate::bitwriter::BitWriter;
+use crate::bmp::*;
+use crate::image::Image;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+// Size of the bitmap file header (magic, file size, reserved, offset).
+const FILE_HEADER_SIZE: u32 = 14;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WriteError {
+    UnsupportedBpp(u16),
+    UnsupportedCompression(CompressionMethod, DibHeaderSize),
+    MissingBitmasks,
+    ImageTooLarge{ width: usize, height: usize },
+    TooManyColors(usize),
+    ColorNotInPalette(Color),
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WriteError::UnsupportedBpp(bpp) => write!(f, "unsupported bpp: {}", bpp),
+            WriteError::UnsupportedCompression(c, h) => write!(f, "compression {:?} is not supported with {:?}", c, h),
+            WriteError::MissingBitmasks => write!(f, "bitfields compression requires bitmasks"),
+            WriteError::ImageTooLarge{width, height} => write!(f, "image too large for this header: {}x{}", width, height),
+            WriteError::TooManyColors(n) => write!(f, "too many colors for the palette: {}", n),
+            WriteError::ColorNotInPalette(c) => write!(f, "color not in palette: {:?}", c),
+        }
+    }
+}
+
+impl std::error::Error for WriteError {}
+
+// Serializes an image into a BMP file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BmpWriter {
+    pub dib_header_size: DibHeaderSize,
+    pub bpp: u16,
+    pub compression: CompressionMethod,
+    // For bpp <= 8. If None, the palette is built from the colors found in the image.
+    pub palette: Option<Vec<Color>>,
+    // For bpp >= 16. Required for BI_BITFIELDS and BI_ALPHABITFIELDS, also honored with BI_RGB
+    // for headers which contain masks (V2 and above), as the parser uses them in that case.
+    pub bitmasks: Option<Bitmasks>,
+    // Write rows from top to bottom (negative height) instead of the usual bottom-up.
+    pub topdown: bool,
+    pub x_px_per_m: u32,
+    pub y_px_per_m: u32,
+}
+
+impl Default for BmpWriter {
+    fn default() -> Self {
+        BmpWriter {
+            dib_header_size: DibHeaderSize::BITMAPINFOHEADER,
+            bpp: 24,
+            compression: CompressionMethod::BI_RGB,
+            palette: None,
+            bitmasks: None,
+            topdown: false,
+            // 72 DPI
+            x_px_per_m: 2835,
+            y_px_per_m: 2835,
+        }
+    }
+}
+
+pub type WriteResult<T> = std::result::Result<T, WriteError>;
+
+fn push_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+// Inverse of normalize_from_mask.
+pub fn denormalize_to_mask(value: u8, mask: u32, shift: u32) -> u32 {
+    if mask == 0 {
+        return 0;
+    }
+    let max_value = (mask >> shift) as f64;
+    (((value as f64) * max_value / 255.0).round() as u32) << shift
+}
+
+impl BmpWriter {
+    pub fn new(dib_header_size: DibHeaderSize, bpp: u16) -> Self {
+        BmpWriter {
+            dib_header_size,
+            bpp,
+            ..Default::default()
+        }
+    }
+
+    fn is_core(&self) -> bool {
+        self.dib_header_size == DibHeaderSize::BITMAPCOREHEADER
+    }
+
+    fn has_compression_field(&self) -> bool {
+        self.dib_header_size != DibHeaderSize::BITMAPCOREHEADER &&
+            self.dib_header_size != DibHeaderSize::OS22XBITMAPHEADER16
+    }
+
+    fn has_masks_in_header(&self) -> bool {
+        self.dib_header_size as u32 >= DibHeaderSize::BITMAPV2INFOHEADER as u32 &&
+            self.dib_header_size != DibHeaderSize::OS22XBITMAPHEADER64
+    }
+
+    fn is_bitfields(&self) -> bool {
+        self.compression == CompressionMethod::BI_BITFIELDS ||
+            self.compression == CompressionMethod::BI_ALPHABITFIELDS
+    }
+
+    fn check(&self, width: usize, height: usize) -> WriteResult<()> {
+        match self.bpp {
+            1 | 2 | 4 | 8 | 16 | 24 | 32 => {},
+            _ => return Err(WriteError::UnsupportedBpp(self.bpp)),
+        }
+        let unsupported = Err(WriteError::UnsupportedCompression(self.compression, self.dib_header_size));
+        match self.compression {
+            CompressionMethod::BI_RGB => {},
+            CompressionMethod::BI_BITFIELDS |
+            CompressionMethod::BI_ALPHABITFIELDS => {
+                // BITMAPCOREHEADER bitmaps do not support color masks, and on OS/2 value 3 means
+                // BI_HUFFMAN1D.
+                if !self.has_compression_field() ||
+                    self.dib_header_size == DibHeaderSize::OS22XBITMAPHEADER64 ||
+                        self.bpp < 16 {
+                    return unsupported;
+                }
+                if self.bitmasks.is_none() {
+                    return Err(WriteError::MissingBitmasks);
+                }
+            },
+            CompressionMethod::BI_RLE4 |
+            CompressionMethod::BI_RLE8 => {
+                let bpp = if self.compression == CompressionMethod::BI_RLE4 { 4 } else { 8 };
+                if !self.has_compression_field() || self.bpp != bpp {
+                    return unsupported;
+                }
+            },
+            // These only exist on OS/2 where they collide with BI_JPEG and BI_BITFIELDS.
+            CompressionMethod::BI_RLE24 => {
+                if self.dib_header_size != DibHeaderSize::OS22XBITMAPHEADER64 || self.bpp != 24 {
+                    return unsupported;
+                }
+            },
+            CompressionMethod::BI_HUFFMAN1D => {
+                // The decoder only supports bottom-up images.
+                if self.dib_header_size != DibHeaderSize::OS22XBITMAPHEADER64 || self.bpp != 1 || self.topdown {
+                    return unsupported;
+                }
+            },
+            _ => return unsupported,
+        }
+        let max = if self.is_core() { i16::MAX as usize } else { i32::MAX as usize };
+        if width > max || height > max {
+            return Err(WriteError::ImageTooLarge{width, height});
+        }
+        Ok(())
+    }
+
+    fn header_bitmasks(&self) -> Option<Bitmasks> {
+        if self.bpp < 16 {
+            return None;
+        }
+        if self.is_bitfields() || self.has_masks_in_header() {
+            self.bitmasks.clone()
+        } else {
+            None
+        }
+    }
+
+    fn build_palette(&self, image: &Image) -> WriteResult<Vec<Color>> {
+        let palette = match &self.palette {
+            Some(palette) => palette.clone(),
+            None => {
+                let mut palette = vec!();
+                let mut seen = HashMap::new();
+                for c in image.colors() {
+                    let key = (c.red, c.green, c.blue);
+                    if let std::collections::hash_map::Entry::Vacant(e) = seen.entry(key) {
+                        e.insert(palette.len());
+                        palette.push(Color{ red: c.red, green: c.green, blue: c.blue, alpha: 255 });
+                    }
+                }
+                palette
+            },
+        };
+        if palette.len() > 1 << self.bpp {
+            return Err(WriteError::TooManyColors(palette.len()));
+        }
+        Ok(palette)
+    }
+
+    // Indexes row by row from the top, like Indexed::indexes.
+    fn palette_indexes(&self, image: &Image, palette: &[Color]) -> WriteResult<Vec<u8>> {
+        let mut lookup = HashMap::new();
+        for (i, c) in palette.iter().enumerate() {
+            lookup.entry((c.red, c.green, c.blue)).or_insert(i as u8);
+        }
+        image.colors().map(|c| {
+            lookup.get(&(c.red, c.green, c.blue)).copied().ok_or(WriteError::ColorNotInPalette(c))
+        }).collect()
+    }
+
+    // Returns the rows of the image in file order.
+    fn rows(&self, height: usize) -> Vec<usize> {
+        if self.topdown {
+            (0..height).collect()
+        } else {
+            (0..height).rev().collect()
+        }
+    }
+
+    fn encode_indexed(&self, indexes: &[u8], width: usize, height: usize) -> Vec<u8> {
+        let bpp = self.bpp as usize;
+        let ppb = 8 / bpp;
+        let mut data = vec!();
+        for y in self.rows(height) {
+            let start = data.len();
+            for chunk in indexes[y*width..(y+1)*width].chunks(ppb) {
+                let mut byte = 0_u8;
+                for (d, index) in chunk.iter().enumerate() {
+                    byte |= index << (8 - (d+1)*bpp);
+                }
+                data.push(byte);
+            }
+            while (data.len() - start) % 4 != 0 {
+                data.push(0);
+            }
+        }
+        data
+    }
+
+    fn encode_rgb(&self, image: &Image) -> Vec<u8> {
+        let bitmasks = self.header_bitmasks().unwrap_or_else(|| default_bitmasks(self.bpp));
+        let bytespp = self.bpp as usize / 8;
+        let mut data = vec!();
+        for y in self.rows(image.height) {
+            let start = data.len();
+            for x in 0..image.width {
+                let c = image.pixel(x, y);
+                let val = denormalize_to_mask(c.red, bitmasks.red_mask, bitmasks.red_shift) |
+                    denormalize_to_mask(c.green, bitmasks.green_mask, bitmasks.green_shift) |
+                    denormalize_to_mask(c.blue, bitmasks.blue_mask, bitmasks.blue_shift) |
+                    denormalize_to_mask(c.alpha, bitmasks.alpha_mask, bitmasks.alpha_shift);
+                data.extend_from_slice(&val.to_le_bytes()[..bytespp]);
+            }
+            while (data.len() - start) % 4 != 0 {
+                data.push(0);
+            }
+        }
+        data
+    }
+
+    // values are row by row from the top.
+    fn encode_rle(&self, values: &[u32], width: usize, height: usize) -> Vec<u8> {
+        let mut data = vec!();
+        let rows = self.rows(height);
+        for (n, y) in rows.iter().enumerate() {
+            self.encode_rle_row(&values[y*width..(y+1)*width], &mut data);
+            if n + 1 < rows.len() {
+                // End of line
+                data.extend_from_slice(&[0, 0]);
+            }
+        }
+        // End of image
+        data.extend_from_slice(&[0, 1]);
+        data
+    }
+
+    fn rle_absolute_cost(&self, length: usize) -> usize {
+        let bytes = match self.bpp {
+            4 => length.div_ceil(2),
+            8 => length,
+            _ => 3 * length,
+        };
+        2 + bytes + bytes % 2
+    }
+
+    // Longest run starting at the beginning of row that can be stored in encoded mode. In 4 bpp,
+    // encoded mode repeats two alternating indexes.
+    fn rle_encoded_length(&self, row: &[u32]) -> usize {
+        let period = if self.bpp == 4 { 2 } else { 1 };
+        let mut length = 0;
+        while length < row.len().min(255) && row[length] == row[length % period] {
+            length += 1;
+        }
+        length
+    }
+
+    // Splits the row between encoded and absolute runs, minimizing the size of the output.
+    fn encode_rle_row(&self, row: &[u32], data: &mut Vec<u8>) {
+        let encoded_cost = if self.bpp == 24 { 4 } else { 2 };
+        // For each prefix length: (cost, start of the last run, whether it's an absolute run)
+        let mut best = vec![(usize::MAX, 0, false); row.len() + 1];
+        best[0].0 = 0;
+        for start in 0..row.len() {
+            let cost = best[start].0;
+            for length in 1..=self.rle_encoded_length(&row[start..]) {
+                let end = start + length;
+                if cost + encoded_cost < best[end].0 {
+                    best[end] = (cost + encoded_cost, start, false);
+                }
+            }
+            // Absolute mode needs at least 3 pixels, lengths of 1 and 2 being escape codes.
+            for length in 3..=(row.len() - start).min(255) {
+                let end = start + length;
+                let absolute_cost = cost + self.rle_absolute_cost(length);
+                if absolute_cost < best[end].0 {
+                    best[end] = (absolute_cost, start, true);
+                }
+            }
+        }
+        let mut runs = vec!();
+        let mut end = row.len();
+        while end > 0 {
+            let (_, start, absolute) = best[end];
+            runs.push((start, end, absolute));
+            end = start;
+        }
+        for (start, end, absolute) in runs.into_iter().rev() {
+            let run = &row[start..end];
+            if absolute {
+                data.extend_from_slice(&[0, run.len() as u8]);
+                let begin = data.len();
+                match self.bpp {
+                    4 => {
+                        for pair in run.chunks(2) {
+                            data.push(((pair[0] << 4) | pair.get(1).copied().unwrap_or(0)) as u8);
+                        }
+                    },
+                    8 => data.extend(run.iter().map(|v| *v as u8)),
+                    _ => {
+                        for v in run.iter() {
+                            data.extend_from_slice(&v.to_le_bytes()[..3]);
+                        }
+                    },
+                }
+                if (data.len() - begin) % 2 == 1 {
+                    data.push(0);
+                }
+            } else {
+                data.push(run.len() as u8);
+                match self.bpp {
+                    4 => data.push(((run[0] << 4) | run.get(1).copied().unwrap_or(0)) as u8),
+                    8 => data.push(run[0] as u8),
+                    _ => data.extend_from_slice(&run[0].to_le_bytes()[..3]),
+                }
+            }
+        }
+    }
+
+    // CCITT Group 3 1-Dimensional encoding, see BmpReader::decode_huffman.
+    fn encode_huffman1d(&self, indexes: &[u8], width: usize, height: usize) -> Vec<u8> {
+        let codes = |color| {
+            huffman_table(&color).into_iter().filter_map(|(code, command)| {
+                match command {
+                    HuffmanCommand::Value(length) => Some((length, code)),
+                    HuffmanCommand::Eol => None,
+                }
+            }).collect::<HashMap<_, _>>()
+        };
+        let white_codes = codes(HuffmanColor::White);
+        let black_codes = codes(HuffmanColor::Black);
+        let eol = HuffmanCodeWord{length: 12, value: 0b000000000001};
+
+        let mut data = vec!();
+        let mut bit_writer = BitWriter::new(&mut data);
+        let mut put = |code: &HuffmanCodeWord| {
+            // Writing to a Vec can't fail.
+            bit_writer.write_bits(code.value, code.length).unwrap();
+        };
+        for y in self.rows(height) {
+            // Each line starts with an EOL and a (possibly empty) white run.
+            put(&eol);
+            let mut color = HuffmanColor::White;
+            let mut x = 0;
+            loop {
+                let mut length = 0;
+                while x < width && indexes[y*width + x] as usize == color.pal_entry() {
+                    length += 1;
+                    x += 1;
+                }
+                let codes = match color {
+                    HuffmanColor::White => &white_codes,
+                    HuffmanColor::Black => &black_codes,
+                };
+                while length > 2560 {
+                    put(&codes[&2560]);
+                    length -= 2560;
+                }
+                if length >= 64 {
+                    put(&codes[&(length / 64 * 64)]);
+                }
+                put(&codes[&(length % 64)]);
+                if x >= width {
+                    break;
+                }
+                color = color.next();
+            }
+        }
+        // RTC (Return to Control) is 6 EOLs.
+        for _ in 0..6 {
+            put(&eol);
+        }
+        bit_writer.flush().unwrap();
+        data
+    }
+
+    fn write_dib_header(&self, out: &mut Vec<u8>, width: usize, height: usize, palette_len: usize, image_size: u32) {
+        let height = if self.topdown { -(height as i32) } else { height as i32 };
+        push_u32(out, self.dib_header_size as u32);
+        if self.is_core() {
+            push_u16(out, width as u16);
+            push_u16(out, height as i16 as u16);
+        } else {
+            push_u32(out, width as u32);
+            push_u32(out, height as u32);
+        }
+        push_u16(out, 1);
+        push_u16(out, self.bpp);
+        if !self.has_compression_field() {
+            return;
+        }
+        push_u32(out, self.compression as u64 as u32);
+        push_u32(out, image_size);
+        push_u32(out, self.x_px_per_m);
+        push_u32(out, self.y_px_per_m);
+        push_u32(out, palette_len as u32);
+        push_u32(out, 0);
+        let bitmasks = self.header_bitmasks();
+        let masks = match &bitmasks {
+            Some(bm) => [bm.red_mask, bm.green_mask, bm.blue_mask, bm.alpha_mask],
+            None => [0; 4],
+        };
+        match self.dib_header_size {
+            DibHeaderSize::BITMAPINFOHEADER => {
+                // Masks immediately follow the header with BI_BITFIELDS.
+                if self.is_bitfields() {
+                    for m in masks.iter().take(3) {
+                        push_u32(out, *m);
+                    }
+                    if self.compression == CompressionMethod::BI_ALPHABITFIELDS {
+                        push_u32(out, masks[3]);
+                    }
+                }
+            },
+            DibHeaderSize::OS22XBITMAPHEADER64 => {
+                // Units, reserved, recording, rendering, size1, size2, color encoding, identifier
+                out.extend_from_slice(&[0; 24]);
+            },
+            _ => {
+                for m in masks.iter().take(3) {
+                    push_u32(out, *m);
+                }
+                if self.dib_header_size as u32 >= DibHeaderSize::BITMAPV3INFOHEADER as u32 {
+                    push_u32(out, masks[3]);
+                }
+                if self.dib_header_size as u32 >= DibHeaderSize::BITMAPV4HEADER as u32 {
+                    // Pixels are already in sRGB, so no endpoints nor gammas.
+                    push_u32(out, ColorSpaceType::LCS_sRGB as u32);
+                    out.extend_from_slice(&[0; 36]);
+                    out.extend_from_slice(&[0; 12]);
+                }
+                if self.dib_header_size as u32 >= DibHeaderSize::BITMAPV5HEADER as u32 {
+                    // Intent, ICC profile data, ICC profile size, reserved
+                    push_u32(out, IntentType::LCS_GM_IMAGES as u32);
+                    out.extend_from_slice(&[0; 12]);
+                }
+            },
+        }
+    }
+
+    pub fn write(&self, image: &Image) -> WriteResult<Vec<u8>> {
+        let (width, height) = (image.width, image.height);
+        self.check(width, height)?;
+
+        let mut palette = vec!();
+        let data = if self.bpp <= 8 {
+            palette = self.build_palette(image)?;
+            let indexes = self.palette_indexes(image, &palette)?;
+            if !self.has_compression_field() {
+                // There's no "colors in table" field, the palette must be complete.
+                palette.resize(1 << self.bpp, Color{ alpha: 255, ..Default::default() });
+            }
+            match self.compression {
+                CompressionMethod::BI_RLE4 |
+                CompressionMethod::BI_RLE8 => {
+                    let values = indexes.iter().map(|i| *i as u32).collect::<Vec<_>>();
+                    self.encode_rle(&values, width, height)
+                },
+                CompressionMethod::BI_HUFFMAN1D => self.encode_huffman1d(&indexes, width, height),
+                _ => self.encode_indexed(&indexes, width, height),
+            }
+        } else if self.compression == CompressionMethod::BI_RLE24 {
+            let values = image.colors().map(|p| (p.blue as u32) | (p.green as u32) << 8 | (p.red as u32) << 16).collect::<Vec<_>>();
+            self.encode_rle(&values, width, height)
+        } else {
+            self.encode_rgb(image)
+        };
+
+        // Sizes in the headers are 32 bits.
+        let too_large = || WriteError::ImageTooLarge{width, height};
+        let image_size = u32::try_from(data.len()).map_err(|_| too_large())?;
+        let mut dib = vec!();
+        self.write_dib_header(&mut dib, width, height, palette.len(), image_size);
+        for c in palette.iter() {
+            dib.extend_from_slice(&[c.blue, c.green, c.red]);
+            if !self.is_core() {
+                dib.push(0);
+            }
+        }
+
+        let offset = FILE_HEADER_SIZE + dib.len() as u32;
+        let filesize = offset.checked_add(image_size).ok_or_else(too_large)?;
+        let mut out = Vec::with_capacity(filesize as usize);
+        push_u16(&mut out, Magic::BM as u16);
+        push_u32(&mut out, filesize);
+        push_u32(&mut out, 0);
+        push_u32(&mut out, offset);
+        out.extend_from_slice(&dib);
+        out.extend_from_slice(&data);
+        Ok(out)
+    }
+}
+
+impl BmpFile {
+    // Builds a writer reproducing the layout of this file as closely as possible.
+    pub fn writer(&self) -> BmpWriter {
+        let palette = if self.palette.is_empty() || self.gammas.is_some() || self.endpoints.is_some() || self.icc_profile.is_some() {
+            // Pixels went through color management and don't match the palette anymore.
+            None
+        } else {
+            // Only the first 2^bpp colors can be used, files may have more.
+            Some(self.palette.iter().take(1 << self.bpp.min(8)).cloned().collect())
+        };
+        BmpWriter {
+            compression: self.compression.unwrap_or(CompressionMethod::BI_RGB),
+            palette,
+            bitmasks: self.bitmasks.clone(),
+            topdown: self.height < 0,
+            x_px_per_m: self.x_px_per_m,
+            y_px_per_m: self.y_px_per_m,
+            ..BmpWriter::new(self.dib_header_size, self.bpp)
+        }
+    }
+
+    pub fn write(&self) -> WriteResult<Vec<u8>> {
+        self.writer().write(&self.image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(width: usize, height: usize) -> Image {
+        Image::from_fn(width, height, |x, y| {
+            Color{ red: (x*37 + y*11) as u8, green: (x*5) as u8, blue: (y*13) as u8, alpha: 255 }
+        })
+    }
+
+    fn indexed(width: usize, height: usize, nb_colors: usize) -> Image {
+        Image::from_fn(width, height, |x, y| {
+            let i = ((x + 3*y) % nb_colors) as u8;
+            Color{ red: i.wrapping_mul(16), green: 255_u8.wrapping_sub(i.wrapping_mul(8)), blue: i, alpha: 255 }
+        })
+    }
+
+    fn roundtrip(writer: &BmpWriter, image: &Image) -> BmpFile {
+        let bytes = writer.write(image).unwrap();
+        let (_, bmp) = BmpFile::parse(&bytes).unwrap();
+        assert_eq!(bmp.filesize as usize, bytes.len());
+        bmp
+    }
+
+    #[test]
+    fn test_indexed_all_headers() {
+        let headers = [
+            DibHeaderSize::BITMAPCOREHEADER,
+            DibHeaderSize::OS22XBITMAPHEADER16,
+            DibHeaderSize::OS22XBITMAPHEADER64,
+            DibHeaderSize::BITMAPINFOHEADER,
+            DibHeaderSize::BITMAPV2INFOHEADER,
+            DibHeaderSize::BITMAPV3INFOHEADER,
+            DibHeaderSize::BITMAPV4HEADER,
+            DibHeaderSize::BITMAPV5HEADER,
+        ];
+        for header in headers.iter() {
+            for bpp in [1, 2, 4, 8].iter() {
+                let pixels = indexed(13, 7, 1 << bpp);
+                let bmp = roundtrip(&BmpWriter::new(*header, *bpp), &pixels);
+                assert_eq!(bmp.dib_header_size, *header);
+                assert_eq!(bmp.bpp, *bpp);
+                assert_eq!(bmp.image.data, pixels.data, "{:?} {}bpp", header, bpp);
+                assert!(bmp.image.indexed.is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn test_rgb_all_headers() {
+        let headers = [
+            DibHeaderSize::BITMAPCOREHEADER,
+            DibHeaderSize::BITMAPINFOHEADER,
+            DibHeaderSize::BITMAPV4HEADER,
+            DibHeaderSize::BITMAPV5HEADER,
+        ];
+        let pixels = gradient(9, 5);
+        for header in headers.iter() {
+            for bpp in [24, 32].iter() {
+                let bmp = roundtrip(&BmpWriter::new(*header, *bpp), &pixels);
+                assert_eq!(bmp.image.data, pixels.data, "{:?} {}bpp", header, bpp);
+            }
+        }
+    }
+
+    #[test]
+    fn test_16bpp_is_idempotent() {
+        let pixels = gradient(6, 6);
+        let writer = BmpWriter::new(DibHeaderSize::BITMAPINFOHEADER, 16);
+        let once = roundtrip(&writer, &pixels);
+        assert_ne!(once.image.data, pixels.data);
+        let twice = roundtrip(&writer, &once.image);
+        assert_eq!(twice.image, once.image);
+    }
+
+    #[test]
+    fn test_bitfields() {
+        let mut pixels = gradient(5, 4);
+        pixels.set_pixel(1, 2, Color{ alpha: 0, ..pixels.pixel(1, 2) });
+        pixels.set_pixel(3, 0, Color{ alpha: 128, ..pixels.pixel(3, 0) });
+        for header in [DibHeaderSize::BITMAPINFOHEADER, DibHeaderSize::BITMAPV4HEADER, DibHeaderSize::BITMAPV5HEADER].iter() {
+            let writer = BmpWriter {
+                compression: if *header == DibHeaderSize::BITMAPINFOHEADER { CompressionMethod::BI_ALPHABITFIELDS } else { CompressionMethod::BI_BITFIELDS },
+                bitmasks: Some(Bitmasks::from_masks(0x0000ff00, 0x00ff0000, 0xff000000, 0x000000ff)),
+                ..BmpWriter::new(*header, 32)
+            };
+            let bmp = roundtrip(&writer, &pixels);
+            assert_eq!(bmp.bitmasks, writer.bitmasks);
+            assert_eq!(bmp.image.data, pixels.data, "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn test_topdown() {
+        let pixels = gradient(3, 8);
+        let writer = BmpWriter { topdown: true, ..BmpWriter::new(DibHeaderSize::BITMAPINFOHEADER, 24) };
+        let bmp = roundtrip(&writer, &pixels);
+        assert_eq!(bmp.height, -8);
+        assert_eq!(bmp.image.data, pixels.data);
+    }
+
+    #[test]
+    fn test_rewrite_parsed_file() {
+        let pixels = indexed(10, 10, 16);
+        let bytes = BmpWriter::new(DibHeaderSize::BITMAPV4HEADER, 4).write(&pixels).unwrap();
+        let (_, bmp) = BmpFile::parse(&bytes).unwrap();
+        assert_eq!(bmp.write().unwrap(), bytes);
+    }
+
+    // Small deterministic generator to fuzz the decoders with our own encoder.
+    fn random_indexes(seed: u64, width: usize, height: usize, nb_colors: usize) -> Image {
+        let mut state = seed;
+        let mut current = 0;
+        Image::from_fn(width, height, |_, _| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            // Keep the same color most of the time to have runs to compress.
+            if (state >> 33) & 3 == 0 {
+                current = ((state >> 40) as usize) % nb_colors;
+            }
+            let i = current as u8;
+            Color{ red: i.wrapping_mul(16), green: 255_u8.wrapping_sub(i.wrapping_mul(8)), blue: i, alpha: 255 }
+        })
+    }
+
+    #[test]
+    fn test_rle_roundtrip() {
+        for seed in 0..20 {
+            let (width, height) = (1 + (seed as usize * 7) % 300, 1 + (seed as usize * 3) % 11);
+            let cases = [
+                (DibHeaderSize::BITMAPINFOHEADER, 4, CompressionMethod::BI_RLE4),
+                (DibHeaderSize::BITMAPV5HEADER, 8, CompressionMethod::BI_RLE8),
+                (DibHeaderSize::OS22XBITMAPHEADER64, 24, CompressionMethod::BI_RLE24),
+            ];
+            for (header, bpp, compression) in cases.iter() {
+                let pixels = random_indexes(seed, width, height, 1 << (*bpp).min(8));
+                let writer = BmpWriter { compression: *compression, ..BmpWriter::new(*header, *bpp) };
+                let bmp = roundtrip(&writer, &pixels);
+                assert_eq!(bmp.compression, Some(*compression));
+                assert_eq!(bmp.image.data, pixels.data, "seed {} {:?}", seed, compression);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rle8_picks_cheapest_runs() {
+        let writer = BmpWriter { compression: CompressionMethod::BI_RLE8, ..BmpWriter::new(DibHeaderSize::BITMAPINFOHEADER, 8) };
+        let mut data = vec!();
+        writer.encode_rle_row(&[1, 1, 1, 1, 1, 2, 3, 4, 5, 6, 6, 6], &mut data);
+        assert_eq!(data, vec!(5, 1, 0, 4, 2, 3, 4, 5, 3, 6));
+        let mut data = vec!();
+        writer.encode_rle_row(&[7, 8], &mut data);
+        assert_eq!(data, vec!(1, 7, 1, 8));
+        let mut data = vec!();
+        writer.encode_rle_row(&[9; 300], &mut data);
+        assert_eq!(data.len(), 4);
+    }
+
+    #[test]
+    fn test_rle4_alternating_runs() {
+        let writer = BmpWriter { compression: CompressionMethod::BI_RLE4, ..BmpWriter::new(DibHeaderSize::BITMAPINFOHEADER, 4) };
+        let mut data = vec!();
+        writer.encode_rle_row(&[1, 2, 1, 2, 1, 2, 1, 2], &mut data);
+        assert_eq!(data, vec!(8, 0x12));
+        let mut data = vec!();
+        writer.encode_rle_row(&[1, 2, 1, 2, 1, 2, 1, 3, 4, 5], &mut data);
+        assert_eq!(data.len(), 6);
+    }
+
+    #[test]
+    fn test_huffman1d_roundtrip() {
+        for seed in 0..10 {
+            let (width, height) = (1 + (seed as usize * 331) % 3000, 1 + seed as usize % 5);
+            let pixels = random_indexes(seed, width, height, 2);
+            let writer = BmpWriter {
+                compression: CompressionMethod::BI_HUFFMAN1D,
+                // Palette entry 0 is used for white runs, the decoder starting each line with one.
+                palette: Some(vec!(Color{ red: 0, green: 255, blue: 0, alpha: 255 }, Color{ red: 16, green: 247, blue: 1, alpha: 255 })),
+                ..BmpWriter::new(DibHeaderSize::OS22XBITMAPHEADER64, 1)
+            };
+            let bmp = roundtrip(&writer, &pixels);
+            assert_eq!(bmp.compression, Some(CompressionMethod::BI_HUFFMAN1D));
+            assert_eq!(bmp.image.data, pixels.data, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let pixels = indexed(8, 8, 16);
+        assert_eq!(BmpWriter::new(DibHeaderSize::BITMAPINFOHEADER, 1).write(&pixels),
+                   Err(WriteError::TooManyColors(16)));
+        assert_eq!(BmpWriter::new(DibHeaderSize::BITMAPINFOHEADER, 3).write(&pixels),
+                   Err(WriteError::UnsupportedBpp(3)));
+        let writer = BmpWriter { compression: CompressionMethod::BI_BITFIELDS, ..BmpWriter::new(DibHeaderSize::BITMAPINFOHEADER, 32) };
+        assert_eq!(writer.write(&pixels), Err(WriteError::MissingBitmasks));
+        let writer = BmpWriter { compression: CompressionMethod::BI_BITFIELDS, ..BmpWriter::new(DibHeaderSize::BITMAPCOREHEADER, 32) };
+        assert_eq!(writer.write(&pixels), Err(WriteError::UnsupportedCompression(CompressionMethod::BI_BITFIELDS, DibHeaderSize::BITMAPCOREHEADER)));
+        let writer = BmpWriter { compression: CompressionMethod::BI_RLE8, ..BmpWriter::new(DibHeaderSize::BITMAPINFOHEADER, 4) };
+        assert_eq!(writer.write(&pixels), Err(WriteError::UnsupportedCompression(CompressionMethod::BI_RLE8, DibHeaderSize::BITMAPINFOHEADER)));
+        let writer = BmpWriter { compression: CompressionMethod::BI_RLE24, ..BmpWriter::new(DibHeaderSize::BITMAPINFOHEADER, 24) };
+        assert_eq!(writer.write(&pixels), Err(WriteError::UnsupportedCompression(CompressionMethod::BI_RLE24, DibHeaderSize::BITMAPINFOHEADER)));
+        let writer = BmpWriter { palette: Some(vec!(Color::default())), ..BmpWriter::new(DibHeaderSize::BITMAPINFOHEADER, 8) };
+        assert!(matches!(writer.write(&pixels), Err(WriteError::ColorNotInPalette(_))));
+        // A given palette must fit in the bits of the indexes too.
+        let writer = BmpWriter { palette: Some(vec!(Color::default(); 17)), ..BmpWriter::new(DibHeaderSize::BITMAPINFOHEADER, 4) };
+        assert_eq!(writer.write(&pixels), Err(WriteError::TooManyColors(17)));
+    }
+
+    #[test]
+    fn test_rewrite_oversized_palette() {
+        // The colors past 2^bpp aren't written back.
+        let pixels = indexed(4, 4, 4);
+        let writer = BmpWriter { palette: Some((0..4).map(|x| pixels.pixel(x, 0)).collect()), ..BmpWriter::new(DibHeaderSize::BITMAPINFOHEADER, 2) };
+        let bytes = writer.write(&pixels).unwrap();
+        let (_, mut bmp) = BmpFile::parse(&bytes).unwrap();
+        bmp.palette.push(Color::default());
+        assert_eq!(bmp.write().unwrap(), bytes);
+    }
+}
@@ -4,13 +4,16 @@ use std::fs;
 use sdl2::event::Event;
 use sdl2::gfx::primitives::DrawRenderer;
 use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
+use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::rect::{Point,Rect};
 use sdl2::render::Canvas;
+use sdl2::surface::Surface;
 use sdl2::video::Window;
 
+mod raster;
 mod ttf;
 
+use raster::Bitmap;
 use ttf::*;
 
 #[derive(FromArgs)]
@@ -25,6 +28,8 @@ struct Args {
 enum Subcommand {
     Info(TtfInfo),
     Display(TtfDisplay),
+    Render(TtfRender),
+    Validate(TtfValidate),
 }
 
 impl Subcommand {
@@ -32,6 +37,8 @@ impl Subcommand {
         match self {
             Subcommand::Info(x) => x.run(),
             Subcommand::Display(x) => x.run(),
+            Subcommand::Render(x) => x.run(),
+            Subcommand::Validate(x) => x.run(),
         }
     }
 }
@@ -42,6 +49,9 @@ impl Subcommand {
 pub struct TtfInfo {
   #[argh(positional)]
   input_path: PathBuf,
+  /// print all the parsed tables instead of a summary
+  #[argh(switch)]
+  dump: bool,
 }
 
 impl TtfInfo {
@@ -50,11 +60,20 @@ impl TtfInfo {
         let input = fs::read(input_path);
         match input {
             Ok(inp) => {
-                let file = TtfFile::parse(&inp);
+                let file = TtfFile::parse_collection(&inp);
                 match file {
-                    Ok((_, ttf)) => {
+                    Ok((_, fonts)) => {
                         println!("{:?}", input_path);
-                        println!("{:#?}", ttf);
+                        for (k, ttf) in fonts.iter().enumerate() {
+                            if fonts.len() > 1 {
+                                println!("Font {} of {}", k, fonts.len());
+                            }
+                            if self.dump {
+                                println!("{:#?}", ttf);
+                            } else {
+                                print!("{}", ttf.report());
+                            }
+                        }
                     },
                     Err(e) => println!("Couldn't parse {:?}: {}", input_path, e),
                 }
@@ -64,12 +83,122 @@ impl TtfInfo {
     }
 }
 
+#[derive(FromArgs)]
+#[argh(subcommand, name = "render")]
+/// Render text with a TTF file to a PGM image
+pub struct TtfRender {
+  #[argh(positional)]
+  input_path: PathBuf,
+  #[argh(positional)]
+  output_path: PathBuf,
+  /// text to render, \n separating lines
+  #[argh(option, default = "String::from(\"The quick brown fox jumps over the lazy dog\")")]
+  text: String,
+  /// size of the font in pixels per em
+  #[argh(option, default = "64")]
+  size: u32,
+  /// grid-fit glyphs with the instructions of the font
+  #[argh(switch)]
+  hinting: bool,
+  /// index of the font in a collection (.ttc)
+  #[argh(option, default = "0")]
+  font: usize,
+}
+
+impl TtfRender {
+    fn run(self) {
+        let input_path = self.input_path.as_path();
+        let input = fs::read(input_path);
+        match input {
+            Ok(inp) => {
+                let file = TtfFile::parse_collection(&inp);
+                match file {
+                    Ok((_, mut fonts)) if self.font < fonts.len() => {
+                        let ttf = fonts.swap_remove(self.font);
+                        let text = self.text.replace("\\n", "\n");
+                        let mut hinter = if self.hinting { load_hinter(&ttf, self.size) } else { None };
+                        match ttf.render(&text, self.size as f64, hinter.as_mut()) {
+                            Some(bitmap) => match fs::write(&self.output_path, bitmap.to_pgm()) {
+                                Ok(()) => println!("Wrote {}x{} image to {:?}", bitmap.width, bitmap.height, self.output_path),
+                                Err(e) => println!("Couldn't write {:?}: {}", self.output_path, e),
+                            },
+                            None => println!("Couldn't render with {:?}: missing head or hhea table", input_path),
+                        }
+                    },
+                    Ok((_, fonts)) => println!("Couldn't find font {} in {:?}, which has {}", self.font, input_path, fonts.len()),
+                    Err(e) => println!("Couldn't parse {:?}: {}", input_path, e),
+                }
+            }
+            Err(e) => println!("Couldn't open {:?}: {}", input_path, e),
+        }
+    }
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "validate")]
+/// Check the checksums, tables and glyphs of a TTF file
+pub struct TtfValidate {
+  #[argh(positional)]
+  input_path: PathBuf,
+}
+
+impl TtfValidate {
+    fn run(self) {
+        let input_path = self.input_path.as_path();
+        let input = fs::read(input_path);
+        match input {
+            Ok(inp) => {
+                println!("{:?}", input_path);
+                let fonts = ttf::validate(&inp);
+                for (k, problems) in fonts.iter().enumerate() {
+                    if fonts.len() > 1 {
+                        println!("Font {} of {}", k, fonts.len());
+                    }
+                    if problems.is_empty() {
+                        println!("No problems found");
+                    }
+                    for problem in problems.iter() {
+                        println!("{}", problem);
+                    }
+                }
+            }
+            Err(e) => println!("Couldn't open {:?}: {}", input_path, e),
+        }
+    }
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand, name = "display")]
-/// Display glyphs from a TTF file
+/// Display text with a TTF file, typing changes it
 pub struct TtfDisplay {
   #[argh(positional)]
   input_path: PathBuf,
+  /// text to display
+  #[argh(option, default = "String::from(\"The quick brown fox jumps over the lazy dog\")")]
+  text: String,
+  /// size of the font in pixels per em, up and down keys change it
+  #[argh(option, default = "64")]
+  size: u32,
+  /// show outlines with their on curve and off curve points
+  #[argh(switch)]
+  points: bool,
+  /// grid-fit glyphs with the instructions of the font
+  #[argh(switch)]
+  hinting: bool,
+  /// index of the font in a collection (.ttc)
+  #[argh(option, default = "0")]
+  font: usize,
+}
+
+// Hinter for a size, None if the font programs fail.
+fn load_hinter(ttf: &TtfFile, size: u32) -> Option<Hinter<'_>> {
+    match Hinter::new(ttf, size.min(u16::MAX as u32) as u16) {
+        Ok(hinter) => Some(hinter),
+        Err(e) => {
+            println!("Couldn't hint at {} px per em: {}", size, e);
+            None
+        },
+    }
 }
 
 const SCREEN_WIDTH : u32 = 800;
@@ -92,8 +221,7 @@ impl TtfDisplay {
         let (y1, y2, y3) = (p1.y as f64, p2.y as f64, p3.y as f64);
         let mut t = 0.0;
         let mut dt = 0.1;
-        let mut first = true;
-        let (mut px, mut py) = (0.0, 0.0);
+        let (mut px, mut py);
         let (mut x, mut y);
         loop {
             if t > 1.0 {
@@ -121,106 +249,179 @@ impl TtfDisplay {
         }
     }
 
-    // TODO: Draw bezier curves. For points x1, x2 and x3, the curve is for t in [0,1]:
-    // (x3 - 2*x2 + x1)*t^2 + 2*(x2 - x3)*t + x3
-    fn draw_glyph_on_canvas(&self, info: &str, glyph: &Glyph, canvas: &mut Canvas<Window>) {
-        let sdl_ttf_context = sdl2::ttf::init().unwrap();
-        let font = sdl_ttf_context.load_font("./resources/DejaVuSans.ttf", 20).unwrap();
-        let text_color = Color::RGB(255, 255, 255);
+    fn cubic(&self, a1: f64, a2: f64, a3: f64, a4: f64, t: f64) -> f64 {
+        let u = 1.0 - t;
+        u*u*u*a1 + 3.0*u*u*t*a2 + 3.0*u*t*t*a3 + t*t*t*a4
+    }
+
+    fn draw_bezier_cubic(&self, p1: &Point, p2: &Point, p3: &Point, p4: &Point, color: Color, canvas: &mut Canvas<Window>) {
+        let (x1, x2, x3, x4) = (p1.x as f64, p2.x as f64, p3.x as f64, p4.x as f64);
+        let (y1, y2, y3, y4) = (p1.y as f64, p2.y as f64, p3.y as f64, p4.y as f64);
+        let mut t: f64 = 0.0;
+        let mut dt = 0.1;
+        loop {
+            let (px, py) = (self.cubic(x1, x2, x3, x4, t), self.cubic(y1, y2, y3, y4, t));
+            canvas.pixel(px as i16, py as i16, color).unwrap();
+            if t >= 1.0 {
+                break;
+            }
+            // Reduce the value of dt if it's too big to have adjacent pixels
+            while dt > 1e-4 {
+                let new_t = (t + dt).min(1.0);
+                let (x, y) = (self.cubic(x1, x2, x3, x4, new_t), self.cubic(y1, y2, y3, y4, new_t));
+                if (x - px).abs() + (y - py).abs() <= 1.0 {
+                    break;
+                }
+                dt /= 2.0;
+            }
+            t = (t + dt).min(1.0);
+        }
+    }
+
+    // Draws the outline of a glyph with its origin at (x, baseline), scale being in pixels per FUnit.
+    fn draw_glyph_on_canvas(&self, contours: &[Contour], x: i32, baseline: i32, scale: f64, canvas: &mut Canvas<Window>) {
         let oncurve_color = Color::RGB(255, 0, 0);
         let offcurve_color = Color::RGB(0, 0, 255);
         let first_color = Color::RGB(255, 255, 0);
         let line_color = Color::RGB(128, 128, 128);
+        let to_screen = |p: &ttf::Point| (x + (p.x as f64 * scale).round() as i32, baseline - (p.y as f64 * scale).round() as i32);
 
-        // Testing Bezier curves
-        /*
-        let x1 = 100;
-        let y1 = 100;
-        let x2 = 100;
-        let y2 = 500;
-        let x3 = 500;
-        let y3 = 300;
-        canvas.set_draw_color(oncurve_color);
-        canvas.fill_rect(Rect::new(x1, y1, 4, 4)).unwrap();
-        canvas.fill_rect(Rect::new(x2, y2, 4, 4)).unwrap();
-        canvas.fill_rect(Rect::new(x3, y3, 4, 4)).unwrap();
-        self.draw_bezier_quadratic(&Point::new(x1, y1), &Point::new(x2, y2), &Point::new(x3, y3), line_color, canvas);
-        return;
-        */
-
-        match &glyph.glyph_data {
-            GlyphData::SimpleGlyph{xs, ys, contours, ..} => {
-                let x_min = xs.iter().min().unwrap();
-                let x_max = xs.iter().max().unwrap();
-                let y_min = ys.iter().min().unwrap();
-                let y_max = ys.iter().max().unwrap();
-                for c in contours.iter() {
-                    let mut ppx = 0;
-                    let mut ppy = 0;
-                    let mut pppx = 0;
-                    let mut pppy = 0;
-                    let mut bezier = false;
-                    let mut s = 0;
-                    let mut on_curve = true;
-                    for p in c.points.iter() {
-                        let px = MARGIN as i32 + (SCREEN_WIDTH - 2*MARGIN) as i32 * (p.x - x_min) / (x_max - x_min);
-                        let py = MARGIN as i32 + (SCREEN_HEIGHT - 2*MARGIN) as i32 * (y_max - p.y) / (y_max - y_min);
-                        if p.on_curve {
-                            canvas.set_draw_color(oncurve_color);
-                        } else {
-                            canvas.set_draw_color(offcurve_color);
-                        }
-                        if s == 0 {
-                            canvas.set_draw_color(first_color);
-                        }
-                        canvas.fill_rect(Rect::new(px - PT_SIZE as i32/2, py - PT_SIZE as i32/2, PT_SIZE, PT_SIZE)).unwrap();
-                        if s == 0 {
-                            s = 1;
-                        } else if s == 1 {
-                            if p.on_curve {
-                                canvas.set_draw_color(line_color);
-                                canvas.draw_line(Point::new(ppx, ppy), Point::new(px, py)).unwrap();
-                            } else {
-                                s = 2;
-                            }
-                        } else if s == 2 {
-                            if p.on_curve {
-                                self.draw_bezier_quadratic(&Point::new(pppx, pppy), &Point::new(ppx, ppy), &Point::new(px, py), line_color, canvas);
-                                s = 1;
-                            } else {
-                                let nx = (ppx + px) / 2;
-                                let ny = (ppy + py) / 2;
-                                self.draw_bezier_quadratic(&Point::new(pppx, pppy), &Point::new(ppx, ppy), &Point::new(nx, ny), line_color, canvas);
-                                ppx = nx;
-                                ppy = ny;
-                            }
-                        }
-                        pppx = ppx;
-                        pppy = ppy;
-                        ppx = px;
-                        ppy = py;
-                        on_curve = p.on_curve;
+        for c in contours.iter() {
+            // Off curve points of cubic contours come in pairs, the control points of a curve.
+            if c.cubic {
+                let points = c.points.iter().map(|p| (to_screen(p), p.on_curve)).collect::<Vec<_>>();
+                for (k, ((px, py), on_curve)) in points.iter().enumerate() {
+                    canvas.set_draw_color(if k == 0 { first_color } else if *on_curve { oncurve_color } else { offcurve_color });
+                    canvas.fill_rect(Rect::new(px - PT_SIZE as i32/2, py - PT_SIZE as i32/2, PT_SIZE, PT_SIZE)).unwrap();
+                }
+                canvas.set_draw_color(line_color);
+                let point = |k: usize| Point::new(points[k % points.len()].0.0, points[k % points.len()].0.1);
+                let mut k = 0;
+                while k < points.len() {
+                    if points[(k + 1) % points.len()].1 {
+                        canvas.draw_line(point(k), point(k + 1)).unwrap();
+                        k += 1;
+                    } else {
+                        self.draw_bezier_cubic(&point(k), &point(k + 1), &point(k + 2), &point(k + 3), line_color, canvas);
+                        k += 3;
                     }
-                    // Close the contour
-                    canvas.set_draw_color(line_color);
-                    if let Some(p) = c.points.first() {
-                        let px = MARGIN as i32 + (SCREEN_WIDTH - 2*MARGIN) as i32 * (p.x - x_min) / (x_max - x_min);
-                        let py = MARGIN as i32 + (SCREEN_HEIGHT - 2*MARGIN) as i32 * (y_max - p.y) / (y_max - y_min);
-                        if on_curve {
-                            canvas.draw_line(Point::new(ppx, ppy), Point::new(px, py)).unwrap();
-                        } else {
-                            self.draw_bezier_quadratic(&Point::new(pppx, pppy), &Point::new(ppx, ppy), &Point::new(px, py), line_color, canvas);
-                        }
+                }
+                continue;
+            }
+            let mut ppx = 0;
+            let mut ppy = 0;
+            let mut pppx = 0;
+            let mut pppy = 0;
+            let mut s = 0;
+            let mut on_curve = true;
+            for p in c.points.iter() {
+                let (px, py) = to_screen(p);
+                if s == 0 {
+                    canvas.set_draw_color(first_color);
+                } else if p.on_curve {
+                    canvas.set_draw_color(oncurve_color);
+                } else {
+                    canvas.set_draw_color(offcurve_color);
+                }
+                canvas.fill_rect(Rect::new(px - PT_SIZE as i32/2, py - PT_SIZE as i32/2, PT_SIZE, PT_SIZE)).unwrap();
+                if s == 0 {
+                    s = 1;
+                } else if s == 1 {
+                    if p.on_curve {
+                        canvas.set_draw_color(line_color);
+                        canvas.draw_line(Point::new(ppx, ppy), Point::new(px, py)).unwrap();
+                    } else {
+                        s = 2;
+                    }
+                } else if s == 2 {
+                    if p.on_curve {
+                        self.draw_bezier_quadratic(&Point::new(pppx, pppy), &Point::new(ppx, ppy), &Point::new(px, py), line_color, canvas);
+                        s = 1;
+                    } else {
+                        let nx = (ppx + px) / 2;
+                        let ny = (ppy + py) / 2;
+                        self.draw_bezier_quadratic(&Point::new(pppx, pppy), &Point::new(ppx, ppy), &Point::new(nx, ny), line_color, canvas);
+                        ppx = nx;
+                        ppy = ny;
                     }
                 }
+                pppx = ppx;
+                pppy = ppy;
+                ppx = px;
+                ppy = py;
+                on_curve = p.on_curve;
             }
-            _ => {},
-        };
-        let help = font.render(&format!("{}, {} contours", info, glyph.nb_contours)).solid(text_color).unwrap();
-        let r = help.rect();
+            // Close the contour
+            canvas.set_draw_color(line_color);
+            if let Some(p) = c.points.first() {
+                let (px, py) = to_screen(p);
+                if on_curve {
+                    canvas.draw_line(Point::new(ppx, ppy), Point::new(px, py)).unwrap();
+                } else {
+                    self.draw_bezier_quadratic(&Point::new(pppx, pppy), &Point::new(ppx, ppy), &Point::new(px, py), line_color, canvas);
+                }
+            }
+        }
+    }
+
+    // Copies the coverage of a window sized bitmap, in white on black.
+    fn draw_bitmap_on_canvas(&self, bitmap: &Bitmap, canvas: &mut Canvas<Window>) {
+        let mut surface = Surface::new(bitmap.width as u32, bitmap.height as u32, PixelFormatEnum::RGB24).unwrap();
+        let pitch = surface.pitch() as usize;
+        surface.with_lock_mut(|pixels| {
+            for (y, row) in bitmap.data.chunks(bitmap.width).enumerate() {
+                for (x, coverage) in row.iter().enumerate() {
+                    pixels[y * pitch + 3 * x..y * pitch + 3 * x + 3].fill(*coverage);
+                }
+            }
+        });
         let texture_creator = canvas.texture_creator();
-        let help = texture_creator.create_texture_from_surface(help).unwrap();
-        canvas.copy(&help, None, r).expect("Rendering text failed");
+        let texture = texture_creator.create_texture_from_surface(surface).unwrap();
+        canvas.copy(&texture, None, None).expect("Rendering glyphs failed");
+    }
+
+    // Lays text out from the top left of the window, wrapping lines at the right margin. Glyphs
+    // are grid-fitted with hinter, which must be for that size.
+    fn draw_text_on_canvas(&self, ttf: &TtfFile, text: &str, size: u32, mut hinter: Option<&mut Hinter>, canvas: &mut Canvas<Window>) {
+        let units_per_em = ttf.units_per_em().unwrap_or(2048) as f64;
+        let scale = size as f64 / units_per_em;
+        let line_height = (ttf.line_height().unwrap_or(units_per_em as i32 * 6 / 5) as f64 * scale).round() as i32;
+        let mut baseline = MARGIN as i32 + size as i32;
+        // Origins of the glyphs in the window.
+        let mut origins = vec!();
+        for line in text.split('\n') {
+            // Position in the laid out line of the start of the current window line.
+            let mut start = 0.0;
+            for glyph in ttf.layout(line, size as f64) {
+                if glyph.x + glyph.advance - start > (SCREEN_WIDTH - 2 * MARGIN) as f64 && glyph.x > start {
+                    start = glyph.x;
+                    baseline += line_height;
+                }
+                let x = MARGIN as i32 + (glyph.x - start).round() as i32;
+                origins.push((glyph.index, x, baseline - glyph.y.round() as i32));
+            }
+            baseline += line_height;
+        }
+
+        // Hinted outlines are in 26.6 pixels.
+        let (outlines, scale) = match hinter.as_mut() {
+            Some(hinter) => (origins.iter().map(|(index, _, _)| hinter.glyph_contours(*index)).collect::<Vec<_>>(), 1.0 / 64.0),
+            None => (origins.iter().map(|(index, _, _)| ttf.glyph_contours(*index)).collect(), scale),
+        };
+        let mut bitmap = Bitmap::new(SCREEN_WIDTH as usize, SCREEN_HEIGHT as usize);
+        for ((_, x, y), contours) in origins.iter().zip(outlines.iter()) {
+            if let Some(glyph_bitmap) = contours.as_ref().and_then(|contours| raster::rasterize(contours, scale)) {
+                bitmap.draw(&glyph_bitmap, *x, *y);
+            }
+        }
+        self.draw_bitmap_on_canvas(&bitmap, canvas);
+        if self.points {
+            for ((_, x, y), contours) in origins.iter().zip(outlines.iter()) {
+                if let Some(contours) = contours {
+                    self.draw_glyph_on_canvas(contours, *x, *y, scale, canvas);
+                }
+            }
+        }
     }
 
     fn run(self) {
@@ -232,19 +433,22 @@ impl TtfDisplay {
             .build()
             .unwrap();
         let mut canvas = window.into_canvas().build().unwrap();
+        let sdl_ttf_context = sdl2::ttf::init().unwrap();
+        let font = sdl_ttf_context.load_font("./resources/DejaVuSans.ttf", 20).unwrap();
+        let text_color = Color::RGB(255, 255, 255);
 
         let input_path = self.input_path.as_path();
         let input = fs::read(input_path);
         match input {
             Ok(inp) => {
-                let file = TtfFile::parse(&inp);
+                let file = TtfFile::parse_collection(&inp);
                 match file {
-                    Ok((_, ttf)) => {
+                    Ok((_, mut fonts)) if self.font < fonts.len() => {
+                        let ttf = fonts.swap_remove(self.font);
                         println!("{:?}", input_path);
-                        //println!("{:#?}", ttf);
-                        let mut glyph_keys = ttf.glyph_table.as_ref().unwrap().glyphs.keys().collect::<Vec<_>>();
-                        glyph_keys.sort();
-                        let mut idx = 0;
+                        let mut text = self.text.clone();
+                        let mut size = self.size;
+                        let mut hinter = if self.hinting { load_hinter(&ttf, size) } else { None };
                         'display_loop: loop {
                             for event in event_pump.poll_iter() {
                                 match event {
@@ -252,15 +456,22 @@ impl TtfDisplay {
                                         Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
                                             break 'display_loop
                                         },
-                                        Event::KeyDown { keycode: Some(Keycode::N), .. } => {
-                                            if idx < (glyph_keys.len() - 1) as u32 {
-                                                idx = idx + 1;
-                                            }
+                                        Event::KeyDown { keycode: Some(Keycode::Backspace), .. } => {
+                                            text.pop();
+                                        },
+                                        Event::KeyDown { keycode: Some(Keycode::Return), .. } => {
+                                            text.push('\n');
+                                        },
+                                        Event::KeyDown { keycode: Some(Keycode::Up), .. } => {
+                                            size += size / 8 + 1;
+                                            hinter = if self.hinting { load_hinter(&ttf, size) } else { None };
+                                        },
+                                        Event::KeyDown { keycode: Some(Keycode::Down), .. } if size > 8 => {
+                                            size -= size / 8 + 1;
+                                            hinter = if self.hinting { load_hinter(&ttf, size) } else { None };
                                         },
-                                        Event::KeyDown { keycode: Some(Keycode::P), .. } => {
-                                            if idx > 0 {
-                                                idx = idx - 1;
-                                            }
+                                        Event::TextInput { text: typed, .. } => {
+                                            text.push_str(&typed);
                                         },
                                         _ => {}
                                 }
@@ -269,13 +480,16 @@ impl TtfDisplay {
                                 let bg_color = Color::RGB(0, 0, 0);
                                 self.fill_background(&mut canvas, bg_color);
                             }
-                            let key = glyph_keys[idx as usize];
-                            let glyph = &ttf.glyph_table.as_ref().unwrap().glyphs[key];
-                            let info = format!("{} - {}", key, idx);
-                            self.draw_glyph_on_canvas(&info, glyph, &mut canvas);
+                            self.draw_text_on_canvas(&ttf, &text, size, hinter.as_mut(), &mut canvas);
+                            let help = font.render(&format!("{} px per em", size)).solid(text_color).unwrap();
+                            let r = help.rect();
+                            let texture_creator = canvas.texture_creator();
+                            let help = texture_creator.create_texture_from_surface(help).unwrap();
+                            canvas.copy(&help, None, r).expect("Rendering text failed");
                             canvas.present();
                         }
                     },
+                    Ok((_, fonts)) => println!("Couldn't find font {} in {:?}, which has {}", self.font, input_path, fonts.len()),
                     Err(e) => println!("Couldn't parse {:?}: {}", input_path, e),
                 }
             }
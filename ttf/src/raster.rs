@@ -0,0 +1,418 @@
+// Scanline rasterizer turning glyph outlines into anti-aliased coverage bitmaps.
+//
+// Quadratic and cubic curves are flattened into lines, then each pixel row is sampled by SUBSAMPLES
+// scanlines. Along a scanline, spans with a non-zero winding number are covered, partially
+// covered pixels at their ends getting the covered fraction of their width.
+use crate::ttf::{Contour, Glyph, GlyphData, Hinter, TtfFile};
+
+const SUBSAMPLES: usize = 16;
+// Maximum distance in pixels between a curve and the lines replacing it.
+const TOLERANCE: f64 = 0.1;
+// Largest width or height of a glyph bitmap, so that corrupted outlines or huge sizes don't
+// exhaust the memory.
+const MAX_DIMENSION: usize = 1 << 13;
+
+// Coverage of pixels, 0 for none and 255 for full.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bitmap {
+    pub width: usize,
+    pub height: usize,
+    // Position of the top left pixel relative to the origin of the glyph, y going down.
+    pub left: i32,
+    pub top: i32,
+    pub data: Vec<u8>,
+}
+
+// In pixels, y going down.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Line {
+    x0: f64,
+    y0: f64,
+    x1: f64,
+    y1: f64,
+}
+
+impl Bitmap {
+    pub fn new(width: usize, height: usize) -> Bitmap {
+        Bitmap{ width, height, left: 0, top: 0, data: vec![0; width * height] }
+    }
+
+    // Adds the coverage of glyph with its origin at (x, y), clipping what's outside.
+    pub fn draw(&mut self, glyph: &Bitmap, x: i32, y: i32) {
+        for row in 0..glyph.height {
+            let ty = y + glyph.top + row as i32;
+            if ty < 0 || ty >= self.height as i32 {
+                continue;
+            }
+            for column in 0..glyph.width {
+                let tx = x + glyph.left + column as i32;
+                if tx < 0 || tx >= self.width as i32 {
+                    continue;
+                }
+                let pixel = &mut self.data[ty as usize * self.width + tx as usize];
+                *pixel = pixel.saturating_add(glyph.data[row * glyph.width + column]);
+            }
+        }
+    }
+
+    // Binary PGM, black on white.
+    pub fn to_pgm(&self) -> Vec<u8> {
+        let mut output = format!("P5\n{} {}\n255\n", self.width, self.height).into_bytes();
+        output.extend(self.data.iter().map(|c| 255 - c));
+        output
+    }
+}
+
+fn flatten_quadratic(lines: &mut Vec<Line>, from: (f64, f64), control: (f64, f64), to: (f64, f64)) {
+    // The distance to the curve of n lines is at most |from - 2 * control + to| / (4 * n^2).
+    let (ddx, ddy) = (from.0 - 2.0 * control.0 + to.0, from.1 - 2.0 * control.1 + to.1);
+    let n = ((ddx.hypot(ddy) / (4.0 * TOLERANCE)).sqrt().ceil() as usize).max(1);
+    let mut previous = from;
+    for step in 1..=n {
+        let t = step as f64 / n as f64;
+        let u = 1.0 - t;
+        let point = (
+            u * u * from.0 + 2.0 * u * t * control.0 + t * t * to.0,
+            u * u * from.1 + 2.0 * u * t * control.1 + t * t * to.1,
+        );
+        lines.push(Line{ x0: previous.0, y0: previous.1, x1: point.0, y1: point.1 });
+        previous = point;
+    }
+}
+
+fn flatten_cubic(lines: &mut Vec<Line>, from: (f64, f64), c1: (f64, f64), c2: (f64, f64), to: (f64, f64)) {
+    // The distance to the curve of n lines is at most 3 * max |second differences| / (4 * n^2).
+    let dd = |a: (f64, f64), b: (f64, f64), c: (f64, f64)| (a.0 - 2.0 * b.0 + c.0).hypot(a.1 - 2.0 * b.1 + c.1);
+    let max_dd = dd(from, c1, c2).max(dd(c1, c2, to));
+    let n = ((3.0 * max_dd / (4.0 * TOLERANCE)).sqrt().ceil() as usize).max(1);
+    let mut previous = from;
+    for step in 1..=n {
+        let t = step as f64 / n as f64;
+        let u = 1.0 - t;
+        let (a, b, c, d) = (u * u * u, 3.0 * u * u * t, 3.0 * u * t * t, t * t * t);
+        let point = (
+            a * from.0 + b * c1.0 + c * c2.0 + d * to.0,
+            a * from.1 + b * c1.1 + c * c2.1 + d * to.1,
+        );
+        lines.push(Line{ x0: previous.0, y0: previous.1, x1: point.0, y1: point.1 });
+        previous = point;
+    }
+}
+
+// Cubic contours start on curve, their off curve points coming in pairs.
+fn flatten_cubic_contour(lines: &mut Vec<Line>, points: &[((f64, f64), bool)]) {
+    let n = points.len();
+    let mut k = 0;
+    while k < n {
+        let from = points[k].0;
+        if points[(k + 1) % n].1 {
+            let to = points[(k + 1) % n].0;
+            lines.push(Line{ x0: from.0, y0: from.1, x1: to.0, y1: to.1 });
+            k += 1;
+        } else {
+            flatten_cubic(lines, from, points[(k + 1) % n].0, points[(k + 2) % n].0, points[(k + 3) % n].0);
+            k += 3;
+        }
+    }
+}
+
+// Two consecutive off curve points of quadratic contours have an implied on curve point in the
+// middle.
+fn flatten(contours: &[Contour], scale: f64) -> Vec<Line> {
+    let mut lines = vec!();
+    for contour in contours.iter() {
+        let points = contour.points.iter().map(|p| ((p.x as f64 * scale, -p.y as f64 * scale), p.on_curve)).collect::<Vec<_>>();
+        let n = points.len();
+        if n == 0 {
+            continue;
+        }
+        if contour.cubic {
+            flatten_cubic_contour(&mut lines, &points);
+            continue;
+        }
+        // Start on a point of the curve, which may be implied.
+        let (start, first) = match points.iter().position(|(_, on_curve)| *on_curve) {
+            Some(first) => (points[first].0, first),
+            None => {
+                let (a, b) = (points[0].0, points[1 % n].0);
+                (((a.0 + b.0) / 2.0, (a.1 + b.1) / 2.0), 0)
+            },
+        };
+        let mut current = start;
+        let mut control: Option<(f64, f64)> = None;
+        for k in 1..=n {
+            let (point, on_curve) = points[(first + k) % n];
+            match (on_curve, control) {
+                (true, None) => lines.push(Line{ x0: current.0, y0: current.1, x1: point.0, y1: point.1 }),
+                (true, Some(c)) => flatten_quadratic(&mut lines, current, c, point),
+                (false, None) => {
+                    control = Some(point);
+                    continue;
+                },
+                (false, Some(c)) => {
+                    let middle = ((c.0 + point.0) / 2.0, (c.1 + point.1) / 2.0);
+                    flatten_quadratic(&mut lines, current, c, middle);
+                    current = middle;
+                    control = Some(point);
+                    continue;
+                },
+            }
+            current = point;
+            control = None;
+        }
+        // Back to the start, from the last off curve point if the start was implied.
+        if let Some(c) = control {
+            flatten_quadratic(&mut lines, current, c, start);
+        } else if current != start {
+            lines.push(Line{ x0: current.0, y0: current.1, x1: start.0, y1: start.1 });
+        }
+    }
+    lines
+}
+
+// Rasterizes contours in FUnits, scale being in pixels per FUnit. None if the bitmap would be
+// larger than MAX_DIMENSION.
+pub fn rasterize(contours: &[Contour], scale: f64) -> Option<Bitmap> {
+    let lines = flatten(contours, scale);
+    if lines.is_empty() {
+        return Some(Bitmap::new(0, 0));
+    }
+    let min_x = lines.iter().map(|l| l.x0.min(l.x1)).fold(f64::INFINITY, f64::min).floor();
+    let max_x = lines.iter().map(|l| l.x0.max(l.x1)).fold(f64::NEG_INFINITY, f64::max).ceil();
+    let min_y = lines.iter().map(|l| l.y0.min(l.y1)).fold(f64::INFINITY, f64::min).floor();
+    let max_y = lines.iter().map(|l| l.y0.max(l.y1)).fold(f64::NEG_INFINITY, f64::max).ceil();
+    let (width, height) = ((max_x - min_x) as usize, (max_y - min_y) as usize);
+    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return None;
+    }
+
+    let mut coverage = vec![0.0_f64; width.checked_mul(height)?];
+    let mut crossings: Vec<(f64, i32)> = vec!();
+    for row in 0..height {
+        for sample in 0..SUBSAMPLES {
+            let y = min_y + row as f64 + (sample as f64 + 0.5) / SUBSAMPLES as f64;
+            crossings.clear();
+            for l in lines.iter() {
+                // Half open so that a scanline through a vertex crosses only one of its lines.
+                let (winding, crosses) = if l.y0 < l.y1 { (1, l.y0 <= y && y < l.y1) } else { (-1, l.y1 <= y && y < l.y0) };
+                if crosses {
+                    let x = l.x0 + (y - l.y0) * (l.x1 - l.x0) / (l.y1 - l.y0);
+                    crossings.push((x - min_x, winding));
+                }
+            }
+            crossings.sort_by(|a, b| a.0.total_cmp(&b.0));
+            let mut winding = 0;
+            for pair in crossings.windows(2) {
+                winding += pair[0].1;
+                if winding != 0 {
+                    add_span(&mut coverage[row * width..(row + 1) * width], pair[0].0, pair[1].0);
+                }
+            }
+        }
+    }
+    let data = coverage.iter().map(|c| ((c / SUBSAMPLES as f64).min(1.0) * 255.0).round() as u8).collect();
+    Some(Bitmap{ width, height, left: min_x as i32, top: min_y as i32, data })
+}
+
+// Covers [from, to) of a row, in pixels.
+fn add_span(row: &mut [f64], from: f64, to: f64) {
+    let (from, to) = (from.max(0.0), to.min(row.len() as f64));
+    if from >= to {
+        return;
+    }
+    let (first, last) = (from.floor() as usize, (to.ceil() as usize - 1).min(row.len() - 1));
+    if first == last {
+        row[first] += to - from;
+        return;
+    }
+    row[first] += first as f64 + 1.0 - from;
+    for pixel in row[first + 1..last].iter_mut() {
+        *pixel += 1.0;
+    }
+    row[last] += to - last as f64;
+}
+
+impl Glyph {
+    // Only simple glyphs have contours of their own, see TtfFile::rasterize for compound ones.
+    pub fn rasterize(&self, scale: f64) -> Option<Bitmap> {
+        match &self.glyph_data {
+            GlyphData::SimpleGlyph{ contours, .. } => rasterize(contours, scale),
+            _ => Some(Bitmap::new(0, 0)),
+        }
+    }
+}
+
+impl TtfFile {
+    // Glyph at size pixels per em, None if it has no outline or is too large.
+    pub fn rasterize(&self, index: u16, size: f64) -> Option<Bitmap> {
+        let scale = size / self.units_per_em()? as f64;
+        match self.glyph(index) {
+            Some(glyph @ Glyph{ glyph_data: GlyphData::SimpleGlyph{ .. }, .. }) => glyph.rasterize(scale),
+            // Compound glyphs and CFF outlines.
+            _ => rasterize(&self.glyph_contours(index)?, scale),
+        }
+    }
+
+    // Lines of text at size pixels per em, with a margin of a quarter em. Glyphs are grid-fitted
+    // with hinter, which must be for that size.
+    pub fn render(&self, text: &str, size: f64, mut hinter: Option<&mut Hinter>) -> Option<Bitmap> {
+        let scale = size / self.units_per_em()? as f64;
+        let line_height = self.line_height()? as f64 * scale;
+        let margin = (size / 4.0).ceil();
+        let lines = text.split('\n').map(|line| self.layout(line, size)).collect::<Vec<_>>();
+        let width = lines.iter()
+            .filter_map(|glyphs| glyphs.last().map(|g| g.x + g.advance))
+            .fold(0.0, f64::max);
+        let mut bitmap = Bitmap::new(
+            (width + 2.0 * margin).ceil() as usize,
+            (line_height * lines.len() as f64 + 2.0 * margin).ceil() as usize,
+        );
+        let mut baseline = margin + self.ascent()? as f64 * scale;
+        for glyphs in lines.iter() {
+            for glyph in glyphs.iter() {
+                let glyph_bitmap = match hinter.as_mut() {
+                    Some(hinter) => hinter.glyph_contours(glyph.index).and_then(|contours| rasterize(&contours, 1.0 / 64.0)),
+                    None => self.rasterize(glyph.index, size),
+                };
+                if let Some(glyph_bitmap) = glyph_bitmap {
+                    bitmap.draw(&glyph_bitmap, (margin + glyph.x).round() as i32, (baseline - glyph.y).round() as i32);
+                }
+            }
+            baseline += line_height;
+        }
+        Some(bitmap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ttf::Point;
+
+    fn contour(points: &[(i32, i32, bool)], cubic: bool) -> Contour {
+        Contour{ points: points.iter().map(|&(x, y, on_curve)| Point{ x, y, on_curve }).collect(), cubic }
+    }
+
+    fn polygon(points: &[(i32, i32)]) -> Contour {
+        contour(&points.iter().map(|&(x, y)| (x, y, true)).collect::<Vec<_>>(), false)
+    }
+
+    fn rows(bitmap: &Bitmap) -> Vec<Vec<u8>> {
+        bitmap.data.chunks(bitmap.width).map(|row| row.to_vec()).collect()
+    }
+
+    // Lines must follow each other and come back to the start.
+    fn assert_closed(lines: &[Line]) {
+        for (k, line) in lines.iter().enumerate() {
+            let next = lines[(k + 1) % lines.len()];
+            assert!((line.x1 - next.x0).abs() < 1e-9 && (line.y1 - next.y0).abs() < 1e-9, "{:?} {:?}", line, next);
+        }
+    }
+
+    // Distance from a point to the nearest line.
+    fn distance(lines: &[Line], (x, y): (f64, f64)) -> f64 {
+        lines.iter().map(|l| {
+            let (dx, dy) = (l.x1 - l.x0, l.y1 - l.y0);
+            let length = dx * dx + dy * dy;
+            let t = if length == 0.0 { 0.0 } else { (((x - l.x0) * dx + (y - l.y0) * dy) / length).clamp(0.0, 1.0) };
+            (x - l.x0 - t * dx).hypot(y - l.y0 - t * dy)
+        }).fold(f64::INFINITY, f64::min)
+    }
+
+    #[test]
+    fn test_square() {
+        // From 0.5 to 4.5 pixels: full interior, half covered edges and quarter covered corners.
+        let bitmap = rasterize(&[polygon(&[(2, 2), (18, 2), (18, 18), (2, 18)])], 0.25).unwrap();
+        assert_eq!((bitmap.width, bitmap.height, bitmap.left, bitmap.top), (5, 5, 0, -5));
+        assert_eq!(rows(&bitmap), vec!(
+            vec!(64, 128, 128, 128, 64),
+            vec!(128, 255, 255, 255, 128),
+            vec!(128, 255, 255, 255, 128),
+            vec!(128, 255, 255, 255, 128),
+            vec!(64, 128, 128, 128, 64),
+        ));
+        // Either direction.
+        let reversed = rasterize(&[polygon(&[(2, 18), (18, 18), (18, 2), (2, 2)])], 0.25).unwrap();
+        assert_eq!(reversed, bitmap);
+        assert_eq!(rasterize(&[], 1.0), Some(Bitmap::new(0, 0)));
+    }
+
+    #[test]
+    fn test_too_large() {
+        let bar = [polygon(&[(0, 0), (16, 0), (16, 1), (0, 1)])];
+        let bitmap = rasterize(&bar, MAX_DIMENSION as f64 / 16.0).unwrap();
+        assert_eq!((bitmap.width, bitmap.height), (MAX_DIMENSION, MAX_DIMENSION / 16));
+        assert_eq!(rasterize(&bar, MAX_DIMENSION as f64 / 15.0), None);
+        assert_eq!(rasterize(&bar, f64::MAX), None);
+    }
+
+    #[test]
+    fn test_non_zero_winding() {
+        let outer = polygon(&[(0, 0), (40, 0), (40, 40), (0, 40)]);
+        let full = vec![255; 4];
+        let ring = vec!(255, 0, 0, 255);
+
+        // Opposite directions leave a hole.
+        let hole = rasterize(&[outer.clone(), polygon(&[(10, 10), (10, 30), (30, 30), (30, 10)])], 0.1).unwrap();
+        assert_eq!(rows(&hole), vec!(full.clone(), ring.clone(), ring, full.clone()));
+        // The same direction doesn't, the winding number being 2.
+        let filled = rasterize(&[outer.clone(), polygon(&[(10, 10), (30, 10), (30, 30), (10, 30)])], 0.1).unwrap();
+        assert_eq!(rows(&filled), vec![full; 4]);
+        assert_eq!(filled, rasterize(&[outer], 0.1).unwrap());
+    }
+
+    #[test]
+    fn test_implied_points() {
+        // Off curve points only: the curve starts between the first two, and goes through the
+        // middle of each pair.
+        let implied = [contour(&[(0, 0, false), (20, 0, false), (20, 20, false), (0, 20, false)], false)];
+        let lines = flatten(&implied, 1.0);
+        assert_closed(&lines);
+        assert_eq!((lines[0].x0, lines[0].y0), (10.0, 0.0));
+        for middle in [(10.0, 0.0), (20.0, -10.0), (10.0, -20.0), (0.0, -10.0)] {
+            assert!(distance(&lines, middle) < 1e-9);
+        }
+        // Same as with the points explicit, from an off curve point.
+        let explicit = [contour(&[
+            (0, 0, false), (10, 0, true), (20, 0, false), (20, 10, true),
+            (20, 20, false), (10, 20, true), (0, 20, false), (0, 10, true),
+        ], false)];
+        assert_eq!(rasterize(&explicit, 1.0), rasterize(&implied, 1.0));
+
+        // Within the tolerance of the curve.
+        for step in 0..=100 {
+            let t = step as f64 / 100.0;
+            let u = 1.0 - t;
+            let point = (u * u * 10.0 + 2.0 * u * t * 20.0 + t * t * 20.0, -(t * t * 10.0));
+            assert!(distance(&lines, point) <= TOLERANCE, "{:?}", point);
+        }
+    }
+
+    #[test]
+    fn test_cubic() {
+        let cubic = [contour(&[(0, 0, true), (10, 20, false), (20, 20, false), (30, 0, true)], true)];
+        let lines = flatten(&cubic, 1.0);
+        assert_closed(&lines);
+        assert_eq!((lines[0].x0, lines[0].y0), (0.0, 0.0));
+        for step in 0..=100 {
+            let t = step as f64 / 100.0;
+            let u = 1.0 - t;
+            let point = (3.0 * u * u * t * 10.0 + 3.0 * u * t * t * 20.0 + t * t * t * 30.0, -(3.0 * u * u * t * 20.0 + 3.0 * u * t * t * 20.0));
+            assert!(distance(&lines, point) <= TOLERANCE, "{:?}", point);
+        }
+
+        // The same curve as a quadratic one, with its line back to the start.
+        let quadratic = rasterize(&[contour(&[(0, 0, true), (15, 30, false), (30, 0, true)], false)], 1.0).unwrap();
+        let bitmap = rasterize(&cubic, 1.0).unwrap();
+        assert_eq!((bitmap.width, bitmap.height, bitmap.left, bitmap.top), (quadratic.width, quadratic.height, 0, -15));
+        assert!(bitmap.data.iter().zip(quadratic.data.iter()).all(|(a, b)| a.abs_diff(*b) <= 26));
+
+        // Two curves and a line.
+        let two = [contour(&[(0, 0, true), (0, 10, false), (10, 10, false), (10, 0, true), (10, -10, false), (20, -10, false), (20, 0, true), (10, 5, true)], true)];
+        let lines = flatten(&two, 1.0);
+        assert_closed(&lines);
+        for end in [(10.0, 0.0), (20.0, 0.0), (10.0, -5.0)] {
+            assert!(lines.iter().any(|l| (l.x1, l.y1) == end), "{:?}", end);
+        }
+    }
+}
@@ -4,11 +4,20 @@ extern crate nom;
 
 use bitflags::bitflags;
 use chrono::NaiveDateTime;
-use fixed::{FixedU32,types::extra::U16};
+use fixed::{FixedI32,FixedU32,types::I2F14,types::extra::U16};
 use num_enum::{TryFromPrimitive,TryFromPrimitiveError};
+use std::cell::OnceCell;
 use std::collections::HashMap;
 use std::fmt;
 
+mod cff;
+pub mod hinting;
+mod report;
+mod validate;
+
+pub use hinting::Hinter;
+pub use validate::validate;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
 #[repr(u32)]
 pub enum ScalerType {
@@ -46,7 +55,16 @@ pub struct FontDirectory {
     table_directory: TableDirectory,
 }
 
-#[derive(Clone, PartialEq)]
+// Header of a TrueType Collection ('ttcf'), fonts sharing tables in a single file.
+#[derive(Debug, PartialEq)]
+pub struct CollectionHeader {
+    major_version: u16,
+    minor_version: u16,
+    // Of the font directory of each font, from the start of the file.
+    offsets: Vec<u32>,
+}
+
+#[derive(Clone, Default, PartialEq)]
 pub struct FWord(i16);
 
 impl fmt::Display for FWord {
@@ -109,11 +127,25 @@ pub struct FontHeader {
     glyph_data_format: i16,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct MaxProfile {
     // TODO: Remove public fields
     pub version: FixedU32<U16>,
-    pub nb_glyphs: u16
+    pub nb_glyphs: u16,
+    // Only in version 1.0, for TrueType outlines, 0 otherwise.
+    max_points: u16,
+    max_contours: u16,
+    max_component_points: u16,
+    max_component_contours: u16,
+    max_zones: u16,
+    max_twilight_points: u16,
+    max_storage: u16,
+    max_function_defs: u16,
+    max_instruction_defs: u16,
+    max_stack_elements: u16,
+    max_size_of_instructions: u16,
+    max_component_elements: u16,
+    max_component_depth: u16,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
@@ -499,6 +531,44 @@ bitflags! {
     }
 }
 
+bitflags! {
+    pub struct ComponentFlags: u16 {
+        const ARG_1_AND_2_ARE_WORDS = 0x0001;
+        const ARGS_ARE_XY_VALUES = 0x0002;
+        const ROUND_XY_TO_GRID = 0x0004;
+        const WE_HAVE_A_SCALE = 0x0008;
+        const MORE_COMPONENTS = 0x0020;
+        const WE_HAVE_AN_X_AND_Y_SCALE = 0x0040;
+        const WE_HAVE_A_TWO_BY_TWO = 0x0080;
+        const WE_HAVE_INSTRUCTIONS = 0x0100;
+        const USE_MY_METRICS = 0x0200;
+        const OVERLAP_COMPOUND = 0x0400;
+        const SCALED_COMPONENT_OFFSET = 0x0800;
+        const UNSCALED_COMPONENT_OFFSET = 0x1000;
+    }
+}
+
+// Where a component is placed in a compound glyph.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ComponentOffset {
+    // Offset in FUnits.
+    Xy(i32, i32),
+    // Point of the compound so far matched with a point of the component.
+    Anchor{ compound_point: u16, component_point: u16 },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Component {
+    flags: ComponentFlags,
+    glyph_index: u16,
+    offset: ComponentOffset,
+    // x' = a * x + c * y, y' = b * x + d * y
+    a: I2F14,
+    b: I2F14,
+    c: I2F14,
+    d: I2F14,
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Point {
     // TODO: remove pub
@@ -511,6 +581,9 @@ pub struct Point {
 pub struct Contour {
     // TODO: remove pub
     pub points: Vec<Point>,
+    // Off curve points of cubic contours (CFF outlines) are the two control points of a curve,
+    // those of quadratic ones (glyf outlines) have implied on curve points between them.
+    pub cubic: bool,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -525,7 +598,10 @@ pub enum GlyphData {
         // TODO: remove pub
         contours: Vec<Contour>,
     },
-    CompoundGlyph,
+    CompoundGlyph{
+        components: Vec<Component>,
+        instructions: Vec<u8>,
+    },
 }
 
 #[derive(Debug, PartialEq)]
@@ -540,9 +616,12 @@ pub struct Glyph {
     pub glyph_data: GlyphData,
 }
 
-#[derive(Debug, PartialEq)]
+// Glyphs by index, each parsed the first time it's asked for.
 pub struct GlyphTable {
-    pub glyphs: HashMap<u32, Glyph>,
+    data: Vec<u8>,
+    // Offset of each glyph in data, and of the end of the last one.
+    locations: Vec<u32>,
+    glyphs: Vec<OnceCell<Option<Glyph>>>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -550,6 +629,236 @@ pub struct LocationTable {
     locations: Vec<u32>,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub struct CmapSegment {
+    start_code: u16,
+    end_code: u16,
+    id_delta: i16,
+    id_range_offset: u16,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CmapGroup {
+    start_char_code: u32,
+    end_char_code: u32,
+    start_glyph_id: u32,
+}
+
+// Variation sequences of one selector: base characters using the glyph of the default mapping, and
+// the ones with a glyph of their own.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VariationSelector {
+    selector: u32,
+    // Ranges of characters, as first and last.
+    default_ranges: Vec<(u32, u32)>,
+    non_default_mappings: Vec<(u32, u16)>,
+}
+
+// From https://developer.apple.com/fonts/TrueType-Reference-Manual/RM06/Chap6cmap.html
+#[derive(Clone, Debug, PartialEq)]
+pub enum CmapSubtableData {
+    // Format 0
+    ByteEncoding{ glyph_ids: Vec<u8> },
+    // Format 4
+    SegmentMapping{ segments: Vec<CmapSegment>, glyph_ids: Vec<u16> },
+    // Format 6
+    TrimmedTable{ first_code: u16, glyph_ids: Vec<u16> },
+    // Format 12
+    SegmentedCoverage{ groups: Vec<CmapGroup> },
+    // Format 14
+    VariationSequences{ selectors: Vec<VariationSelector> },
+    // Formats 2, 8, 10 and 13 are not needed by the fonts we care about.
+    Unsupported{ format: u16 },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CmapSubtable {
+    platform_id: PlatformId,
+    // Not a PlatformSpecificId, as fonts use values outside of the spec: DejaVu has a (0, 10)
+    // subtable, 10 being the Microsoft encoding for full Unicode.
+    encoding_id: u16,
+    language: u32,
+    data: CmapSubtableData,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CharacterMapTable {
+    version: u16,
+    subtables: Vec<CmapSubtable>,
+    // Index of the best ranked Unicode subtable, if any.
+    unicode: Option<usize>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct HorizontalHeader {
+    version: FixedU32<U16>,
+    ascent: FWord,
+    descent: FWord,
+    line_gap: FWord,
+    advance_width_max: u16,
+    min_left_side_bearing: FWord,
+    min_right_side_bearing: FWord,
+    x_max_extent: FWord,
+    caret_slope_rise: i16,
+    caret_slope_run: i16,
+    caret_offset: FWord,
+    metric_data_format: i16,
+    nb_long_hor_metrics: u16,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LongHorMetric {
+    advance_width: u16,
+    left_side_bearing: i16,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HorizontalMetrics {
+    metrics: Vec<LongHorMetric>,
+    // Glyphs after the last long metric have the same advance width, only their bearing is stored.
+    left_side_bearings: Vec<i16>,
+}
+
+// From https://docs.microsoft.com/en-us/typography/opentype/spec/kern and
+// https://developer.apple.com/fonts/TrueType-Reference-Manual/RM06/Chap6kern.html
+#[derive(Clone, Debug, PartialEq)]
+pub struct KerningSubtable {
+    horizontal: bool,
+    // Minimum values instead of kerning ones.
+    minimum: bool,
+    cross_stream: bool,
+    // Replaces the value accumulated so far instead of adding to it.
+    replace: bool,
+    // Only format 0 is supported, other formats have no pairs.
+    pairs: HashMap<(u16, u16), i16>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct KerningTable {
+    subtables: Vec<KerningSubtable>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Coverage {
+    Glyphs(Vec<u16>),
+    // First glyph, last glyph and coverage index of the first glyph.
+    Ranges(Vec<(u16, u16, u16)>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClassDefinition {
+    Glyphs{ start_glyph: u16, classes: Vec<u16> },
+    // First glyph, last glyph and class.
+    Ranges(Vec<(u16, u16, u16)>),
+}
+
+// Pair adjustment positioning subtables, only the advance of the first glyph is kept, which is all
+// kerning uses.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PairAdjustment {
+    // Format 1: second glyphs and advances, sorted by second glyph, for each covered first glyph.
+    Glyphs{ coverage: Coverage, pair_sets: Vec<Vec<(u16, i16)>> },
+    // Format 2: advances for each pair of classes.
+    Classes{ coverage: Coverage, first_classes: ClassDefinition, second_classes: ClassDefinition, nb_second_classes: u16, advances: Vec<i16> },
+}
+
+// From https://docs.microsoft.com/en-us/typography/opentype/spec/gpos, only the lookups of the kern
+// feature are read.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GlyphPositioningTable {
+    // Subtables of each lookup, the first one covering a pair applies.
+    kerning_lookups: Vec<Vec<PairAdjustment>>,
+}
+
+// Values referenced by instructions, in FUnits.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ControlValueTable {
+    values: Vec<i16>,
+}
+
+bitflags! {
+    #[derive(Default)]
+    pub struct SelectionFlags: u16 {
+        const ITALIC = 0x0001;
+        const UNDERSCORE = 0x0002;
+        const NEGATIVE = 0x0004;
+        const OUTLINED = 0x0008;
+        const STRIKEOUT = 0x0010;
+        const BOLD = 0x0020;
+        const REGULAR = 0x0040;
+        const USE_TYPO_METRICS = 0x0080;
+        const WWS = 0x0100;
+        const OBLIQUE = 0x0200;
+    }
+}
+
+// From https://docs.microsoft.com/en-us/typography/opentype/spec/os2
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Os2Table {
+    version: u16,
+    x_avg_char_width: FWord,
+    weight_class: u16,
+    width_class: u16,
+    // Embedding permissions.
+    fs_type: u16,
+    subscript_x_size: FWord,
+    subscript_y_size: FWord,
+    subscript_x_offset: FWord,
+    subscript_y_offset: FWord,
+    superscript_x_size: FWord,
+    superscript_y_size: FWord,
+    superscript_x_offset: FWord,
+    superscript_y_offset: FWord,
+    strikeout_size: FWord,
+    strikeout_position: FWord,
+    family_class: i16,
+    panose: [u8; 10],
+    // One bit per Unicode block, from bit 0 for Basic Latin.
+    unicode_ranges: u128,
+    vendor_id: String,
+    selection: SelectionFlags,
+    first_char_index: u16,
+    last_char_index: u16,
+    typo_ascender: FWord,
+    typo_descender: FWord,
+    typo_line_gap: FWord,
+    win_ascent: u16,
+    win_descent: u16,
+    // From version 1, 0 before.
+    code_page_ranges: u64,
+    // From version 2, 0 before.
+    x_height: FWord,
+    cap_height: FWord,
+    default_char: u16,
+    break_char: u16,
+    max_context: u16,
+    // From version 5, 0 before.
+    lower_optical_point_size: u16,
+    upper_optical_point_size: u16,
+}
+
+// From https://docs.microsoft.com/en-us/typography/opentype/spec/post
+#[derive(Clone, Debug, PartialEq)]
+pub struct PostScriptTable {
+    version: FixedU32<U16>,
+    // In degrees counter-clockwise from the vertical.
+    italic_angle: FixedI32<U16>,
+    underline_position: FWord,
+    underline_thickness: FWord,
+    is_fixed_pitch: bool,
+    // By glyph index, empty for version 3 which has no names.
+    glyph_names: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PositionedGlyph {
+    pub index: u16,
+    // Position of the origin of the glyph in pixels, from the start of the baseline.
+    pub x: f64,
+    pub y: f64,
+    pub advance: f64,
+}
+
 #[derive(Debug, PartialEq)]
 pub struct TtfFile {
     font_directory: FontDirectory,
@@ -557,8 +866,20 @@ pub struct TtfFile {
     // TODO: Remove public fields
     pub max_profile: Option<MaxProfile>,
     name_table: Option<NameTable>,
-    loca_table: Option<LocationTable>,
+    os2_table: Option<Os2Table>,
+    post_table: Option<PostScriptTable>,
+    cmap_table: Option<CharacterMapTable>,
+    horizontal_header: Option<HorizontalHeader>,
+    horizontal_metrics: Option<HorizontalMetrics>,
+    kern_table: Option<KerningTable>,
+    gpos_table: Option<GlyphPositioningTable>,
+    cvt_table: Option<ControlValueTable>,
+    // Instructions run once per font (fpgm) and each time the size changes (prep).
+    font_program: Option<Vec<u8>>,
+    control_value_program: Option<Vec<u8>>,
     pub glyph_table: Option<GlyphTable>,
+    // Outlines of OpenType fonts with PostScript outlines, instead of the glyf table.
+    cff_table: Option<cff::CffTable>,
 }
 
 pub type Input<'a> = &'a [u8];
@@ -641,6 +962,30 @@ impl FontDirectory {
     }
 }
 
+impl CollectionHeader {
+    pub fn parse(input: Input) -> Result<Self> {
+        use nom::{
+            bytes::complete::tag,
+            error::context,
+            multi::count,
+            number::complete::{be_u16, be_u32},
+            sequence::tuple,
+        };
+        let (i, (_tag, major_version, minor_version, nb_fonts)) = tuple((
+            context("Tag", tag("ttcf")),
+            context("Major Version", be_u16),
+            context("Minor Version", be_u16),
+            context("Num Fonts", be_u32),
+        ))(input)?;
+        let (i, offsets) = context("Offset Table", count(be_u32, nb_fonts as usize))(i)?;
+        Ok((i, CollectionHeader{
+            major_version,
+            minor_version,
+            offsets,
+        }))
+    }
+}
+
 impl FontHeader {
     pub fn parse(input: Input) -> Result<Self> {
         use nom::{
@@ -704,9 +1049,45 @@ impl MaxProfile {
             context("Version", map_res::<_,_,_,_,Error,_,_>(be_u32, |x| Ok(FixedU32::<U16>::from_bits(x)))),
             context("Nb Glyphs", be_u16),
         ))(input)?;
+        // Version 0.5 is for CFF outlines.
+        if version != FixedU32::<U16>::from_num(1) {
+            return Ok((i, MaxProfile{
+                version,
+                nb_glyphs,
+                ..Default::default()
+            }));
+        }
+        let (i, (max_points, max_contours, max_component_points, max_component_contours, max_zones, max_twilight_points, max_storage, max_function_defs, max_instruction_defs, max_stack_elements, max_size_of_instructions, max_component_elements, max_component_depth)) = tuple((
+            context("Max Points", be_u16),
+            context("Max Contours", be_u16),
+            context("Max Component Points", be_u16),
+            context("Max Component Contours", be_u16),
+            context("Max Zones", be_u16),
+            context("Max Twilight Points", be_u16),
+            context("Max Storage", be_u16),
+            context("Max Function Defs", be_u16),
+            context("Max Instruction Defs", be_u16),
+            context("Max Stack Elements", be_u16),
+            context("Max Size Of Instructions", be_u16),
+            context("Max Component Elements", be_u16),
+            context("Max Component Depth", be_u16),
+        ))(i)?;
         Ok((i, MaxProfile{
             version,
             nb_glyphs,
+            max_points,
+            max_contours,
+            max_component_points,
+            max_component_contours,
+            max_zones,
+            max_twilight_points,
+            max_storage,
+            max_function_defs,
+            max_instruction_defs,
+            max_stack_elements,
+            max_size_of_instructions,
+            max_component_elements,
+            max_component_depth,
         }))
     }
 }
@@ -753,9 +1134,7 @@ impl NameRecordPos {
 impl NameTable {
     pub fn parse(input: Input) -> Result<Self> {
         use nom::{
-            combinator::map_res,
             error::context,
-            multi::count,
             number::complete::be_u16,
             sequence::tuple,
         };
@@ -767,17 +1146,12 @@ impl NameTable {
         let mut records = vec!();
         for _ in 0..cnt {
             let (j, recordpos) = NameRecordPos::parse(i)?;
-            let start = usize::from(string_offset+recordpos.offset);
+            let start = usize::from(string_offset) + usize::from(recordpos.offset);
             let end = start + usize::from(recordpos.length);
-            // TODO: Identify cases where this is not UTF-16 BE.
-            // From https://developer.apple.com/fonts/TrueType-Reference-Manual/RM06/Chap6name.html:
-            // The character set encoding used for the raw name data is determined by the
-            // platform and specific identifier codes. For example, if the platform identifier is
-            // for macOS and the specific identifier is Roman, then the raw string data is
-            // MacRoman. If the platform identifier is Unicode, then the raw string data is Unicode
-            // text in the UTF-16BE encoding format. The character strings can thus be localized to
-            // any language and script.
-            let (_, value) = map_res(count(be_u16, (recordpos.length/2).into()), |x| String::from_utf16(&x))(&input[start..end])?;
+            let data = input.get(start..end).ok_or_else(|| {
+                nom::Err::Error(nom::error::VerboseError{ errors: vec!((input, nom::error::VerboseErrorKind::Context("String"))) })
+            })?;
+            let value = decode_name(&recordpos.platform_specific_id, data);
             let record = NameRecord {
                 platform_id: recordpos.platform_id,
                 platform_specific_id: recordpos.platform_specific_id,
@@ -793,6 +1167,51 @@ impl NameTable {
             records,
         }))
     }
+
+    // English on the Microsoft platform is the most common, then Unicode and Macintosh English.
+    pub fn name(&self, id: KnownNameId) -> Option<&str> {
+        self.records.iter()
+            .filter(|r| r.name_id.0 == id as u16)
+            .min_by_key(|r| match r.language_id {
+                LanguageId::Microsoft(MicrosoftLanguageId::EnglishAmerican) => 0,
+                LanguageId::Unicode(_) => 1,
+                LanguageId::Microsoft(_) => 2,
+                LanguageId::Macintosh(MacintoshLanguageId::English) => 3,
+                LanguageId::Macintosh(_) => 4,
+            })
+            .map(|r| r.value.as_str())
+    }
+}
+
+// Characters 0x80 to 0xFF of Mac Roman, the first half being ASCII.
+const MAC_ROMAN: [char; 128] = [
+    'Ä', 'Å', 'Ç', 'É', 'Ñ', 'Ö', 'Ü', 'á', 'à', 'â', 'ä', 'ã', 'å', 'ç', 'é', 'è',
+    'ê', 'ë', 'í', 'ì', 'î', 'ï', 'ñ', 'ó', 'ò', 'ô', 'ö', 'õ', 'ú', 'ù', 'û', 'ü',
+    '†', '°', '¢', '£', '§', '•', '¶', 'ß', '®', '©', '™', '´', '¨', '≠', 'Æ', 'Ø',
+    '∞', '±', '≤', '≥', '¥', 'µ', '∂', '∑', '∏', 'π', '∫', 'ª', 'º', 'Ω', 'æ', 'ø',
+    '¿', '¡', '¬', '√', 'ƒ', '≈', '∆', '«', '»', '…', '\u{A0}', 'À', 'Ã', 'Õ', 'Œ', 'œ',
+    '–', '—', '“', '”', '‘', '’', '÷', '◊', 'ÿ', 'Ÿ', '⁄', '€', '‹', '›', 'ﬁ', 'ﬂ',
+    '‡', '·', '‚', '„', '‰', 'Â', 'Ê', 'Á', 'Ë', 'È', 'Í', 'Î', 'Ï', 'Ì', 'Ó', 'Ô',
+    '\u{F8FF}', 'Ò', 'Ú', 'Û', 'Ù', 'ı', 'ˆ', '˜', '¯', '˘', '˙', '˚', '¸', '˝', '˛', 'ˇ',
+];
+
+// From https://developer.apple.com/fonts/TrueType-Reference-Manual/RM06/Chap6name.html: the
+// encoding of a name depends on its platform and specific ID. Unicode and Microsoft names are in
+// UTF-16BE, Macintosh ones in one byte per character for Roman. Other Macintosh scripts and the
+// double byte Microsoft encodings would need large tables, only their ASCII characters are kept.
+fn decode_name(platform_specific_id: &PlatformSpecificId, data: &[u8]) -> String {
+    let ascii = |c: u16| if c < 0x80 { c as u8 as char } else { char::REPLACEMENT_CHARACTER };
+    let units = || data.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]]));
+    match platform_specific_id {
+        PlatformSpecificId::Macintosh(MacintoshPlatformSpecificId::Roman) => {
+            data.iter().map(|&b| if b < 0x80 { b as char } else { MAC_ROMAN[usize::from(b - 0x80)] }).collect()
+        },
+        PlatformSpecificId::Macintosh(_) => data.iter().map(|&b| ascii(b.into())).collect(),
+        PlatformSpecificId::Microsoft(MicrosoftPlatformSpecificId::ShiftJIS | MicrosoftPlatformSpecificId::PRC |
+            MicrosoftPlatformSpecificId::Big5 | MicrosoftPlatformSpecificId::Wansung |
+            MicrosoftPlatformSpecificId::Johab) => units().map(ascii).collect(),
+        _ => String::from_utf16_lossy(&units().collect::<Vec<_>>()),
+    }
 }
 
 impl Glyph {
@@ -813,9 +1232,13 @@ impl Glyph {
         let glyph_data = if nb_contours == 0 {
             GlyphData::VoidGlyph
         } else if nb_contours > 0 {
-            let mut endpoints_idx = vec!();
+            let mut endpoints_idx: Vec<u16> = vec!();
             for _ in 0..nb_contours {
                 let (j, idx) = context("Endpoint of contour", be_u16)(i)?;
+                // Contours have at least one point.
+                if endpoints_idx.last().is_some_and(|&previous| idx <= previous) {
+                    return Err(nom::Err::Error(nom::error::VerboseError{ errors: vec!((i, nom::error::VerboseErrorKind::Context("Endpoint Order"))) }));
+                }
                 endpoints_idx.push(idx);
                 i = j;
             }
@@ -903,7 +1326,7 @@ impl Glyph {
                     points.push(Point { x, y, on_curve });
                     i += 1;
                 }
-                contours.push(Contour{points});
+                contours.push(Contour{points, cubic: false});
             }
             // TODO: Read xCoordinates & yCoordinates taking into account repeats.
             // Flags (xshort & yshort) also tell if it's 1 byte (set) or 2 bytes (reset).
@@ -921,7 +1344,19 @@ impl Glyph {
             }
         } else {
             // nb_contours < 0 means compound glyph
-            GlyphData::CompoundGlyph
+            let (j, components) = Glyph::parse_components(i)?;
+            i = j;
+            let mut instructions = vec!();
+            if components.iter().any(|c| c.flags.contains(ComponentFlags::WE_HAVE_INSTRUCTIONS)) {
+                let (j, nb_instructions) = context("Instructions Length", be_u16)(i)?;
+                let (j, bytes) = context("Instructions", nom::bytes::complete::take(nb_instructions))(j)?;
+                i = j;
+                instructions = bytes.to_vec();
+            }
+            GlyphData::CompoundGlyph{
+                components,
+                instructions,
+            }
         };
         Ok((i, Glyph{
             nb_contours,
@@ -934,35 +1369,119 @@ impl Glyph {
     }
 }
 
-impl GlyphTable {
-    pub fn parse(input: Input) -> Result<Self> {
-        let mut glyphs = HashMap::new();
+impl Glyph {
+    fn parse_components(input: Input) -> Result<Vec<Component>> {
+        use nom::{
+            combinator::map,
+            error::context,
+            number::complete::{be_i8, be_u8, be_i16, be_u16},
+            sequence::tuple,
+        };
+        let f2dot14 = || map(be_i16, I2F14::from_bits);
+        let mut components = vec!();
         let mut i = input;
-        let mut n = 0;
         loop {
-            println!("Remaining glyf length: {}", i.len());
-            match Glyph::parse(i) {
-                Ok((j, glyph)) => {
-                    i = j;
-                    glyphs.insert(n, glyph);
-                },
-                Err(nom::Err::Error(nom::error::VerboseError{errors})) if errors[0].1 == nom::error::VerboseErrorKind::Nom(nom::error::ErrorKind::Eof) => {
-                    println!("Reached end of glyphs while reading it: {:?}", errors);
-                    break;
-                }
-                Err(e) => {
-                    return Err(e);
-                }
+            let (j, (flags, glyph_index)) = tuple((
+                context("Component Flags", map(be_u16, ComponentFlags::from_bits_truncate)),
+                context("Glyph Index", be_u16),
+            ))(i)?;
+            i = j;
+            let words = flags.contains(ComponentFlags::ARG_1_AND_2_ARE_WORDS);
+            let offset = if flags.contains(ComponentFlags::ARGS_ARE_XY_VALUES) {
+                let (j, (arg1, arg2)) = if words {
+                    context("Offset (i16)", tuple((map(be_i16, i32::from), map(be_i16, i32::from))))(i)?
+                } else {
+                    context("Offset (i8)", tuple((map(be_i8, i32::from), map(be_i8, i32::from))))(i)?
+                };
+                i = j;
+                ComponentOffset::Xy(arg1, arg2)
+            } else {
+                let (j, (compound_point, component_point)) = if words {
+                    context("Anchor Points (u16)", tuple((be_u16, be_u16)))(i)?
+                } else {
+                    context("Anchor Points (u8)", tuple((map(be_u8, u16::from), map(be_u8, u16::from))))(i)?
+                };
+                i = j;
+                ComponentOffset::Anchor{ compound_point, component_point }
+            };
+            let (zero, one) = (I2F14::from_bits(0), I2F14::from_bits(1 << 14));
+            let (mut a, mut b, mut c, mut d) = (one, zero, zero, one);
+            if flags.contains(ComponentFlags::WE_HAVE_A_SCALE) {
+                let (j, scale) = context("Scale", f2dot14())(i)?;
+                i = j;
+                a = scale;
+                d = scale;
+            } else if flags.contains(ComponentFlags::WE_HAVE_AN_X_AND_Y_SCALE) {
+                let (j, (x_scale, y_scale)) = context("X and Y Scale", tuple((f2dot14(), f2dot14())))(i)?;
+                i = j;
+                a = x_scale;
+                d = y_scale;
+            } else if flags.contains(ComponentFlags::WE_HAVE_A_TWO_BY_TWO) {
+                let (j, matrix) = context("Two by Two", tuple((f2dot14(), f2dot14(), f2dot14(), f2dot14())))(i)?;
+                i = j;
+                (a, b, c, d) = matrix;
             }
-            if i.is_empty() {
+            components.push(Component{
+                flags,
+                glyph_index,
+                offset,
+                a,
+                b,
+                c,
+                d,
+            });
+            if !flags.contains(ComponentFlags::MORE_COMPONENTS) {
                 break;
             }
-            n += 1;
         }
-        Ok((i, GlyphTable{
-            glyphs,
+        Ok((i, components))
+    }
+}
+
+impl GlyphTable {
+    // Checks that locations are in order and inside the table, for nb_glyphs glyphs.
+    pub fn parse<'a>(input: Input<'a>, loca_table: &LocationTable, nb_glyphs: u16) -> Result<'a, Self> {
+        let error = |field| nom::Err::Error(nom::error::VerboseError{ errors: vec!((input, nom::error::VerboseErrorKind::Context(field))) });
+        let locations = loca_table.locations.get(..=usize::from(nb_glyphs)).ok_or_else(|| error("Glyph Locations"))?;
+        if locations.windows(2).any(|l| l[0] > l[1]) {
+            return Err(error("Glyph Location Order"));
+        }
+        if locations.last().is_some_and(|&end| end as usize > input.len()) {
+            return Err(error("Glyph Location Bounds"));
+        }
+        Ok((&input[input.len()..], GlyphTable{
+            data: input.to_vec(),
+            locations: locations.to_vec(),
+            glyphs: (0..nb_glyphs).map(|_| OnceCell::new()).collect(),
         }))
     }
+
+    // None for glyphs without an outline (space...) and for those that don't parse.
+    pub fn glyph(&self, index: u16) -> Option<&Glyph> {
+        let index = usize::from(index);
+        self.glyphs.get(index)?.get_or_init(|| {
+            let (start, end) = (self.locations[index] as usize, self.locations[index + 1] as usize);
+            if start == end {
+                return None;
+            }
+            Glyph::parse(&self.data[start..end]).ok().map(|(_, glyph)| glyph)
+        }).as_ref()
+    }
+}
+
+// Shows the glyphs by index, parsing them all.
+impl fmt::Debug for GlyphTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries((0..self.glyphs.len() as u16).filter_map(|index| Some((index, self.glyph(index)?))))
+            .finish()
+    }
+}
+
+impl PartialEq for GlyphTable {
+    fn eq(&self, other: &Self) -> bool {
+        self.data == other.data && self.locations == other.locations
+    }
 }
 
 impl LocationTable {
@@ -976,7 +1495,8 @@ impl LocationTable {
         for _ in 0..input.len()/2 {
             let (j, offset) = context("Offset", be_u16)(i)?;
             i = j;
-            locations.push(offset as u32);
+            // Offsets divided by 2.
+            locations.push(offset as u32 * 2);
         }
         Ok((input, LocationTable{
             locations
@@ -1001,57 +1521,1726 @@ impl LocationTable {
     }
 }
 
-// Required tables (for TrueType font, not necessarily for OpenType, bitmap...):
-// 'cmap'   character to glyph mapping
-// 'glyf'   glyph data
-// 'head'   font header
-// 'hhea'   horizontal header
-// 'hmtx'   horizontal metrics
-// 'loca'   index to location
-// 'maxp'   maximum profile
-// 'name'   naming
-// 'post'   PostScript
-impl TtfFile {
-    pub fn parse_table<'a, F, T>(input: Input<'a>, directory: TableDirectory, tag: &str, parser: F) -> SimpleResult<'a, Option<T>>
-        where F: Fn(Input) -> Result<T> {
-        let entry = &directory.entries.get(tag);
-        match entry {
-            Some(e) => Ok(Some(parser(&input[e.offset as usize..(e.offset+e.length) as usize])?.1)),
-            None => Ok(None),
-        }
+// Data of a table at offset from its start.
+fn at_offset<'a>(table: Input<'a>, offset: u32, field: &'static str) -> SimpleResult<'a, Input<'a>> {
+    table.get(offset as usize..).ok_or_else(|| {
+        nom::Err::Error(nom::error::VerboseError{ errors: vec!((table, nom::error::VerboseErrorKind::Context(field))) })
+    })
+}
+
+impl CmapSubtableData {
+    fn parse_byte_encoding(input: Input) -> Result<(u32, Self)> {
+        use nom::{
+            error::context,
+            multi::count,
+            number::complete::{be_u8, be_u16},
+            sequence::tuple,
+        };
+        let (i, (_length, language)) = tuple((
+            context("Length", be_u16),
+            context("Language", be_u16),
+        ))(input)?;
+        let (i, glyph_ids) = context("Glyph IDs", count(be_u8, 256))(i)?;
+        Ok((i, (language.into(), CmapSubtableData::ByteEncoding{ glyph_ids })))
     }
 
-    pub fn parse(input: Input) -> Result<Self> {
-        let (i, font_directory) = FontDirectory::parse(input)?;
-        let font_header = TtfFile::parse_table(input, font_directory.table_directory.clone(), "head", FontHeader::parse)?;
-        let max_profile = TtfFile::parse_table(input, font_directory.table_directory.clone(), "maxp", MaxProfile::parse)?;
-        let name_table = TtfFile::parse_table(input, font_directory.table_directory.clone(), "name", NameTable::parse)?;
-        let loca_table = if font_header.as_ref().unwrap().index_to_loc_format == IndexToLocFormat::ShortOffsets {
-            TtfFile::parse_table(input, font_directory.table_directory.clone(), "loca", LocationTable::parse_short)?
-        } else {
-            TtfFile::parse_table(input, font_directory.table_directory.clone(), "loca", LocationTable::parse_long)?
+    fn parse_segment_mapping(input: Input) -> Result<(u32, Self)> {
+        use nom::{
+            bytes::complete::take,
+            error::context,
+            multi::count,
+            number::complete::{be_i16, be_u16},
+            sequence::tuple,
         };
-        //let glyph_table = TtfFile::parse_table(input, font_directory.table_directory.clone(), "glyf", GlyphTable::parse)?;
-        let entry = &font_directory.table_directory.entries.get("glyf").unwrap();
-        let mut glyphs = HashMap::new();
-        for l in loca_table.clone().unwrap().locations {
-            if l == entry.length {
-                break;
+        // The length is relative to the start of the subtable, whose format has already been read.
+        let (i, (length, language, seg_count_x2, _search_range, _entry_selector, _range_shift)) = tuple((
+            context("Length", be_u16),
+            context("Language", be_u16),
+            context("Seg Count X2", be_u16),
+            context("Search Range", be_u16),
+            context("Entry Selector", be_u16),
+            context("Range Shift", be_u16),
+        ))(input)?;
+        let seg_count = usize::from(seg_count_x2 / 2);
+        let (i, (end_codes, _reserved_pad, start_codes, id_deltas, id_range_offsets)) = tuple((
+            context("End Code", count(be_u16, seg_count)),
+            context("Reserved Pad", be_u16),
+            context("Start Code", count(be_u16, seg_count)),
+            context("Id Delta", count(be_i16, seg_count)),
+            context("Id Range Offset", count(be_u16, seg_count)),
+        ))(i)?;
+        // Some fonts have a wrong length when the subtable is more than 64kB, so the glyph IDs go
+        // up to the end of the table in that case.
+        let remaining = (usize::from(length)).saturating_sub(16 + 8 * seg_count);
+        let (i, glyph_ids) = context("Glyph IDs", take(remaining.min(i.len()) & !1))(i)?;
+        let (_, glyph_ids) = count(be_u16, glyph_ids.len() / 2)(glyph_ids)?;
+        let segments = (0..seg_count).map(|n| CmapSegment{
+            start_code: start_codes[n],
+            end_code: end_codes[n],
+            id_delta: id_deltas[n],
+            id_range_offset: id_range_offsets[n],
+        }).collect();
+        Ok((i, (language.into(), CmapSubtableData::SegmentMapping{ segments, glyph_ids })))
+    }
+
+    fn parse_trimmed_table(input: Input) -> Result<(u32, Self)> {
+        use nom::{
+            error::context,
+            multi::count,
+            number::complete::be_u16,
+            sequence::tuple,
+        };
+        let (i, (_length, language, first_code, entry_count)) = tuple((
+            context("Length", be_u16),
+            context("Language", be_u16),
+            context("First Code", be_u16),
+            context("Entry Count", be_u16),
+        ))(input)?;
+        let (i, glyph_ids) = context("Glyph IDs", count(be_u16, entry_count.into()))(i)?;
+        Ok((i, (language.into(), CmapSubtableData::TrimmedTable{ first_code, glyph_ids })))
+    }
+
+    fn parse_segmented_coverage(input: Input) -> Result<(u32, Self)> {
+        use nom::{
+            error::context,
+            multi::count,
+            number::complete::{be_u16, be_u32},
+            sequence::tuple,
+        };
+        let (i, (_reserved, _length, language, nb_groups)) = tuple((
+            context("Reserved", be_u16),
+            context("Length", be_u32),
+            context("Language", be_u32),
+            context("Nb Groups", be_u32),
+        ))(input)?;
+        // Each group takes 12 bytes, don't trust nb_groups for the allocation.
+        let (i, groups) = context("Groups", count(
+            |i| {
+                let (i, (start_char_code, end_char_code, start_glyph_id)) = tuple((be_u32, be_u32, be_u32))(i)?;
+                Ok((i, CmapGroup{ start_char_code, end_char_code, start_glyph_id }))
+            },
+            (nb_groups as usize).min(i.len() / 12 + 1),
+        ))(i)?;
+        Ok((i, (language, CmapSubtableData::SegmentedCoverage{ groups })))
+    }
+
+    // Offsets of the default and non default UVS tables are relative to subtable. There is no
+    // language, this subtable applies to the Unicode one.
+    fn parse_variation_sequences(subtable: Input) -> Result<(u32, Self)> {
+        use nom::{
+            error::context,
+            multi::count,
+            number::complete::{be_u8, be_u16, be_u24, be_u32},
+            sequence::tuple,
+        };
+        let (i, (_format, _length, nb_records)) = tuple((
+            context("Format", be_u16),
+            context("Length", be_u32),
+            context("Nb Var Selector Records", be_u32),
+        ))(subtable)?;
+        let (i, records) = context("Var Selector Records", count(
+            tuple((be_u24, be_u32, be_u32)),
+            (nb_records as usize).min(i.len() / 11 + 1),
+        ))(i)?;
+        let mut selectors = vec!();
+        for (selector, default_offset, non_default_offset) in records {
+            let mut default_ranges = vec!();
+            if default_offset != 0 {
+                let (j, nb_ranges) = context("Nb Unicode Value Ranges", be_u32)(at_offset(subtable, default_offset, "Default UVS Offset")?)?;
+                let (_, ranges) = context("Unicode Value Ranges", count(
+                    tuple((be_u24, be_u8)),
+                    (nb_ranges as usize).min(j.len() / 4 + 1),
+                ))(j)?;
+                default_ranges = ranges.into_iter().map(|(start, additional)| (start, start + u32::from(additional))).collect();
             }
-            if !glyphs.contains_key(&l) {
-                glyphs.insert(l, Glyph::parse(&input[(entry.offset+l) as usize..(entry.offset+entry.length) as usize])?.1);
+            let mut non_default_mappings = vec!();
+            if non_default_offset != 0 {
+                let (j, nb_mappings) = context("Nb UVS Mappings", be_u32)(at_offset(subtable, non_default_offset, "Non Default UVS Offset")?)?;
+                let (_, mappings) = context("UVS Mappings", count(
+                    tuple((be_u24, be_u16)),
+                    (nb_mappings as usize).min(j.len() / 5 + 1),
+                ))(j)?;
+                non_default_mappings = mappings;
             }
+            selectors.push(VariationSelector{ selector, default_ranges, non_default_mappings });
         }
-        let glyph_table = Some(GlyphTable{
-            glyphs
-        });
-        Ok((i, TtfFile{
-            font_directory,
-            font_header,
-            max_profile,
-            name_table,
-            loca_table,
-            glyph_table,
+        Ok((i, (0, CmapSubtableData::VariationSequences{ selectors })))
+    }
+
+    // Glyph 0 (missing glyph) is returned as None.
+    pub fn glyph_index(&self, c: u32) -> Option<u16> {
+        let glyph = match self {
+            CmapSubtableData::ByteEncoding{ glyph_ids } => u16::from(*glyph_ids.get(c as usize)?),
+            CmapSubtableData::SegmentMapping{ segments, glyph_ids } => {
+                let c = u16::try_from(c).ok()?;
+                // Segments are sorted by end code.
+                let n = segments.iter().position(|s| s.end_code >= c)?;
+                let segment = &segments[n];
+                if segment.start_code > c {
+                    return None;
+                }
+                if segment.id_range_offset == 0 {
+                    (c as i32 + segment.id_delta as i32) as u16
+                } else {
+                    // id_range_offset is the distance in bytes from its own position to the glyph ID
+                    // of start_code, and glyph_ids follows the id_range_offset array.
+                    let index = usize::from(segment.id_range_offset / 2) + usize::from(c - segment.start_code) + n;
+                    let glyph = *glyph_ids.get(index.checked_sub(segments.len())?)?;
+                    if glyph == 0 {
+                        return None;
+                    }
+                    (glyph as i32 + segment.id_delta as i32) as u16
+                }
+            },
+            CmapSubtableData::TrimmedTable{ first_code, glyph_ids } => {
+                *glyph_ids.get((c.checked_sub(u32::from(*first_code))?) as usize)?
+            },
+            CmapSubtableData::SegmentedCoverage{ groups } => {
+                let group = groups.iter().find(|g| g.start_char_code <= c && c <= g.end_char_code)?;
+                u16::try_from(group.start_glyph_id.checked_add(c - group.start_char_code)?).ok()?
+            },
+            CmapSubtableData::VariationSequences{ .. } |
+            CmapSubtableData::Unsupported{ .. } => return None,
+        };
+        if glyph == 0 { None } else { Some(glyph) }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VariantGlyph {
+    // The glyph of the base character in the Unicode subtable.
+    Default,
+    Glyph(u16),
+}
+
+impl VariationSelector {
+    pub fn variant(&self, c: u32) -> Option<VariantGlyph> {
+        if self.default_ranges.iter().any(|(first, last)| *first <= c && c <= *last) {
+            return Some(VariantGlyph::Default);
+        }
+        self.non_default_mappings.iter().find(|(base, _)| *base == c).map(|(_, glyph)| VariantGlyph::Glyph(*glyph))
+    }
+}
+
+impl CmapSubtable {
+    pub fn parse(subtable: Input, platform_id: PlatformId, encoding_id: u16) -> Result<Self> {
+        use nom::{
+            error::context,
+            number::complete::be_u16,
+        };
+        let (i, format) = context("Format", be_u16)(subtable)?;
+        let (i, (language, data)) = match format {
+            0 => CmapSubtableData::parse_byte_encoding(i)?,
+            4 => CmapSubtableData::parse_segment_mapping(i)?,
+            6 => CmapSubtableData::parse_trimmed_table(i)?,
+            12 => CmapSubtableData::parse_segmented_coverage(i)?,
+            14 => CmapSubtableData::parse_variation_sequences(subtable)?,
+            _ => (i, (0, CmapSubtableData::Unsupported{ format })),
+        };
+        Ok((i, CmapSubtable{
+            platform_id,
+            encoding_id,
+            language,
+            data,
         }))
     }
+
+    // Lower is better, None for subtables that don't map Unicode characters.
+    fn unicode_rank(&self) -> Option<u8> {
+        let coverage = match self.data {
+            CmapSubtableData::SegmentedCoverage{ .. } => 0,
+            CmapSubtableData::SegmentMapping{ .. } => 1,
+            CmapSubtableData::TrimmedTable{ .. } => 2,
+            CmapSubtableData::ByteEncoding{ .. } => 3,
+            _ => return None,
+        };
+        match (self.platform_id, self.encoding_id) {
+            (PlatformId::Unicode, _) |
+            (PlatformId::Microsoft, 1) |
+            (PlatformId::Microsoft, 10) => Some(coverage),
+            _ => None,
+        }
+    }
+}
+
+impl CharacterMapTable {
+    pub fn parse(input: Input) -> Result<Self> {
+        use nom::{
+            combinator::map_res,
+            error::context,
+            number::complete::{be_u16, be_u32},
+            sequence::tuple,
+        };
+        let (mut i, (version, nb_subtables)) = tuple((
+            context("Version", be_u16),
+            context("Nb Subtables", be_u16),
+        ))(input)?;
+        let mut subtables = vec!();
+        for _ in 0..nb_subtables {
+            let (j, (platform_id, encoding_id, offset)) = tuple((
+                context("Platform ID", map_res(be_u16, PlatformId::try_from)),
+                context("Platform Specific ID", be_u16),
+                context("Offset", be_u32),
+            ))(i)?;
+            i = j;
+            let (_, subtable) = CmapSubtable::parse(at_offset(input, offset, "Offset")?, platform_id, encoding_id)?;
+            subtables.push(subtable);
+        }
+        let unicode = subtables.iter().enumerate()
+            .filter_map(|(k, s)| Some((s.unicode_rank()?, k)))
+            .min_by_key(|(rank, _)| *rank)
+            .map(|(_, k)| k);
+        Ok((i, CharacterMapTable{
+            version,
+            subtables,
+            unicode,
+        }))
+    }
+
+    pub fn glyph_index(&self, c: char) -> Option<u16> {
+        if let Some(k) = self.unicode {
+            return self.subtables[k].data.glyph_index(c as u32);
+        }
+        for subtable in self.subtables.iter() {
+            match (subtable.platform_id, subtable.encoding_id) {
+                // Symbol fonts map their characters to the private area starting at 0xF000.
+                (PlatformId::Microsoft, 0) => {
+                    let glyph = subtable.data.glyph_index(c as u32);
+                    if glyph.is_some() || (c as u32) >= 0x100 {
+                        return glyph;
+                    }
+                    return subtable.data.glyph_index(0xF000 + c as u32);
+                },
+                // Mac Roman matches ASCII.
+                (PlatformId::Macintosh, 0) if c.is_ascii() => return subtable.data.glyph_index(c as u32),
+                _ => {},
+            }
+        }
+        None
+    }
+
+    pub fn glyph_variant_index(&self, c: char, selector: char) -> Option<u16> {
+        let variant = self.subtables.iter().find_map(|s| match &s.data {
+            CmapSubtableData::VariationSequences{ selectors } => {
+                selectors.iter().find(|v| v.selector == selector as u32)?.variant(c as u32)
+            },
+            _ => None,
+        });
+        match variant? {
+            VariantGlyph::Default => self.glyph_index(c),
+            VariantGlyph::Glyph(glyph) => Some(glyph),
+        }
+    }
+}
+
+impl HorizontalHeader {
+    pub fn parse(input: Input) -> Result<Self> {
+        use nom::{
+            combinator::map_res,
+            error::context,
+            number::complete::{be_i16, be_u16, be_u32},
+            sequence::tuple,
+        };
+        let (i, (version, ascent, descent, line_gap, advance_width_max, min_left_side_bearing, min_right_side_bearing, x_max_extent)) = tuple((
+            context("Version", map_res::<_,_,_,_,Error,_,_>(be_u32, |x| Ok(FixedU32::<U16>::from_bits(x)))),
+            context("Ascent", map_res::<_,_,_,_,Error,_,_>(be_i16, |x| Ok(FWord(x)))),
+            context("Descent", map_res::<_,_,_,_,Error,_,_>(be_i16, |x| Ok(FWord(x)))),
+            context("Line Gap", map_res::<_,_,_,_,Error,_,_>(be_i16, |x| Ok(FWord(x)))),
+            context("Advance Width Max", be_u16),
+            context("Min Left Side Bearing", map_res::<_,_,_,_,Error,_,_>(be_i16, |x| Ok(FWord(x)))),
+            context("Min Right Side Bearing", map_res::<_,_,_,_,Error,_,_>(be_i16, |x| Ok(FWord(x)))),
+            context("X Max Extent", map_res::<_,_,_,_,Error,_,_>(be_i16, |x| Ok(FWord(x)))),
+        ))(input)?;
+        let (i, (caret_slope_rise, caret_slope_run, caret_offset, _reserved, metric_data_format, nb_long_hor_metrics)) = tuple((
+            context("Caret Slope Rise", be_i16),
+            context("Caret Slope Run", be_i16),
+            context("Caret Offset", map_res::<_,_,_,_,Error,_,_>(be_i16, |x| Ok(FWord(x)))),
+            context("Reserved", tuple((be_i16, be_i16, be_i16, be_i16))),
+            context("Metric Data Format", be_i16),
+            context("Nb Long Hor Metrics", be_u16),
+        ))(i)?;
+        Ok((i, HorizontalHeader{
+            version,
+            ascent,
+            descent,
+            line_gap,
+            advance_width_max,
+            min_left_side_bearing,
+            min_right_side_bearing,
+            x_max_extent,
+            caret_slope_rise,
+            caret_slope_run,
+            caret_offset,
+            metric_data_format,
+            nb_long_hor_metrics,
+        }))
+    }
+}
+
+impl HorizontalMetrics {
+    pub fn parse(input: Input, nb_long_hor_metrics: u16, nb_glyphs: u16) -> Result<Self> {
+        use nom::{
+            error::context,
+            multi::count,
+            number::complete::{be_i16, be_u16},
+            sequence::tuple,
+        };
+        let (i, metrics) = context("Long Hor Metrics", count(
+            |i| {
+                let (i, (advance_width, left_side_bearing)) = tuple((be_u16, be_i16))(i)?;
+                Ok((i, LongHorMetric{ advance_width, left_side_bearing }))
+            },
+            nb_long_hor_metrics.into(),
+        ))(input)?;
+        let (i, left_side_bearings) = context("Left Side Bearings", count(be_i16, nb_glyphs.saturating_sub(nb_long_hor_metrics).into()))(i)?;
+        Ok((i, HorizontalMetrics{
+            metrics,
+            left_side_bearings,
+        }))
+    }
+
+    pub fn advance_width(&self, index: u16) -> Option<u16> {
+        let metric = self.metrics.get(usize::from(index)).or_else(|| self.metrics.last())?;
+        Some(metric.advance_width)
+    }
+
+    pub fn left_side_bearing(&self, index: u16) -> Option<i16> {
+        match self.metrics.get(usize::from(index)) {
+            Some(metric) => Some(metric.left_side_bearing),
+            None => self.left_side_bearings.get(usize::from(index) - self.metrics.len()).copied(),
+        }
+    }
+}
+
+impl ControlValueTable {
+    pub fn parse(input: Input) -> Result<Self> {
+        use nom::{
+            error::context,
+            multi::count,
+            number::complete::be_i16,
+        };
+        let (i, values) = context("Values", count(be_i16, input.len() / 2))(input)?;
+        Ok((i, ControlValueTable{
+            values,
+        }))
+    }
+}
+
+impl Os2Table {
+    pub fn parse(input: Input) -> Result<Self> {
+        use nom::{
+            bytes::complete::take,
+            combinator::map,
+            error::context,
+            number::complete::{be_i16, be_u16, be_u32},
+            sequence::tuple,
+        };
+        let fword = || map(be_i16, FWord);
+        let (i, (version, x_avg_char_width, weight_class, width_class, fs_type)) = tuple((
+            context("Version", be_u16),
+            context("X Avg Char Width", fword()),
+            context("Weight Class", be_u16),
+            context("Width Class", be_u16),
+            context("Type", be_u16),
+        ))(input)?;
+        let (i, (subscript_x_size, subscript_y_size, subscript_x_offset, subscript_y_offset, superscript_x_size, superscript_y_size, superscript_x_offset, superscript_y_offset, strikeout_size, strikeout_position, family_class)) = tuple((
+            context("Subscript X Size", fword()),
+            context("Subscript Y Size", fword()),
+            context("Subscript X Offset", fword()),
+            context("Subscript Y Offset", fword()),
+            context("Superscript X Size", fword()),
+            context("Superscript Y Size", fword()),
+            context("Superscript X Offset", fword()),
+            context("Superscript Y Offset", fword()),
+            context("Strikeout Size", fword()),
+            context("Strikeout Position", fword()),
+            context("Family Class", be_i16),
+        ))(i)?;
+        let (i, (panose, unicode_ranges, vendor_id, selection, first_char_index, last_char_index)) = tuple((
+            context("Panose", map(take(10usize), |x: Input| x.try_into().unwrap())),
+            context("Unicode Ranges", map(tuple((be_u32, be_u32, be_u32, be_u32)), |(a, b, c, d)| {
+                a as u128 | (b as u128) << 32 | (c as u128) << 64 | (d as u128) << 96
+            })),
+            context("Vendor ID", map(take(4usize), |x| String::from_utf8_lossy(x).into_owned())),
+            context("Selection", map(be_u16, SelectionFlags::from_bits_truncate)),
+            context("First Char Index", be_u16),
+            context("Last Char Index", be_u16),
+        ))(i)?;
+        let (mut i, (typo_ascender, typo_descender, typo_line_gap, win_ascent, win_descent)) = tuple((
+            context("Typo Ascender", fword()),
+            context("Typo Descender", fword()),
+            context("Typo Line Gap", fword()),
+            context("Win Ascent", be_u16),
+            context("Win Descent", be_u16),
+        ))(i)?;
+        let mut table = Os2Table{
+            version,
+            x_avg_char_width,
+            weight_class,
+            width_class,
+            fs_type,
+            subscript_x_size,
+            subscript_y_size,
+            subscript_x_offset,
+            subscript_y_offset,
+            superscript_x_size,
+            superscript_y_size,
+            superscript_x_offset,
+            superscript_y_offset,
+            strikeout_size,
+            strikeout_position,
+            family_class,
+            panose,
+            unicode_ranges,
+            vendor_id,
+            selection,
+            first_char_index,
+            last_char_index,
+            typo_ascender,
+            typo_descender,
+            typo_line_gap,
+            win_ascent,
+            win_descent,
+            ..Default::default()
+        };
+        if version >= 1 {
+            let (j, code_page_ranges) = context("Code Page Ranges", map(tuple((be_u32, be_u32)), |(a, b)| a as u64 | (b as u64) << 32))(i)?;
+            i = j;
+            table.code_page_ranges = code_page_ranges;
+        }
+        if version >= 2 {
+            let (j, (x_height, cap_height, default_char, break_char, max_context)) = tuple((
+                context("X Height", fword()),
+                context("Cap Height", fword()),
+                context("Default Char", be_u16),
+                context("Break Char", be_u16),
+                context("Max Context", be_u16),
+            ))(i)?;
+            i = j;
+            table.x_height = x_height;
+            table.cap_height = cap_height;
+            table.default_char = default_char;
+            table.break_char = break_char;
+            table.max_context = max_context;
+        }
+        if version >= 5 {
+            let (j, (lower_optical_point_size, upper_optical_point_size)) = tuple((
+                context("Lower Optical Point Size", be_u16),
+                context("Upper Optical Point Size", be_u16),
+            ))(i)?;
+            i = j;
+            table.lower_optical_point_size = lower_optical_point_size;
+            table.upper_optical_point_size = upper_optical_point_size;
+        }
+        Ok((i, table))
+    }
+}
+
+// Names of the glyphs of the standard Macintosh character set, which version 1 of the post table
+// uses in this order and version 2 refers to by index.
+const STANDARD_GLYPH_NAMES: [&str; 258] = [
+    ".notdef", ".null", "nonmarkingreturn", "space", "exclam", "quotedbl", "numbersign", "dollar",
+    "percent", "ampersand", "quotesingle", "parenleft", "parenright", "asterisk", "plus", "comma",
+    "hyphen", "period", "slash", "zero", "one", "two", "three", "four", "five", "six", "seven",
+    "eight", "nine", "colon", "semicolon", "less", "equal", "greater", "question", "at", "A", "B",
+    "C", "D", "E", "F", "G", "H", "I", "J", "K", "L", "M", "N", "O", "P", "Q", "R", "S", "T", "U",
+    "V", "W", "X", "Y", "Z", "bracketleft", "backslash", "bracketright", "asciicircum",
+    "underscore", "grave", "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n",
+    "o", "p", "q", "r", "s", "t", "u", "v", "w", "x", "y", "z", "braceleft", "bar", "braceright",
+    "asciitilde", "Adieresis", "Aring", "Ccedilla", "Eacute", "Ntilde", "Odieresis", "Udieresis",
+    "aacute", "agrave", "acircumflex", "adieresis", "atilde", "aring", "ccedilla", "eacute",
+    "egrave", "ecircumflex", "edieresis", "iacute", "igrave", "icircumflex", "idieresis", "ntilde",
+    "oacute", "ograve", "ocircumflex", "odieresis", "otilde", "uacute", "ugrave", "ucircumflex",
+    "udieresis", "dagger", "degree", "cent", "sterling", "section", "bullet", "paragraph",
+    "germandbls", "registered", "copyright", "trademark", "acute", "dieresis", "notequal", "AE",
+    "Oslash", "infinity", "plusminus", "lessequal", "greaterequal", "yen", "mu", "partialdiff",
+    "summation", "product", "pi", "integral", "ordfeminine", "ordmasculine", "Omega", "ae",
+    "oslash", "questiondown", "exclamdown", "logicalnot", "radical", "florin", "approxequal",
+    "Delta", "guillemotleft", "guillemotright", "ellipsis", "nonbreakingspace", "Agrave", "Atilde",
+    "Otilde", "OE", "oe", "endash", "emdash", "quotedblleft", "quotedblright", "quoteleft",
+    "quoteright", "divide", "lozenge", "ydieresis", "Ydieresis", "fraction", "currency",
+    "guilsinglleft", "guilsinglright", "fi", "fl", "daggerdbl", "periodcentered", "quotesinglbase",
+    "quotedblbase", "perthousand", "Acircumflex", "Ecircumflex", "Aacute", "Edieresis", "Egrave",
+    "Iacute", "Icircumflex", "Idieresis", "Igrave", "Oacute", "Ocircumflex", "apple", "Ograve",
+    "Uacute", "Ucircumflex", "Ugrave", "dotlessi", "circumflex", "tilde", "macron", "breve",
+    "dotaccent", "ring", "cedilla", "hungarumlaut", "ogonek", "caron", "Lslash", "lslash", "Scaron",
+    "scaron", "Zcaron", "zcaron", "brokenbar", "Eth", "eth", "Yacute", "yacute", "Thorn", "thorn",
+    "minus", "multiply", "onesuperior", "twosuperior", "threesuperior", "onehalf", "onequarter",
+    "threequarters", "franc", "Gbreve", "gbreve", "Idotaccent", "Scedilla", "scedilla", "Cacute",
+    "cacute", "Ccaron", "ccaron", "dcroat",
+];
+
+impl PostScriptTable {
+    pub fn parse(input: Input) -> Result<Self> {
+        use nom::{
+            combinator::map,
+            error::context,
+            multi::{count, length_data, many0},
+            number::complete::{be_i8, be_i16, be_i32, be_u8, be_u16, be_u32},
+            sequence::tuple,
+        };
+        let (i, (version, italic_angle, underline_position, underline_thickness, is_fixed_pitch, _memory_usage)) = tuple((
+            context("Version", map(be_u32, FixedU32::<U16>::from_bits)),
+            context("Italic Angle", map(be_i32, FixedI32::<U16>::from_bits)),
+            context("Underline Position", map(be_i16, FWord)),
+            context("Underline Thickness", map(be_i16, FWord)),
+            context("Is Fixed Pitch", map(be_u32, |x| x != 0)),
+            context("Memory Usage", tuple((be_u32, be_u32, be_u32, be_u32))),
+        ))(input)?;
+        let error = || nom::Err::Error(nom::error::VerboseError{ errors: vec!((input, nom::error::VerboseErrorKind::Context("Glyph Name Index"))) });
+        let standard_name = |index: usize| STANDARD_GLYPH_NAMES.get(index).map(|name| name.to_string()).ok_or_else(error);
+        let (i, glyph_names) = match version.to_bits() {
+            0x00010000 => (i, STANDARD_GLYPH_NAMES.iter().map(|name| name.to_string()).collect()),
+            // Indices in the standard names, then in the Pascal strings that follow.
+            0x00020000 => {
+                let (i, nb_glyphs) = context("Nb Glyphs", be_u16)(i)?;
+                let (i, indices) = context("Glyph Name Indices", count(be_u16, nb_glyphs.into()))(i)?;
+                let (i, names) = context("Names", many0(length_data(be_u8)))(i)?;
+                let glyph_names = indices.iter().map(|&index| match usize::from(index).checked_sub(STANDARD_GLYPH_NAMES.len()) {
+                    None => standard_name(index.into()),
+                    Some(index) => names.get(index).map(|name| String::from_utf8_lossy(name).into_owned()).ok_or_else(error),
+                }).collect::<SimpleResult<_>>()?;
+                (i, glyph_names)
+            },
+            // Deprecated, offsets from the glyph index to its index in the standard names.
+            0x00025000 => {
+                let (i, nb_glyphs) = context("Nb Glyphs", be_u16)(i)?;
+                let (i, offsets) = context("Offsets", count(be_i8, nb_glyphs.into()))(i)?;
+                let glyph_names = offsets.iter().enumerate()
+                    .map(|(glyph, &offset)| standard_name((glyph as isize + offset as isize) as usize))
+                    .collect::<SimpleResult<_>>()?;
+                (i, glyph_names)
+            },
+            _ => (i, vec!()),
+        };
+        Ok((i, PostScriptTable{
+            version,
+            italic_angle,
+            underline_position,
+            underline_thickness,
+            is_fixed_pitch,
+            glyph_names,
+        }))
+    }
+}
+
+impl KerningSubtable {
+    // Pairs of format 0 subtables.
+    fn parse_pairs(input: Input) -> Result<HashMap<(u16, u16), i16>> {
+        use nom::{
+            error::context,
+            multi::count,
+            number::complete::{be_i16, be_u16},
+            sequence::tuple,
+        };
+        let (i, (nb_pairs, _search_range, _entry_selector, _range_shift)) = tuple((
+            context("Nb Pairs", be_u16),
+            context("Search Range", be_u16),
+            context("Entry Selector", be_u16),
+            context("Range Shift", be_u16),
+        ))(input)?;
+        let (i, pairs) = context("Kerning Pairs", count(tuple((be_u16, be_u16, be_i16)), nb_pairs.into()))(i)?;
+        Ok((i, pairs.into_iter().map(|(left, right, value)| ((left, right), value)).collect()))
+    }
+
+    // Microsoft subtables, in version 0 tables.
+    pub fn parse_microsoft(input: Input) -> Result<Self> {
+        use nom::{
+            bytes::complete::take,
+            error::context,
+            number::complete::be_u16,
+            sequence::tuple,
+        };
+        let (i, (_version, length, coverage)) = tuple((
+            context("Version", be_u16),
+            context("Length", be_u16),
+            context("Coverage", be_u16),
+        ))(input)?;
+        // Fonts with more than 10920 pairs overflow length, the number of pairs is the one to trust.
+        let (i, pairs) = match coverage >> 8 {
+            0 => KerningSubtable::parse_pairs(i)?,
+            _ => (context("Length", take(usize::from(length).saturating_sub(6)))(i)?.0, HashMap::new()),
+        };
+        Ok((i, KerningSubtable{
+            horizontal: coverage & 1 != 0,
+            minimum: coverage & 2 != 0,
+            cross_stream: coverage & 4 != 0,
+            replace: coverage & 8 != 0,
+            pairs,
+        }))
+    }
+
+    // Apple subtables, in version 1.0 tables.
+    pub fn parse_apple(input: Input) -> Result<Self> {
+        use nom::{
+            bytes::complete::take,
+            error::context,
+            number::complete::{be_u16, be_u32},
+            sequence::tuple,
+        };
+        let (i, (length, coverage, _tuple_index)) = tuple((
+            context("Length", be_u32),
+            context("Coverage", be_u16),
+            context("Tuple Index", be_u16),
+        ))(input)?;
+        let (i, pairs) = match coverage & 0xff {
+            0 => KerningSubtable::parse_pairs(i)?,
+            _ => (context("Length", take((length as usize).saturating_sub(8)))(i)?.0, HashMap::new()),
+        };
+        Ok((i, KerningSubtable{
+            horizontal: coverage & 0x8000 == 0,
+            minimum: false,
+            cross_stream: coverage & 0x4000 != 0,
+            replace: false,
+            pairs,
+        }))
+    }
+}
+
+impl KerningTable {
+    pub fn parse(input: Input) -> Result<Self> {
+        use nom::{
+            error::context,
+            number::complete::{be_u16, be_u32},
+            sequence::tuple,
+        };
+        let (_, version) = context("Version", be_u16)(input)?;
+        let mut subtables = vec!();
+        let i = if version == 0 {
+            let (i, (_version, nb_subtables)) = tuple((
+                context("Version", be_u16),
+                context("Nb Subtables", be_u16),
+            ))(input)?;
+            let mut i = i;
+            for _ in 0..nb_subtables {
+                let (j, subtable) = KerningSubtable::parse_microsoft(i)?;
+                subtables.push(subtable);
+                i = j;
+            }
+            i
+        } else {
+            let (i, (_version, nb_subtables)) = tuple((
+                context("Version", be_u32),
+                context("Nb Subtables", be_u32),
+            ))(input)?;
+            let mut i = i;
+            for _ in 0..nb_subtables {
+                let (j, subtable) = KerningSubtable::parse_apple(i)?;
+                subtables.push(subtable);
+                i = j;
+            }
+            i
+        };
+        Ok((i, KerningTable{
+            subtables,
+        }))
+    }
+
+    pub fn kerning(&self, left: u16, right: u16) -> i16 {
+        let mut value = 0;
+        for subtable in self.subtables.iter() {
+            if !subtable.horizontal || subtable.minimum || subtable.cross_stream {
+                continue;
+            }
+            if let Some(kerning) = subtable.pairs.get(&(left, right)) {
+                value = if subtable.replace { *kerning } else { value.saturating_add(*kerning) };
+            }
+        }
+        value
+    }
+}
+
+impl Coverage {
+    pub fn parse(input: Input) -> Result<Self> {
+        use nom::{
+            error::context,
+            multi::count,
+            number::complete::be_u16,
+            sequence::tuple,
+        };
+        let (i, (format, nb)) = tuple((
+            context("Coverage Format", be_u16),
+            context("Coverage Count", be_u16),
+        ))(input)?;
+        match format {
+            1 => {
+                let (i, glyphs) = context("Coverage Glyphs", count(be_u16, nb.into()))(i)?;
+                Ok((i, Coverage::Glyphs(glyphs)))
+            },
+            _ => {
+                let (i, ranges) = context("Coverage Ranges", count(tuple((be_u16, be_u16, be_u16)), nb.into()))(i)?;
+                Ok((i, Coverage::Ranges(ranges)))
+            },
+        }
+    }
+
+    pub fn index(&self, glyph: u16) -> Option<usize> {
+        match self {
+            Coverage::Glyphs(glyphs) => glyphs.binary_search(&glyph).ok(),
+            Coverage::Ranges(ranges) => {
+                let (first, _, index) = ranges.iter().find(|(first, last, _)| *first <= glyph && glyph <= *last)?;
+                Some(usize::from(*index) + usize::from(glyph - first))
+            },
+        }
+    }
+}
+
+impl ClassDefinition {
+    pub fn parse(input: Input) -> Result<Self> {
+        use nom::{
+            error::context,
+            multi::count,
+            number::complete::be_u16,
+            sequence::tuple,
+        };
+        let (i, format) = context("Class Format", be_u16)(input)?;
+        match format {
+            1 => {
+                let (i, (start_glyph, nb)) = tuple((
+                    context("Start Glyph", be_u16),
+                    context("Glyph Count", be_u16),
+                ))(i)?;
+                let (i, classes) = context("Classes", count(be_u16, nb.into()))(i)?;
+                Ok((i, ClassDefinition::Glyphs{ start_glyph, classes }))
+            },
+            _ => {
+                let (i, nb) = context("Class Range Count", be_u16)(i)?;
+                let (i, ranges) = context("Class Ranges", count(tuple((be_u16, be_u16, be_u16)), nb.into()))(i)?;
+                Ok((i, ClassDefinition::Ranges(ranges)))
+            },
+        }
+    }
+
+    // Glyphs not listed are in class 0.
+    pub fn class(&self, glyph: u16) -> u16 {
+        match self {
+            ClassDefinition::Glyphs{ start_glyph, classes } => {
+                glyph.checked_sub(*start_glyph).and_then(|n| classes.get(usize::from(n))).copied().unwrap_or(0)
+            },
+            ClassDefinition::Ranges(ranges) => {
+                ranges.iter().find(|(first, last, _)| *first <= glyph && glyph <= *last).map(|(_, _, class)| *class).unwrap_or(0)
+            },
+        }
+    }
+}
+
+impl PairAdjustment {
+    // Returns the X advance of a value record, which is made of the fields present in format.
+    fn parse_value_record(input: Input, format: u16) -> Result<i16> {
+        use nom::{
+            error::context,
+            number::complete::be_i16,
+        };
+        let mut i = input;
+        let mut x_advance = 0;
+        for bit in 0..8 {
+            if format & (1 << bit) != 0 {
+                let (j, value) = context("Value Record", be_i16)(i)?;
+                if bit == 2 {
+                    x_advance = value;
+                }
+                i = j;
+            }
+        }
+        Ok((i, x_advance))
+    }
+
+    // None for formats other than 1 and 2.
+    pub fn parse(subtable: Input) -> SimpleResult<Option<Self>> {
+        use nom::{
+            error::context,
+            multi::count,
+            number::complete::be_u16,
+            sequence::tuple,
+        };
+        let (i, (format, coverage_offset, value_format1, value_format2)) = tuple((
+            context("Pos Format", be_u16),
+            context("Coverage Offset", be_u16),
+            context("Value Format 1", be_u16),
+            context("Value Format 2", be_u16),
+        ))(subtable)?;
+        let (_, coverage) = Coverage::parse(at_offset(subtable, coverage_offset.into(), "Coverage Offset")?)?;
+        let value_records = |i| -> Result<i16> {
+            let (i, advance) = PairAdjustment::parse_value_record(i, value_format1)?;
+            let (i, _) = PairAdjustment::parse_value_record(i, value_format2)?;
+            Ok((i, advance))
+        };
+        match format {
+            1 => {
+                let (i, nb_pair_sets) = context("Pair Set Count", be_u16)(i)?;
+                let (_, offsets) = context("Pair Set Offsets", count(be_u16, nb_pair_sets.into()))(i)?;
+                let mut pair_sets = vec!();
+                for offset in offsets {
+                    let (j, nb_pairs) = context("Pair Value Count", be_u16)(at_offset(subtable, offset.into(), "Pair Set Offset")?)?;
+                    let (_, pairs) = context("Pair Value Records", count(tuple((be_u16, value_records)), nb_pairs.into()))(j)?;
+                    pair_sets.push(pairs);
+                }
+                Ok(Some(PairAdjustment::Glyphs{ coverage, pair_sets }))
+            },
+            2 => {
+                let (i, (first_classes_offset, second_classes_offset, nb_first_classes, nb_second_classes)) = tuple((
+                    context("Class Def 1 Offset", be_u16),
+                    context("Class Def 2 Offset", be_u16),
+                    context("Class 1 Count", be_u16),
+                    context("Class 2 Count", be_u16),
+                ))(i)?;
+                let (_, first_classes) = ClassDefinition::parse(at_offset(subtable, first_classes_offset.into(), "Class Def 1 Offset")?)?;
+                let (_, second_classes) = ClassDefinition::parse(at_offset(subtable, second_classes_offset.into(), "Class Def 2 Offset")?)?;
+                let nb_records = usize::from(nb_first_classes) * usize::from(nb_second_classes);
+                let (_, advances) = context("Class Records", count(value_records, nb_records))(i)?;
+                Ok(Some(PairAdjustment::Classes{ coverage, first_classes, second_classes, nb_second_classes, advances }))
+            },
+            _ => Ok(None),
+        }
+    }
+
+    // None if the pair isn't covered by this subtable.
+    pub fn advance(&self, first: u16, second: u16) -> Option<i16> {
+        match self {
+            PairAdjustment::Glyphs{ coverage, pair_sets } => {
+                let pairs = pair_sets.get(coverage.index(first)?)?;
+                let n = pairs.binary_search_by_key(&second, |(glyph, _)| *glyph).ok()?;
+                Some(pairs[n].1)
+            },
+            PairAdjustment::Classes{ coverage, first_classes, second_classes, nb_second_classes, advances } => {
+                coverage.index(first)?;
+                let n = usize::from(first_classes.class(first)) * usize::from(*nb_second_classes) + usize::from(second_classes.class(second));
+                advances.get(n).copied()
+            },
+        }
+    }
+}
+
+impl GlyphPositioningTable {
+    pub fn parse(input: Input) -> Result<Self> {
+        use nom::{
+            bytes::complete::take,
+            error::context,
+            multi::count,
+            number::complete::{be_u16, be_u32},
+            sequence::tuple,
+        };
+        let (i, (_major_version, _minor_version, _script_list_offset, feature_list_offset, lookup_list_offset)) = tuple((
+            context("Major Version", be_u16),
+            context("Minor Version", be_u16),
+            context("Script List Offset", be_u16),
+            context("Feature List Offset", be_u16),
+            context("Lookup List Offset", be_u16),
+        ))(input)?;
+
+        // Kerning lookups of all scripts and languages.
+        let feature_list = at_offset(input, feature_list_offset.into(), "Feature List Offset")?;
+        let (j, nb_features) = context("Feature Count", be_u16)(feature_list)?;
+        let (_, features) = context("Feature Records", count(tuple((take(4usize), be_u16)), nb_features.into()))(j)?;
+        let mut lookup_indices = vec!();
+        for (tag, offset) in features {
+            if tag != b"kern" {
+                continue;
+            }
+            let (j, (_feature_params, nb_lookups)) = tuple((
+                context("Feature Params", be_u16),
+                context("Lookup Index Count", be_u16),
+            ))(at_offset(feature_list, offset.into(), "Feature Offset")?)?;
+            let (_, indices) = context("Lookup List Indices", count(be_u16, nb_lookups.into()))(j)?;
+            lookup_indices.extend(indices);
+        }
+        lookup_indices.sort_unstable();
+        lookup_indices.dedup();
+
+        let lookup_list = at_offset(input, lookup_list_offset.into(), "Lookup List Offset")?;
+        let (j, nb_lookups) = context("Lookup Count", be_u16)(lookup_list)?;
+        let (_, lookup_offsets) = context("Lookup Offsets", count(be_u16, nb_lookups.into()))(j)?;
+        let mut kerning_lookups = vec!();
+        for index in lookup_indices {
+            let offset = *lookup_offsets.get(usize::from(index)).ok_or_else(|| {
+                nom::Err::Error(nom::error::VerboseError{ errors: vec!((lookup_list, nom::error::VerboseErrorKind::Context("Lookup List Index"))) })
+            })?;
+            let lookup = at_offset(lookup_list, offset.into(), "Lookup Offset")?;
+            let (j, (lookup_type, _lookup_flag, nb_subtables)) = tuple((
+                context("Lookup Type", be_u16),
+                context("Lookup Flag", be_u16),
+                context("Subtable Count", be_u16),
+            ))(lookup)?;
+            let (_, subtable_offsets) = context("Subtable Offsets", count(be_u16, nb_subtables.into()))(j)?;
+            let mut subtables = vec!();
+            for offset in subtable_offsets {
+                let mut subtable = at_offset(lookup, offset.into(), "Subtable Offset")?;
+                // Extension subtables (type 9) point to subtables of another type with a 32 bits offset.
+                let mut subtable_type = lookup_type;
+                if lookup_type == 9 {
+                    let (_, (_format, extension_type, extension_offset)) = tuple((
+                        context("Pos Format", be_u16),
+                        context("Extension Lookup Type", be_u16),
+                        context("Extension Offset", be_u32),
+                    ))(subtable)?;
+                    subtable_type = extension_type;
+                    subtable = at_offset(subtable, extension_offset, "Extension Offset")?;
+                }
+                if subtable_type == 2 {
+                    subtables.extend(PairAdjustment::parse(subtable)?);
+                }
+            }
+            kerning_lookups.push(subtables);
+        }
+        Ok((i, GlyphPositioningTable{
+            kerning_lookups,
+        }))
+    }
+
+    // None if the table has no kerning lookup, so that the kern table can be used instead.
+    pub fn kerning(&self, first: u16, second: u16) -> Option<i16> {
+        if self.kerning_lookups.is_empty() {
+            return None;
+        }
+        Some(self.kerning_lookups.iter()
+            .filter_map(|subtables| subtables.iter().find_map(|s| s.advance(first, second)))
+            .fold(0, i16::saturating_add))
+    }
+}
+
+// Required tables (for TrueType font, not necessarily for OpenType, bitmap...):
+// 'cmap'   character to glyph mapping
+// 'glyf'   glyph data
+// 'head'   font header
+// 'hhea'   horizontal header
+// 'hmtx'   horizontal metrics
+// 'loca'   index to location
+// 'maxp'   maximum profile
+// 'name'   naming
+// 'post'   PostScript
+impl TtfFile {
+    pub fn parse_table<'a, F, T>(input: Input<'a>, directory: TableDirectory, tag: &str, parser: F) -> SimpleResult<'a, Option<T>>
+        where F: Fn(Input) -> Result<T> {
+        let entry = &directory.entries.get(tag);
+        match entry {
+            Some(e) => {
+                let table = input.get(e.offset as usize..e.offset as usize + e.length as usize).ok_or_else(|| {
+                    nom::Err::Error(nom::error::VerboseError{ errors: vec!((input, nom::error::VerboseErrorKind::Context("Table Bounds"))) })
+                })?;
+                Ok(Some(parser(table)?.1))
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub fn parse(input: Input) -> Result<Self> {
+        TtfFile::parse_at(input, 0)
+    }
+
+    // The font whose directory is at offset, tables being at offsets from the start of the file
+    // in collections too.
+    pub fn parse_at(input: Input, offset: u32) -> Result<Self> {
+        let (i, font_directory) = FontDirectory::parse(at_offset(input, offset, "Font Directory")?)?;
+        let font_header = TtfFile::parse_table(input, font_directory.table_directory.clone(), "head", FontHeader::parse)?;
+        let max_profile = TtfFile::parse_table(input, font_directory.table_directory.clone(), "maxp", MaxProfile::parse)?;
+        let name_table = TtfFile::parse_table(input, font_directory.table_directory.clone(), "name", NameTable::parse)?;
+        let os2_table = TtfFile::parse_table(input, font_directory.table_directory.clone(), "OS/2", Os2Table::parse)?;
+        let post_table = TtfFile::parse_table(input, font_directory.table_directory.clone(), "post", PostScriptTable::parse)?;
+        let cmap_table = TtfFile::parse_table(input, font_directory.table_directory.clone(), "cmap", CharacterMapTable::parse)?;
+        let horizontal_header = TtfFile::parse_table(input, font_directory.table_directory.clone(), "hhea", HorizontalHeader::parse)?;
+        let horizontal_metrics = match (&horizontal_header, &max_profile) {
+            (Some(hhea), Some(maxp)) => TtfFile::parse_table(input, font_directory.table_directory.clone(), "hmtx", |i| HorizontalMetrics::parse(i, hhea.nb_long_hor_metrics, maxp.nb_glyphs))?,
+            _ => None,
+        };
+        let kern_table = TtfFile::parse_table(input, font_directory.table_directory.clone(), "kern", KerningTable::parse)?;
+        let gpos_table = TtfFile::parse_table(input, font_directory.table_directory.clone(), "GPOS", GlyphPositioningTable::parse)?;
+        let cvt_table = TtfFile::parse_table(input, font_directory.table_directory.clone(), "cvt ", ControlValueTable::parse)?;
+        let font_program = TtfFile::parse_table(input, font_directory.table_directory.clone(), "fpgm", |i| Ok((&i[i.len()..], i.to_vec())))?;
+        let control_value_program = TtfFile::parse_table(input, font_directory.table_directory.clone(), "prep", |i| Ok((&i[i.len()..], i.to_vec())))?;
+        let loca_table = match font_header.as_ref().map(|head| head.index_to_loc_format) {
+            Some(IndexToLocFormat::ShortOffsets) => TtfFile::parse_table(input, font_directory.table_directory.clone(), "loca", LocationTable::parse_short)?,
+            Some(IndexToLocFormat::LongOffsets) => TtfFile::parse_table(input, font_directory.table_directory.clone(), "loca", LocationTable::parse_long)?,
+            None => None,
+        };
+        let glyph_table = match (&loca_table, &max_profile) {
+            (Some(loca), Some(maxp)) => TtfFile::parse_table(input, font_directory.table_directory.clone(), "glyf", |i| GlyphTable::parse(i, loca, maxp.nb_glyphs))?,
+            _ => None,
+        };
+        let cff_table = TtfFile::parse_table(input, font_directory.table_directory.clone(), "CFF ", cff::CffTable::parse)?;
+        Ok((i, TtfFile{
+            font_directory,
+            font_header,
+            max_profile,
+            name_table,
+            os2_table,
+            post_table,
+            cmap_table,
+            horizontal_header,
+            horizontal_metrics,
+            kern_table,
+            gpos_table,
+            cvt_table,
+            font_program,
+            control_value_program,
+            glyph_table,
+            cff_table,
+        }))
+    }
+
+    // The fonts of a collection, or the only font of other files.
+    pub fn parse_collection(input: Input) -> Result<Vec<Self>> {
+        if !input.starts_with(b"ttcf") {
+            let (i, font) = TtfFile::parse(input)?;
+            return Ok((i, vec!(font)));
+        }
+        let (i, header) = CollectionHeader::parse(input)?;
+        let mut fonts = vec!();
+        for offset in header.offsets.iter() {
+            fonts.push(TtfFile::parse_at(input, *offset)?.1);
+        }
+        Ok((i, fonts))
+    }
+
+    pub fn units_per_em(&self) -> Option<u16> {
+        Some(self.font_header.as_ref()?.units_per_em)
+    }
+
+    // Index of the glyph for a character, None if the font doesn't have it (use glyph 0, the
+    // missing glyph, to show it).
+    pub fn glyph_index(&self, c: char) -> Option<u16> {
+        self.cmap_table.as_ref()?.glyph_index(c)
+    }
+
+    // Index of the glyph for a character followed by a variation selector (U+FE00 to U+FE0F or
+    // U+E0100 to U+E01EF), None if the font doesn't have this variation sequence.
+    pub fn glyph_variant_index(&self, c: char, selector: char) -> Option<u16> {
+        self.cmap_table.as_ref()?.glyph_variant_index(c, selector)
+    }
+
+    pub fn advance_width(&self, index: u16) -> Option<u16> {
+        self.horizontal_metrics.as_ref()?.advance_width(index)
+    }
+
+    pub fn left_side_bearing(&self, index: u16) -> Option<i16> {
+        self.horizontal_metrics.as_ref()?.left_side_bearing(index)
+    }
+
+    // Distance between baselines in FUnits.
+    pub fn line_height(&self) -> Option<i32> {
+        let hhea = self.horizontal_header.as_ref()?;
+        Some(hhea.ascent.0 as i32 - hhea.descent.0 as i32 + hhea.line_gap.0 as i32)
+    }
+
+    // Distance from the baseline to the top of the tallest glyphs, in FUnits.
+    pub fn ascent(&self) -> Option<i16> {
+        Some(self.horizontal_header.as_ref()?.ascent.0)
+    }
+
+    // Adjustment of the advance of left when followed by right, in FUnits. GPOS kerning takes
+    // precedence over the kern table, as it would have been generated from it.
+    pub fn kerning(&self, left: u16, right: u16) -> i16 {
+        if let Some(kerning) = self.gpos_table.as_ref().and_then(|gpos| gpos.kerning(left, right)) {
+            return kerning;
+        }
+        self.kern_table.as_ref().map_or(0, |kern| kern.kerning(left, right))
+    }
+
+    // Positions the glyphs of a line of text at size pixels per em. Characters followed by a
+    // variation selector use the glyph of the sequence if the font has one.
+    pub fn layout(&self, text: &str, size: f64) -> Vec<PositionedGlyph> {
+        let units_per_em = self.units_per_em().unwrap_or(2048);
+        let scale = size / units_per_em as f64;
+        let is_selector = |c: &char| matches!(*c, '\u{FE00}'..='\u{FE0F}' | '\u{E0100}'..='\u{E01EF}');
+        let mut glyphs: Vec<PositionedGlyph> = vec!();
+        let mut chars = text.chars().peekable();
+        let mut x = 0.0;
+        while let Some(c) = chars.next() {
+            let index = match chars.next_if(is_selector) {
+                Some(selector) => self.glyph_variant_index(c, selector).or_else(|| self.glyph_index(c)),
+                None => self.glyph_index(c),
+            };
+            // Characters the font doesn't have are shown with the missing glyph.
+            let index = index.unwrap_or(0);
+            if let Some(previous) = glyphs.last_mut() {
+                let kerning = self.kerning(previous.index, index) as f64 * scale;
+                previous.advance += kerning;
+                x += kerning;
+            }
+            let advance = self.advance_width(index).unwrap_or(units_per_em / 2) as f64 * scale;
+            glyphs.push(PositionedGlyph{ index, x, y: 0.0, advance });
+            x += advance;
+        }
+        glyphs
+    }
+
+    // Outline of a glyph, with the components of compound glyphs resolved. None for glyphs without
+    // an outline (space...) or with invalid components.
+    pub fn glyph_contours(&self, index: u16) -> Option<Vec<Contour>> {
+        if let Some(cff) = &self.cff_table {
+            return cff.glyph_contours(index);
+        }
+        self.resolve_contours(index, 0)
+    }
+
+    fn resolve_contours(&self, index: u16, depth: u32) -> Option<Vec<Contour>> {
+        // Fonts need very few levels, this only stops cycles.
+        const MAX_COMPONENT_DEPTH: u32 = 16;
+        if depth > MAX_COMPONENT_DEPTH {
+            return None;
+        }
+        match &self.glyph(index)?.glyph_data {
+            GlyphData::VoidGlyph => None,
+            GlyphData::SimpleGlyph{ contours, .. } => Some(contours.clone()),
+            GlyphData::CompoundGlyph{ components, .. } => {
+                let mut contours: Vec<Contour> = vec!();
+                for component in components.iter() {
+                    // Components can be empty glyphs, like a space in a ligature.
+                    let mut parts = match self.resolve_contours(component.glyph_index, depth + 1) {
+                        Some(parts) => parts,
+                        None if self.glyph(component.glyph_index).is_none() => continue,
+                        None => return None,
+                    };
+                    let (a, b, c, d) = (component.a.to_num::<f64>(), component.b.to_num::<f64>(), component.c.to_num::<f64>(), component.d.to_num::<f64>());
+                    let transform = |x: f64, y: f64| (a * x + c * y, b * x + d * y);
+                    for p in parts.iter_mut().flat_map(|c| c.points.iter_mut()) {
+                        let (x, y) = transform(p.x as f64, p.y as f64);
+                        p.x = x.round() as i32;
+                        p.y = y.round() as i32;
+                    }
+                    let (dx, dy) = match component.offset {
+                        // The offset is only transformed when asked to, as in Microsoft's
+                        // rasterizer (Apple's transforms it by default).
+                        ComponentOffset::Xy(x, y) if component.flags.contains(ComponentFlags::SCALED_COMPONENT_OFFSET) &&
+                            !component.flags.contains(ComponentFlags::UNSCALED_COMPONENT_OFFSET) => {
+                            let (x, y) = transform(x as f64, y as f64);
+                            (x.round() as i32, y.round() as i32)
+                        },
+                        ComponentOffset::Xy(x, y) => (x, y),
+                        ComponentOffset::Anchor{ compound_point, component_point } => {
+                            let compound = contours.iter().flat_map(|c| c.points.iter()).nth(compound_point.into())?;
+                            let component = parts.iter().flat_map(|c| c.points.iter()).nth(component_point.into())?;
+                            (compound.x - component.x, compound.y - component.y)
+                        },
+                    };
+                    for p in parts.iter_mut().flat_map(|c| c.points.iter_mut()) {
+                        p.x += dx;
+                        p.y += dy;
+                    }
+                    contours.extend(parts);
+                }
+                Some(contours)
+            },
+        }
+    }
+
+    // None for glyphs without an outline (space...).
+    pub fn glyph(&self, index: u16) -> Option<&Glyph> {
+        self.glyph_table.as_ref()?.glyph(index)
+    }
+
+    pub fn name(&self, id: KnownNameId) -> Option<&str> {
+        self.name_table.as_ref()?.name(id)
+    }
+
+    // From the post table, None if it doesn't name glyphs.
+    pub fn glyph_name(&self, index: u16) -> Option<&str> {
+        self.post_table.as_ref()?.glyph_names.get(usize::from(index)).map(|name| name.as_str())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    pub fn dejavu_sans() -> TtfFile {
+        TtfFile::parse(include_bytes!("../resources/DejaVuSans.ttf")).unwrap().1
+    }
+
+    fn be16(values: &[u16]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+
+    fn be32(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+
+    fn glyph_indexes(subtable: &[u8], characters: &[u32]) -> Vec<Option<u16>> {
+        let (_, subtable) = CmapSubtable::parse(subtable, PlatformId::Microsoft, 10).unwrap();
+        characters.iter().map(|&c| subtable.data.glyph_index(c)).collect()
+    }
+
+    fn cmap(subtables: &[(u16, u16, Vec<u8>)]) -> CharacterMapTable {
+        let mut table = be16(&[0, subtables.len() as u16]);
+        let mut offset = 4 + 8 * subtables.len();
+        for (platform_id, encoding_id, subtable) in subtables.iter() {
+            table.extend(be16(&[*platform_id, *encoding_id]));
+            table.extend(be32(&[offset as u32]));
+            offset += subtable.len();
+        }
+        for (_, _, subtable) in subtables.iter() {
+            table.extend_from_slice(subtable);
+        }
+        CharacterMapTable::parse(&table).unwrap().1
+    }
+
+    fn byte_encoding() -> Vec<u8> {
+        let mut glyph_ids = [0; 256];
+        glyph_ids[0x41] = 3;
+        glyph_ids[0xFF] = 4;
+        [be16(&[0, 262, 0]), glyph_ids.to_vec()].concat()
+    }
+
+    // A to C as glyphs 1 to 3 with a delta, 0x100 to 0x102 through glyph IDs, and the final 0xFFFF
+    // segment.
+    fn segment_mapping() -> Vec<u8> {
+        be16(&[
+            4, 46, 0, 6, 4, 1, 2,
+            0x43, 0x102, 0xFFFF, 0,
+            0x41, 0x100, 0xFFFF,
+            (-64_i16) as u16, 5, 1,
+            0, 4, 0,
+            10, 0, 12,
+        ])
+    }
+
+    fn segmented_coverage() -> Vec<u8> {
+        [be16(&[12, 0]), be32(&[40, 0, 2, 0x1F600, 0x1F602, 100, 0x41, 0x41, 7])].concat()
+    }
+
+    #[test]
+    fn test_cmap_formats() {
+        assert_eq!(glyph_indexes(&byte_encoding(), &[0x41, 0x42, 0xFF, 0x100]), vec!(Some(3), None, Some(4), None));
+        assert!(CmapSubtable::parse(&byte_encoding()[..200], PlatformId::Macintosh, 0).is_err());
+
+        let segments = segment_mapping();
+        let characters = [0x41, 0x43, 0x44, 0xFF, 0x100, 0x101, 0x102, 0x103, 0xFFFE, 0xFFFF, 0x10041];
+        assert_eq!(glyph_indexes(&segments, &characters), vec!(Some(1), Some(3), None, None, Some(15), None, Some(17), None, None, None, None));
+        let (_, subtable) = CmapSubtable::parse(&segments, PlatformId::Microsoft, 1).unwrap();
+        assert_eq!(subtable.data, CmapSubtableData::SegmentMapping{
+            segments: vec!(
+                CmapSegment{ start_code: 0x41, end_code: 0x43, id_delta: -64, id_range_offset: 0 },
+                CmapSegment{ start_code: 0x100, end_code: 0x102, id_delta: 5, id_range_offset: 4 },
+                CmapSegment{ start_code: 0xFFFF, end_code: 0xFFFF, id_delta: 1, id_range_offset: 0 },
+            ),
+            glyph_ids: vec!(10, 0, 12),
+        });
+        // Glyph IDs past the end of the subtable are missing.
+        let mut short = segments.clone();
+        short[3] = 42;
+        assert_eq!(glyph_indexes(&short, &[0x41, 0x100, 0x102]), vec!(Some(1), Some(15), None));
+
+        let trimmed = be16(&[6, 16, 0, 0x20, 3, 5, 6, 0]);
+        assert_eq!(glyph_indexes(&trimmed, &[0x1F, 0x20, 0x21, 0x22, 0x23]), vec!(None, Some(5), Some(6), None, None));
+
+        let groups = segmented_coverage();
+        assert_eq!(glyph_indexes(&groups, &[0x1F600, 0x1F602, 0x1F603, 0x41, 0x42]), vec!(Some(100), Some(102), None, Some(7), None));
+        assert!(CmapSubtable::parse(&groups[..30], PlatformId::Microsoft, 10).is_err());
+        // Glyph IDs past u16 or u32.
+        let overflowing = [be16(&[12, 0]), be32(&[28, 0, 1, 0x41, 0x42, 0xFFFFFFFF])].concat();
+        assert_eq!(glyph_indexes(&overflowing, &[0x41, 0x42]), vec!(None, None));
+
+        assert_eq!(glyph_indexes(&be16(&[2, 6, 0]), &[0x41]), vec!(None));
+    }
+
+    #[test]
+    fn test_cmap_subtables() {
+        // Format 12 is preferred, then format 4.
+        let table = cmap(&[(3, 1, segment_mapping()), (3, 10, segmented_coverage()), (1, 0, byte_encoding())]);
+        assert_eq!((table.glyph_index('A'), table.glyph_index('\u{100}'), table.glyph_index('\u{1F601}')), (Some(7), None, Some(101)));
+        assert_eq!(table.unicode, Some(1));
+        let table = cmap(&[(1, 0, byte_encoding()), (3, 1, segment_mapping())]);
+        assert_eq!((table.glyph_index('A'), table.glyph_index('\u{100}')), (Some(1), Some(15)));
+        // Mac Roman for ASCII only, and symbol fonts.
+        let table = cmap(&[(1, 0, byte_encoding())]);
+        assert_eq!((table.glyph_index('A'), table.glyph_index('\u{FF}')), (Some(3), None));
+        let symbol = [be16(&[4, 24, 0, 2, 2, 0, 0, 0xF041, 0, 0xF041]), be16(&[(-0xF040_i32) as u16, 0])].concat();
+        let table = cmap(&[(3, 0, symbol)]);
+        assert_eq!((table.glyph_index('A'), table.glyph_index('B')), (Some(1), None));
+
+        // Format 14: A and B use their default glyph with U+FE0F, U+263A has a glyph of its own.
+        let variations = [
+            be16(&[14]), be32(&[39, 1]), vec!(0, 0xFE, 0x0F), be32(&[21, 29]),
+            be32(&[1]), vec!(0, 0, 0x41, 1),
+            be32(&[1]), vec!(0, 0x26, 0x3A, 0, 42),
+        ].concat();
+        let table = cmap(&[(3, 1, segment_mapping()), (0, 5, variations)]);
+        assert_eq!(table.glyph_variant_index('A', '\u{FE0F}'), Some(1));
+        assert_eq!(table.glyph_variant_index('B', '\u{FE0F}'), Some(2));
+        assert_eq!(table.glyph_variant_index('\u{263A}', '\u{FE0F}'), Some(42));
+        assert_eq!(table.glyph_variant_index('C', '\u{FE0F}'), None);
+        assert_eq!(table.glyph_variant_index('A', '\u{FE0E}'), None);
+        assert_eq!(table.glyph_index('\u{263A}'), None);
+    }
+
+    #[test]
+    fn test_dejavu_sans_cmap() {
+        let ttf = dejavu_sans();
+        assert_eq!(ttf.glyph_index('A'), Some(36));
+        assert_eq!(ttf.glyph_index('é'), Some(171));
+        assert_eq!(ttf.glyph_index('\u{10FFFD}'), None);
+        assert_eq!(ttf.glyph_index('\u{E0100}'), None);
+    }
+
+    #[test]
+    fn test_layout() {
+        let ttf = dejavu_sans();
+        // At 2048 pixels per em, pixels are FUnits.
+        let layout = |text| ttf.layout(text, 2048.0).iter().map(|g| (g.index, g.x, g.advance)).collect::<Vec<_>>();
+        // A and V are 1401 wide, kerned by -131.
+        assert_eq!(layout("AV"), vec!((36, 0.0, 1270.0), (57, 1270.0, 1401.0)));
+        // T is 1251 wide, kerned by -348 before o.
+        assert_eq!(layout("To"), vec!((55, 0.0, 903.0), (82, 903.0, 1253.0)));
+        // Variation selectors without a sequence in the font are dropped, missing characters use
+        // glyph 0.
+        assert_eq!(layout("A\u{FE0F}\u{10FFFD}"), vec!((36, 0.0, 1401.0), (0, 1401.0, 1229.0)));
+
+        // GPOS and kern agree, the first is used.
+        let (a, v) = (ttf.glyph_index('A').unwrap(), ttf.glyph_index('V').unwrap());
+        assert_eq!(ttf.gpos_table.as_ref().unwrap().kerning(a, v), Some(-131));
+        assert_eq!(ttf.kern_table.as_ref().unwrap().kerning(v, a), -131);
+        assert_eq!((ttf.kerning(a, a), ttf.kerning(v, v)), (57, 0));
+        assert_eq!(ttf.layout("AV", 20.48)[1].x, 12.7);
+    }
+
+    #[test]
+    fn test_kerning_sums() {
+        // Kerning values adding up past i16 saturate.
+        let subtable = KerningSubtable{ horizontal: true, minimum: false, cross_stream: false, replace: false, pairs: HashMap::from([((1, 2), 30000)]) };
+        let kern = KerningTable{ subtables: vec!(subtable.clone(), subtable) };
+        assert_eq!(kern.kerning(1, 2), i16::MAX);
+        let lookup = vec!(PairAdjustment::Glyphs{ coverage: Coverage::Glyphs(vec!(1)), pair_sets: vec!(vec!((2, -30000))) });
+        let gpos = GlyphPositioningTable{ kerning_lookups: vec!(lookup.clone(), lookup) };
+        assert_eq!(gpos.kerning(1, 2), Some(i16::MIN));
+    }
+
+    #[test]
+    fn test_horizontal_metrics() {
+        // Glyphs after the long metrics have the last advance and their own left side bearing.
+        let hmtx = [be16(&[500, 10, 600]), be16(&[20, 30, (-40_i16) as u16])].concat();
+        let (_, metrics) = HorizontalMetrics::parse(&hmtx, 2, 4).unwrap();
+        let advances = (0..5).map(|g| metrics.advance_width(g)).collect::<Vec<_>>();
+        let bearings = (0..5).map(|g| metrics.left_side_bearing(g)).collect::<Vec<_>>();
+        assert_eq!(advances, vec!(Some(500), Some(600), Some(600), Some(600), Some(600)));
+        assert_eq!(bearings, vec!(Some(10), Some(20), Some(30), Some(-40), None));
+        assert!(HorizontalMetrics::parse(&hmtx[..10], 2, 4).is_err());
+
+        // DejaVu Sans has 6226 long metrics for 6241 glyphs.
+        let ttf = dejavu_sans();
+        assert_eq!((ttf.advance_width(6225), ttf.left_side_bearing(6225)), (Some(1508), Some(165)));
+        assert_eq!((ttf.advance_width(6235), ttf.left_side_bearing(6235)), (Some(1508), Some(-93)));
+        assert_eq!((ttf.advance_width(6240), ttf.left_side_bearing(6240)), (Some(1508), Some(151)));
+        assert_eq!(ttf.left_side_bearing(6241), None);
+    }
+
+    // One contour of on curve points, with word coordinates.
+    fn simple_glyph(points: &[(i16, i16)]) -> Vec<u8> {
+        let mut glyph = be16(&[1, 0, 0, 0, 0, points.len() as u16 - 1, 0]);
+        glyph.extend(vec![0x01; points.len()]);
+        for axis in [0, 1] {
+            let mut previous = 0;
+            for p in points.iter() {
+                let value = if axis == 0 { p.0 } else { p.1 };
+                glyph.extend((value - previous).to_be_bytes());
+                previous = value;
+            }
+        }
+        glyph
+    }
+
+    fn compound_glyph(components: &[u8]) -> Vec<u8> {
+        [be16(&[0xFFFF, 0, 0, 0, 0]).as_slice(), components].concat()
+    }
+
+    // Glyphs padded to even lengths, as with short locations.
+    fn glyph_table(glyphs: &[Vec<u8>]) -> GlyphTable {
+        let mut data = vec!();
+        let mut locations = vec!(0);
+        for glyph in glyphs.iter() {
+            data.extend_from_slice(glyph);
+            data.resize((data.len() + 1) & !1, 0);
+            locations.push(data.len() as u32);
+        }
+        GlyphTable::parse(&data, &LocationTable{ locations }, glyphs.len() as u16).unwrap().1
+    }
+
+    // DejaVu Sans with other glyphs.
+    fn with_glyphs(glyphs: &[Vec<u8>]) -> TtfFile {
+        TtfFile{ glyph_table: Some(glyph_table(glyphs)), ..dejavu_sans() }
+    }
+
+    fn points(contours: &[Contour]) -> Vec<Vec<(i32, i32)>> {
+        contours.iter().map(|c| c.points.iter().map(|p| (p.x, p.y)).collect()).collect()
+    }
+
+    fn error_context<O: fmt::Debug>(result: Result<O>) -> &'static str {
+        match result {
+            Err(nom::Err::Error(nom::error::VerboseError{ errors })) => match errors.last() {
+                Some((_, nom::error::VerboseErrorKind::Context(context))) => context,
+                error => panic!("{:?}", error),
+            },
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_glyph_table() {
+        let (triangle, square) = (simple_glyph(&[(0, 0), (10, 0), (0, 10)]), simple_glyph(&[(0, 0), (10, 0), (10, 10), (0, 10)]));
+        let data = [triangle.clone(), square.clone(), vec!(0xFF, 0xFF)].concat();
+        let (a, b) = (triangle.len() as u32, (triangle.len() + square.len()) as u32);
+        let loca = |locations: &[u32]| LocationTable{ locations: locations.to_vec() };
+
+        // Glyph 1 is empty, 4 doesn't parse.
+        let (_, table) = GlyphTable::parse(&data, &loca(&[0, a, a, b, b, b + 2]), 5).unwrap();
+        assert_eq!(table.glyph(0).map(|g| g.nb_contours), Some(1));
+        assert!(table.glyph(1).is_none());
+        assert_eq!(table.glyph(2), Some(&Glyph::parse(&square).unwrap().1));
+        assert_eq!(table.glyph(0), Some(&Glyph::parse(&triangle).unwrap().1));
+        assert!(table.glyph(3).is_none());
+        assert!(table.glyph(4).is_none());
+        assert!(table.glyph(5).is_none());
+
+        // Extra locations are ignored, missing ones aren't.
+        assert_eq!(GlyphTable::parse(&data, &loca(&[0, a, b, b + 2]), 2).unwrap().1.locations, vec!(0, a, b));
+        assert_eq!(error_context(GlyphTable::parse(&data, &loca(&[0, a, b]), 3)), "Glyph Locations");
+        assert_eq!(error_context(GlyphTable::parse(&data, &loca(&[0, b, a]), 2)), "Glyph Location Order");
+        assert_eq!(error_context(GlyphTable::parse(&data, &loca(&[0, a, b + 3]), 2)), "Glyph Location Bounds");
+        assert!(GlyphTable::parse(&data, &loca(&[0, a, b + 2]), 2).is_ok());
+
+        // Short locations are halved.
+        assert_eq!(LocationTable::parse_short(&be16(&[0, 6, 6, 0x8000])).unwrap().1.locations, vec!(0, 12, 12, 0x10000));
+        assert_eq!(LocationTable::parse_long(&be32(&[0, 6, 0x10000])).unwrap().1.locations, vec!(0, 6, 0x10000));
+    }
+
+    #[test]
+    fn test_dejavu_sans_glyphs() {
+        let ttf = dejavu_sans();
+        let table = ttf.glyph_table.as_ref().unwrap();
+        assert_eq!(table.glyphs.len(), 6241);
+        // Space is empty, and glyphs are only parsed when asked for.
+        assert!(ttf.glyph(ttf.glyph_index(' ').unwrap()).is_none());
+        assert!(table.glyphs[36].get().is_none());
+        let a = ttf.glyph(36).unwrap();
+        assert_eq!((a.nb_contours, a.x_min.0, a.y_min.0, a.x_max.0, a.y_max.0), (2, 16, 0, 1384, 1493));
+        assert!(table.glyphs[36].get().is_some());
+        assert!(ttf.glyph(6241).is_none());
+    }
+
+    #[test]
+    fn test_components() {
+        let components = [
+            // Word offsets.
+            be16(&[0x0023, 5, (-300_i16) as u16, 400]),
+            // Byte offsets and a scale of 0.5.
+            be16(&[0x002A, 6, 0xFF02, 0x2000]),
+            // Scale of 1 in x and -1 in y.
+            be16(&[0x0062, 7, 0, 0x4000, 0xC000]),
+            // Rotation by 90°.
+            be16(&[0x00A2, 8, 0x0101, 0, 0x4000, 0xC000, 0]),
+            // Byte then word anchor points, with the last component.
+            be16(&[0x0020, 9, 0x0300, 0x0001, 10, 300, 2]),
+        ].concat();
+        let data = compound_glyph(&[components.as_slice(), &[1, 2]].concat());
+        let (rest, glyph) = Glyph::parse(&data).unwrap();
+        assert_eq!(rest, &[1, 2]);
+        let components = match glyph.glyph_data {
+            GlyphData::CompoundGlyph{ components, .. } => components,
+            data => panic!("{:?}", data),
+        };
+        let fixed = |bits: i16| I2F14::from_bits(bits);
+        let summary = components.iter()
+            .map(|c| (c.glyph_index, c.offset.clone(), [c.a, c.b, c.c, c.d]))
+            .collect::<Vec<_>>();
+        let identity = [fixed(0x4000), fixed(0), fixed(0), fixed(0x4000)];
+        assert_eq!(summary, vec!(
+            (5, ComponentOffset::Xy(-300, 400), identity),
+            (6, ComponentOffset::Xy(-1, 2), [fixed(0x2000), fixed(0), fixed(0), fixed(0x2000)]),
+            (7, ComponentOffset::Xy(0, 0), [fixed(0x4000), fixed(0), fixed(0), fixed(-0x4000)]),
+            (8, ComponentOffset::Xy(1, 1), [fixed(0), fixed(0x4000), fixed(-0x4000), fixed(0)]),
+            (9, ComponentOffset::Anchor{ compound_point: 3, component_point: 0 }, identity),
+            (10, ComponentOffset::Anchor{ compound_point: 300, component_point: 2 }, identity),
+        ));
+
+        // Missing arguments or transforms.
+        assert!(Glyph::parse(&compound_glyph(&be16(&[0x0003, 5, 1]))).is_err());
+        assert!(Glyph::parse(&compound_glyph(&be16(&[0x000A, 5, 0]))).is_err());
+        assert!(Glyph::parse(&compound_glyph(&be16(&[0x0022, 5, 0]))).is_err());
+    }
+
+    #[test]
+    fn test_compound_contours() {
+        let square = simple_glyph(&[(0, 0), (100, 0), (100, 100), (0, 100)]);
+        let ttf = with_glyphs(&[
+            vec!(),
+            square,
+            compound_glyph(&[
+                be16(&[0x0023, 1, 10, 20]),
+                be16(&[0x002A, 1, 0x0000, 0x2000]),
+                be16(&[0x00A2, 1, 0, 0, 0x4000, 0xC000, 0]),
+                // Point 2 of the compound, (110, 120), on point 0 of the component.
+                be16(&[0x0000, 1, 0x0200]),
+            ].concat()),
+            // Empty components are skipped.
+            compound_glyph(&be16(&[0x0022, 0, 0x0101, 0x0002, 1, 0])),
+            // Scaled offsets.
+            compound_glyph(&be16(&[0x080A, 1, 0x1010, 0x2000])),
+            // Anchor points out of range.
+            compound_glyph(&be16(&[0x0022, 1, 0, 0x0000, 1, 0x0400])),
+        ]);
+        assert_eq!(points(&ttf.glyph_contours(2).unwrap()), vec!(
+            vec!((10, 20), (110, 20), (110, 120), (10, 120)),
+            vec!((0, 0), (50, 0), (50, 50), (0, 50)),
+            vec!((0, 0), (0, 100), (-100, 100), (-100, 0)),
+            vec!((110, 120), (210, 120), (210, 220), (110, 220)),
+        ));
+        assert_eq!(points(&ttf.glyph_contours(3).unwrap()), points(&ttf.glyph_contours(1).unwrap()));
+        assert_eq!(points(&ttf.glyph_contours(4).unwrap()), vec!(vec!((8, 8), (58, 8), (58, 58), (8, 58))));
+        assert_eq!(ttf.glyph_contours(5), None);
+        assert!(ttf.rasterize(2, 20.48).is_some());
+    }
+
+    #[test]
+    fn test_compound_recursion() {
+        let mut glyphs = vec!(vec!(), simple_glyph(&[(0, 0), (100, 0), (0, 100)]));
+        // Glyph 2 is its own component, 3 uses 2.
+        glyphs.push(compound_glyph(&be16(&[0x0002, 2, 0])));
+        glyphs.push(compound_glyph(&be16(&[0x0002, 2, 0])));
+        // Glyphs 4 to 24 each use the next one, 25 uses the simple glyph.
+        for next in 5..=25 {
+            glyphs.push(compound_glyph(&be16(&[0x0002, next, 0])));
+        }
+        glyphs.push(compound_glyph(&be16(&[0x0002, 1, 0])));
+        let ttf = with_glyphs(&glyphs);
+        assert_eq!((ttf.glyph_contours(2), ttf.glyph_contours(3)), (None, None));
+        // 16 levels of components are fine, not more.
+        assert_eq!(points(&ttf.glyph_contours(10).unwrap()), vec!(vec!((0, 0), (100, 0), (0, 100))));
+        assert_eq!(ttf.glyph_contours(9), None);
+
+        let mut hinter = Hinter::new(&ttf, 12).unwrap();
+        assert_eq!((hinter.glyph_contours(2), hinter.glyph_contours(9)), (None, None));
+        assert!(hinter.glyph_contours(10).is_some());
+    }
+
+    #[test]
+    fn test_dejavu_sans_compound() {
+        // é is e with the acute accent moved by 139 FUnits.
+        let ttf = dejavu_sans();
+        let (e, acute) = (ttf.glyph_index('e').unwrap(), 118);
+        let mut expected = ttf.glyph_contours(e).unwrap();
+        let accent = ttf.glyph_contours(acute).unwrap();
+        expected.extend(accent.iter().map(|c| Contour{
+            points: c.points.iter().map(|p| Point{ x: p.x + 139, ..p.clone() }).collect(),
+            cubic: false,
+        }));
+        assert!(!accent.is_empty());
+        assert_eq!(ttf.glyph_contours(ttf.glyph_index('é').unwrap()), Some(expected));
+    }
+
+    #[test]
+    fn test_decode_name() {
+        let roman = PlatformSpecificId::Macintosh(MacintoshPlatformSpecificId::Roman);
+        assert_eq!(decode_name(&roman, b"Caf\x8E \xAA\xD0"), "Café ™–");
+        let japanese = PlatformSpecificId::Macintosh(MacintoshPlatformSpecificId::Japanese);
+        assert_eq!(decode_name(&japanese, b"A\x82\xA0"), "A\u{FFFD}\u{FFFD}");
+
+        // UTF-16BE with a surrogate pair, a lone surrogate being replaced.
+        let utf16 = be16(&[0x0055, 0x00F1, 0xD835, 0xDC00, 0x0021]);
+        let bmp = PlatformSpecificId::Microsoft(MicrosoftPlatformSpecificId::UnicodeBmp);
+        assert_eq!(decode_name(&bmp, &utf16), "Uñ𝐀!");
+        let unicode = PlatformSpecificId::Unicode(UnicodePlatformSpecificId::Unicode2);
+        assert_eq!(decode_name(&unicode, &utf16), "Uñ𝐀!");
+        assert_eq!(decode_name(&unicode, &be16(&[0x0041, 0xD835])), "A\u{FFFD}");
+        let big5 = PlatformSpecificId::Microsoft(MicrosoftPlatformSpecificId::Big5);
+        assert_eq!(decode_name(&big5, &be16(&[0x0041, 0xA4A4])), "A\u{FFFD}");
+    }
+
+    #[test]
+    fn test_name_table() {
+        // A Macintosh Roman and a Microsoft UTF-16BE family name, after the 2 records.
+        let mac = b"Caf\x8E".to_vec();
+        let windows = be16(&[0x0043, 0x0061, 0x0066, 0x00E9, 0xD83D, 0xDE00]);
+        let mut data = be16(&[0, 2, 30]);
+        data.extend(be16(&[1, 0, 0, 1, mac.len() as u16, 0]));
+        data.extend(be16(&[3, 1, 0x0409, 1, windows.len() as u16, mac.len() as u16]));
+        data.extend(&mac);
+        data.extend(&windows);
+        let (_, table) = NameTable::parse(&data).unwrap();
+        let values = table.records.iter().map(|r| r.value.as_str()).collect::<Vec<_>>();
+        assert_eq!(values, ["Café", "Café😀"]);
+        assert_eq!(table.records[0].language_id, LanguageId::Macintosh(MacintoshLanguageId::English));
+        // American English on the Microsoft platform is preferred.
+        assert_eq!(table.name(KnownNameId::FontFamily), Some("Café😀"));
+        assert_eq!(table.name(KnownNameId::Version), None);
+
+        // Strings must be in the table.
+        data.truncate(data.len() - 1);
+        assert_eq!(error_context(NameTable::parse(&data)), "String");
+        // An offset past 65535 once added to the string offset.
+        data[16..18].copy_from_slice(&[0xFF, 0xFF]);
+        assert_eq!(error_context(NameTable::parse(&data)), "String");
+    }
+
+    // Header of a post table of the given version, for DejaVu Sans metrics.
+    fn post_header(version: u32) -> Vec<u8> {
+        let mut data = be32(&[version, 0]);
+        data.extend(be16(&[-130_i16 as u16, 90]));
+        data.extend(be32(&[0; 5]));
+        data
+    }
+
+    #[test]
+    fn test_post_glyph_names() {
+        // Standard Macintosh names below 258, then Pascal strings in order.
+        let mut data = post_header(0x0002_0000);
+        data.extend(be16(&[5, 0, 36, 259, 258, 171]));
+        data.extend(b"\x07uni0041\x03foo");
+        let (_, table) = PostScriptTable::parse(&data).unwrap();
+        assert_eq!(table.glyph_names, [".notdef", "A", "foo", "uni0041", "ellipsis"]);
+        assert_eq!((table.underline_position, table.underline_thickness), (FWord(-130), FWord(90)));
+
+        // Out of the names.
+        data[34 + 2 * 2..34 + 2 * 3].copy_from_slice(&260_u16.to_be_bytes());
+        assert_eq!(error_context(PostScriptTable::parse(&data)), "Glyph Name Index");
+
+        // Version 1 has the standard names, version 3 none.
+        let (_, table) = PostScriptTable::parse(&post_header(0x0001_0000)).unwrap();
+        assert_eq!((table.glyph_names.len(), table.glyph_names[3].as_str()), (258, "space"));
+        let (_, table) = PostScriptTable::parse(&post_header(0x0003_0000)).unwrap();
+        assert!(table.glyph_names.is_empty());
+
+        let ttf = dejavu_sans();
+        let names = [ttf.glyph_name(0), ttf.glyph_name(36), ttf.glyph_name(171), ttf.glyph_name(6241)];
+        assert_eq!(names, [Some(".notdef"), Some("A"), Some("eacute"), None]);
+    }
+
+    // Collection of two fonts sharing the tables of DejaVu Sans, moved after the header.
+    fn dejavu_sans_collection() -> Vec<u8> {
+        let font = include_bytes!("../resources/DejaVuSans.ttf");
+        let mut collection = b"ttcf".to_vec();
+        for value in [0x0001_0000_u32, 2, 20, 20] {
+            collection.extend_from_slice(&value.to_be_bytes());
+        }
+        let start = collection.len();
+        collection.extend_from_slice(font);
+        let nb_tables = u16::from_be_bytes([font[4], font[5]]) as usize;
+        for k in 0..nb_tables {
+            let offset = start + 12 + 16 * k + 8;
+            let moved = u32::from_be_bytes(collection[offset..offset + 4].try_into().unwrap()) + start as u32;
+            collection[offset..offset + 4].copy_from_slice(&moved.to_be_bytes());
+        }
+        collection
+    }
+
+    #[test]
+    fn test_collection_header() {
+        let collection = dejavu_sans_collection();
+        let (_, header) = CollectionHeader::parse(&collection).unwrap();
+        assert_eq!(header, CollectionHeader{ major_version: 1, minor_version: 0, offsets: vec!(20, 20) });
+        assert!(CollectionHeader::parse(&collection[..16]).is_err());
+        assert!(CollectionHeader::parse(b"true\0\x01\0\0\0\0\0\x01").is_err());
+
+        let (_, fonts) = TtfFile::parse_collection(&collection).unwrap();
+        assert_eq!(fonts.len(), 2);
+        let font = dejavu_sans();
+        for ttf in fonts.iter() {
+            assert_eq!((ttf.max_profile.as_ref().unwrap().nb_glyphs, ttf.name(KnownNameId::FontFamily)), (6241, Some("DejaVu Sans")));
+            assert_eq!(ttf.glyph_contours(ttf.glyph_index('A').unwrap()), font.glyph_contours(font.glyph_index('A').unwrap()));
+        }
+
+        // Other files are a collection of one font, and fonts must be in the file.
+        let (_, fonts) = TtfFile::parse_collection(include_bytes!("../resources/DejaVuSans.ttf")).unwrap();
+        assert_eq!(fonts, vec!(font));
+        let mut outside = collection;
+        outside[16..20].copy_from_slice(&1_000_000_u32.to_be_bytes());
+        assert!(TtfFile::parse_collection(&outside).is_err());
+    }
 }
@@ -0,0 +1,870 @@
+// Compact Font Format outlines of OpenType fonts ('CFF ' table), with Type 2 charstrings.
+// See Adobe's technical notes #5176 (CFF) and #5177 (Type 2 charstrings).
+use std::collections::HashMap;
+use std::fmt;
+
+use super::{Contour, Error, Input, Point, Result, SimpleResult};
+
+// DICT operators, two byte ones being 12 followed by their second byte.
+const CHAR_STRINGS: u16 = 17;
+const PRIVATE: u16 = 18;
+const SUBRS: u16 = 19;
+const CHARSTRING_TYPE: u16 = 0x0C06;
+const FD_ARRAY: u16 = 0x0C24;
+const FD_SELECT: u16 = 0x0C25;
+
+// Limits of Type 2 charstrings.
+const MAX_STACK: usize = 48;
+const MAX_SUBR_DEPTH: u32 = 10;
+const NB_TRANSIENTS: usize = 32;
+
+#[derive(PartialEq)]
+pub struct CffTable {
+    name: String,
+    char_strings: Vec<Vec<u8>>,
+    global_subrs: Vec<Vec<u8>>,
+    // Local subroutines of each Font DICT of CID-keyed fonts, or of the only Private DICT.
+    local_subrs: Vec<Vec<Vec<u8>>>,
+    // Font DICT of each glyph, empty if there's only one.
+    fd_select: Vec<u8>,
+}
+
+impl fmt::Debug for CffTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CffTable")
+            .field("name", &self.name)
+            .field("nb_char_strings", &self.char_strings.len())
+            .field("nb_global_subrs", &self.global_subrs.len())
+            .field("nb_local_subrs", &self.local_subrs.iter().map(|subrs| subrs.len()).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+fn error<'a>(input: Input<'a>, field: &'static str) -> nom::Err<Error<'a>> {
+    nom::Err::Error(nom::error::VerboseError{ errors: vec!((input, nom::error::VerboseErrorKind::Context(field))) })
+}
+
+// Data at offset from the start of the table.
+fn at<'a>(table: Input<'a>, offset: usize, field: &'static str) -> SimpleResult<'a, Input<'a>> {
+    table.get(offset..).ok_or_else(|| error(table, field))
+}
+
+// An INDEX: a count, the size of offsets, count + 1 offsets from the byte before the data,
+// then the data of the items.
+fn parse_index(input: Input) -> Result<Vec<Input>> {
+    use nom::{
+        error::context,
+        multi::count,
+        number::complete::{be_u8, be_u16},
+    };
+    let (i, nb_items) = context("Count", be_u16)(input)?;
+    if nb_items == 0 {
+        return Ok((i, vec!()));
+    }
+    let (i, offset_size) = context("Offset Size", be_u8)(i)?;
+    if !(1..=4).contains(&offset_size) {
+        return Err(error(i, "Offset Size"));
+    }
+    let (data, offsets) = context("Offsets", count(|i| parse_offset(i, offset_size), usize::from(nb_items) + 1))(i)?;
+    let mut items = vec!();
+    for pair in offsets.windows(2) {
+        let item = pair[0].checked_sub(1).zip(pair[1].checked_sub(1)).and_then(|(start, end)| data.get(start..end));
+        items.push(item.ok_or_else(|| error(data, "Offsets"))?);
+    }
+    let rest = offsets[offsets.len() - 1].checked_sub(1).and_then(|end| data.get(end..));
+    Ok((rest.ok_or_else(|| error(data, "Offsets"))?, items))
+}
+
+fn parse_offset(input: Input, size: u8) -> Result<usize> {
+    let (i, bytes) = nom::bytes::complete::take(size)(input)?;
+    Ok((i, bytes.iter().fold(0, |offset, &b| offset << 8 | b as usize)))
+}
+
+// Operands of each operator of a DICT.
+fn parse_dict(input: Input) -> SimpleResult<HashMap<u16, Vec<f64>>> {
+    let mut dict = HashMap::new();
+    let mut operands = vec!();
+    let mut i = input;
+    while let Some(&b0) = i.first() {
+        let bytes = |n| i.get(1..n).ok_or_else(|| error(i, "Operand"));
+        let (operand, size) = match b0 {
+            12 => {
+                let operator = 0x0C00 | bytes(2)?[0] as u16;
+                dict.insert(operator, std::mem::take(&mut operands));
+                i = &i[2..];
+                continue;
+            },
+            0..=21 => {
+                dict.insert(b0 as u16, std::mem::take(&mut operands));
+                i = &i[1..];
+                continue;
+            },
+            28 => (i16::from_be_bytes([bytes(3)?[0], bytes(3)?[1]]) as f64, 3),
+            29 => {
+                let b = bytes(5)?;
+                (i32::from_be_bytes([b[0], b[1], b[2], b[3]]) as f64, 5)
+            },
+            30 => parse_real(&i[1..]).ok_or_else(|| error(i, "Real Operand"))?,
+            32..=246 => (b0 as f64 - 139.0, 1),
+            247..=250 => ((b0 as f64 - 247.0) * 256.0 + bytes(2)?[0] as f64 + 108.0, 2),
+            251..=254 => (-(b0 as f64 - 251.0) * 256.0 - bytes(2)?[0] as f64 - 108.0, 2),
+            _ => return Err(error(i, "Operand")),
+        };
+        operands.push(operand);
+        i = &i[size..];
+    }
+    Ok(dict)
+}
+
+// A real number in nibbles, ending with 0xF. Returns it with its size, including the 30 before.
+fn parse_real(input: Input) -> Option<(f64, usize)> {
+    let mut number = String::new();
+    for (k, byte) in input.iter().enumerate() {
+        for nibble in [byte >> 4, byte & 0xF] {
+            match nibble {
+                0..=9 => number.push((b'0' + nibble) as char),
+                0xA => number.push('.'),
+                0xB => number.push('E'),
+                0xC => number.push_str("E-"),
+                0xE => number.push('-'),
+                0xF => return Some((number.parse().ok()?, k + 2)),
+                _ => return None,
+            }
+        }
+    }
+    None
+}
+
+// First operand of an operator, as an offset or a size.
+fn operand(dict: &HashMap<u16, Vec<f64>>, operator: u16) -> Option<usize> {
+    dict.get(&operator)?.first().filter(|o| **o >= 0.0).map(|o| *o as usize)
+}
+
+// Local subroutines of the Private DICT of a Top or Font DICT. Their offset is from the start of
+// the Private DICT.
+fn parse_private<'a>(input: Input<'a>, dict: &HashMap<u16, Vec<f64>>) -> SimpleResult<'a, Vec<Vec<u8>>> {
+    let (size, offset) = match dict.get(&PRIVATE).map(|o| o.as_slice()) {
+        Some(&[size, offset]) if size >= 0.0 && offset >= 0.0 => (size as usize, offset as usize),
+        _ => return Ok(vec!()),
+    };
+    let private_dict = offset.checked_add(size).and_then(|end| input.get(offset..end));
+    let private_dict = parse_dict(private_dict.ok_or_else(|| error(input, "Private DICT"))?)?;
+    match operand(&private_dict, SUBRS) {
+        Some(subrs) => {
+            let subrs = offset.checked_add(subrs).ok_or_else(|| error(input, "Local Subrs"))?;
+            Ok(parse_index(at(input, subrs, "Local Subrs")?)?.1.iter().map(|s| s.to_vec()).collect())
+        },
+        None => Ok(vec!()),
+    }
+}
+
+fn parse_fd_select(input: Input, nb_glyphs: usize) -> Result<Vec<u8>> {
+    use nom::{
+        error::context,
+        multi::count,
+        number::complete::{be_u8, be_u16},
+        sequence::tuple,
+    };
+    let (i, format) = context("FDSelect Format", be_u8)(input)?;
+    match format {
+        0 => context("FDSelect", count(be_u8, nb_glyphs))(i),
+        3 => {
+            let (i, nb_ranges) = context("FDSelect Ranges", be_u16)(i)?;
+            let (i, ranges) = context("FDSelect Ranges", count(tuple((be_u16, be_u8)), usize::from(nb_ranges)))(i)?;
+            let (i, sentinel) = context("FDSelect Sentinel", be_u16)(i)?;
+            let mut fd_select = vec!();
+            for (k, &(first, fd)) in ranges.iter().enumerate() {
+                let end = ranges.get(k + 1).map_or(sentinel, |r| r.0);
+                if first as usize != fd_select.len() || end < first {
+                    return Err(error(input, "FDSelect Ranges"));
+                }
+                fd_select.resize(usize::from(end), fd);
+            }
+            Ok((i, fd_select))
+        },
+        _ => Err(error(input, "FDSelect Format")),
+    }
+}
+
+impl CffTable {
+    pub fn parse(input: Input) -> Result<Self> {
+        use nom::{
+            error::context,
+            number::complete::be_u8,
+            sequence::tuple,
+        };
+        let (_, (_major, _minor, header_size)) = tuple((
+            context("Major", be_u8),
+            context("Minor", be_u8),
+            context("Header Size", be_u8),
+        ))(input)?;
+        let (i, names) = context("Name INDEX", parse_index)(at(input, header_size.into(), "Header Size")?)?;
+        let (i, top_dicts) = context("Top DICT INDEX", parse_index)(i)?;
+        let (i, _strings) = context("String INDEX", parse_index)(i)?;
+        let (_, global_subrs) = context("Global Subr INDEX", parse_index)(i)?;
+        // OpenType fonts have a single font in their CFF table.
+        let top_dict = parse_dict(top_dicts.first().ok_or_else(|| error(input, "Top DICT INDEX"))?)?;
+        if operand(&top_dict, CHARSTRING_TYPE).is_some_and(|t| t != 2) {
+            return Err(error(input, "Charstring Type"));
+        }
+        let char_strings = operand(&top_dict, CHAR_STRINGS).ok_or_else(|| error(input, "CharStrings"))?;
+        let (_, char_strings) = context("CharStrings INDEX", parse_index)(at(input, char_strings, "CharStrings")?)?;
+        let (local_subrs, fd_select) = match operand(&top_dict, FD_ARRAY) {
+            Some(fd_array) => {
+                let (_, font_dicts) = context("Font DICT INDEX", parse_index)(at(input, fd_array, "FDArray")?)?;
+                let mut local_subrs = vec!();
+                for font_dict in font_dicts.iter() {
+                    local_subrs.push(parse_private(input, &parse_dict(font_dict)?)?);
+                }
+                let fd_select = operand(&top_dict, FD_SELECT).ok_or_else(|| error(input, "FDSelect"))?;
+                let (_, fd_select) = parse_fd_select(at(input, fd_select, "FDSelect")?, char_strings.len())?;
+                (local_subrs, fd_select)
+            },
+            None => (vec!(parse_private(input, &top_dict)?), vec!()),
+        };
+        Ok((&input[input.len()..], CffTable{
+            name: names.first().map(|name| String::from_utf8_lossy(name).into_owned()).unwrap_or_default(),
+            char_strings: char_strings.iter().map(|c| c.to_vec()).collect(),
+            global_subrs: global_subrs.iter().map(|s| s.to_vec()).collect(),
+            local_subrs,
+            fd_select,
+        }))
+    }
+
+    // Outline of a glyph in FUnits, with cubic curves. None for glyphs without an outline or with
+    // an invalid charstring.
+    pub fn glyph_contours(&self, index: u16) -> Option<Vec<Contour>> {
+        let char_string = self.char_strings.get(usize::from(index))?;
+        let font_dict = self.fd_select.get(usize::from(index)).map_or(0, |fd| usize::from(*fd));
+        let mut machine = Machine{
+            global_subrs: &self.global_subrs,
+            local_subrs: self.local_subrs.get(font_dict)?,
+            stack: vec!(),
+            transients: [0.0; NB_TRANSIENTS],
+            nb_stems: 0,
+            width_parsed: false,
+            position: (0.0, 0.0),
+            points: vec!(),
+            contours: vec!(),
+        };
+        machine.execute(char_string, 0)?;
+        machine.close_contour();
+        if machine.contours.is_empty() {
+            None
+        } else {
+            Some(machine.contours)
+        }
+    }
+}
+
+// Subroutine numbers are biased to use more of the short operands.
+fn subr(subrs: &[Vec<u8>], number: f64) -> Option<&[u8]> {
+    let bias = match subrs.len() {
+        0..=1239 => 107,
+        1240..=33899 => 1131,
+        _ => 32768,
+    };
+    subrs.get(usize::try_from(number as i32 + bias).ok()?).map(|s| s.as_slice())
+}
+
+struct Machine<'a> {
+    global_subrs: &'a [Vec<u8>],
+    local_subrs: &'a [Vec<u8>],
+    stack: Vec<f64>,
+    transients: [f64; NB_TRANSIENTS],
+    // Hint masks have a bit per stem.
+    nb_stems: usize,
+    // The advance width can be before the arguments of the first stack clearing operator.
+    width_parsed: bool,
+    position: (f64, f64),
+    points: Vec<Point>,
+    contours: Vec<Contour>,
+}
+
+impl Machine<'_> {
+    fn push(&mut self, value: f64) -> Option<()> {
+        if self.stack.len() >= MAX_STACK {
+            return None;
+        }
+        self.stack.push(value);
+        Some(())
+    }
+
+    fn pop(&mut self) -> Option<f64> {
+        self.stack.pop()
+    }
+
+    // Drops the width if there's one more argument than the operator takes.
+    fn take_width(&mut self, has_width: bool) {
+        if !self.width_parsed {
+            self.width_parsed = true;
+            if has_width && !self.stack.is_empty() {
+                self.stack.remove(0);
+            }
+        }
+    }
+
+    fn add_point(&mut self, dx: f64, dy: f64, on_curve: bool) {
+        self.position = (self.position.0 + dx, self.position.1 + dy);
+        self.points.push(Point{ x: self.position.0.round() as i32, y: self.position.1.round() as i32, on_curve });
+    }
+
+    // Contours are closed by a line back to their start if they don't end on it.
+    fn close_contour(&mut self) {
+        if self.points.len() > 1 && self.points.first() == self.points.last() {
+            self.points.pop();
+        }
+        let points = std::mem::take(&mut self.points);
+        if points.len() > 1 {
+            self.contours.push(Contour{ points, cubic: true });
+        }
+    }
+
+    fn move_to(&mut self, dx: f64, dy: f64) {
+        self.close_contour();
+        self.add_point(dx, dy, true);
+    }
+
+    fn line_to(&mut self, dx: f64, dy: f64) -> Option<()> {
+        // Drawing must start with a moveto.
+        if self.points.is_empty() {
+            return None;
+        }
+        self.add_point(dx, dy, true);
+        Some(())
+    }
+
+    fn curve_to(&mut self, dx1: f64, dy1: f64, dx2: f64, dy2: f64, dx3: f64, dy3: f64) -> Option<()> {
+        if self.points.is_empty() {
+            return None;
+        }
+        self.add_point(dx1, dy1, false);
+        self.add_point(dx2, dy2, false);
+        self.add_point(dx3, dy3, true);
+        Some(())
+    }
+
+    // Stems and hint masks only matter for hinting, their number is needed to skip masks.
+    fn stems(&mut self) {
+        self.take_width(self.stack.len() % 2 == 1);
+        self.nb_stems += self.stack.len() / 2;
+        self.stack.clear();
+    }
+
+    // Returns true at endchar.
+    fn execute(&mut self, code: &[u8], depth: u32) -> Option<bool> {
+        if depth > MAX_SUBR_DEPTH {
+            return None;
+        }
+        let mut ip = 0;
+        while let Some(&b0) = code.get(ip) {
+            ip += 1;
+            match b0 {
+                28 => {
+                    let bytes = code.get(ip..ip + 2)?;
+                    self.push(i16::from_be_bytes([bytes[0], bytes[1]]) as f64)?;
+                    ip += 2;
+                },
+                32..=246 => self.push(b0 as f64 - 139.0)?,
+                247..=250 => {
+                    self.push((b0 as f64 - 247.0) * 256.0 + *code.get(ip)? as f64 + 108.0)?;
+                    ip += 1;
+                },
+                251..=254 => {
+                    self.push(-(b0 as f64 - 251.0) * 256.0 - *code.get(ip)? as f64 - 108.0)?;
+                    ip += 1;
+                },
+                // 16.16 fixed point number.
+                255 => {
+                    let bytes = code.get(ip..ip + 4)?;
+                    self.push(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64 / 65536.0)?;
+                    ip += 4;
+                },
+                // hstem, vstem, hstemhm, vstemhm
+                1 | 3 | 18 | 23 => self.stems(),
+                // hintmask, cntrmask, which can have the arguments of an implied vstemhm before.
+                19 | 20 => {
+                    self.stems();
+                    ip += self.nb_stems.div_ceil(8);
+                },
+                // rmoveto
+                21 => {
+                    self.take_width(self.stack.len() > 2);
+                    let (dx, dy) = (*self.stack.first()?, *self.stack.get(1)?);
+                    self.move_to(dx, dy);
+                    self.stack.clear();
+                },
+                // hmoveto, vmoveto
+                22 | 4 => {
+                    self.take_width(self.stack.len() > 1);
+                    let d = *self.stack.first()?;
+                    if b0 == 22 {
+                        self.move_to(d, 0.0);
+                    } else {
+                        self.move_to(0.0, d);
+                    }
+                    self.stack.clear();
+                },
+                // rlineto
+                5 => {
+                    for args in std::mem::take(&mut self.stack).chunks_exact(2) {
+                        self.line_to(args[0], args[1])?;
+                    }
+                },
+                // hlineto, vlineto: alternate horizontal and vertical lines.
+                6 | 7 => {
+                    let mut horizontal = b0 == 6;
+                    for d in std::mem::take(&mut self.stack) {
+                        if horizontal {
+                            self.line_to(d, 0.0)?;
+                        } else {
+                            self.line_to(0.0, d)?;
+                        }
+                        horizontal = !horizontal;
+                    }
+                },
+                // rrcurveto
+                8 => {
+                    for a in std::mem::take(&mut self.stack).chunks_exact(6) {
+                        self.curve_to(a[0], a[1], a[2], a[3], a[4], a[5])?;
+                    }
+                },
+                // rcurveline: curves then a line.
+                24 => {
+                    let args = std::mem::take(&mut self.stack);
+                    let (curves, line) = args.split_at(args.len().checked_sub(2)?);
+                    for a in curves.chunks_exact(6) {
+                        self.curve_to(a[0], a[1], a[2], a[3], a[4], a[5])?;
+                    }
+                    self.line_to(line[0], line[1])?;
+                },
+                // rlinecurve: lines then a curve.
+                25 => {
+                    let args = std::mem::take(&mut self.stack);
+                    let (lines, a) = args.split_at(args.len().checked_sub(6)?);
+                    for line in lines.chunks_exact(2) {
+                        self.line_to(line[0], line[1])?;
+                    }
+                    self.curve_to(a[0], a[1], a[2], a[3], a[4], a[5])?;
+                },
+                // vvcurveto, hhcurveto: vertical or horizontal tangents at both ends, except
+                // at the start of the first curve if the number of arguments is odd.
+                26 | 27 => {
+                    let args = std::mem::take(&mut self.stack);
+                    let (mut d1, curves) = match args.len() % 2 {
+                        1 => (args[0], &args[1..]),
+                        _ => (0.0, &args[..]),
+                    };
+                    for a in curves.chunks_exact(4) {
+                        if b0 == 26 {
+                            self.curve_to(d1, a[0], a[1], a[2], 0.0, a[3])?;
+                        } else {
+                            self.curve_to(a[0], d1, a[1], a[2], a[3], 0.0)?;
+                        }
+                        d1 = 0.0;
+                    }
+                },
+                // vhcurveto, hvcurveto: alternate vertical and horizontal tangents, a fifth
+                // argument of the last curve ending it in any direction.
+                30 | 31 => {
+                    let args = std::mem::take(&mut self.stack);
+                    let mut horizontal = b0 == 31;
+                    let mut k = 0;
+                    while k + 4 <= args.len() {
+                        let a = &args[k..k + 4];
+                        let last = if args.len() - k == 5 { args[k + 4] } else { 0.0 };
+                        if horizontal {
+                            self.curve_to(a[0], 0.0, a[1], a[2], last, a[3])?;
+                        } else {
+                            self.curve_to(0.0, a[0], a[1], a[2], a[3], last)?;
+                        }
+                        horizontal = !horizontal;
+                        k += 4;
+                    }
+                },
+                // callsubr, callgsubr
+                10 | 29 => {
+                    let number = self.pop()?;
+                    let subrs = if b0 == 10 { self.local_subrs } else { self.global_subrs };
+                    if self.execute(subr(subrs, number)?, depth + 1)? {
+                        return Some(true);
+                    }
+                },
+                // return
+                11 => return Some(false),
+                // endchar, whose accent arguments (seac) aren't supported.
+                14 => {
+                    self.take_width(self.stack.len() == 1 || self.stack.len() == 5);
+                    self.stack.clear();
+                    return Some(true);
+                },
+                12 => {
+                    let b1 = *code.get(ip)?;
+                    ip += 1;
+                    self.execute_escape(b1)?;
+                },
+                _ => return None,
+            }
+        }
+        Some(false)
+    }
+
+    fn execute_escape(&mut self, b1: u8) -> Option<()> {
+        let truth = |b: bool| if b { 1.0 } else { 0.0 };
+        match b1 {
+            // flex
+            35 => {
+                let a = std::mem::take(&mut self.stack);
+                let a = a.get(..12)?;
+                self.curve_to(a[0], a[1], a[2], a[3], a[4], a[5])?;
+                self.curve_to(a[6], a[7], a[8], a[9], a[10], a[11])?;
+            },
+            // hflex
+            34 => {
+                let a = std::mem::take(&mut self.stack);
+                let a = a.get(..7)?;
+                self.curve_to(a[0], 0.0, a[1], a[2], a[3], 0.0)?;
+                self.curve_to(a[4], 0.0, a[5], -a[2], a[6], 0.0)?;
+            },
+            // hflex1
+            36 => {
+                let a = std::mem::take(&mut self.stack);
+                let a = a.get(..9)?;
+                self.curve_to(a[0], a[1], a[2], a[3], a[4], 0.0)?;
+                self.curve_to(a[5], 0.0, a[6], a[7], a[8], -(a[1] + a[3] + a[7]))?;
+            },
+            // flex1: the last point is back to the start on the axis it moved least along.
+            37 => {
+                let a = std::mem::take(&mut self.stack);
+                let a = a.get(..11)?;
+                let dx = a[0] + a[2] + a[4] + a[6] + a[8];
+                let dy = a[1] + a[3] + a[5] + a[7] + a[9];
+                let (dx6, dy6) = if dx.abs() > dy.abs() { (a[10], -dy) } else { (-dx, a[10]) };
+                self.curve_to(a[0], a[1], a[2], a[3], a[4], a[5])?;
+                self.curve_to(a[6], a[7], a[8], a[9], dx6, dy6)?;
+            },
+            // and, or, not
+            3 => {
+                let (b, a) = (self.pop()?, self.pop()?);
+                self.push(truth(a != 0.0 && b != 0.0))?;
+            },
+            4 => {
+                let (b, a) = (self.pop()?, self.pop()?);
+                self.push(truth(a != 0.0 || b != 0.0))?;
+            },
+            5 => {
+                let a = self.pop()?;
+                self.push(truth(a == 0.0))?;
+            },
+            // abs, add, sub, div, neg, eq
+            9 => {
+                let a = self.pop()?;
+                self.push(a.abs())?;
+            },
+            10 => {
+                let (b, a) = (self.pop()?, self.pop()?);
+                self.push(a + b)?;
+            },
+            11 => {
+                let (b, a) = (self.pop()?, self.pop()?);
+                self.push(a - b)?;
+            },
+            12 => {
+                let (b, a) = (self.pop()?, self.pop()?);
+                self.push(if b == 0.0 { 0.0 } else { a / b })?;
+            },
+            14 => {
+                let a = self.pop()?;
+                self.push(-a)?;
+            },
+            15 => {
+                let (b, a) = (self.pop()?, self.pop()?);
+                self.push(truth(a == b))?;
+            },
+            // drop
+            18 => {
+                self.pop()?;
+            },
+            // put, get
+            20 => {
+                let (i, value) = (self.pop()?, self.pop()?);
+                *self.transients.get_mut(usize::try_from(i as i32).ok()?)? = value;
+            },
+            21 => {
+                let i = self.pop()?;
+                self.push(*self.transients.get(usize::try_from(i as i32).ok()?)?)?;
+            },
+            // ifelse
+            22 => {
+                let (v2, v1, s2, s1) = (self.pop()?, self.pop()?, self.pop()?, self.pop()?);
+                self.push(if v1 <= v2 { s1 } else { s2 })?;
+            },
+            // random, which only needs to be in (0, 1], is made repeatable.
+            23 => self.push(0.5)?,
+            // mul, sqrt
+            24 => {
+                let (b, a) = (self.pop()?, self.pop()?);
+                self.push(a * b)?;
+            },
+            26 => {
+                let a = self.pop()?;
+                self.push(a.abs().sqrt())?;
+            },
+            // dup, exch
+            27 => {
+                let a = *self.stack.last()?;
+                self.push(a)?;
+            },
+            28 => {
+                let (b, a) = (self.pop()?, self.pop()?);
+                self.push(b)?;
+                self.push(a)?;
+            },
+            // index: a copy of the element i below the top, the top for a negative i.
+            29 => {
+                let i = self.pop()?.max(0.0) as usize;
+                let a = *self.stack.get(self.stack.len().checked_sub(i.checked_add(1)?)?)?;
+                self.push(a)?;
+            },
+            // roll: the top n elements by j positions up.
+            30 => {
+                let (j, n) = (self.pop()? as i32, self.pop()?);
+                let n = usize::try_from(n as i32).ok().filter(|n| *n <= self.stack.len())?;
+                if n > 0 {
+                    let start = self.stack.len() - n;
+                    let j = j.rem_euclid(n as i32) as usize;
+                    self.stack[start..].rotate_right(j);
+                }
+            },
+            // The deprecated dotsection does nothing.
+            0 => self.stack.clear(),
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ttf::tests::dejavu_sans;
+    use crate::ttf::TtfFile;
+
+    // Charstring operands, in one byte or as a 16 bits integer.
+    fn args(values: &[i32]) -> Vec<u8> {
+        values.iter().flat_map(|&v| match v {
+            -107..=107 => vec!((v + 139) as u8),
+            _ => [[28].as_slice(), &(v as i16).to_be_bytes()].concat(),
+        }).collect()
+    }
+
+    fn machine<'a>(global_subrs: &'a [Vec<u8>], local_subrs: &'a [Vec<u8>]) -> Machine<'a> {
+        Machine{
+            global_subrs,
+            local_subrs,
+            stack: vec!(),
+            transients: [0.0; NB_TRANSIENTS],
+            nb_stems: 0,
+            width_parsed: false,
+            position: (0.0, 0.0),
+            points: vec!(),
+            contours: vec!(),
+        }
+    }
+
+    fn outline_with_subrs(char_string: &[u8], global_subrs: Vec<Vec<u8>>, local_subrs: Vec<Vec<u8>>) -> Option<Vec<Vec<(i32, i32, bool)>>> {
+        let cff = CffTable{
+            name: "Test".to_string(),
+            char_strings: vec!(char_string.to_vec()),
+            global_subrs,
+            local_subrs: vec!(local_subrs),
+            fd_select: vec!(),
+        };
+        let contours = cff.glyph_contours(0)?;
+        assert!(contours.iter().all(|c| c.cubic));
+        Some(contours.iter().map(|c| c.points.iter().map(|p| (p.x, p.y, p.on_curve)).collect()).collect())
+    }
+
+    fn outline(char_string: &[u8]) -> Option<Vec<Vec<(i32, i32, bool)>>> {
+        outline_with_subrs(char_string, vec!(), vec!())
+    }
+
+    #[test]
+    fn test_parse_index() {
+        let index = [0, 2, 1, 1, 3, 4, b'a', b'b', b'c', 9];
+        assert_eq!(parse_index(&index), Ok((&[9][..], vec!(&b"ab"[..], &b"c"[..]))));
+        assert_eq!(parse_index(&[0, 0, 7]), Ok((&[7][..], vec!())));
+        assert_eq!(parse_index(&[0, 1, 2, 0, 1, 0, 3, b'x', b'y']), Ok((&[][..], vec!(&b"xy"[..]))));
+
+        // Offset size, offsets past the data or before the first item, truncated offsets.
+        assert!(parse_index(&[0, 1, 5, 0, 0, 0, 0, 1, 0, 0, 0, 0, 2, 0]).is_err());
+        assert!(parse_index(&[0, 1, 1, 1, 5, b'a']).is_err());
+        assert!(parse_index(&[0, 1, 1, 0, 1, b'a']).is_err());
+        assert!(parse_index(&[0, 2, 1, 1]).is_err());
+
+        // Corrupted offsets: a zero offset, then offsets going backwards.
+        assert!(parse_index(&[0, 1, 1, 1, 0, b'a']).is_err());
+        assert!(parse_index(&[0, 2, 1, 1, 3, 2, b'a', b'b']).is_err());
+    }
+
+    #[test]
+    fn test_parse_dict() {
+        // 0 and 108 for CharStrings, then 1.2 and -2.5E-3 for CharstringType.
+        let dict = [139, 247, 0, 17, 30, 0x1A, 0x2F, 30, 0xE2, 0xA5, 0xC3, 0xFF, 12, 6];
+        let expected = HashMap::from([(CHAR_STRINGS, vec!(0.0, 108.0)), (CHARSTRING_TYPE, vec!(1.2, -2.5E-3))]);
+        assert_eq!(parse_dict(&dict), Ok(expected));
+        let dict = parse_dict(&[251, 0, 28, 0x12, 0x34, 29, 0, 1, 0, 0, 18, 29, 0xFF, 0xFF, 0xFF, 0xFF, 19]).unwrap();
+        assert_eq!(dict[&PRIVATE], vec!(-108.0, 0x1234 as f64, 65536.0));
+        assert_eq!((operand(&dict, PRIVATE), operand(&dict, SUBRS), operand(&dict, FD_ARRAY)), (None, None, None));
+        assert_eq!(operand(&parse_dict(&[28, 0x12, 0x34, 17]).unwrap(), CHAR_STRINGS), Some(0x1234));
+
+        assert!(parse_dict(&[28, 1]).is_err());
+        // Private DICT ranges overflowing once cast.
+        assert!(parse_private(&[0; 8], &HashMap::from([(PRIVATE, vec!(1.0, 1E30))])).is_err());
+        assert!(parse_private(&[0; 8], &HashMap::from([(PRIVATE, vec!(1E30, 1.0))])).is_err());
+        assert!(parse_dict(&[255, 17]).is_err());
+        assert!(parse_dict(&[30, 0x1A]).is_err());
+        assert!(parse_dict(&[12]).is_err());
+    }
+
+    #[test]
+    fn test_parse_fd_select() {
+        assert_eq!(parse_fd_select(&[0, 1, 0, 1], 3), Ok((&[][..], vec!(1, 0, 1))));
+        assert_eq!(parse_fd_select(&[3, 0, 2, 0, 0, 1, 0, 2, 0, 0, 3], 3), Ok((&[][..], vec!(1, 1, 0))));
+        // Ranges must start at glyph 0 and follow each other.
+        assert!(parse_fd_select(&[3, 0, 1, 0, 1, 0, 0, 3], 3).is_err());
+        assert!(parse_fd_select(&[3, 0, 2, 0, 0, 1, 0, 4, 0, 0, 3], 3).is_err());
+        assert!(parse_fd_select(&[1, 0, 0], 3).is_err());
+    }
+
+    #[test]
+    fn test_move_and_line() {
+        // rmoveto, rlineto, endchar.
+        let square = [args(&[10, 20]), vec!(21), args(&[100, 0, 0, 100, -100, 0]), vec!(5, 14)].concat();
+        let expected = vec!(vec!((10, 20, true), (110, 20, true), (110, 120, true), (10, 120, true)));
+        assert_eq!(outline(&square), Some(expected.clone()));
+        // With the width, and a line back to the start, which isn't kept.
+        let square = [args(&[500, 10, 20]), vec!(21), args(&[100, 0, 0, 100, -100, 0, 0, -100]), vec!(5, 14)].concat();
+        assert_eq!(outline(&square), Some(expected));
+
+        // hmoveto, vlineto, hlineto, then another contour.
+        let two = [args(&[300, 10]), vec!(22), args(&[50, 50]), vec!(7), args(&[0, 0]), vec!(21), args(&[-50, -50]), vec!(6, 14)].concat();
+        assert_eq!(outline(&two), Some(vec!(
+            vec!((10, 0, true), (10, 50, true), (60, 50, true)),
+            vec!((60, 50, true), (10, 50, true), (10, 0, true)),
+        )));
+
+        // Drawing must start with a moveto, and unknown operators are errors.
+        assert_eq!(outline(&[args(&[1, 1]), vec!(5, 14)].concat()), None);
+        assert_eq!(outline(&[args(&[0, 0]), vec!(21, 2, 14)].concat()), None);
+    }
+
+    #[test]
+    fn test_curve() {
+        let curves = [args(&[0, 0]), vec!(21), args(&[10, 0, 20, 10, 20, 30, 0, 20, -30, 20, -20, -20]), vec!(8, 14)].concat();
+        assert_eq!(outline(&curves), Some(vec!(vec!(
+            (0, 0, true), (10, 0, false), (30, 10, false), (50, 40, true), (50, 60, false), (20, 80, false), (0, 60, true),
+        ))));
+        // hvcurveto with a last curve ending in any direction.
+        let curves = [args(&[0, 0]), vec!(21), args(&[10, 20, 30, 40, 50, 60, 70, 80, 5]), vec!(31, 14)].concat();
+        assert_eq!(outline(&curves), Some(vec!(vec!(
+            (0, 0, true), (10, 0, false), (30, 30, false), (30, 70, true), (30, 120, false), (90, 190, false), (170, 195, true),
+        ))));
+    }
+
+    #[test]
+    fn test_hint_mask() {
+        // 2 stems of hstemhm and 1 implied vstemhm: the mask of hintmask is 1 byte, which would be
+        // an rmoveto if it wasn't skipped.
+        let triangle = [args(&[0, 0]), vec!(21), args(&[10, 0, 0, 10]), vec!(5, 14)].concat();
+        let masked = [args(&[10, 20, 30, 40]), vec!(18), args(&[50, 60]), vec!(19, 21), triangle.clone()].concat();
+        let expected = Some(vec!(vec!((0, 0, true), (10, 0, true), (10, 10, true))));
+        assert_eq!(outline(&masked), expected);
+        // 9 stems and a 2 bytes mask.
+        let masked = [args(&[1; 18]), vec!(18, 19, 21, 21), triangle.clone()].concat();
+        assert_eq!(outline(&masked), expected);
+        // A width before hstem, then cntrmask with 1 stem.
+        let masked = [args(&[500, 10, 20]), vec!(1, 20, 21), triangle].concat();
+        assert_eq!(outline(&masked), expected);
+    }
+
+    #[test]
+    fn test_subroutines() {
+        let local = vec!([args(&[10, 0]), vec!(5, 11)].concat(), vec!(14));
+        let global = vec!([args(&[0, 10]), vec!(5, 11)].concat());
+        // Subroutine 0 is -107 with less than 1240 of them, endchar in a subroutine ends the glyph.
+        let calls = [args(&[0, 0]), vec!(21), args(&[-107]), vec!(10), args(&[-107]), vec!(29), args(&[-106]), vec!(10), args(&[99, 99]), vec!(5)].concat();
+        let expected = Some(vec!(vec!((0, 0, true), (10, 0, true), (10, 10, true))));
+        assert_eq!(outline_with_subrs(&calls, global.clone(), local.clone()), expected);
+        assert_eq!(outline_with_subrs(&[args(&[0, 0]), vec!(21), args(&[-105]), vec!(10)].concat(), global.clone(), local), None);
+
+        // Bias of 1131 and 32768.
+        let mut many = vec!(vec!(11); 1240);
+        many[0] = [args(&[10, 0, 0, 10]), vec!(5, 11)].concat();
+        let calls = [args(&[0, 0]), vec!(21), args(&[-1131]), vec!(10, 14)].concat();
+        assert_eq!(outline_with_subrs(&calls, global.clone(), many.clone()), expected);
+        many.resize(33900, vec!(11));
+        let calls = [args(&[0, 0]), vec!(21), args(&[-32768]), vec!(10, 14)].concat();
+        assert_eq!(outline_with_subrs(&calls, global, many), expected);
+
+        // Endless recursion.
+        let recursive = vec!([args(&[-107]), vec!(10)].concat());
+        assert_eq!(outline_with_subrs(&[args(&[0, 0]), vec!(21), args(&[-107]), vec!(10)].concat(), vec!(), recursive), None);
+    }
+
+    #[test]
+    fn test_index_operator() {
+        let mut index = machine(&[], &[]);
+        assert_eq!(index.execute(&[args(&[1, 2, 3, 1]), vec!(12, 29)].concat(), 0), Some(false));
+        assert_eq!(index.stack, vec!(1.0, 2.0, 3.0, 2.0));
+        assert_eq!(index.execute(&[args(&[-5]), vec!(12, 29)].concat(), 0), Some(false));
+        assert_eq!(index.stack, vec!(1.0, 2.0, 3.0, 2.0, 2.0));
+        // An index saturating usize.
+        let huge = [args(&[30000; 5]), vec!(12, 24, 12, 24, 12, 24, 12, 24, 12, 29)].concat();
+        assert_eq!(machine(&[], &[]).execute(&huge, 0), None);
+    }
+
+    #[test]
+    fn test_end_char_width() {
+        // A glyph with only its width, like space.
+        let mut space = machine(&[], &[]);
+        assert_eq!(space.execute(&[args(&[300]), vec!(14)].concat(), 0), Some(true));
+        assert!(space.width_parsed && space.stack.is_empty() && space.contours.is_empty());
+        assert_eq!(outline(&[args(&[300]), vec!(14)].concat()), None);
+
+        // Without a width, endchar doesn't take anything.
+        let mut empty = machine(&[], &[]);
+        assert_eq!(empty.execute(&[14], 0), Some(true));
+        assert!(empty.width_parsed);
+        // Once the width is parsed, the arguments of endchar are for seac.
+        let mut seac = machine(&[], &[]);
+        assert_eq!(seac.execute(&[args(&[0, 0]), vec!(21), args(&[1, 2, 3, 4, 5]), vec!(14)].concat(), 0), Some(true));
+        assert_eq!((seac.position, seac.stack.len()), ((0.0, 0.0), 0));
+    }
+
+    #[test]
+    fn test_collection_fixture() {
+        // DejaVu Sans with CFF outlines, name-keyed then CID-keyed, for ASCII and é, Å and ñ.
+        let (_, fonts) = TtfFile::parse_collection(include_bytes!("../../resources/DejaVuSansCFF.ttc")).unwrap();
+        assert_eq!(fonts.len(), 2);
+        assert_eq!(fonts[0].cff_table.as_ref().unwrap().name, "DejaVuSansCFF");
+        assert_eq!(fonts[1].cff_table.as_ref().unwrap().name, "DejaVuSansCID");
+        assert_eq!(fonts[1].cff_table.as_ref().unwrap().local_subrs.len(), 2);
+
+        // Same outlines as the TrueType font, with curves converted to cubic ones.
+        let ttf = dejavu_sans();
+        let points = |font: &TtfFile, c| font.glyph_contours(font.glyph_index(c).unwrap()).unwrap()
+            .iter().flat_map(|c| c.points.iter().map(|p| (p.x, p.y, p.on_curve))).collect::<Vec<_>>();
+        let reference = ttf.rasterize(ttf.glyph_index('o').unwrap(), 24.0).unwrap();
+        for font in fonts.iter() {
+            assert!(font.glyph_table.is_none());
+            assert_eq!(points(font, 'H'), points(&ttf, 'H'));
+            let bitmap = font.rasterize(font.glyph_index('o').unwrap(), 24.0).unwrap();
+            assert_eq!((bitmap.width, bitmap.height, bitmap.left, bitmap.top), (reference.width, reference.height, reference.left, reference.top));
+            // Curves are flattened differently, within 0.1 pixel.
+            assert!(bitmap.data.iter().zip(reference.data.iter()).all(|(a, b)| a.abs_diff(*b) <= 26));
+            assert!(font.rasterize(font.glyph_index('é').unwrap(), 24.0).is_some());
+            assert_eq!(font.rasterize(font.glyph_index(' ').unwrap(), 24.0), None);
+        }
+    }
+}
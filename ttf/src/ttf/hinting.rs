@@ -0,0 +1,1585 @@
+// TrueType bytecode interpreter, grid-fitting outlines with the instructions of the font.
+//
+// From https://developer.apple.com/fonts/TrueType-Reference-Manual/RM05/Chap5.html and
+// https://docs.microsoft.com/en-us/typography/opentype/spec/tt_instructions, following FreeType
+// where they are unclear. Coordinates are in 26.6 fixed point pixels, unit vectors in 2.14 and
+// scales in 16.16, with FreeType's rounding so that outlines are the same.
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use super::{ComponentFlags, ComponentOffset, Contour, GlyphData, Point, TtfFile};
+
+// Bounds for broken or hostile programs.
+const MAX_STEPS: usize = 1_000_000;
+const MAX_CALL_DEPTH: u32 = 64;
+const MAX_COMPONENT_DEPTH: u32 = 16;
+// Points added after those of a glyph for its horizontal and vertical metrics.
+const NB_PHANTOM_POINTS: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HintingError {
+    MissingTable(&'static str),
+    StackUnderflow,
+    StackOverflow,
+    InvalidOpcode(u8),
+    InvalidPoint(i32),
+    InvalidContour(i32),
+    InvalidZone(i32),
+    InvalidFunction(i32),
+    InvalidJump(i32),
+    DivisionByZero,
+    // Instructions missing their data, or IF, FDEF and IDEF without their end.
+    UnexpectedEnd,
+    CallsTooDeep,
+    TooManySteps,
+}
+
+impl fmt::Display for HintingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HintingError::MissingTable(tag) => write!(f, "missing {} table", tag),
+            HintingError::StackUnderflow => write!(f, "stack underflow"),
+            HintingError::StackOverflow => write!(f, "stack overflow"),
+            HintingError::InvalidOpcode(opcode) => write!(f, "invalid opcode 0x{:02X}", opcode),
+            HintingError::InvalidPoint(point) => write!(f, "invalid point {}", point),
+            HintingError::InvalidContour(contour) => write!(f, "invalid contour {}", contour),
+            HintingError::InvalidZone(zone) => write!(f, "invalid zone {}", zone),
+            HintingError::InvalidFunction(function) => write!(f, "invalid function {}", function),
+            HintingError::InvalidJump(offset) => write!(f, "invalid jump by {}", offset),
+            HintingError::DivisionByZero => write!(f, "division by zero"),
+            HintingError::UnexpectedEnd => write!(f, "unexpected end of instructions"),
+            HintingError::CallsTooDeep => write!(f, "calls too deep"),
+            HintingError::TooManySteps => write!(f, "too many instructions executed"),
+        }
+    }
+}
+
+type HintingResult<T> = std::result::Result<T, HintingError>;
+
+// All rounding modes are super rounding with some period, phase and threshold.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RoundState {
+    HalfGrid,
+    Grid,
+    DoubleGrid,
+    DownToGrid,
+    UpToGrid,
+    Off,
+    Super{ period: i32, phase: i32, threshold: i32 },
+}
+
+impl RoundState {
+    // Rounds a distance, keeping its sign.
+    fn round(&self, distance: i32) -> i32 {
+        let (period, phase, threshold) = match *self {
+            RoundState::HalfGrid => (64, 32, 32),
+            RoundState::Grid => (64, 0, 32),
+            RoundState::DoubleGrid => (32, 0, 16),
+            RoundState::DownToGrid => (64, 0, 0),
+            RoundState::UpToGrid => (64, 0, 63),
+            RoundState::Off => return distance,
+            RoundState::Super{ period, phase, threshold } => (period, phase, threshold),
+        };
+        let magnitude = distance.unsigned_abs() as i64;
+        let rounded = ((magnitude - phase as i64 + threshold as i64).div_euclid(period as i64) * period as i64 + phase as i64)
+            .max(phase as i64)
+            .min(i32::MAX as i64) as i32;
+        if distance < 0 { -rounded } else { rounded }
+    }
+
+    // SROUND and S45ROUND, grid_period being 1 or √2/2 pixel in 18.14.
+    fn parse_super(selector: i32, grid_period: i32) -> RoundState {
+        let period = match (selector >> 6) & 3 {
+            0 => grid_period / 2,
+            2 => grid_period * 2,
+            _ => grid_period,
+        };
+        let phase = ((selector >> 4) & 3) * period / 4;
+        let threshold = match selector & 0xF {
+            0 => period - 1,
+            n => (n - 4) * period / 8,
+        };
+        RoundState::Super{ period: period >> 8, phase: phase >> 8, threshold: threshold >> 8 }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct GraphicsState {
+    auto_flip: bool,
+    control_value_cut_in: i32,
+    delta_base: i32,
+    delta_shift: i32,
+    // Projection vector for original positions, they can't be measured with the projection
+    // vector once it has been set from current positions.
+    dual_projection_vector: (i32, i32),
+    freedom_vector: (i32, i32),
+    projection_vector: (i32, i32),
+    // Bit 0 stops glyph programs, bit 1 makes them start from the default graphics state.
+    instruct_control: i32,
+    loop_count: i32,
+    minimum_distance: i32,
+    round_state: RoundState,
+    rp0: usize,
+    rp1: usize,
+    rp2: usize,
+    single_width_cut_in: i32,
+    single_width_value: i32,
+    zp0: usize,
+    zp1: usize,
+    zp2: usize,
+}
+
+impl Default for GraphicsState {
+    fn default() -> Self {
+        GraphicsState{
+            auto_flip: true,
+            control_value_cut_in: 68,
+            delta_base: 9,
+            delta_shift: 3,
+            dual_projection_vector: (0x4000, 0),
+            freedom_vector: (0x4000, 0),
+            projection_vector: (0x4000, 0),
+            instruct_control: 0,
+            loop_count: 1,
+            minimum_distance: 64,
+            round_state: RoundState::Grid,
+            rp0: 0,
+            rp1: 0,
+            rp2: 0,
+            single_width_cut_in: 0,
+            single_width_value: 0,
+            zp0: 1,
+            zp1: 1,
+            zp2: 1,
+        }
+    }
+}
+
+// Points of the twilight zone (0), only used by instructions, or of a glyph (1).
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Zone {
+    // For original distances in the glyph zone, more precise than scaled ones: in FUnits, or
+    // hinted positions for compound glyphs.
+    unscaled: Vec<(i32, i32)>,
+    original: Vec<(i32, i32)>,
+    current: Vec<(i32, i32)>,
+    // In x and y.
+    touched: Vec<(bool, bool)>,
+    on_curve: Vec<bool>,
+    // Index of the last point of each contour.
+    endpoints: Vec<usize>,
+}
+
+impl Zone {
+    fn twilight(nb_points: usize) -> Zone {
+        Zone{
+            unscaled: vec![(0, 0); nb_points],
+            original: vec![(0, 0); nb_points],
+            current: vec![(0, 0); nb_points],
+            touched: vec![(false, false); nb_points],
+            on_curve: vec![true; nb_points],
+            endpoints: vec!(),
+        }
+    }
+
+    fn push(&mut self, unscaled: (i32, i32), original: (i32, i32), current: (i32, i32), on_curve: bool) {
+        self.unscaled.push(unscaled);
+        self.original.push(original);
+        self.current.push(current);
+        self.touched.push((false, false));
+        self.on_curve.push(on_curve);
+    }
+
+    // Points of the contours, without the phantom points.
+    fn contours(&self) -> Vec<Contour> {
+        let mut start = 0;
+        self.endpoints.iter().map(|&end| {
+            let points = (start..=end).map(|p| Point{ x: self.current[p].0, y: self.current[p].1, on_curve: self.on_curve[p] }).collect();
+            start = end + 1;
+            Contour{ points, cubic: false }
+        }).collect()
+    }
+
+    // IUP: moves the points not touched along an axis like the touched points around them in
+    // their contour, interpolating when between them and shifting otherwise.
+    fn interpolate_untouched(&mut self, x_axis: bool) {
+        let coordinate = |p: (i32, i32)| if x_axis { p.0 } else { p.1 };
+        let mut start = 0;
+        for &end in self.endpoints.iter() {
+            let touched = (start..=end).filter(|&p| if x_axis { self.touched[p].0 } else { self.touched[p].1 }).collect::<Vec<_>>();
+            for (k, &first) in touched.iter().enumerate() {
+                let second = touched[(k + 1) % touched.len()];
+                let (low, high) = if coordinate(self.unscaled[first]) <= coordinate(self.unscaled[second]) { (first, second) } else { (second, first) };
+                let (unscaled1, unscaled2) = (coordinate(self.unscaled[low]), coordinate(self.unscaled[high]));
+                let (original1, original2) = (coordinate(self.original[low]), coordinate(self.original[high]));
+                let (current1, current2) = (coordinate(self.current[low]), coordinate(self.current[high]));
+                let scale = if current1 == current2 || unscaled1 == unscaled2 { 0 } else { div_fix(current2.wrapping_sub(current1), unscaled2 - unscaled1) };
+                let mut p = if first == end { start } else { first + 1 };
+                while p != second {
+                    let o = coordinate(self.original[p]);
+                    let c = if o <= original1 {
+                        o.wrapping_add(current1).wrapping_sub(original1)
+                    } else if o >= original2 {
+                        o.wrapping_add(current2).wrapping_sub(original2)
+                    } else if scale == 0 {
+                        current1
+                    } else {
+                        current1.wrapping_add(mul_fix(coordinate(self.unscaled[p]) - unscaled1, scale))
+                    };
+                    if x_axis {
+                        self.current[p].0 = c;
+                    } else {
+                        self.current[p].1 = c;
+                    }
+                    p = if p == end { start } else { p + 1 };
+                }
+            }
+            start = end + 1;
+        }
+    }
+}
+
+// a * b / c, rounded half away from 0.
+fn mul_div(a: i32, b: i32, c: i32) -> i32 {
+    let (n, d) = (a as i64 * b as i64, c as i64);
+    if d == 0 {
+        return if n < 0 { -i32::MAX } else { i32::MAX };
+    }
+    let q = (n.abs() + d.abs() / 2) / d.abs();
+    (if (n < 0) != (d < 0) { -q } else { q }).clamp(-i32::MAX as i64, i32::MAX as i64) as i32
+}
+
+// Product with a 16.16 number.
+fn mul_fix(a: i32, b: i32) -> i32 {
+    mul_div(a, b, 0x10000)
+}
+
+// Quotient as a 16.16 number.
+fn div_fix(a: i32, b: i32) -> i32 {
+    mul_div(a, 0x10000, b)
+}
+
+// Product with a 2.14 number.
+fn mul_fix14(a: i32, b: i32) -> i32 {
+    mul_div(a, b, 0x4000)
+}
+
+// Product of two 2.14 vectors, or of a 26.6 one and a 2.14 one, in the format of the first.
+fn dot(a: (i32, i32), b: (i32, i32)) -> i32 {
+    let product = a.0 as i64 * b.0 as i64 + a.1 as i64 * b.1 as i64;
+    ((product + 0x2000 - (product < 0) as i64) >> 14) as i32
+}
+
+// Unit vector in 2.14, the x axis for a null vector. Computed in 16.16 then truncated, as in
+// FreeType.
+fn normalize(v: (i32, i32)) -> (i32, i32) {
+    let (x, y) = (v.0 as f64, v.1 as f64);
+    let length = x.hypot(y);
+    if length == 0.0 {
+        return (0x4000, 0);
+    }
+    ((x * 65536.0 / length).round() as i32 / 4, (y * 65536.0 / length).round() as i32 / 4)
+}
+
+// Length of the instruction at ip, with its inline data.
+fn instruction_length(code: &[u8], ip: usize) -> HintingResult<usize> {
+    let length = match code[ip] {
+        // NPUSHB, NPUSHW
+        0x40 => 2 + *code.get(ip + 1).ok_or(HintingError::UnexpectedEnd)? as usize,
+        0x41 => 2 + 2 * *code.get(ip + 1).ok_or(HintingError::UnexpectedEnd)? as usize,
+        // PUSHB, PUSHW
+        opcode @ 0xB0..=0xB7 => 2 + (opcode - 0xB0) as usize,
+        opcode @ 0xB8..=0xBF => 3 + 2 * (opcode - 0xB8) as usize,
+        _ => 1,
+    };
+    if ip + length > code.len() {
+        return Err(HintingError::UnexpectedEnd);
+    }
+    Ok(length)
+}
+
+// Position after the ELSE (if stop_at_else) or EIF ending the IF or ELSE block at ip.
+fn skip_block(code: &[u8], mut ip: usize, stop_at_else: bool) -> HintingResult<usize> {
+    let mut level = 0;
+    while ip < code.len() {
+        let length = instruction_length(code, ip)?;
+        match code[ip] {
+            0x58 => level += 1,
+            0x1B if level == 0 && stop_at_else => return Ok(ip + length),
+            0x59 if level == 0 => return Ok(ip + length),
+            0x59 => level -= 1,
+            _ => {},
+        }
+        ip += length;
+    }
+    Err(HintingError::UnexpectedEnd)
+}
+
+// Position of the ENDF ending the FDEF or IDEF whose body starts at ip.
+fn find_end_of_definition(code: &[u8], mut ip: usize) -> HintingResult<usize> {
+    while ip < code.len() {
+        if code[ip] == 0x2D {
+            return Ok(ip);
+        }
+        ip += instruction_length(code, ip)?;
+    }
+    Err(HintingError::UnexpectedEnd)
+}
+
+// Grid-fits the glyphs of a font at a size, after running its font and control value programs.
+pub struct Hinter<'a> {
+    ttf: &'a TtfFile,
+    ppem: u16,
+    // 26.6 pixels per FUnit, in 16.16.
+    scale: i32,
+    functions: HashMap<i32, Rc<[u8]>>,
+    instruction_defs: HashMap<u8, Rc<[u8]>>,
+    storage: Vec<i32>,
+    // Scaled, and changed by the programs.
+    control_values: Vec<i32>,
+    // Left by the control value program for glyph programs.
+    graphics_state: GraphicsState,
+    nb_twilight_points: usize,
+    max_stack_elements: usize,
+}
+
+// Execution of a program, with the state it doesn't keep.
+struct Machine<'h, 'a> {
+    hinter: &'h mut Hinter<'a>,
+    state: GraphicsState,
+    stack: Vec<i32>,
+    zones: [Zone; 2],
+    // Of unscaled positions, in 16.16.
+    scale: i32,
+    control_value_program: bool,
+    depth: u32,
+    steps: usize,
+}
+
+impl<'h, 'a> Machine<'h, 'a> {
+    // Programs start with the vectors, zone pointers, rounding and loop reset, as in FreeType.
+    fn new(hinter: &'h mut Hinter<'a>, state: GraphicsState, glyph: Zone) -> Self {
+        let twilight = Zone::twilight(hinter.nb_twilight_points);
+        let default = GraphicsState::default();
+        let scale = hinter.scale;
+        Machine{
+            hinter,
+            state: GraphicsState{
+                dual_projection_vector: default.dual_projection_vector,
+                freedom_vector: default.freedom_vector,
+                projection_vector: default.projection_vector,
+                loop_count: default.loop_count,
+                round_state: default.round_state,
+                zp0: default.zp0,
+                zp1: default.zp1,
+                zp2: default.zp2,
+                ..state
+            },
+            stack: vec!(),
+            zones: [twilight, glyph],
+            scale,
+            control_value_program: false,
+            depth: 0,
+            steps: 0,
+        }
+    }
+
+    fn push(&mut self, value: i32) -> HintingResult<()> {
+        if self.stack.len() >= self.hinter.max_stack_elements {
+            return Err(HintingError::StackOverflow);
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> HintingResult<i32> {
+        self.stack.pop().ok_or(HintingError::StackUnderflow)
+    }
+
+    fn pop_point(&mut self, zone: usize) -> HintingResult<usize> {
+        let point = self.pop()?;
+        self.point(zone, point)
+    }
+
+    fn pop_zone(&mut self) -> HintingResult<usize> {
+        match self.pop()? {
+            zone @ 0..=1 => Ok(zone as usize),
+            zone => Err(HintingError::InvalidZone(zone)),
+        }
+    }
+
+    fn point(&self, zone: usize, point: i32) -> HintingResult<usize> {
+        if point < 0 || point as usize >= self.zones[zone].current.len() {
+            return Err(HintingError::InvalidPoint(point));
+        }
+        Ok(point as usize)
+    }
+
+    // Reference points can be set to anything, they are only checked when used.
+    fn reference(&self, zone: usize, point: usize) -> HintingResult<usize> {
+        self.point(zone, point.min(i32::MAX as usize) as i32)
+    }
+
+    // Number of times to repeat an instruction using the loop variable, which is reset.
+    fn take_loop_count(&mut self) -> i32 {
+        std::mem::replace(&mut self.state.loop_count, 1)
+    }
+
+    fn project(&self, a: (i32, i32), b: (i32, i32)) -> i32 {
+        dot((a.0.wrapping_sub(b.0), a.1.wrapping_sub(b.1)), self.state.projection_vector)
+    }
+
+    fn dual_project(&self, a: (i32, i32), b: (i32, i32)) -> i32 {
+        dot((a.0.wrapping_sub(b.0), a.1.wrapping_sub(b.1)), self.state.dual_projection_vector)
+    }
+
+    fn round(&self, distance: i32) -> i32 {
+        self.state.round_state.round(distance)
+    }
+
+    // Distance between the original positions of two points, from their unscaled positions
+    // unless they are in the twilight zone, which has none.
+    fn original_distance(&self, zone_a: usize, a: usize, zone_b: usize, b: usize) -> i32 {
+        if zone_a == 0 || zone_b == 0 {
+            self.dual_project(self.zones[zone_a].original[a], self.zones[zone_b].original[b])
+        } else {
+            mul_fix(self.dual_project(self.zones[zone_a].unscaled[a], self.zones[zone_b].unscaled[b]), self.scale)
+        }
+    }
+
+    // Moves a point along the freedom vector so that its projection changes by distance.
+    fn move_point(&mut self, zone: usize, point: usize, distance: i32, touch: bool) {
+        let (fx, fy) = self.state.freedom_vector;
+        let (px, py) = self.state.projection_vector;
+        let mut f_dot_p = ((fx as i64 * px as i64 + fy as i64 * py as i64) >> 14) as i32;
+        // Nearly perpendicular vectors would move points very far.
+        if f_dot_p.abs() < 0x400 {
+            f_dot_p = 0x4000;
+        }
+        let zone = &mut self.zones[zone];
+        if fx != 0 {
+            zone.current[point].0 = zone.current[point].0.saturating_add(mul_div(distance, fx, f_dot_p));
+            zone.touched[point].0 |= touch;
+        }
+        if fy != 0 {
+            zone.current[point].1 = zone.current[point].1.saturating_add(mul_div(distance, fy, f_dot_p));
+            zone.touched[point].1 |= touch;
+        }
+    }
+
+    // Vector from a point of zp2 to one of zp1, for SPVTL, SFVTL and SDPVTL.
+    fn line_vector(&mut self, original: bool, perpendicular: bool) -> HintingResult<(i32, i32)> {
+        let p2 = self.pop_point(self.state.zp2)?;
+        let p1 = self.pop_point(self.state.zp1)?;
+        let (a, b) = if original {
+            (self.zones[self.state.zp1].original[p1], self.zones[self.state.zp2].original[p2])
+        } else {
+            (self.zones[self.state.zp1].current[p1], self.zones[self.state.zp2].current[p2])
+        };
+        let (x, y) = (a.0.wrapping_sub(b.0), a.1.wrapping_sub(b.1));
+        Ok(normalize(if perpendicular { (y.wrapping_neg(), x) } else { (x, y) }))
+    }
+
+    // Displacement of the reference point of SHP, SHC and SHZ, with its zone and index.
+    fn reference_displacement(&self, opcode: u8) -> HintingResult<(usize, usize, i32)> {
+        let (zone, point) = if opcode & 1 == 1 { (self.state.zp0, self.state.rp1) } else { (self.state.zp1, self.state.rp2) };
+        let point = self.reference(zone, point)?;
+        Ok((zone, point, self.project(self.zones[zone].current[point], self.zones[zone].original[point])))
+    }
+
+    fn call(&mut self, body: &[u8]) -> HintingResult<()> {
+        if self.depth >= MAX_CALL_DEPTH {
+            return Err(HintingError::CallsTooDeep);
+        }
+        self.depth += 1;
+        self.execute(body)?;
+        self.depth -= 1;
+        Ok(())
+    }
+
+    fn function(&self, index: i32) -> HintingResult<Rc<[u8]>> {
+        self.hinter.functions.get(&index).cloned().ok_or(HintingError::InvalidFunction(index))
+    }
+
+    // DELTAP1, DELTAP2 and DELTAP3 (base 0, 16 and 32 ppem), or the DELTAC ones.
+    fn delta(&mut self, base: i32, control_values: bool) -> HintingResult<()> {
+        let n = self.pop()?;
+        for _ in 0..n {
+            let target = self.pop()?;
+            let argument = self.pop()?;
+            if self.state.delta_base.wrapping_add(base + ((argument >> 4) & 0xF)) != self.hinter.ppem as i32 {
+                continue;
+            }
+            let steps = (argument & 0xF) - 8;
+            let steps = if steps >= 0 { steps + 1 } else { steps };
+            let distance = steps * 64 / (1 << self.state.delta_shift.clamp(0, 6));
+            if control_values {
+                if let Some(value) = self.hinter.control_values.get_mut(target as usize) {
+                    *value = value.saturating_add(distance);
+                }
+            } else {
+                let point = self.point(self.state.zp0, target)?;
+                self.move_point(self.state.zp0, point, distance, true);
+            }
+        }
+        Ok(())
+    }
+
+    fn control_value(&self, index: i32) -> i32 {
+        // Out of range values read as 0, as in FreeType, fonts rely on it.
+        usize::try_from(index).ok().and_then(|i| self.hinter.control_values.get(i)).copied().unwrap_or(0)
+    }
+
+    // Counts an instruction or an iteration of LOOPCALL against the limit of steps.
+    fn step(&mut self) -> HintingResult<()> {
+        self.steps += 1;
+        if self.steps > MAX_STEPS {
+            return Err(HintingError::TooManySteps);
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, code: &[u8]) -> HintingResult<()> {
+        let mut ip = 0;
+        while ip < code.len() {
+            self.step()?;
+            let opcode = code[ip];
+            let mut next = ip + instruction_length(code, ip)?;
+            match opcode {
+                // SVTCA, SPVTCA, SFVTCA: y axis for even opcodes, x axis for odd ones.
+                0x00..=0x05 => {
+                    let axis = if opcode & 1 == 1 { (0x4000, 0) } else { (0, 0x4000) };
+                    if opcode <= 0x03 {
+                        self.state.projection_vector = axis;
+                        self.state.dual_projection_vector = axis;
+                    }
+                    if opcode <= 0x01 || opcode >= 0x04 {
+                        self.state.freedom_vector = axis;
+                    }
+                },
+                // SPVTL
+                0x06 | 0x07 => {
+                    let vector = self.line_vector(false, opcode & 1 == 1)?;
+                    self.state.projection_vector = vector;
+                    self.state.dual_projection_vector = vector;
+                },
+                // SFVTL
+                0x08 | 0x09 => self.state.freedom_vector = self.line_vector(false, opcode & 1 == 1)?,
+                // SPVFS, SFVFS
+                0x0A | 0x0B => {
+                    let y = self.pop()?;
+                    let x = self.pop()?;
+                    let vector = normalize((x, y));
+                    if opcode == 0x0A {
+                        self.state.projection_vector = vector;
+                        self.state.dual_projection_vector = vector;
+                    } else {
+                        self.state.freedom_vector = vector;
+                    }
+                },
+                // GPV, GFV
+                0x0C | 0x0D => {
+                    let vector = if opcode == 0x0C { self.state.projection_vector } else { self.state.freedom_vector };
+                    self.push(vector.0)?;
+                    self.push(vector.1)?;
+                },
+                // SFVTPV
+                0x0E => self.state.freedom_vector = self.state.projection_vector,
+                // ISECT
+                0x0F => {
+                    let b1 = self.pop_point(self.state.zp0)?;
+                    let b0 = self.pop_point(self.state.zp0)?;
+                    let a1 = self.pop_point(self.state.zp1)?;
+                    let a0 = self.pop_point(self.state.zp1)?;
+                    let point = self.pop_point(self.state.zp2)?;
+                    let to_f64 = |p: (i32, i32)| (p.0 as f64, p.1 as f64);
+                    let (a0, a1) = (to_f64(self.zones[self.state.zp1].current[a0]), to_f64(self.zones[self.state.zp1].current[a1]));
+                    let (b0, b1) = (to_f64(self.zones[self.state.zp0].current[b0]), to_f64(self.zones[self.state.zp0].current[b1]));
+                    let (da, db) = ((a1.0 - a0.0, a1.1 - a0.1), (b1.0 - b0.0, b1.1 - b0.1));
+                    let denominator = da.0 * db.1 - da.1 * db.0;
+                    let (x, y) = if denominator == 0.0 {
+                        // Parallel lines, the middle of the points.
+                        ((a0.0 + a1.0 + b0.0 + b1.0) / 4.0, (a0.1 + a1.1 + b0.1 + b1.1) / 4.0)
+                    } else {
+                        let t = ((b0.0 - a0.0) * db.1 - (b0.1 - a0.1) * db.0) / denominator;
+                        (a0.0 + t * da.0, a0.1 + t * da.1)
+                    };
+                    let zone = &mut self.zones[self.state.zp2];
+                    zone.current[point] = (x.round() as i32, y.round() as i32);
+                    zone.touched[point] = (true, true);
+                },
+                // SRP0, SRP1, SRP2
+                0x10..=0x12 => {
+                    let point = self.pop()?.max(0) as usize;
+                    match opcode {
+                        0x10 => self.state.rp0 = point,
+                        0x11 => self.state.rp1 = point,
+                        _ => self.state.rp2 = point,
+                    }
+                },
+                // SZP0, SZP1, SZP2, SZPS
+                0x13..=0x16 => {
+                    let zone = self.pop_zone()?;
+                    match opcode {
+                        0x13 => self.state.zp0 = zone,
+                        0x14 => self.state.zp1 = zone,
+                        0x15 => self.state.zp2 = zone,
+                        _ => {
+                            self.state.zp0 = zone;
+                            self.state.zp1 = zone;
+                            self.state.zp2 = zone;
+                        },
+                    }
+                },
+                // SLOOP
+                0x17 => self.state.loop_count = self.pop()?,
+                // RTG, RTHG
+                0x18 => self.state.round_state = RoundState::Grid,
+                0x19 => self.state.round_state = RoundState::HalfGrid,
+                // SMD
+                0x1A => self.state.minimum_distance = self.pop()?,
+                // ELSE, reached at the end of an IF block that was executed.
+                0x1B => next = skip_block(code, next, false)?,
+                // JMPR
+                0x1C => {
+                    let offset = self.pop()?;
+                    next = jump(code, ip, offset)?;
+                },
+                // SCVTCI, SSWCI, SSW
+                0x1D => self.state.control_value_cut_in = self.pop()?,
+                0x1E => self.state.single_width_cut_in = self.pop()?,
+                0x1F => {
+                    let value = self.pop()?;
+                    self.state.single_width_value = mul_fix(value, self.hinter.scale);
+                },
+                // DUP
+                0x20 => {
+                    let value = *self.stack.last().ok_or(HintingError::StackUnderflow)?;
+                    self.push(value)?;
+                },
+                // POP
+                0x21 => {
+                    self.pop()?;
+                },
+                // CLEAR
+                0x22 => self.stack.clear(),
+                // SWAP
+                0x23 => {
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    self.push(b)?;
+                    self.push(a)?;
+                },
+                // DEPTH
+                0x24 => self.push(self.stack.len() as i32)?,
+                // CINDEX, MINDEX
+                0x25 | 0x26 => {
+                    let k = self.pop()?;
+                    if k <= 0 || k as usize > self.stack.len() {
+                        return Err(HintingError::StackUnderflow);
+                    }
+                    let index = self.stack.len() - k as usize;
+                    let value = if opcode == 0x25 { self.stack[index] } else { self.stack.remove(index) };
+                    self.push(value)?;
+                },
+                // ALIGNPTS
+                0x27 => {
+                    let p2 = self.pop_point(self.state.zp0)?;
+                    let p1 = self.pop_point(self.state.zp1)?;
+                    let distance = self.project(self.zones[self.state.zp0].current[p2], self.zones[self.state.zp1].current[p1]) / 2;
+                    self.move_point(self.state.zp1, p1, distance, true);
+                    self.move_point(self.state.zp0, p2, -distance, true);
+                },
+                // UTP
+                0x29 => {
+                    let point = self.pop_point(self.state.zp0)?;
+                    let (fx, fy) = self.state.freedom_vector;
+                    let touched = &mut self.zones[self.state.zp0].touched[point];
+                    touched.0 &= fx == 0;
+                    touched.1 &= fy == 0;
+                },
+                // LOOPCALL, CALL
+                0x2A => {
+                    let function = self.pop()?;
+                    let body = self.function(function)?;
+                    let count = self.pop()?;
+                    for _ in 0..count {
+                        self.step()?;
+                        self.call(&body)?;
+                    }
+                },
+                0x2B => {
+                    let function = self.pop()?;
+                    let body = self.function(function)?;
+                    self.call(&body)?;
+                },
+                // FDEF
+                0x2C => {
+                    let function = self.pop()?;
+                    let end = find_end_of_definition(code, next)?;
+                    self.hinter.functions.insert(function, code[next..end].into());
+                    next = end + 1;
+                },
+                // ENDF, reached at the end of a function.
+                0x2D => return Ok(()),
+                // MDAP
+                0x2E | 0x2F => {
+                    let point = self.pop_point(self.state.zp0)?;
+                    let distance = if opcode == 0x2F {
+                        let position = dot(self.zones[self.state.zp0].current[point], self.state.projection_vector);
+                        self.round(position) - position
+                    } else {
+                        0
+                    };
+                    self.move_point(self.state.zp0, point, distance, true);
+                    self.state.rp0 = point;
+                    self.state.rp1 = point;
+                },
+                // IUP, in y then x.
+                0x30 | 0x31 => self.zones[1].interpolate_untouched(opcode == 0x31),
+                // SHP
+                0x32 | 0x33 => {
+                    let (_, _, distance) = self.reference_displacement(opcode)?;
+                    for _ in 0..self.take_loop_count() {
+                        let point = self.pop_point(self.state.zp2)?;
+                        self.move_point(self.state.zp2, point, distance, true);
+                    }
+                },
+                // SHC
+                0x34 | 0x35 => {
+                    let (zone, reference, distance) = self.reference_displacement(opcode)?;
+                    let contour = self.pop()?;
+                    let endpoints = &self.zones[self.state.zp2].endpoints;
+                    let end = *usize::try_from(contour).ok().and_then(|c| endpoints.get(c)).ok_or(HintingError::InvalidContour(contour))?;
+                    let start = if contour == 0 { 0 } else { endpoints[contour as usize - 1] + 1 };
+                    for point in start..=end {
+                        if zone != self.state.zp2 || point != reference {
+                            self.move_point(self.state.zp2, point, distance, true);
+                        }
+                    }
+                },
+                // SHZ
+                0x36 | 0x37 => {
+                    let (zone, reference, distance) = self.reference_displacement(opcode)?;
+                    let shifted = self.pop_zone()?;
+                    for point in 0..self.zones[shifted].current.len() {
+                        if zone != shifted || point != reference {
+                            self.move_point(shifted, point, distance, false);
+                        }
+                    }
+                },
+                // SHPIX
+                0x38 => {
+                    let distance = self.pop()?;
+                    let (fx, fy) = self.state.freedom_vector;
+                    let (dx, dy) = (mul_fix14(distance, fx), mul_fix14(distance, fy));
+                    for _ in 0..self.take_loop_count() {
+                        let point = self.pop_point(self.state.zp2)?;
+                        let zone = &mut self.zones[self.state.zp2];
+                        zone.current[point] = (zone.current[point].0.saturating_add(dx), zone.current[point].1.saturating_add(dy));
+                        zone.touched[point].0 |= fx != 0;
+                        zone.touched[point].1 |= fy != 0;
+                    }
+                },
+                // IP
+                0x39 => {
+                    let rp1 = self.reference(self.state.zp0, self.state.rp1)?;
+                    let rp2 = self.reference(self.state.zp1, self.state.rp2)?;
+                    // Only ratios of original distances matter, unscaled ones are fine.
+                    let twilight = self.state.zp0 == 0 || self.state.zp1 == 0 || self.state.zp2 == 0;
+                    let original = |zone: &Zone, point: usize| if twilight { zone.original[point] } else { zone.unscaled[point] };
+                    let (reference1, reference2) = (&self.zones[self.state.zp0], &self.zones[self.state.zp1]);
+                    let (original1, current1) = (original(reference1, rp1), reference1.current[rp1]);
+                    let original_range = self.dual_project(original(reference2, rp2), original1);
+                    let current_range = self.project(reference2.current[rp2], current1);
+                    for _ in 0..self.take_loop_count() {
+                        let point = self.pop_point(self.state.zp2)?;
+                        let zone = &self.zones[self.state.zp2];
+                        let original_distance = self.dual_project(original(zone, point), original1);
+                        let current_distance = self.project(zone.current[point], current1);
+                        let distance = match (original_distance, original_range) {
+                            (0, _) => 0,
+                            (_, 0) => current_distance,
+                            _ => mul_div(original_distance, current_range, original_range),
+                        };
+                        self.move_point(self.state.zp2, point, distance - current_distance, true);
+                    }
+                },
+                // MSIRP
+                0x3A | 0x3B => {
+                    let distance = self.pop()?;
+                    let point = self.pop_point(self.state.zp1)?;
+                    let rp0 = self.reference(self.state.zp0, self.state.rp0)?;
+                    if self.state.zp1 == 0 {
+                        let reference = self.zones[self.state.zp0].original[rp0];
+                        let zone = &mut self.zones[0];
+                        zone.original[point] = reference;
+                        zone.current[point] = reference;
+                    }
+                    let current_distance = self.project(self.zones[self.state.zp1].current[point], self.zones[self.state.zp0].current[rp0]);
+                    self.move_point(self.state.zp1, point, distance.wrapping_sub(current_distance), true);
+                    self.state.rp1 = rp0;
+                    self.state.rp2 = point;
+                    if opcode == 0x3B {
+                        self.state.rp0 = point;
+                    }
+                },
+                // ALIGNRP
+                0x3C => {
+                    let rp0 = self.reference(self.state.zp0, self.state.rp0)?;
+                    let reference = self.zones[self.state.zp0].current[rp0];
+                    for _ in 0..self.take_loop_count() {
+                        let point = self.pop_point(self.state.zp1)?;
+                        let distance = self.project(self.zones[self.state.zp1].current[point], reference);
+                        self.move_point(self.state.zp1, point, -distance, true);
+                    }
+                },
+                // RTDG
+                0x3D => self.state.round_state = RoundState::DoubleGrid,
+                // MIAP
+                0x3E | 0x3F => {
+                    let index = self.pop()?;
+                    let point = self.pop_point(self.state.zp0)?;
+                    let mut distance = self.control_value(index);
+                    if self.state.zp0 == 0 {
+                        let (fx, fy) = self.state.freedom_vector;
+                        let position = (mul_fix14(distance, fx), mul_fix14(distance, fy));
+                        let zone = &mut self.zones[0];
+                        zone.original[point] = position;
+                        zone.current[point] = position;
+                    }
+                    let position = dot(self.zones[self.state.zp0].current[point], self.state.projection_vector);
+                    if opcode == 0x3F {
+                        if distance.wrapping_sub(position).wrapping_abs() > self.state.control_value_cut_in {
+                            distance = position;
+                        }
+                        distance = self.round(distance);
+                    }
+                    self.move_point(self.state.zp0, point, distance.wrapping_sub(position), true);
+                    self.state.rp0 = point;
+                    self.state.rp1 = point;
+                },
+                // NPUSHB, NPUSHW, PUSHB, PUSHW
+                0x40 | 0x41 | 0xB0..=0xBF => {
+                    let words = opcode == 0x41 || opcode >= 0xB8;
+                    let data = if opcode <= 0x41 { &code[ip + 2..next] } else { &code[ip + 1..next] };
+                    if words {
+                        for word in data.chunks(2) {
+                            self.push(i16::from_be_bytes([word[0], word[1]]) as i32)?;
+                        }
+                    } else {
+                        for &byte in data.iter() {
+                            self.push(byte as i32)?;
+                        }
+                    }
+                },
+                // WS, RS: out of range storage is ignored, as in FreeType.
+                0x42 => {
+                    let value = self.pop()?;
+                    let index = self.pop()?;
+                    if let Some(slot) = usize::try_from(index).ok().and_then(|i| self.hinter.storage.get_mut(i)) {
+                        *slot = value;
+                    }
+                },
+                0x43 => {
+                    let index = self.pop()?;
+                    let value = usize::try_from(index).ok().and_then(|i| self.hinter.storage.get(i)).copied().unwrap_or(0);
+                    self.push(value)?;
+                },
+                // WCVTP, WCVTF (in FUnits)
+                0x44 | 0x70 => {
+                    let value = self.pop()?;
+                    let index = self.pop()?;
+                    let value = if opcode == 0x70 { mul_fix(value, self.hinter.scale) } else { value };
+                    if let Some(slot) = usize::try_from(index).ok().and_then(|i| self.hinter.control_values.get_mut(i)) {
+                        *slot = value;
+                    }
+                },
+                // RCVT
+                0x45 => {
+                    let index = self.pop()?;
+                    self.push(self.control_value(index))?;
+                },
+                // GC, of the current position or of the original one.
+                0x46 | 0x47 => {
+                    let point = self.pop_point(self.state.zp2)?;
+                    let zone = &self.zones[self.state.zp2];
+                    let value = if opcode == 0x46 {
+                        dot(zone.current[point], self.state.projection_vector)
+                    } else {
+                        dot(zone.original[point], self.state.dual_projection_vector)
+                    };
+                    self.push(value)?;
+                },
+                // SCFS
+                0x48 => {
+                    let value = self.pop()?;
+                    let point = self.pop_point(self.state.zp2)?;
+                    let position = dot(self.zones[self.state.zp2].current[point], self.state.projection_vector);
+                    self.move_point(self.state.zp2, point, value.wrapping_sub(position), true);
+                    if self.state.zp2 == 0 {
+                        let zone = &mut self.zones[0];
+                        zone.original[point] = zone.current[point];
+                    }
+                },
+                // MD, between current positions for MD[0] and original ones for MD[1].
+                0x49 | 0x4A => {
+                    let p2 = self.pop_point(self.state.zp1)?;
+                    let p1 = self.pop_point(self.state.zp0)?;
+                    let distance = if opcode == 0x49 {
+                        self.project(self.zones[self.state.zp0].current[p1], self.zones[self.state.zp1].current[p2])
+                    } else {
+                        self.original_distance(self.state.zp0, p1, self.state.zp1, p2)
+                    };
+                    self.push(distance)?;
+                },
+                // MPPEM, MPS
+                0x4B | 0x4C => self.push(self.hinter.ppem as i32)?,
+                // FLIPON, FLIPOFF
+                0x4D => self.state.auto_flip = true,
+                0x4E => self.state.auto_flip = false,
+                // DEBUG
+                0x4F => {
+                    self.pop()?;
+                },
+                // LT, LTEQ, GT, GTEQ, EQ, NEQ, AND, OR
+                0x50..=0x55 | 0x5A | 0x5B => {
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    let result = match opcode {
+                        0x50 => a < b,
+                        0x51 => a <= b,
+                        0x52 => a > b,
+                        0x53 => a >= b,
+                        0x54 => a == b,
+                        0x55 => a != b,
+                        0x5A => a != 0 && b != 0,
+                        _ => a != 0 || b != 0,
+                    };
+                    self.push(result as i32)?;
+                },
+                // ODD, EVEN
+                0x56 | 0x57 => {
+                    let value = self.pop()?;
+                    let value = self.round(value) & 127;
+                    self.push((value == if opcode == 0x56 { 64 } else { 0 }) as i32)?;
+                },
+                // IF
+                0x58 => {
+                    if self.pop()? == 0 {
+                        next = skip_block(code, next, true)?;
+                    }
+                },
+                // EIF
+                0x59 => {},
+                // NOT
+                0x5C => {
+                    let value = self.pop()?;
+                    self.push((value == 0) as i32)?;
+                },
+                // DELTAP1, DELTAP2, DELTAP3
+                0x5D => self.delta(0, false)?,
+                0x71 => self.delta(16, false)?,
+                0x72 => self.delta(32, false)?,
+                // DELTAC1, DELTAC2, DELTAC3
+                0x73 => self.delta(0, true)?,
+                0x74 => self.delta(16, true)?,
+                0x75 => self.delta(32, true)?,
+                // SDB, SDS
+                0x5E => self.state.delta_base = self.pop()?,
+                0x5F => self.state.delta_shift = self.pop()?,
+                // ADD, SUB, DIV, MUL, MAX, MIN
+                0x60..=0x63 | 0x8B | 0x8C => {
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    let result = match opcode {
+                        0x60 => a.wrapping_add(b),
+                        0x61 => a.wrapping_sub(b),
+                        0x62 => {
+                            if b == 0 {
+                                return Err(HintingError::DivisionByZero);
+                            }
+                            (a as i64 * 64 / b as i64) as i32
+                        },
+                        0x63 => mul_div(a, b, 64),
+                        0x8B => a.max(b),
+                        _ => a.min(b),
+                    };
+                    self.push(result)?;
+                },
+                // ABS, NEG, FLOOR, CEILING
+                0x64..=0x67 => {
+                    let value = self.pop()?;
+                    let result = match opcode {
+                        0x64 => value.wrapping_abs(),
+                        0x65 => value.wrapping_neg(),
+                        0x66 => value & !63,
+                        _ => value.wrapping_add(63) & !63,
+                    };
+                    self.push(result)?;
+                },
+                // ROUND, NROUND: there is no engine compensation.
+                0x68..=0x6F => {
+                    let value = self.pop()?;
+                    let result = if opcode <= 0x6B { self.round(value) } else { value };
+                    self.push(result)?;
+                },
+                // SROUND, S45ROUND
+                0x76 => {
+                    let selector = self.pop()?;
+                    self.state.round_state = RoundState::parse_super(selector, 0x4000);
+                },
+                0x77 => {
+                    let selector = self.pop()?;
+                    self.state.round_state = RoundState::parse_super(selector, 0x2D41);
+                },
+                // JROT, JROF
+                0x78 | 0x79 => {
+                    let condition = self.pop()?;
+                    let offset = self.pop()?;
+                    if (condition != 0) == (opcode == 0x78) {
+                        next = jump(code, ip, offset)?;
+                    }
+                },
+                // ROFF, RUTG, RDTG
+                0x7A => self.state.round_state = RoundState::Off,
+                0x7C => self.state.round_state = RoundState::UpToGrid,
+                0x7D => self.state.round_state = RoundState::DownToGrid,
+                // SANGW and AA are obsolete, SCANCTRL and SCANTYPE are for the rasterizer.
+                0x7E | 0x7F | 0x85 | 0x8D => {
+                    self.pop()?;
+                },
+                // FLIPPT
+                0x80 => {
+                    for _ in 0..self.take_loop_count() {
+                        let point = self.pop_point(1)?;
+                        self.zones[1].on_curve[point] ^= true;
+                    }
+                },
+                // FLIPRGON, FLIPRGOFF
+                0x81 | 0x82 => {
+                    let high = self.pop_point(1)?;
+                    let low = self.pop_point(1)?;
+                    for point in low..=high {
+                        self.zones[1].on_curve[point] = opcode == 0x81;
+                    }
+                },
+                // SDPVTL
+                0x86 | 0x87 => {
+                    // Both take the same points, restore them for the second vector.
+                    let (p2, p1) = (self.pop()?, self.pop()?);
+                    self.push(p1)?;
+                    self.push(p2)?;
+                    self.state.dual_projection_vector = self.line_vector(true, opcode == 0x87)?;
+                    self.push(p1)?;
+                    self.push(p2)?;
+                    self.state.projection_vector = self.line_vector(false, opcode == 0x87)?;
+                },
+                // GETINFO: version 35 of the scaler, as FreeType, grayscale rendering.
+                0x88 => {
+                    let selector = self.pop()?;
+                    let mut result = 0;
+                    if selector & 1 != 0 {
+                        result |= 35;
+                    }
+                    if selector & 32 != 0 {
+                        result |= 1 << 12;
+                    }
+                    self.push(result)?;
+                },
+                // IDEF
+                0x89 => {
+                    let defined = self.pop()?;
+                    let end = find_end_of_definition(code, next)?;
+                    self.hinter.instruction_defs.insert(defined as u8, code[next..end].into());
+                    next = end + 1;
+                },
+                // ROLL
+                0x8A => {
+                    let c = self.pop()?;
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    self.push(b)?;
+                    self.push(c)?;
+                    self.push(a)?;
+                },
+                // INSTCTRL, only allowed in the control value program.
+                0x8E => {
+                    let selector = self.pop()?;
+                    let value = self.pop()?;
+                    if self.control_value_program && (1..=2).contains(&selector) {
+                        let bit = if value != 0 { selector } else { 0 };
+                        self.state.instruct_control = (self.state.instruct_control & !selector) | bit;
+                    }
+                },
+                // MDRP
+                0xC0..=0xDF => {
+                    let point = self.pop_point(self.state.zp1)?;
+                    let rp0 = self.reference(self.state.zp0, self.state.rp0)?;
+                    let mut original_distance = self.original_distance(self.state.zp1, point, self.state.zp0, rp0);
+                    let current_distance = self.project(self.zones[self.state.zp1].current[point], self.zones[self.state.zp0].current[rp0]);
+                    if original_distance.wrapping_sub(self.state.single_width_value).wrapping_abs() < self.state.single_width_cut_in {
+                        original_distance = if original_distance >= 0 { self.state.single_width_value } else { self.state.single_width_value.wrapping_neg() };
+                    }
+                    let mut distance = if opcode & 0x04 != 0 { self.round(original_distance) } else { original_distance };
+                    if opcode & 0x08 != 0 {
+                        distance = self.keep_minimum_distance(distance, original_distance);
+                    }
+                    self.move_point(self.state.zp1, point, distance.wrapping_sub(current_distance), true);
+                    self.state.rp1 = rp0;
+                    self.state.rp2 = point;
+                    if opcode & 0x10 != 0 {
+                        self.state.rp0 = point;
+                    }
+                },
+                // MIRP
+                0xE0..=0xFF => {
+                    let index = self.pop()?;
+                    let point = self.pop_point(self.state.zp1)?;
+                    let rp0 = self.reference(self.state.zp0, self.state.rp0)?;
+                    let mut control_value = self.control_value(index);
+                    if control_value.wrapping_sub(self.state.single_width_value).wrapping_abs() < self.state.single_width_cut_in {
+                        control_value = if control_value >= 0 { self.state.single_width_value } else { self.state.single_width_value.wrapping_neg() };
+                    }
+                    if self.state.zp1 == 0 {
+                        let (fx, fy) = self.state.freedom_vector;
+                        let reference = self.zones[self.state.zp0].original[rp0];
+                        let position = (reference.0.wrapping_add(mul_fix14(control_value, fx)), reference.1.wrapping_add(mul_fix14(control_value, fy)));
+                        let zone = &mut self.zones[0];
+                        zone.original[point] = position;
+                        zone.current[point] = position;
+                    }
+                    let (reference, zone) = (&self.zones[self.state.zp0], &self.zones[self.state.zp1]);
+                    let original_distance = self.dual_project(zone.original[point], reference.original[rp0]);
+                    let current_distance = self.project(zone.current[point], reference.current[rp0]);
+                    if self.state.auto_flip && (original_distance ^ control_value) < 0 {
+                        control_value = control_value.wrapping_neg();
+                    }
+                    let mut distance = if opcode & 0x04 != 0 {
+                        if self.state.zp0 == self.state.zp1 && control_value.wrapping_sub(original_distance).wrapping_abs() > self.state.control_value_cut_in {
+                            control_value = original_distance;
+                        }
+                        self.round(control_value)
+                    } else {
+                        control_value
+                    };
+                    if opcode & 0x08 != 0 {
+                        distance = self.keep_minimum_distance(distance, original_distance);
+                    }
+                    self.move_point(self.state.zp1, point, distance.wrapping_sub(current_distance), true);
+                    self.state.rp1 = rp0;
+                    self.state.rp2 = point;
+                    if opcode & 0x10 != 0 {
+                        self.state.rp0 = point;
+                    }
+                },
+                _ => match self.hinter.instruction_defs.get(&opcode).cloned() {
+                    Some(body) => self.call(&body)?,
+                    None => return Err(HintingError::InvalidOpcode(opcode)),
+                },
+            }
+            ip = next;
+        }
+        Ok(())
+    }
+
+    // Distance at least the minimum distance, in the direction of the original one.
+    fn keep_minimum_distance(&self, distance: i32, original_distance: i32) -> i32 {
+        if original_distance >= 0 {
+            distance.max(self.state.minimum_distance)
+        } else {
+            distance.min(-self.state.minimum_distance)
+        }
+    }
+}
+
+// Position of the instruction offset bytes away from the one at ip.
+fn jump(code: &[u8], ip: usize, offset: i32) -> HintingResult<usize> {
+    let target = ip as i64 + offset as i64;
+    if offset == 0 || target < 0 || target > code.len() as i64 {
+        return Err(HintingError::InvalidJump(offset));
+    }
+    Ok(target as usize)
+}
+
+impl<'a> Hinter<'a> {
+    // Runs the font program, then the control value program for the size in pixels per em.
+    pub fn new(ttf: &'a TtfFile, ppem: u16) -> HintingResult<Self> {
+        let max_profile = ttf.max_profile.as_ref().ok_or(HintingError::MissingTable("maxp"))?;
+        let units_per_em = ttf.units_per_em().ok_or(HintingError::MissingTable("head"))?;
+        // Only glyf outlines have instructions.
+        ttf.glyph_table.as_ref().ok_or(HintingError::MissingTable("glyf"))?;
+        let scale = div_fix(ppem as i32 * 64, units_per_em as i32);
+        let control_values = ttf.cvt_table.as_ref()
+            .map(|cvt| cvt.values.iter().map(|&v| mul_fix(v as i32, scale)).collect())
+            .unwrap_or_default();
+        let mut hinter = Hinter{
+            ttf,
+            ppem,
+            scale,
+            functions: HashMap::new(),
+            instruction_defs: HashMap::new(),
+            storage: vec![0; max_profile.max_storage.into()],
+            control_values,
+            graphics_state: GraphicsState::default(),
+            nb_twilight_points: max_profile.max_twilight_points.into(),
+            // FreeType allows a few more than announced, as fonts get it wrong.
+            max_stack_elements: usize::from(max_profile.max_stack_elements) + 32,
+        };
+        if let Some(program) = &ttf.font_program {
+            Machine::new(&mut hinter, GraphicsState::default(), Zone::default()).execute(program)?;
+        }
+        if let Some(program) = &ttf.control_value_program {
+            let mut machine = Machine::new(&mut hinter, GraphicsState::default(), Zone::default());
+            machine.control_value_program = true;
+            machine.execute(program)?;
+            hinter.graphics_state = machine.state;
+        }
+        Ok(hinter)
+    }
+
+    // Grid-fitted outline of a glyph in 26.6 pixels. None for glyphs without an outline
+    // (space...) or with invalid components.
+    pub fn glyph_contours(&mut self, index: u16) -> Option<Vec<Contour>> {
+        Some(self.load_glyph(index, 0)?.contours())
+    }
+
+    // Zone with the hinted points of a glyph, followed by its phantom points.
+    fn load_glyph(&mut self, index: u16, depth: u32) -> Option<Zone> {
+        if depth > MAX_COMPONENT_DEPTH {
+            return None;
+        }
+        let ttf = self.ttf;
+        let glyph = ttf.glyph(index)?;
+        match &glyph.glyph_data {
+            GlyphData::VoidGlyph => None,
+            GlyphData::SimpleGlyph{ contours, instructions, .. } => {
+                let mut zone = Zone::default();
+                for contour in contours.iter().filter(|c| !c.points.is_empty()) {
+                    for p in contour.points.iter() {
+                        let position = self.scale_point((p.x, p.y));
+                        zone.push((p.x, p.y), position, position, p.on_curve);
+                    }
+                    zone.endpoints.push(zone.current.len() - 1);
+                }
+                for phantom in self.phantom_points(index, glyph.x_min.0) {
+                    let position = self.scale_point(phantom);
+                    zone.push(phantom, position, position, true);
+                }
+                Some(self.hint(zone, instructions, false))
+            },
+            GlyphData::CompoundGlyph{ components, instructions } => {
+                // Components are hinted first, the instructions of the compound glyph see them
+                // as their original outline, as in FreeType.
+                let mut zone = Zone::default();
+                let mut phantoms = self.phantom_points(index, glyph.x_min.0).map(|p| self.scale_point(p));
+                for component in components.iter() {
+                    let part = match self.load_glyph(component.glyph_index, depth + 1) {
+                        Some(part) => part,
+                        None if ttf.glyph(component.glyph_index).is_none() => continue,
+                        None => return None,
+                    };
+                    let nb_points = part.current.len() - NB_PHANTOM_POINTS;
+                    let (a, b, c, d) = (component.a.to_num::<f64>(), component.b.to_num::<f64>(), component.c.to_num::<f64>(), component.d.to_num::<f64>());
+                    let transform = |(x, y): (i32, i32)| {
+                        let (x, y) = (x as f64, y as f64);
+                        ((a * x + c * y).round() as i32, (b * x + d * y).round() as i32)
+                    };
+                    let (dx, dy) = match component.offset {
+                        ComponentOffset::Xy(x, y) => {
+                            let offset = if component.flags.contains(ComponentFlags::SCALED_COMPONENT_OFFSET) &&
+                                !component.flags.contains(ComponentFlags::UNSCALED_COMPONENT_OFFSET) {
+                                transform((x, y))
+                            } else {
+                                (x, y)
+                            };
+                            let (dx, dy) = self.scale_point(offset);
+                            if component.flags.contains(ComponentFlags::ROUND_XY_TO_GRID) {
+                                (dx.wrapping_add(32) & !63, dy.wrapping_add(32) & !63)
+                            } else {
+                                (dx, dy)
+                            }
+                        },
+                        ComponentOffset::Anchor{ compound_point, component_point } => {
+                            let compound = *zone.current.get(usize::from(compound_point))?;
+                            let component = transform(*part.current[..nb_points].get(usize::from(component_point))?);
+                            (compound.0.wrapping_sub(component.0), compound.1.wrapping_sub(component.1))
+                        },
+                    };
+                    let first_point = zone.current.len();
+                    for p in 0..nb_points {
+                        let (x, y) = transform(part.current[p]);
+                        let position = (x.wrapping_add(dx), y.wrapping_add(dy));
+                        zone.push(position, position, position, part.on_curve[p]);
+                    }
+                    zone.endpoints.extend(part.endpoints.iter().map(|e| e + first_point));
+                    // The compound glyph takes the hinted metrics of this component.
+                    if component.flags.contains(ComponentFlags::USE_MY_METRICS) {
+                        phantoms.copy_from_slice(&part.current[nb_points..]);
+                    }
+                }
+                for phantom in phantoms {
+                    zone.push(phantom, phantom, phantom, true);
+                }
+                Some(self.hint(zone, instructions, true))
+            },
+        }
+    }
+
+    fn scale_point(&self, (x, y): (i32, i32)) -> (i32, i32) {
+        (mul_fix(x, self.scale), mul_fix(y, self.scale))
+    }
+
+    // Origin and advance of a glyph along x then y, in FUnits.
+    fn phantom_points(&self, index: u16, x_min: i16) -> [(i32, i32); NB_PHANTOM_POINTS] {
+        let ttf = self.ttf;
+        let left_side_bearing = ttf.left_side_bearing(index).unwrap_or(x_min) as i32;
+        let advance_width = ttf.advance_width(index).unwrap_or(0) as i32;
+        let origin = x_min as i32 - left_side_bearing;
+        let ascent = ttf.ascent().unwrap_or(0) as i32;
+        let descent = ttf.horizontal_header.as_ref().map_or(0, |hhea| hhea.descent.0 as i32);
+        [(origin, 0), (origin + advance_width, 0), (0, ascent), (0, descent)]
+    }
+
+    // Runs the instructions of a glyph on its zone, ending with the phantom points. Those are
+    // first rounded, as in FreeType, compound glyphs without instructions being left as they are.
+    fn hint(&mut self, mut zone: Zone, instructions: &[u8], compound: bool) -> Zone {
+        if self.graphics_state.instruct_control & 1 != 0 || (compound && instructions.is_empty()) {
+            return zone;
+        }
+        let nb_points = zone.current.len() - NB_PHANTOM_POINTS;
+        let round = |v: i32| (v + 32) & !63;
+        for (k, phantom) in zone.current[nb_points..].iter_mut().enumerate() {
+            if k < 2 {
+                phantom.0 = round(phantom.0);
+            } else {
+                phantom.1 = round(phantom.1);
+            }
+        }
+        if instructions.is_empty() {
+            return zone;
+        }
+
+        // What glyph instructions write to the control values and the storage, or the functions
+        // and instructions they define, only lasts for the glyph, as in FreeType.
+        let (control_values, storage) = (self.control_values.clone(), self.storage.clone());
+        let (functions, instruction_defs) = (self.functions.clone(), self.instruction_defs.clone());
+        let state = if self.graphics_state.instruct_control & 2 != 0 { GraphicsState::default() } else { self.graphics_state.clone() };
+        let mut machine = Machine::new(self, state, zone);
+        // Instructions of compound glyphs see the hinted components as their unscaled outline,
+        // as in FreeType.
+        if compound {
+            machine.scale = 0x10000;
+        }
+        // An error stops the instructions and keeps what they did so far, as in FreeType: fonts
+        // have bugs, such as DejaVu Sans ending some glyph programs with an IP on an empty stack.
+        let _ = machine.execute(instructions);
+        let zone = std::mem::take(&mut machine.zones[1]);
+        self.control_values = control_values;
+        self.storage = storage;
+        self.functions = functions;
+        self.instruction_defs = instruction_defs;
+        zone
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ttf::tests::dejavu_sans;
+
+    // Glyph zone of on-curve points, whose original and unscaled positions are the same.
+    fn zone(contours: &[&[(i32, i32)]]) -> Zone {
+        let mut zone = Zone::default();
+        for contour in contours.iter() {
+            for &p in contour.iter() {
+                zone.push(p, p, p, true);
+            }
+            zone.endpoints.push(zone.current.len() - 1);
+        }
+        zone
+    }
+
+    // Runs code without font and control value programs, one FUnit being 1/64 pixel.
+    fn run(code: &[u8], glyph: Zone, control_values: &[i32]) -> HintingResult<(Vec<i32>, Zone)> {
+        let ttf = dejavu_sans();
+        let mut hinter = Hinter{
+            ttf: &ttf,
+            ppem: 12,
+            scale: 0x10000,
+            functions: HashMap::new(),
+            instruction_defs: HashMap::new(),
+            storage: vec![0; 4],
+            control_values: control_values.to_vec(),
+            graphics_state: GraphicsState::default(),
+            nb_twilight_points: 2,
+            max_stack_elements: 16,
+        };
+        let mut machine = Machine::new(&mut hinter, GraphicsState::default(), glyph);
+        machine.execute(code)?;
+        Ok((machine.stack, std::mem::take(&mut machine.zones[1])))
+    }
+
+    fn stack(code: &[u8]) -> HintingResult<Vec<i32>> {
+        Ok(run(code, Zone::default(), &[])?.0)
+    }
+
+    fn x_coordinates(zone: &Zone) -> Vec<i32> {
+        zone.current.iter().map(|p| p.0).collect()
+    }
+
+    #[test]
+    fn test_push() {
+        // NPUSHB, NPUSHW, PUSHB[1], PUSHW[0]: words are signed.
+        let code = [0x40, 3, 1, 2, 3, 0x41, 2, 0xFF, 0xFE, 0x01, 0x00, 0xB1, 7, 8, 0xB8, 0x80, 0x00];
+        assert_eq!(stack(&code), Ok(vec!(1, 2, 3, -2, 256, 7, 8, -32768)));
+        assert_eq!(stack(&[0x40, 3, 1, 2]), Err(HintingError::UnexpectedEnd));
+        assert_eq!(stack(&[0xB9, 0, 1, 0]), Err(HintingError::UnexpectedEnd));
+        assert_eq!(stack(&[0x40, 17]), Err(HintingError::UnexpectedEnd));
+        assert_eq!(stack(&[[0x40, 17].as_slice(), &[0; 17]].concat()), Err(HintingError::StackOverflow));
+    }
+
+    #[test]
+    fn test_stack() {
+        // CINDEX copies the third element, DUP the top one, MINDEX moves the fourth to the top.
+        let code = [0xB3, 10, 20, 30, 3, 0x25, 0x20, 0xB0, 4, 0x26];
+        assert_eq!(stack(&code), Ok(vec!(10, 30, 10, 10, 20)));
+        // SWAP, DEPTH, POP, ROLL
+        assert_eq!(stack(&[0xB2, 1, 2, 3, 0x23, 0x24, 0x21, 0x8A]), Ok(vec!(3, 2, 1)));
+        assert_eq!(stack(&[0xB2, 1, 2, 3, 0x22, 0x24]), Ok(vec!(0)));
+    }
+
+    #[test]
+    fn test_functions() {
+        // FDEF 1 pushing 10, then CALL and LOOPCALL it 3 times.
+        let definition = [0xB0, 1, 0x2C, 0xB0, 5, 0x20, 0x60, 0x2D];
+        assert_eq!(stack(&[definition.as_slice(), &[0xB0, 1, 0x2B]].concat()), Ok(vec!(10)));
+        assert_eq!(stack(&[definition.as_slice(), &[0xB1, 3, 1, 0x2A]].concat()), Ok(vec!(10, 10, 10)));
+        // Recursion is stopped.
+        assert_eq!(stack(&[0xB0, 0, 0x2C, 0xB0, 0, 0x2B, 0x2D, 0xB0, 0, 0x2B]), Err(HintingError::CallsTooDeep));
+
+        // IDEF of the undefined opcode 0x93, which can then be used.
+        assert_eq!(stack(&[0xB0, 0x93, 0x89, 0xB0, 42, 0x2D, 0x93, 0x93]), Ok(vec!(42, 42)));
+        assert_eq!(stack(&[0xB0, 0x93, 0x89, 0xB0, 42, 0x2D, 0x92]), Err(HintingError::InvalidOpcode(0x92)));
+
+        // Each iteration of LOOPCALL is a step, even of an empty function: 32767² / 64 of them.
+        let code = [0xB0, 1, 0x2C, 0x2D, 0xB8, 0x7F, 0xFF, 0x20, 0x63, 0xB0, 1, 0x2A];
+        assert_eq!(stack(&code), Err(HintingError::TooManySteps));
+    }
+
+    #[test]
+    fn test_move_relative() {
+        // From point 0 to point 1, along the x axis: 100 is 1.5625 pixels.
+        let glyph = || zone(&[&[(0, 0), (100, 0), (20, 0)]]);
+        let moved = |code: &[u8], control_values: &[i32]| x_coordinates(&run(code, glyph(), control_values).unwrap().1);
+
+        // MDRP, rounded with minimum distance or not.
+        assert_eq!(moved(&[0xB0, 1, 0xC0], &[]), vec!(0, 100, 20));
+        assert_eq!(moved(&[0xB0, 1, 0xC4], &[]), vec!(0, 128, 20));
+        assert_eq!(moved(&[0xB0, 2, 0xC4], &[]), vec!(0, 100, 0));
+        assert_eq!(moved(&[0xB0, 2, 0xCC], &[]), vec!(0, 100, 64));
+
+        // MIRP: the control value is used when within the cut-in of 68 from the original distance.
+        assert_eq!(moved(&[0xB1, 1, 0, 0xE0], &[160]), vec!(0, 160, 20));
+        assert_eq!(moved(&[0xB1, 1, 0, 0xE4], &[160]), vec!(0, 192, 20));
+        assert_eq!(moved(&[0xB1, 1, 0, 0xE4], &[250]), vec!(0, 128, 20));
+        // Auto flip follows the original direction, out of range control values being 0.
+        assert_eq!(moved(&[0xB1, 1, 0, 0xE0], &[-160]), vec!(0, 160, 20));
+        assert_eq!(moved(&[0xB1, 2, 5, 0xEC], &[]), vec!(0, 100, 64));
+    }
+
+    #[test]
+    fn test_shift() {
+        let glyph = || zone(&[&[(0, 0), (10, 0)], &[(20, 0), (30, 0)]]);
+        // SHPIX moves point 0 by a pixel, which becomes rp1 for SHP[1], SHC[1] and SHZ[1].
+        let shifted = |code: &[u8]| {
+            let prefix = [0xB1, 0, 64, 0x38, 0xB0, 0, 0x11];
+            let (_, zone) = run(&[prefix.as_slice(), code].concat(), glyph(), &[]).unwrap();
+            (x_coordinates(&zone), zone.touched.iter().map(|t| t.0).collect::<Vec<_>>())
+        };
+        assert_eq!(shifted(&[0xB0, 2, 0x33]), (vec!(64, 10, 84, 30), vec!(true, false, true, false)));
+        assert_eq!(shifted(&[0xB0, 1, 0x35]), (vec!(64, 10, 84, 94), vec!(true, false, true, true)));
+        // The contour of the reference point is moved, but not the reference.
+        assert_eq!(shifted(&[0xB0, 0, 0x35]), (vec!(64, 74, 20, 30), vec!(true, true, false, false)));
+        // SHZ doesn't touch points.
+        assert_eq!(shifted(&[0xB0, 1, 0x37]), (vec!(64, 74, 84, 94), vec!(true, false, false, false)));
+
+        assert_eq!(run(&[0xB0, 2, 0x35], glyph(), &[]).unwrap_err(), HintingError::InvalidContour(2));
+        assert_eq!(run(&[0xB0, 2, 0x37], glyph(), &[]).unwrap_err(), HintingError::InvalidZone(2));
+    }
+
+    #[test]
+    fn test_interpolate_untouched() {
+        let glyph = zone(&[&[(0, 0), (100, 10), (200, 20), (300, 30), (-50, 40)], &[(1000, 0), (1100, 0)]]);
+        // MDAP[0] touches point 0 where it is, SHPIX moves point 2 by a pixel, then IUP[x].
+        let code = [0xB0, 0, 0x2E, 0xB1, 2, 64, 0x38, 0x31];
+        let (_, zone) = run(&code, glyph, &[]).unwrap();
+        // Point 1 is interpolated, 3 shifted like 2, and -50 like 0. The contour without touched
+        // points and the y axis are left as they are.
+        assert_eq!(zone.current, vec!((0, 0), (132, 10), (264, 20), (364, 30), (-50, 40), (1000, 0), (1100, 0)));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(stack(&[0x20]), Err(HintingError::StackUnderflow));
+        assert_eq!(stack(&[0xB0, 1, 0x60]), Err(HintingError::StackUnderflow));
+        assert_eq!(stack(&[0xB1, 1, 3, 0x25]), Err(HintingError::StackUnderflow));
+        assert_eq!(stack(&[0xB1, 1, 0, 0x26]), Err(HintingError::StackUnderflow));
+
+        let glyph = || zone(&[&[(0, 0), (64, 0)]]);
+        assert_eq!(run(&[0xB0, 9, 0x2E], glyph(), &[]).unwrap_err(), HintingError::InvalidPoint(9));
+        assert_eq!(run(&[0xB0, 9, 0xC0], glyph(), &[]).unwrap_err(), HintingError::InvalidPoint(9));
+        // Reference points are only checked when used.
+        assert_eq!(run(&[0xB0, 9, 0x10, 0xB0, 1, 0xC0], glyph(), &[]).unwrap_err(), HintingError::InvalidPoint(9));
+
+        assert_eq!(stack(&[0xB0, 5, 0x2B]), Err(HintingError::InvalidFunction(5)));
+        assert_eq!(stack(&[0xB0, 1, 0x2C, 0xB0, 5]), Err(HintingError::UnexpectedEnd));
+        assert_eq!(stack(&[0xB0, 0, 0x1C]), Err(HintingError::InvalidJump(0)));
+        assert_eq!(stack(&[0xB1, 1, 0, 0x62]), Err(HintingError::DivisionByZero));
+        assert_eq!(stack(&[0xB0, 0, 0x58, 0x20]), Err(HintingError::UnexpectedEnd));
+        // JMPR back to the PUSHW of its offset.
+        assert_eq!(stack(&[0xB8, 0xFF, 0xFD, 0x1C]), Err(HintingError::TooManySteps));
+    }
+
+    #[test]
+    fn test_overflows() {
+        // MIAP[1] then MIRP[0] to a control value of i32::MIN from a point a pixel away.
+        let glyph = || zone(&[&[(64, 0), (0, 0)]]);
+        assert!(run(&[0xB1, 0, 0, 0x3F], glyph(), &[i32::MIN]).is_ok());
+        assert!(run(&[0xB1, 1, 0, 0xE0], glyph(), &[i32::MIN]).is_ok());
+        // IUP[x] of a point shifted with a touched one moved far away.
+        let glyph = zone(&[&[(0, 0), (i32::MAX - 10, 0), (i32::MAX, 0)]]);
+        let code = [0xB0, 0, 0x2E, 0xB8, 0x7F, 0xFF, 0x20, 0x63, 0xB0, 0, 0x23, 0x38, 0x31];
+        assert!(run(&code, glyph, &[]).is_ok());
+    }
+
+    #[test]
+    fn test_hint_glyph() {
+        let ttf = dejavu_sans();
+        let mut hinter = Hinter::new(&ttf, 12).unwrap();
+        let points = |index: u16, hinter: &mut Hinter| hinter.glyph_contours(index).unwrap().iter()
+            .flat_map(|c| c.points.iter().map(|p| (p.x, p.y)))
+            .collect::<Vec<_>>();
+
+        // As FreeType with interpreter version 35: the stems of H are on the grid.
+        let h = ttf.glyph_index('H').unwrap();
+        assert_eq!(points(h, &mut hinter), vec!(
+            (64, 576), (128, 576), (128, 320), (448, 320), (448, 576), (512, 576),
+            (512, 0), (448, 0), (448, 256), (128, 256), (128, 0), (64, 0),
+        ));
+        let a = ttf.glyph_index('A').unwrap();
+        assert_eq!(points(a, &mut hinter), vec!(
+            (256, 507), (139, 192), (373, 192),
+            (213, 576), (299, 576), (512, 0), (444, 0), (397, 128), (116, 128), (68, 0), (0, 0),
+        ));
+        assert_eq!(hinter.glyph_contours(ttf.glyph_index(' ').unwrap()), None);
+
+        // Functions and instructions defined by a glyph are forgotten after it.
+        let nb_functions = hinter.functions.len();
+        let definitions = [0xB0, 200, 0x2C, 0x2D, 0xB0, 0x93, 0x89, 0x2D];
+        hinter.hint(zone(&[&[(0, 0); NB_PHANTOM_POINTS]]), &definitions, false);
+        assert_eq!((hinter.functions.len(), hinter.instruction_defs.len()), (nb_functions, 0));
+    }
+}
@@ -0,0 +1,259 @@
+// Human readable summary of a font, from its name, OS/2, post and cmap tables.
+use std::fmt::Write;
+
+use super::{KnownNameId, TtfFile};
+
+// Block of each bit of the Unicode ranges of the OS/2 table, bits 123 to 127 being reserved.
+const UNICODE_RANGES: [&str; 123] = [
+    "Basic Latin",
+    "Latin-1 Supplement",
+    "Latin Extended-A",
+    "Latin Extended-B",
+    "IPA Extensions",
+    "Spacing Modifier Letters",
+    "Combining Diacritical Marks",
+    "Greek and Coptic",
+    "Coptic",
+    "Cyrillic",
+    "Armenian",
+    "Hebrew",
+    "Vai",
+    "Arabic",
+    "NKo",
+    "Devanagari",
+    "Bengali",
+    "Gurmukhi",
+    "Gujarati",
+    "Oriya",
+    "Tamil",
+    "Telugu",
+    "Kannada",
+    "Malayalam",
+    "Thai",
+    "Lao",
+    "Georgian",
+    "Balinese",
+    "Hangul Jamo",
+    "Latin Extended Additional",
+    "Greek Extended",
+    "General Punctuation",
+    "Superscripts And Subscripts",
+    "Currency Symbols",
+    "Combining Diacritical Marks For Symbols",
+    "Letterlike Symbols",
+    "Number Forms",
+    "Arrows",
+    "Mathematical Operators",
+    "Miscellaneous Technical",
+    "Control Pictures",
+    "Optical Character Recognition",
+    "Enclosed Alphanumerics",
+    "Box Drawing",
+    "Block Elements",
+    "Geometric Shapes",
+    "Miscellaneous Symbols",
+    "Dingbats",
+    "CJK Symbols And Punctuation",
+    "Hiragana",
+    "Katakana",
+    "Bopomofo",
+    "Hangul Compatibility Jamo",
+    "Phags-pa",
+    "Enclosed CJK Letters And Months",
+    "CJK Compatibility",
+    "Hangul Syllables",
+    "Non-Plane 0",
+    "Phoenician",
+    "CJK Unified Ideographs",
+    "Private Use Area",
+    "CJK Strokes",
+    "Alphabetic Presentation Forms",
+    "Arabic Presentation Forms-A",
+    "Combining Half Marks",
+    "Vertical Forms",
+    "Small Form Variants",
+    "Arabic Presentation Forms-B",
+    "Halfwidth And Fullwidth Forms",
+    "Specials",
+    "Tibetan",
+    "Syriac",
+    "Thaana",
+    "Sinhala",
+    "Myanmar",
+    "Ethiopic",
+    "Cherokee",
+    "Unified Canadian Aboriginal Syllabics",
+    "Ogham",
+    "Runic",
+    "Khmer",
+    "Mongolian",
+    "Braille Patterns",
+    "Yi Syllables",
+    "Tagalog",
+    "Old Italic",
+    "Gothic",
+    "Deseret",
+    "Byzantine Musical Symbols",
+    "Mathematical Alphanumeric Symbols",
+    "Private Use (plane 15)",
+    "Variation Selectors",
+    "Tags",
+    "Limbu",
+    "Tai Le",
+    "New Tai Lue",
+    "Buginese",
+    "Glagolitic",
+    "Tifinagh",
+    "Yijing Hexagram Symbols",
+    "Syloti Nagri",
+    "Linear B Syllabary",
+    "Ancient Greek Numbers",
+    "Ugaritic",
+    "Old Persian",
+    "Shavian",
+    "Osmanya",
+    "Cypriot Syllabary",
+    "Kharoshthi",
+    "Tai Xuan Jing Symbols",
+    "Cuneiform",
+    "Counting Rod Numerals",
+    "Sundanese",
+    "Lepcha",
+    "Ol Chiki",
+    "Saurashtra",
+    "Kayah Li",
+    "Rejang",
+    "Cham",
+    "Ancient Symbols",
+    "Phaistos Disc",
+    "Carian",
+    "Domino Tiles",
+];
+
+// Weight classes 100 to 900 and width classes 1 to 9.
+const WEIGHTS: [&str; 9] = ["Thin", "Extra Light", "Light", "Regular", "Medium", "Semi Bold", "Bold", "Extra Bold", "Black"];
+const WIDTHS: [&str; 9] = ["Ultra Condensed", "Extra Condensed", "Condensed", "Semi Condensed", "Medium", "Semi Expanded", "Expanded", "Extra Expanded", "Ultra Expanded"];
+
+// Writing to a String can't fail, hence the ignored results.
+fn add_line(report: &mut String, label: &str, value: &str) {
+    // Continuation lines of long texts (copyright, license...) are indented.
+    let _ = write!(report, "{:18}", format!("{}:", label));
+    for (k, line) in value.trim_end().lines().enumerate() {
+        let indent = if k == 0 || line.trim().is_empty() { "" } else { "                  " };
+        let _ = writeln!(report, "{}{}", indent, line.trim_end());
+    }
+}
+
+impl TtfFile {
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        let names = [
+            ("Family", self.name(KnownNameId::PreferredFamily).or_else(|| self.name(KnownNameId::FontFamily))),
+            ("Style", self.name(KnownNameId::PreferredSubfamily).or_else(|| self.name(KnownNameId::FontSubfamily))),
+            ("Full name", self.name(KnownNameId::FontName)),
+            ("Version", self.name(KnownNameId::Version)),
+            ("PostScript name", self.name(KnownNameId::PostScriptName)),
+            ("Manufacturer", self.name(KnownNameId::Manufacturer)),
+            ("Designer", self.name(KnownNameId::Designer)),
+            ("Copyright", self.name(KnownNameId::Copyright)),
+            ("Trademark", self.name(KnownNameId::Trademark)),
+            ("License", self.name(KnownNameId::License)),
+            ("License URL", self.name(KnownNameId::LicenseUrl)),
+        ];
+        for (label, name) in names.iter() {
+            if let Some(name) = name {
+                add_line(&mut report, label, name);
+            }
+        }
+
+        if let Some(os2) = &self.os2_table {
+            add_line(&mut report, "Vendor", os2.vendor_id.trim_end_matches([' ', '\0']));
+            let weight = (usize::from(os2.weight_class) + 50) / 100;
+            match weight.checked_sub(1).and_then(|w| WEIGHTS.get(w)) {
+                Some(name) => add_line(&mut report, "Weight", &format!("{} ({})", os2.weight_class, name)),
+                None => add_line(&mut report, "Weight", &os2.weight_class.to_string()),
+            }
+            match usize::from(os2.width_class).checked_sub(1).and_then(|w| WIDTHS.get(w)) {
+                Some(name) => add_line(&mut report, "Width", &format!("{} ({})", os2.width_class, name)),
+                None => add_line(&mut report, "Width", &os2.width_class.to_string()),
+            }
+            add_line(&mut report, "Selection", &format!("{:?}", os2.selection));
+            let permissions = match os2.fs_type & 0x000F {
+                0 => "installable",
+                2 => "restricted",
+                4 => "preview and print",
+                8 => "editable",
+                _ => "invalid",
+            };
+            let mut embedding = permissions.to_string();
+            if os2.fs_type & 0x0100 != 0 {
+                embedding += ", no subsetting";
+            }
+            if os2.fs_type & 0x0200 != 0 {
+                embedding += ", bitmaps only";
+            }
+            add_line(&mut report, "Embedding", &embedding);
+            add_line(&mut report, "Panose", &os2.panose.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(" "));
+        }
+        if let Some(post) = &self.post_table {
+            add_line(&mut report, "Italic angle", &format!("{}°", post.italic_angle));
+            add_line(&mut report, "Fixed pitch", if post.is_fixed_pitch { "yes" } else { "no" });
+            add_line(&mut report, "Underline", &format!("position {}, thickness {}", post.underline_position, post.underline_thickness));
+        }
+
+        if self.cff_table.is_some() {
+            add_line(&mut report, "Outlines", "PostScript (CFF)");
+        } else if self.glyph_table.is_some() {
+            add_line(&mut report, "Outlines", "TrueType (glyf)");
+        }
+        if let Some(max_profile) = &self.max_profile {
+            let nb_named = (0..max_profile.nb_glyphs).filter(|&g| self.glyph_name(g).is_some_and(|name| !name.is_empty())).count();
+            add_line(&mut report, "Glyphs", &format!("{}, {} named", max_profile.nb_glyphs, nb_named));
+        }
+        let characters = (0..=char::MAX as u32)
+            .filter_map(char::from_u32)
+            .filter(|&c| self.glyph_index(c).is_some_and(|g| g != 0))
+            .collect::<Vec<_>>();
+        if let (Some(first), Some(last)) = (characters.first(), characters.last()) {
+            add_line(&mut report, "Characters", &format!("{}, from U+{:04X} to U+{:04X}", characters.len(), *first as u32, *last as u32));
+        }
+        if let Some(os2) = &self.os2_table {
+            let ranges = UNICODE_RANGES.iter().enumerate()
+                .filter(|(bit, _)| os2.unicode_ranges & (1 << bit) != 0)
+                .map(|(_, name)| *name)
+                .collect::<Vec<_>>();
+            add_line(&mut report, "Unicode ranges", &ranges.join(", "));
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ttf::tests::dejavu_sans;
+
+    #[test]
+    fn test_report() {
+        let report = dejavu_sans().report();
+        let lines = report.lines().collect::<Vec<_>>();
+        assert_eq!(lines[..5], [
+            "Family:           DejaVu Sans",
+            "Style:            Book",
+            "Full name:        DejaVu Sans",
+            "Version:          Version 2.35",
+            "PostScript name:  DejaVuSans",
+        ]);
+        // Following lines of multi-line names are indented.
+        assert!(lines.contains(&"                  Copyright (c) 2006 by Tavmjong Bah. All Rights Reserved."));
+        for line in [
+            "Weight:           400 (Regular)",
+            "Width:            5 (Medium)",
+            "Outlines:         TrueType (glyf)",
+            "Glyphs:           6241, 6241 named",
+            "Characters:       5906, from U+0020 to U+1F640",
+        ] {
+            assert!(lines.contains(&line), "{}", line);
+        }
+        assert!(lines.last().unwrap().starts_with("Unicode ranges:   Basic Latin, Latin-1 Supplement, "));
+    }
+}
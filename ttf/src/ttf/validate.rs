@@ -0,0 +1,451 @@
+// Checks of a font file beyond what parsing needs: table checksums and bounds, required tables,
+// bounding boxes of glyphs and the limits of the maximum profile.
+use std::fmt;
+
+use super::{CollectionHeader, Contour, Error, FontDirectory, GlyphData, Glyph, Input, TableDirectory, TtfFile};
+
+// The tables of the comment above impl TtfFile, fonts with CFF outlines having a 'CFF ' table
+// instead of 'glyf' and 'loca'.
+const REQUIRED_TABLES: [&str; 7] = ["cmap", "head", "hhea", "hmtx", "maxp", "name", "post"];
+const TRUETYPE_TABLES: [&str; 2] = ["glyf", "loca"];
+
+// The sum of a font file with its checksum adjustment is this magic number.
+const CHECKSUM_MAGIC: u32 = 0xB1B0AFBA;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Problem {
+    // What couldn't be parsed, where in the file and the contexts of the error.
+    Unparsable{ part: String, offset: usize, context: String },
+    MissingTable(&'static str),
+    TableOutOfBounds{ tag: String, offset: u32, length: u32 },
+    WrongChecksum{ tag: String, expected: u32, computed: u32 },
+    WrongChecksumAdjustment{ expected: u32, computed: u32 },
+    // Bounding boxes are x min, y min, x max and y max.
+    WrongGlyphBoundingBox{ index: u16, expected: [i16; 4], computed: [i32; 4] },
+    WrongFontBoundingBox{ expected: [i16; 4], computed: [i32; 4] },
+    // A maxp field smaller than the value of a glyph.
+    LimitExceeded{ field: &'static str, limit: u16, value: usize, index: u16 },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::Unparsable{ part, offset, context } => write!(f, "couldn't parse {} at byte {}: {}", part, offset, context),
+            Problem::MissingTable(tag) => write!(f, "missing {} table", tag),
+            Problem::TableOutOfBounds{ tag, offset, length } =>
+                write!(f, "{} table of {} bytes at byte {} is past the end of the file", tag, length, offset),
+            Problem::WrongChecksum{ tag, expected, computed } =>
+                write!(f, "{} table checksum is 0x{:08X} instead of 0x{:08X}", tag, expected, computed),
+            Problem::WrongChecksumAdjustment{ expected, computed } =>
+                write!(f, "head checksum adjustment is 0x{:08X} instead of 0x{:08X}", expected, computed),
+            Problem::WrongGlyphBoundingBox{ index, expected, computed } =>
+                write!(f, "glyph {} bounding box is {:?} instead of {:?}", index, expected, computed),
+            Problem::WrongFontBoundingBox{ expected, computed } =>
+                write!(f, "head bounding box is {:?} instead of {:?}", expected, computed),
+            Problem::LimitExceeded{ field, limit, value, index } =>
+                write!(f, "maxp {} is {} but glyph {} has {}", field, limit, index, value),
+        }
+    }
+}
+
+// Sum of the big endian u32 of data, padded with zeros.
+fn checksum(data: &[u8]) -> u32 {
+    data.chunks(4).fold(0, |sum: u32, chunk| {
+        let mut word = [0; 4];
+        word[..chunk.len()].copy_from_slice(chunk);
+        sum.wrapping_add(u32::from_be_bytes(word))
+    })
+}
+
+// The contexts of a parse error, with its offset from the start of the file when its input is in
+// data, which is at data_offset in the file.
+fn unparsable(part: String, e: nom::Err<Error>, data: Input, data_offset: usize) -> Problem {
+    let errors = match e {
+        nom::Err::Error(e) | nom::Err::Failure(e) => e.errors,
+        nom::Err::Incomplete(_) => vec!(),
+    };
+    let offset = errors.first()
+        .and_then(|(i, _)| (i.as_ptr() as usize).checked_sub(data.as_ptr() as usize))
+        .filter(|&offset| offset <= data.len())
+        .map_or(data_offset, |offset| data_offset + offset);
+    let context = errors.iter()
+        .filter_map(|(_, kind)| match kind {
+            nom::error::VerboseErrorKind::Context(context) => Some(*context),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join(" in ");
+    Problem::Unparsable{ part, offset, context }
+}
+
+// Problems of each font of a file, a collection having several.
+pub fn validate(input: Input) -> Vec<Vec<Problem>> {
+    if !input.starts_with(b"ttcf") {
+        return vec!(validate_font(input, 0, true));
+    }
+    match CollectionHeader::parse(input) {
+        // The checksum adjustment of a font of a collection is for the font on its own, which
+        // doesn't exist.
+        Ok((_, header)) => header.offsets.iter().map(|&offset| validate_font(input, offset, false)).collect(),
+        Err(e) => vec!(vec!(unparsable(String::from("collection header"), e, input, 0))),
+    }
+}
+
+// Problems of the font whose directory is at offset.
+fn validate_font(input: Input, offset: u32, single: bool) -> Vec<Problem> {
+    let mut problems = vec!();
+    let directory = match super::at_offset(input, offset, "Font Directory").and_then(|i| FontDirectory::parse(i)) {
+        Ok((_, directory)) => directory.table_directory,
+        Err(e) => {
+            problems.push(unparsable(String::from("font directory"), e, input, 0));
+            return problems;
+        },
+    };
+    validate_tables(input, &directory, single, &mut problems);
+    match TtfFile::parse_at(input, offset) {
+        Ok((_, ttf)) => ttf.validate_glyphs(&mut problems),
+        Err(e) => problems.push(unparsable(String::from("font"), e, input, 0)),
+    }
+    problems
+}
+
+fn validate_tables(input: Input, directory: &TableDirectory, single: bool, problems: &mut Vec<Problem>) {
+    let has_cff = directory.entries.contains_key("CFF ");
+    for tag in REQUIRED_TABLES.iter().chain(if has_cff { [].iter() } else { TRUETYPE_TABLES.iter() }) {
+        if !directory.entries.contains_key(*tag) {
+            problems.push(Problem::MissingTable(tag));
+        }
+    }
+    let mut entries = directory.entries.values().collect::<Vec<_>>();
+    entries.sort_by_key(|e| e.offset);
+    for entry in entries {
+        let start = entry.offset as usize;
+        let table = match start.checked_add(entry.length as usize).and_then(|end| input.get(start..end)) {
+            Some(table) => table,
+            None => {
+                problems.push(Problem::TableOutOfBounds{ tag: entry.tag.clone(), offset: entry.offset, length: entry.length });
+                continue;
+            },
+        };
+        // The checksum of head is with a checksum adjustment of 0, as it's set after.
+        let mut computed = checksum(table);
+        if entry.tag == "head" && table.len() >= 12 {
+            let adjustment = u32::from_be_bytes([table[8], table[9], table[10], table[11]]);
+            computed = computed.wrapping_sub(adjustment);
+            if single {
+                let mut file = input.to_vec();
+                file[start + 8..start + 12].fill(0);
+                let computed_adjustment = CHECKSUM_MAGIC.wrapping_sub(checksum(&file));
+                if adjustment != computed_adjustment {
+                    problems.push(Problem::WrongChecksumAdjustment{ expected: adjustment, computed: computed_adjustment });
+                }
+            }
+        }
+        if computed != entry.checksum {
+            problems.push(Problem::WrongChecksum{ tag: entry.tag.clone(), expected: entry.checksum, computed });
+        }
+    }
+}
+
+// Maximum of a glyph value, with the glyph.
+#[derive(Default)]
+struct Maximum {
+    value: usize,
+    index: u16,
+}
+
+impl Maximum {
+    fn add(&mut self, value: usize, index: u16) {
+        if value > self.value {
+            *self = Maximum{ value, index };
+        }
+    }
+}
+
+impl TtfFile {
+    fn validate_glyphs(&self, problems: &mut Vec<Problem>) {
+        let (Some(glyph_table), Some(entry)) = (&self.glyph_table, self.font_directory.table_directory.entries.get("glyf")) else {
+            return;
+        };
+        let mut font_box: Option<[i32; 4]> = None;
+        let (mut points, mut contours, mut instructions) = (Maximum::default(), Maximum::default(), Maximum::default());
+        let (mut component_points, mut component_contours) = (Maximum::default(), Maximum::default());
+        let (mut component_elements, mut component_depth) = (Maximum::default(), Maximum::default());
+        for index in 0..glyph_table.glyphs.len() as u16 {
+            let (start, end) = (glyph_table.locations[usize::from(index)] as usize, glyph_table.locations[usize::from(index) + 1] as usize);
+            if start == end {
+                continue;
+            }
+            let data = &glyph_table.data[start..end];
+            let glyph = match Glyph::parse(data) {
+                Ok((_, glyph)) => glyph,
+                Err(e) => {
+                    problems.push(unparsable(format!("glyph {}", index), e, data, entry.offset as usize + start));
+                    continue;
+                },
+            };
+            let outline = match &glyph.glyph_data {
+                GlyphData::VoidGlyph => continue,
+                GlyphData::SimpleGlyph{ contours: glyph_contours, instructions: glyph_instructions, .. } => {
+                    points.add(glyph_contours.iter().map(|c| c.points.len()).sum(), index);
+                    contours.add(glyph_contours.len(), index);
+                    instructions.add(glyph_instructions.len(), index);
+                    Some(glyph_contours.clone())
+                },
+                GlyphData::CompoundGlyph{ components, instructions: glyph_instructions } => {
+                    instructions.add(glyph_instructions.len(), index);
+                    component_elements.add(components.len(), index);
+                    if let Some(depth) = self.component_depth(index, 0) {
+                        component_depth.add(depth, index);
+                    }
+                    let outline = self.glyph_contours(index);
+                    if let Some(outline) = &outline {
+                        component_points.add(outline.iter().map(|c| c.points.len()).sum(), index);
+                        component_contours.add(outline.len(), index);
+                    }
+                    outline
+                },
+            };
+            let Some(computed) = outline.as_deref().and_then(bounding_box) else {
+                continue;
+            };
+            let expected = [glyph.x_min.0, glyph.y_min.0, glyph.x_max.0, glyph.y_max.0];
+            if expected.iter().zip(computed.iter()).any(|(e, c)| *e as i32 != *c) {
+                problems.push(Problem::WrongGlyphBoundingBox{ index, expected, computed });
+            }
+            font_box = Some(match font_box {
+                Some(b) => [b[0].min(computed[0]), b[1].min(computed[1]), b[2].max(computed[2]), b[3].max(computed[3])],
+                None => computed,
+            });
+        }
+        if let (Some(head), Some(computed)) = (&self.font_header, font_box) {
+            let expected = [head.x_min.0, head.y_min.0, head.x_max.0, head.y_max.0];
+            if expected.iter().zip(computed.iter()).any(|(e, c)| *e as i32 != *c) {
+                problems.push(Problem::WrongFontBoundingBox{ expected, computed });
+            }
+        }
+        // Only version 1.0 has the limits of TrueType outlines.
+        let Some(maxp) = self.max_profile.as_ref().filter(|maxp| maxp.version.to_bits() >= 0x10000) else {
+            return;
+        };
+        let limits = [
+            ("maxPoints", maxp.max_points, points),
+            ("maxContours", maxp.max_contours, contours),
+            ("maxCompositePoints", maxp.max_component_points, component_points),
+            ("maxCompositeContours", maxp.max_component_contours, component_contours),
+            ("maxSizeOfInstructions", maxp.max_size_of_instructions, instructions),
+            ("maxComponentElements", maxp.max_component_elements, component_elements),
+            ("maxComponentDepth", maxp.max_component_depth, component_depth),
+        ];
+        for (field, limit, maximum) in limits {
+            if maximum.value > usize::from(limit) {
+                problems.push(Problem::LimitExceeded{ field, limit, value: maximum.value, index: maximum.index });
+            }
+        }
+    }
+
+    // Levels of components, 1 for compound glyphs of simple glyphs. None for cycles.
+    fn component_depth(&self, index: u16, depth: u32) -> Option<usize> {
+        const MAX_COMPONENT_DEPTH: u32 = 16;
+        if depth > MAX_COMPONENT_DEPTH {
+            return None;
+        }
+        match &self.glyph(index)?.glyph_data {
+            GlyphData::CompoundGlyph{ components, .. } => {
+                let mut component_depth = 0;
+                for component in components.iter() {
+                    if self.glyph(component.glyph_index).is_some() {
+                        component_depth = component_depth.max(self.component_depth(component.glyph_index, depth + 1)?);
+                    }
+                }
+                Some(component_depth + 1)
+            },
+            _ => Some(0),
+        }
+    }
+}
+
+fn bounding_box(contours: &[Contour]) -> Option<[i32; 4]> {
+    let mut points = contours.iter().flat_map(|c| c.points.iter());
+    let first = points.next()?;
+    Some(points.fold([first.x, first.y, first.x, first.y], |b, p| [b[0].min(p.x), b[1].min(p.y), b[2].max(p.x), b[3].max(p.y)]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FONT: &[u8] = include_bytes!("../../resources/DejaVuSans.ttf");
+
+    // Offset of the table directory entry of tag.
+    fn entry(font: &[u8], tag: &[u8; 4]) -> usize {
+        let nb_tables = usize::from(u16::from_be_bytes([font[4], font[5]]));
+        (0..nb_tables).map(|k| 12 + 16 * k).find(|&e| &font[e..e + 4] == tag).unwrap()
+    }
+
+    fn read32(font: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(font[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn table(font: &[u8], tag: &[u8; 4]) -> usize {
+        read32(font, entry(font, tag) + 8) as usize
+    }
+
+    // Checksums of the tables in the file and the checksum adjustment after a change.
+    fn update_checksums(font: &mut [u8]) {
+        let head = table(font, b"head");
+        font[head + 8..head + 12].fill(0);
+        let nb_tables = usize::from(u16::from_be_bytes([font[4], font[5]]));
+        for e in (0..nb_tables).map(|k| 12 + 16 * k) {
+            let (offset, length) = (read32(font, e + 8) as usize, read32(font, e + 12) as usize);
+            if let Some(table) = font.get(offset..offset + length) {
+                let sum = checksum(table);
+                font[e + 4..e + 8].copy_from_slice(&sum.to_be_bytes());
+            }
+        }
+        let adjustment = CHECKSUM_MAGIC.wrapping_sub(checksum(font));
+        font[head + 8..head + 12].copy_from_slice(&adjustment.to_be_bytes());
+    }
+
+    // The problems of a font besides the glyph bounding boxes DejaVu Sans gets wrong.
+    fn problems(font: &[u8]) -> Vec<Problem> {
+        let pristine = validate(FONT).remove(0);
+        let mut problems = validate(font);
+        assert_eq!(problems.len(), 1);
+        problems.remove(0).into_iter().filter(|p| !pristine.contains(p)).collect()
+    }
+
+    #[test]
+    fn test_dejavu_sans() {
+        // Off by one bounding boxes of glyphs, with rounded coordinates or compound ones.
+        let problems = validate(FONT);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].len(), 28);
+        assert!(problems[0].iter().all(|p| matches!(p, Problem::WrongGlyphBoundingBox{ .. })));
+        assert_eq!(problems[0][0], Problem::WrongGlyphBoundingBox{ index: 482, expected: [201, -426, 1305, 1522], computed: [201, -426, 1305, 1521] });
+
+        // Recomputing the checksums changes nothing.
+        let mut font = FONT.to_vec();
+        update_checksums(&mut font);
+        assert_eq!(font, FONT);
+    }
+
+    #[test]
+    fn test_checksum() {
+        // FFTM isn't parsed, a change is only seen in its checksum and the one of the file.
+        let mut font = FONT.to_vec();
+        let fftm = table(&font, b"FFTM");
+        font[fftm + 3] += 1;
+        let expected = read32(&font, entry(&font, b"FFTM") + 4);
+        let head = table(&font, b"head");
+        let adjustment = read32(&font, head + 8);
+        let problems = problems(&font);
+        assert_eq!(problems, [
+            Problem::WrongChecksum{ tag: String::from("FFTM"), expected, computed: expected + 1 },
+            Problem::WrongChecksumAdjustment{ expected: adjustment, computed: adjustment - 1 },
+        ]);
+        assert_eq!(problems[0].to_string(), "FFTM table checksum is 0x7748DCB0 instead of 0x7748DCB1");
+    }
+
+    #[test]
+    fn test_checksum_adjustment() {
+        // Not part of the checksum of head.
+        let mut font = FONT.to_vec();
+        let head = table(&font, b"head");
+        let adjustment = read32(&font, head + 8);
+        font[head + 8..head + 12].copy_from_slice(&0x12345678_u32.to_be_bytes());
+        assert_eq!(problems(&font), [Problem::WrongChecksumAdjustment{ expected: 0x12345678, computed: adjustment }]);
+
+        // Fonts of collections have none.
+        assert_eq!(validate_font(&font, 0, false), validate(FONT).remove(0));
+    }
+
+    #[test]
+    fn test_missing_table() {
+        let mut font = FONT.to_vec();
+        let post = entry(&font, b"post");
+        font[post..post + 4].copy_from_slice(b"pots");
+        update_checksums(&mut font);
+        let problems = problems(&font);
+        assert_eq!(problems, [Problem::MissingTable("post")]);
+        assert_eq!(problems[0].to_string(), "missing post table");
+    }
+
+    #[test]
+    fn test_table_out_of_bounds() {
+        let mut font = FONT.to_vec();
+        let fftm = entry(&font, b"FFTM");
+        let end = font.len() as u32 - 27;
+        font[fftm + 8..fftm + 12].copy_from_slice(&end.to_be_bytes());
+        update_checksums(&mut font);
+        let problems = problems(&font);
+        assert_eq!(problems, [Problem::TableOutOfBounds{ tag: String::from("FFTM"), offset: end, length: 28 }]);
+        assert_eq!(problems[0].to_string(), "FFTM table of 28 bytes at byte 756045 is past the end of the file");
+    }
+
+    #[test]
+    fn test_bounding_boxes() {
+        // x max of A, and y max of the font.
+        let mut font = FONT.to_vec();
+        let glyph_table = TtfFile::parse(FONT).unwrap().1.glyph_table.unwrap();
+        let a = table(&font, b"glyf") + glyph_table.locations[36] as usize;
+        font[a + 6..a + 8].copy_from_slice(&1385_i16.to_be_bytes());
+        let head = table(&font, b"head");
+        let y_max = i16::from_be_bytes([font[head + 42], font[head + 43]]);
+        font[head + 42..head + 44].copy_from_slice(&(y_max + 1).to_be_bytes());
+        update_checksums(&mut font);
+        let problems = problems(&font);
+        assert_eq!(problems[0], Problem::WrongGlyphBoundingBox{ index: 36, expected: [16, 0, 1385, 1493], computed: [16, 0, 1384, 1493] });
+        assert_eq!(problems[1], Problem::WrongFontBoundingBox{ expected: [-2090, -948, 3673, 2525], computed: [-2090, -948, 3673, 2524] });
+        assert_eq!(problems.len(), 2);
+        assert_eq!(problems[0].to_string(), "glyph 36 bounding box is [16, 0, 1385, 1493] instead of [16, 0, 1384, 1493]");
+    }
+
+    #[test]
+    fn test_maximum_profile() {
+        let mut font = FONT.to_vec();
+        let maxp = table(&font, b"maxp");
+        font[maxp + 6..maxp + 8].copy_from_slice(&10_u16.to_be_bytes());
+        font[maxp + 28..maxp + 30].copy_from_slice(&1_u16.to_be_bytes());
+        update_checksums(&mut font);
+        let problems = problems(&font);
+        // The maximum with its first glyph.
+        assert_eq!(problems, [
+            Problem::LimitExceeded{ field: "maxPoints", limit: 10, value: 852, index: 3802 },
+            Problem::LimitExceeded{ field: "maxComponentElements", limit: 1, value: 8, index: 4442 },
+        ]);
+        assert_eq!(problems[0].to_string(), "maxp maxPoints is 10 but glyph 3802 has 852");
+    }
+
+    #[test]
+    fn test_unparsable_name() {
+        // The offset of the first string past the end of the table, even with the u16 sum wrapping.
+        let mut font = FONT.to_vec();
+        let name = table(&font, b"name");
+        font[name + 16..name + 18].copy_from_slice(&0xFFFF_u16.to_be_bytes());
+        update_checksums(&mut font);
+        let problems = problems(&font);
+        assert_eq!(problems, [Problem::Unparsable{ part: String::from("font"), offset: 677120, context: String::from("String") }]);
+        assert_eq!(problems[0].to_string(), "couldn't parse font at byte 677120: String");
+    }
+
+    #[test]
+    fn test_unparsable_cff() {
+        // A zero end offset for the font name of the first face, the second one having its own table.
+        let mut collection = include_bytes!("../../resources/DejaVuSansCFF.ttc").to_vec();
+        assert_eq!(validate(&collection), [vec!(), vec!()]);
+        let font = read32(&collection, 12) as usize;
+        let cff = (0..u16::from_be_bytes([collection[font + 4], collection[font + 5]]))
+            .map(|k| font + 12 + 16 * usize::from(k))
+            .find(|&e| &collection[e..e + 4] == b"CFF ")
+            .map(|e| read32(&collection, e + 8) as usize)
+            .unwrap();
+        let names = cff + usize::from(collection[cff + 2]);
+        assert_eq!(collection[names..names + 5], [0, 1, 1, 1, 14]);
+        collection[names + 4] = 0;
+        let problems = validate(&collection);
+        assert_eq!(problems[0][1], Problem::Unparsable{ part: String::from("font"), offset: names + 5, context: String::from("Offsets in Name INDEX") });
+        assert!(matches!(&problems[0][0], Problem::WrongChecksum{ tag, .. } if tag == "CFF "));
+        assert_eq!((problems[0].len(), problems[1].len()), (2, 0));
+    }
+}